@@ -0,0 +1,125 @@
+//! Entry points for embedding crowbar's Okta to AWS flow in other tools.
+//! Nothing in here reads from stdin, all questions are asked and the progress
+//! of waits shown through the given [`Prompter`](crate::prompt::Prompter).
+//! The password and device trust of a login are only cached in the keyring
+//! by [`OktaSession::remember_login`].
+
+use crate::config::app::AppProfile;
+use crate::credentials::aws::AwsCredentials;
+use crate::prompt::{self, Prompter};
+use crate::providers::okta::{self, OktaProvider};
+use crate::providers::ProviderType;
+
+use anyhow::{anyhow, Context, Result};
+use std::rc::Rc;
+
+pub use crate::providers::okta::OktaSession;
+
+/// Logs into Okta, including MFA, and returns the resulting session
+pub fn authenticate(profile: &AppProfile, prompter: Rc<dyn Prompter>) -> Result<OktaSession> {
+    ensure_okta(profile)?;
+
+    prompt::with_prompter(prompter, || new_session(profile))
+}
+
+/// Exchanges an Okta session for temporary AWS credentials of the profile's role.
+/// If Okta turns down a stale session, it is replaced by logging in again, once.
+/// Call `remember_login` afterwards to cache that login as well.
+/// The credentials can be given to rusoto clients as their credentials provider
+pub fn get_aws_credentials(
+    session: &mut OktaSession,
+    profile: &AppProfile,
+    prompter: Rc<dyn Prompter>,
+) -> Result<AwsCredentials> {
+    ensure_okta(profile)?;
+
+    prompt::with_prompter(prompter, || {
+        retry_with_new_session(
+            session,
+            |session| session.provider(profile)?.fetch_aws_credentials(),
+            || new_session(profile),
+        )
+    })
+}
+
+fn new_session(profile: &AppProfile) -> Result<OktaSession> {
+    let mut provider = OktaProvider::new(profile)?;
+    provider.new_session()?;
+    provider
+        .into_session()
+        .with_context(|| "Okta didn't return a session token")
+}
+
+fn retry_with_new_session<T, F, L>(session: &mut OktaSession, fetch: F, login: L) -> Result<T>
+where
+    F: Fn(&OktaSession) -> Result<T>,
+    L: FnOnce() -> Result<OktaSession>,
+{
+    match fetch(session) {
+        Err(e) if okta::needs_login(&e) => {
+            debug!("Okta session is stale, logging in again: {}", e);
+            *session = login()?;
+            fetch(session)
+        }
+        result => result,
+    }
+}
+
+fn ensure_okta(profile: &AppProfile) -> Result<()> {
+    match profile.provider {
+        ProviderType::Okta => Ok(()),
+        _ => Err(anyhow!(
+            "Profile {} doesn't use Okta, only Okta profiles are supported",
+            profile.name
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::providers::okta::SignInPage;
+    use std::cell::Cell;
+
+    #[test]
+    fn logs_in_again_for_stale_session() -> Result<()> {
+        let mut session = OktaSession::new("stale".to_string());
+        let logins = Cell::new(0);
+
+        let token = retry_with_new_session(
+            &mut session,
+            |session| match session.session_token.as_str() {
+                "stale" => Err(SignInPage.into()),
+                token => Ok(token.to_string()),
+            },
+            || {
+                logins.set(logins.get() + 1);
+                Ok(OktaSession::new("fresh".to_string()))
+            },
+        )?;
+
+        assert_eq!("fresh", token);
+        assert_eq!("fresh", session.session_token);
+        assert_eq!(1, logins.get());
+
+        Ok(())
+    }
+
+    #[test]
+    fn retries_only_once() {
+        let mut session = OktaSession::new("stale".to_string());
+        let fetches = Cell::new(0);
+
+        let result: Result<()> = retry_with_new_session(
+            &mut session,
+            |_| {
+                fetches.set(fetches.get() + 1);
+                Err(SignInPage.into())
+            },
+            || Ok(OktaSession::new("also stale".to_string())),
+        );
+
+        assert!(result.is_err());
+        assert_eq!(2, fetches.get());
+    }
+}
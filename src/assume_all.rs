@@ -0,0 +1,675 @@
+//! Assumes every role an assertion grants after a single login, storing the
+//! credentials of each under a name of its own, e.g.
+//! `work-123456789012-admin`. Every name gets a profile in the AWS CLI
+//! configuration reading them back with `crowbar get`.
+
+use crate::aws::accounts::AccountNames;
+use crate::aws::duration::{self, SessionDuration};
+use crate::aws::role::{self as RoleManager, Role};
+use crate::clock;
+use crate::config::app::AppProfile;
+use crate::config::aws::AwsConfig;
+use crate::credentials::aws::AwsCredentials;
+use crate::credentials::Credential;
+use crate::error::{self, ErrorKind};
+use crate::output::SummaryFormat;
+use crate::saml;
+use crate::ui;
+use crate::utils::{self, RoleOptions};
+
+use anyhow::{anyhow, Context, Result};
+use regex::Regex;
+use rusoto_sts::AssumeRoleWithSAMLResponse;
+use std::collections::HashMap;
+use std::panic;
+use std::thread;
+
+pub const DEFAULT_NAME_TEMPLATE: &str = "{profile}-{account}-{role}";
+// `{account_alias}` and `{role_name}` are there for templates shared with
+// tools using those names
+const PLACEHOLDERS: [&str; 6] = [
+    "{profile}",
+    "{account}",
+    "{account_alias}",
+    "{account_id}",
+    "{role}",
+    "{role_name}",
+];
+// STS throttles AssumeRoleWithSAML, so only a few roles are assumed at once
+const MAX_PARALLEL_ASSUMPTIONS: usize = 4;
+
+/// A granted role along with the name its credentials are stored under
+#[derive(Debug)]
+pub struct Assumed {
+    pub name: String,
+    pub role: Role,
+    // The name of the role's account in the account_map, otherwise its id
+    pub account: String,
+    pub credentials: Result<AwsCredentials>,
+    // The duration STS accepted, to remember for `duration = "max"`
+    seconds: Option<i64>,
+}
+
+/// Assumes every role of the assertion in the HTML form `input` that matches
+/// the role filter of the profile, naming each after `template`
+pub fn assume_all(profile: &AppProfile, input: &str, template: &str) -> Result<Vec<Assumed>> {
+    let saml = saml::extract_saml_assertion(input)?;
+    saml.conditions.check(clock::now())?;
+
+    let options = RoleOptions::from(profile);
+    let session_limit = saml.session_duration;
+    let roles = utils::matching_roles(saml.roles, &options)?;
+    let requests = roles
+        .into_iter()
+        .map(|role| {
+            let candidates = duration::candidates(&role, options.duration, session_limit);
+            (role, candidates)
+        })
+        .collect();
+
+    let raw = saml.raw;
+    assume_roles(
+        &profile.name,
+        template,
+        &options.accounts,
+        requests,
+        |role, seconds| {
+            RoleManager::assume_role(
+                role,
+                raw.clone(),
+                seconds,
+                options.region.as_ref(),
+                options.global_sts,
+                options.sts_endpoint_url.as_deref(),
+            )
+        },
+    )
+}
+
+/// Fails for a template with anything in braces but the placeholders
+/// `profile_name` replaces, so a typo doesn't end up in every name
+pub fn check_template(template: &str) -> Result<()> {
+    let placeholder = Regex::new(r"\{[^{}]*\}").unwrap();
+
+    if let Some(unknown) = placeholder
+        .find_iter(template)
+        .map(|m| m.as_str())
+        .find(|p| !PLACEHOLDERS.contains(p))
+    {
+        return Err(error::error(
+            ErrorKind::Config,
+            format!(
+                "Unknown placeholder {} in the name template {}, use {}",
+                unknown,
+                template,
+                PLACEHOLDERS.join(", ")
+            ),
+        ));
+    }
+
+    if placeholder
+        .replace_all(template, "")
+        .contains(&['{', '}'][..])
+    {
+        return Err(error::error(
+            ErrorKind::Config,
+            format!("Unbalanced braces in the name template {}", template),
+        ));
+    }
+
+    Ok(())
+}
+
+/// The name the credentials of `role` are stored under, replacing
+/// `{profile}`, `{account}` and `{role}` in the template. `{account}` or
+/// `{account_alias}` is the name of the account in the profile's
+/// account_map, otherwise its id like `{account_id}`. `{role_name}` is the
+/// same as `{role}`. Names are lowercase, with a dash for spaces and anything
+/// else that doesn't belong in the name of an AWS profile
+pub fn profile_name(template: &str, profile: &str, role: &Role, accounts: &AccountNames) -> String {
+    let account = accounts.name(role.account_id());
+
+    template
+        .replace("{profile}", profile)
+        .replace("{account_id}", role.account_id())
+        .replace("{account_alias}", account)
+        .replace("{account}", account)
+        .replace("{role_name}", role.name())
+        .replace("{role}", role.name())
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | '0'..='9' | '-' | '_' | '.' => c,
+            'A'..='Z' => c.to_ascii_lowercase(),
+            _ => '-',
+        })
+        .collect()
+}
+
+/// Stores the credentials of every assumed role under its name and points a
+/// profile of that name in the AWS CLI configuration at them, then sums up
+/// every role in `format`. Fails once the others are stored if any role
+/// couldn't be assumed
+pub fn store(
+    profile: &AppProfile,
+    assumed: Vec<Assumed>,
+    aws_config: AwsConfig,
+    format: SummaryFormat,
+) -> Result<()> {
+    let summary = assumed.iter().map(SummaryEntry::new).collect::<Vec<_>>();
+    let mut aws_config = aws_config;
+
+    for assumed in assumed {
+        if let Ok(credentials) = assumed.credentials {
+            if let (Some(SessionDuration::Max), Some(seconds)) = (profile.duration, assumed.seconds)
+            {
+                duration::remember_max(&assumed.role, seconds);
+            }
+
+            credentials.write(&AppProfile {
+                name: assumed.name.clone(),
+                ..profile.clone()
+            })?;
+            aws_config = aws_config.install_stored(&assumed.name)?;
+        }
+    }
+
+    aws_config.write()?;
+
+    match format {
+        SummaryFormat::Table => print!("{}", table(&summary)),
+        SummaryFormat::Json => println!("{}", serde_json::to_string_pretty(&summary)?),
+    }
+
+    match summary.iter().filter(|entry| entry.error.is_some()).count() {
+        0 => Ok(()),
+        failed => Err(error::error(
+            ErrorKind::Aws,
+            format!("{} of {} roles couldn't be assumed", failed, summary.len()),
+        )),
+    }
+}
+
+// One role of the summary, `status` is either ok or failed
+#[derive(Serialize, Debug, PartialEq)]
+struct SummaryEntry {
+    profile: String,
+    account: String,
+    role: String,
+    role_arn: String,
+    expiration: Option<String>,
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_kind: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl SummaryEntry {
+    fn new(assumed: &Assumed) -> SummaryEntry {
+        let (expiration, error) = match &assumed.credentials {
+            Ok(credentials) => (credentials.expiration.clone(), None),
+            Err(e) => (None, Some(e)),
+        };
+
+        SummaryEntry {
+            profile: assumed.name.clone(),
+            account: assumed.account.clone(),
+            role: assumed.role.name().to_string(),
+            role_arn: assumed.role.role_arn.clone(),
+            expiration,
+            status: if error.is_some() { "failed" } else { "ok" },
+            error_kind: error.map(|e| error::kind_of(e).to_string()),
+            error: error.map(|e| format!("{:#}", e)),
+        }
+    }
+}
+
+// The summary in aligned columns, with the errors of failed roles below
+fn table(summary: &[SummaryEntry]) -> String {
+    let rows = summary
+        .iter()
+        .map(|entry| {
+            vec![
+                entry.profile.clone(),
+                entry.account.clone(),
+                entry.role.clone(),
+                entry.expiration.clone().unwrap_or_else(|| "-".to_string()),
+                match &entry.error_kind {
+                    Some(kind) => ui::error(&format!("FAILED ({})", kind)),
+                    None => ui::success("OK"),
+                },
+            ]
+        })
+        .collect::<Vec<_>>();
+
+    let mut table = ui::table(&["PROFILE", "ACCOUNT", "ROLE", "EXPIRES", "STATUS"], &rows);
+    for entry in summary {
+        if let Some(error) = &entry.error {
+            table.push_str(&format!("{}: {}\n", entry.profile, error));
+        }
+    }
+
+    table
+}
+
+fn assume_roles<F>(
+    profile: &str,
+    template: &str,
+    accounts: &AccountNames,
+    requests: Vec<(Role, Vec<Option<i64>>)>,
+    assume: F,
+) -> Result<Vec<Assumed>>
+where
+    F: Fn(&Role, Option<i64>) -> Result<AssumeRoleWithSAMLResponse> + Sync,
+{
+    check_template(template)?;
+    if requests.is_empty() {
+        return Err(anyhow!("No roles were granted by the SAML assertion"));
+    }
+
+    let named = requests
+        .into_iter()
+        .map(|(role, candidates)| {
+            (
+                profile_name(template, profile, &role, accounts),
+                role,
+                candidates,
+            )
+        })
+        .collect::<Vec<(String, Role, Vec<Option<i64>>)>>();
+    check_unique(template, &named)?;
+
+    Ok(in_parallel(
+        named,
+        MAX_PARALLEL_ASSUMPTIONS,
+        |(name, role, candidates)| {
+            let account = accounts.name(role.account_id()).to_string();
+            let result = duration::assume_first(&candidates, |seconds| assume(&role, seconds))
+                .and_then(|(seconds, response)| {
+                    let credentials = response.credentials.with_context(|| {
+                        "Error fetching credentials for the role from the assumption response"
+                    })?;
+                    Ok((seconds, AwsCredentials::from(credentials)))
+                });
+
+            let (seconds, credentials) = match result {
+                Ok((seconds, credentials)) => (seconds, Ok(credentials)),
+                Err(e) => (None, Err(e)),
+            };
+
+            Assumed {
+                name,
+                role,
+                account,
+                credentials,
+                seconds,
+            }
+        },
+    ))
+}
+
+fn check_unique(template: &str, named: &[(String, Role, Vec<Option<i64>>)]) -> Result<()> {
+    let mut roles_by_name: HashMap<&str, Vec<&str>> = HashMap::new();
+    for (name, role, _) in named {
+        roles_by_name
+            .entry(name)
+            .or_default()
+            .push(role.role_arn.as_str());
+    }
+
+    match named
+        .iter()
+        .find(|(name, _, _)| roles_by_name[name.as_str()].len() > 1)
+    {
+        Some((name, _, _)) => Err(error::error(
+            ErrorKind::Config,
+            format!(
+                "The name template {} names the roles {} all {}, add {{account}} or {{role}} to it",
+                template,
+                roles_by_name[name.as_str()].join(", "),
+                name
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
+// Runs `f` on at most `limit` items at a time, keeping the order of the items
+fn in_parallel<T, R, F>(items: Vec<T>, limit: usize, f: F) -> Vec<R>
+where
+    T: Send,
+    R: Send,
+    F: Fn(T) -> R + Sync,
+{
+    let f = &f;
+    let mut results = Vec::with_capacity(items.len());
+    let mut items = items.into_iter().peekable();
+
+    while items.peek().is_some() {
+        let batch = items.by_ref().take(limit).collect::<Vec<T>>();
+
+        thread::scope(|scope| {
+            let handles = batch
+                .into_iter()
+                .map(|item| scope.spawn(move || f(item)))
+                .collect::<Vec<_>>();
+
+            results.extend(
+                handles
+                    .into_iter()
+                    .map(|h| h.join().unwrap_or_else(|e| panic::resume_unwind(e))),
+            );
+        });
+    }
+
+    results
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::aws::accounts::AccountMap;
+    use crate::config::app::test::{okta_profile, OKTA_URL};
+    use crate::credentials::aws::stored_role_credentials;
+    use crate::credentials::keystore::{self, test::MemoryBackend};
+    use claim::assert_ok;
+    use ini::Ini;
+    use rusoto_sts::Credentials;
+    use std::rc::Rc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn stores_every_role_under_its_own_profile() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let location = dir.path().join("config");
+        let profile = okta_profile("work", OKTA_URL);
+        let roles = [
+            "arn:aws:iam::123456789012:role/Admin",
+            "arn:aws:iam::123456789012:role/ReadOnly",
+            "arn:aws:iam::210987654321:role/path/Admin",
+        ];
+
+        keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+            let assumed = assume_roles(
+                "work",
+                DEFAULT_NAME_TEMPLATE,
+                &AccountNames::default(),
+                requests(&roles),
+                |role, _| Ok(response(role)),
+            )?;
+            let aws_config = AwsConfig {
+                profiles: Ini::new(),
+                location: location.clone(),
+            };
+            store(&profile, assumed, aws_config, SummaryFormat::Table)?;
+
+            let config = Ini::load_from_file(&location)?;
+            for (name, arn) in &[
+                ("work-123456789012-admin", roles[0]),
+                ("work-123456789012-readonly", roles[1]),
+                ("work-210987654321-admin", roles[2]),
+            ] {
+                let section = format!("profile {}", name);
+                assert_eq!(
+                    Some(format!("crowbar get {}", name).as_str()),
+                    config.get_from(Some(section), "credential_process")
+                );
+                assert_eq!(
+                    Some(format!("token-of-{}", arn)),
+                    stored_role_credentials(name)?.and_then(|c| c.session_token)
+                );
+            }
+            assert_eq!(3, config.sections().count());
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn sums_up_every_attempted_role() -> Result<()> {
+        let mut names = std::collections::BTreeMap::new();
+        names.insert("123456789012".to_string(), "production".to_string());
+        let accounts = AccountNames::load(&AccountMap::Names(names))?;
+        let roles = [
+            "arn:aws:iam::123456789012:role/Admin",
+            "arn:aws:iam::210987654321:role/path/ReadOnly",
+        ];
+
+        let assumed = assume_roles(
+            "work",
+            DEFAULT_NAME_TEMPLATE,
+            &accounts,
+            requests(&roles),
+            |role, _| match role.name() {
+                "Admin" => Ok(response(role)),
+                _ => Err(error::error(ErrorKind::Aws, "Access denied")),
+            },
+        )?;
+        let summary = assumed.iter().map(SummaryEntry::new).collect::<Vec<_>>();
+
+        assert_eq!(
+            serde_json::json!([
+                {
+                    "profile": "work-production-admin",
+                    "account": "production",
+                    "role": "Admin",
+                    "role_arn": roles[0],
+                    "expiration": "2038-01-01T10:10:10Z",
+                    "status": "ok",
+                },
+                {
+                    "profile": "work-210987654321-readonly",
+                    "account": "210987654321",
+                    "role": "ReadOnly",
+                    "role_arn": roles[1],
+                    "expiration": null,
+                    "status": "failed",
+                    "error_kind": "aws",
+                    "error": "Access denied",
+                },
+            ]),
+            serde_json::to_value(&summary)?
+        );
+
+        let table = console::strip_ansi_codes(&table(&summary)).to_string();
+        assert_eq!(
+            vec![
+                "PROFILE                     ACCOUNT       ROLE      EXPIRES               STATUS",
+                "work-production-admin       production    Admin     2038-01-01T10:10:10Z  OK",
+                "work-210987654321-readonly  210987654321  ReadOnly  -                     FAILED (aws)",
+                "work-210987654321-readonly: Access denied",
+            ],
+            table.lines().collect::<Vec<_>>()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn names_profiles_after_mapped_accounts() -> Result<()> {
+        let accounts = AccountNames::load(&AccountMap::File(
+            "tests/fixtures/account_map.toml".to_string(),
+        ))?;
+        let mapped = role("arn:aws:iam::123456789012:role/Admin");
+        let unmapped = role("arn:aws:iam::987654321098:role/Admin");
+
+        assert_eq!(
+            "work-production-admin",
+            profile_name(DEFAULT_NAME_TEMPLATE, "work", &mapped, &accounts)
+        );
+        assert_eq!(
+            "work-987654321098-admin",
+            profile_name(DEFAULT_NAME_TEMPLATE, "work", &unmapped, &accounts)
+        );
+        assert_eq!(
+            "production-123456789012",
+            profile_name("{account}-{account_id}", "work", &mapped, &accounts)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn renders_name_templates() -> Result<()> {
+        let mut names = std::collections::BTreeMap::new();
+        names.insert("123456789012".to_string(), "Production EU".to_string());
+        let accounts = AccountNames::load(&AccountMap::Names(names))?;
+        let mapped = role("arn:aws:iam::123456789012:role/path/PowerUser");
+        let unmapped = role("arn:aws:iam::987654321098:role/Read Only");
+
+        for (template, role, name) in &[
+            (
+                "{account_alias}-{role_name}",
+                &mapped,
+                "production-eu-poweruser",
+            ),
+            ("{account_id}_{role}", &mapped, "123456789012_poweruser"),
+            (
+                "{account_alias}-{role_name}",
+                &unmapped,
+                "987654321098-read-only",
+            ),
+            ("{profile}.{account}", &unmapped, "work.987654321098"),
+        ] {
+            assert_eq!(*name, profile_name(template, "Work", role, &accounts));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_unknown_placeholders() {
+        assert_ok!(check_template(DEFAULT_NAME_TEMPLATE));
+        assert_ok!(check_template("{account_alias}-{role_name}"));
+
+        for (template, message) in &[
+            ("{account}-{rol}", "Unknown placeholder {rol}"),
+            ("{Account}", "Unknown placeholder {Account}"),
+            ("{}-{role}", "Unknown placeholder {}"),
+            ("{account-{role}", "Unbalanced braces"),
+            ("{role}}", "Unbalanced braces"),
+        ] {
+            let error = check_template(template).unwrap_err();
+            assert_eq!(ErrorKind::Config, error::kind_of(&error));
+            assert!(error.to_string().contains(message), "{}", error);
+        }
+    }
+
+    #[test]
+    fn assumes_a_limited_number_of_roles_at_once() -> Result<()> {
+        let roles = (0..10)
+            .map(|i| format!("arn:aws:iam::123456789012:role/Role{}", i))
+            .collect::<Vec<String>>();
+        let running = AtomicUsize::new(0);
+        let most_running = AtomicUsize::new(0);
+
+        let assumed = assume_roles(
+            "work",
+            "{role}",
+            &AccountNames::default(),
+            requests(&roles.iter().map(|r| r.as_str()).collect::<Vec<&str>>()),
+            |role, _| {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                most_running.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(std::time::Duration::from_millis(10));
+                running.fetch_sub(1, Ordering::SeqCst);
+                Ok(response(role))
+            },
+        )?;
+
+        assert_eq!(
+            (0..10)
+                .map(|i| format!("role{}", i))
+                .collect::<Vec<String>>(),
+            assumed
+                .iter()
+                .map(|a| a.name.clone())
+                .collect::<Vec<String>>()
+        );
+        assert!(most_running.load(Ordering::SeqCst) <= MAX_PARALLEL_ASSUMPTIONS);
+
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_template_naming_roles_alike() {
+        let roles = [
+            "arn:aws:iam::123456789012:role/Admin",
+            "arn:aws:iam::210987654321:role/Admin",
+        ];
+
+        let error = assume_roles(
+            "work",
+            "{profile}-{role}",
+            &AccountNames::default(),
+            requests(&roles),
+            |role, _| Ok(response(role)),
+        )
+        .unwrap_err();
+
+        assert_eq!(ErrorKind::Config, error::kind_of(&error));
+        assert!(error.to_string().contains("all work-admin"), "{}", error);
+    }
+
+    #[test]
+    fn stores_other_roles_if_one_fails() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let roles = [
+            "arn:aws:iam::123456789012:role/Admin",
+            "arn:aws:iam::123456789012:role/Denied",
+        ];
+
+        keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+            let assumed = assume_roles(
+                "work",
+                "{role}",
+                &AccountNames::default(),
+                requests(&roles),
+                |role, _| match role.name() {
+                    "Denied" => Err(anyhow!("Access denied")),
+                    _ => Ok(response(role)),
+                },
+            )?;
+            let aws_config = AwsConfig {
+                profiles: Ini::new(),
+                location: dir.path().join("config"),
+            };
+
+            let error = store(
+                &okta_profile("work", OKTA_URL),
+                assumed,
+                aws_config,
+                SummaryFormat::Table,
+            )
+            .unwrap_err();
+            assert_eq!("1 of 2 roles couldn't be assumed", error.to_string());
+            assert!(stored_role_credentials("admin")?.is_some());
+            assert!(stored_role_credentials("denied")?.is_none());
+
+            Ok(())
+        })
+    }
+
+    fn role(arn: &str) -> Role {
+        Role {
+            provider_arn: "arn:aws:iam::123456789012:saml-provider/okta-idp".to_string(),
+            role_arn: arn.to_string(),
+        }
+    }
+
+    fn requests(roles: &[&str]) -> Vec<(Role, Vec<Option<i64>>)> {
+        roles.iter().map(|arn| (role(arn), vec![None])).collect()
+    }
+
+    fn response(role: &Role) -> AssumeRoleWithSAMLResponse {
+        AssumeRoleWithSAMLResponse {
+            credentials: Some(Credentials {
+                access_key_id: "some_key".to_string(),
+                secret_access_key: "some_secret".to_string(),
+                session_token: format!("token-of-{}", role.role_arn),
+                expiration: "2038-01-01T10:10:10Z".to_string(),
+            }),
+            ..Default::default()
+        }
+    }
+}
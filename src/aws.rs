@@ -1 +1,3 @@
+pub mod accounts;
+pub mod duration;
 pub mod role;
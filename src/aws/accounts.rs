@@ -0,0 +1,76 @@
+//! Friendly names of AWS accounts, kept by the user in a file or right in the
+//! profile with `account_map`. The role menu shows them next to the role ARNs
+//! and assume-all names profiles after them, without asking AWS for account
+//! aliases. Accounts without a name keep their id.
+
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::fs;
+
+/// How a profile gives the names of its accounts, either as the path of a
+/// TOML file like `123456789012 = "production"` or as a table of its own
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum AccountMap {
+    File(String),
+    Names(BTreeMap<String, String>),
+}
+
+/// The names of accounts by their id
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct AccountNames(BTreeMap<String, String>);
+
+impl AccountNames {
+    pub fn load(map: &AccountMap) -> Result<AccountNames> {
+        match map {
+            AccountMap::Names(names) => Ok(AccountNames(names.clone())),
+            AccountMap::File(path) => {
+                let content = fs::read_to_string(path)
+                    .with_context(|| format!("Unable to read the account_map {}", path))?;
+                let names = toml::from_str(&content).with_context(|| {
+                    format!(
+                        "The account_map {} needs to map account ids to names, e.g. 123456789012 = \"production\"",
+                        path
+                    )
+                })?;
+
+                Ok(AccountNames(names))
+            }
+        }
+    }
+
+    pub fn get(&self, account_id: &str) -> Option<&str> {
+        self.0.get(account_id).map(|n| n.as_str())
+    }
+
+    /// The name of the account, or its id if it has none
+    pub fn name<'a>(&'a self, account_id: &'a str) -> &'a str {
+        self.get(account_id).unwrap_or(account_id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use claim::assert_err;
+
+    #[test]
+    fn loads_names_from_file_or_profile() -> Result<()> {
+        let from_file = AccountNames::load(&AccountMap::File(
+            "tests/fixtures/account_map.toml".to_string(),
+        ))?;
+        assert_eq!("production", from_file.name("123456789012"));
+        assert_eq!("987654321098", from_file.name("987654321098"));
+
+        let mut names = BTreeMap::new();
+        names.insert("123456789012".to_string(), "production".to_string());
+        names.insert("210987654321".to_string(), "staging".to_string());
+        assert_eq!(from_file, AccountNames::load(&AccountMap::Names(names))?);
+
+        assert_err!(AccountNames::load(&AccountMap::File(
+            "tests/fixtures/missing_account_map.toml".to_string()
+        )));
+
+        Ok(())
+    }
+}
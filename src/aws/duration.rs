@@ -0,0 +1,480 @@
+//! How long the sessions of assumed roles last. With `duration = "max"`
+//! crowbar asks for the longest session a role allows, which STS only tells
+//! by rejecting longer requests, so the maximum found is cached per role.
+//! With `--prompt-duration` and no duration configured, it asks for one.
+
+use crate::aws::role::{DurationTooLong, Role};
+use crate::credentials::keystore;
+use crate::credentials::CredentialType;
+use crate::prompt;
+use crate::ui;
+
+use anyhow::{anyhow, Result};
+use std::convert::TryFrom;
+use std::fmt;
+
+/// What STS grants without a DurationSeconds
+pub const DEFAULT_SECONDS: i64 = 3600;
+pub const MIN_SECONDS: i64 = 900;
+/// The longest MaxSessionDuration a role can have
+pub const MAX_SECONDS: i64 = 43200;
+const MAX_KEYWORD: &str = "max";
+/// The session lengths `--prompt-duration` offers besides a custom one
+const PROMPT_CHOICES: [i64; 4] = [3600, 14400, 28800, 43200];
+const CUSTOM_CHOICE: &str = "custom";
+// The keyring can't list what it keeps, so the roles with a cached maximum
+// are listed under a service of their own for `clean`
+const ROLES_SERVICE: &str = "roles";
+const ROLES_USERNAME: &str = "crowbar";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(try_from = "DurationSetting", into = "DurationSetting")]
+pub enum SessionDuration {
+    Seconds(i64),
+    Max,
+}
+
+// How a duration is written in the configuration file, e.g. `duration = 7200`
+// or `duration = "max"`
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum DurationSetting {
+    Seconds(i64),
+    Keyword(String),
+}
+
+impl TryFrom<DurationSetting> for SessionDuration {
+    type Error = String;
+
+    fn try_from(setting: DurationSetting) -> Result<Self, Self::Error> {
+        match setting {
+            DurationSetting::Seconds(seconds) => Ok(SessionDuration::Seconds(seconds)),
+            DurationSetting::Keyword(keyword) if keyword == MAX_KEYWORD => Ok(SessionDuration::Max),
+            DurationSetting::Keyword(keyword) => Err(format!(
+                "the duration {} is neither a number of seconds nor \"{}\"",
+                keyword, MAX_KEYWORD
+            )),
+        }
+    }
+}
+
+impl From<SessionDuration> for DurationSetting {
+    fn from(duration: SessionDuration) -> Self {
+        match duration {
+            SessionDuration::Seconds(seconds) => DurationSetting::Seconds(seconds),
+            SessionDuration::Max => DurationSetting::Keyword(MAX_KEYWORD.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for SessionDuration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SessionDuration::Seconds(seconds) => write!(f, "{}", seconds),
+            SessionDuration::Max => write!(f, "{}", MAX_KEYWORD),
+        }
+    }
+}
+
+impl SessionDuration {
+    /// Why STS would refuse the duration, if it would
+    pub fn problem(&self) -> Option<String> {
+        match self {
+            SessionDuration::Seconds(seconds) if !(MIN_SECONDS..=MAX_SECONDS).contains(seconds) => {
+                Some(format!(
+                    "the duration {} isn't between {} and {} seconds",
+                    seconds, MIN_SECONDS, MAX_SECONDS
+                ))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Assumes `role` with the configured duration. For `max` that is the cached
+/// maximum of the role or else the longest session the assertion allows,
+/// falling back to the default if STS finds it too long
+pub fn assume_with<T, F>(
+    role: &Role,
+    duration: Option<SessionDuration>,
+    session_limit: Option<i64>,
+    assume: F,
+) -> Result<T>
+where
+    F: Fn(Option<i64>) -> Result<T>,
+{
+    let (seconds, result) = assume_first(&candidates(role, duration, session_limit), assume)?;
+
+    if let (Some(SessionDuration::Max), Some(seconds)) = (duration, seconds) {
+        remember_max(role, seconds);
+    }
+
+    Ok(result)
+}
+
+/// The durations to request for `role` in the order to try them, only more
+/// than one for `max`
+pub fn candidates(
+    role: &Role,
+    duration: Option<SessionDuration>,
+    session_limit: Option<i64>,
+) -> Vec<Option<i64>> {
+    match duration {
+        None => vec![None],
+        Some(SessionDuration::Seconds(seconds)) => vec![Some(seconds)],
+        Some(SessionDuration::Max) => max_candidates(cached_max(role), session_limit)
+            .into_iter()
+            .map(Some)
+            .collect(),
+    }
+}
+
+/// Asks for the length of the session of `role`, unless the role allows no
+/// more than the shortest session anyway. Lengths beyond the longest the role
+/// is known to allow are clamped to it with a warning
+pub fn ask(role: &Role, session_limit: Option<i64>) -> Result<Option<SessionDuration>> {
+    let longest = longest(cached_max(role), session_limit);
+    if longest <= MIN_SECONDS {
+        return Ok(None);
+    }
+
+    let prompter = prompt::current();
+    let items = PROMPT_CHOICES
+        .iter()
+        .map(|seconds| format!("{}h", seconds / 3600))
+        .chain(std::iter::once(CUSTOM_CHOICE.to_string()))
+        .collect::<Vec<String>>();
+    let index = prompter.select(&format!("Session length of {}", role.role_arn), &items)?;
+    let seconds = match PROMPT_CHOICES.get(index) {
+        Some(seconds) => *seconds,
+        None => parse_length(&prompter.input("Session length, e.g. 90m or 2h")?)?,
+    };
+
+    Ok(Some(SessionDuration::Seconds(clamp(seconds, longest))))
+}
+
+fn clamp(seconds: i64, longest: i64) -> i64 {
+    let clamped = seconds.max(MIN_SECONDS).min(longest);
+
+    if clamped != seconds {
+        eprintln!(
+            "{}",
+            ui::warning(&format!(
+                "Sessions of the role last between {} and {} seconds, using {} instead of {}",
+                MIN_SECONDS, longest, clamped, seconds
+            ))
+        );
+    }
+
+    clamped
+}
+
+// Plain numbers are seconds
+fn parse_length(s: &str) -> Result<i64> {
+    let s = s.trim();
+    let (value, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => s.split_at(index),
+        None => (s, "s"),
+    };
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        _ => 0,
+    };
+
+    match value.parse::<i64>() {
+        Ok(value) if seconds > 0 => Ok(value * seconds),
+        _ => Err(anyhow!(
+            "The session length needs to be a number of seconds, minutes or hours like 5400, 90m or 2h, got {}",
+            s
+        )),
+    }
+}
+
+/// Tries the durations in order as long as STS finds them too long, along
+/// with the one it accepted. Unlike [`assume_with`] this doesn't touch the
+/// keyring, so roles can be assumed on other threads
+pub fn assume_first<T, F>(candidates: &[Option<i64>], assume: F) -> Result<(Option<i64>, T)>
+where
+    F: Fn(Option<i64>) -> Result<T>,
+{
+    let mut last_error = None;
+    for seconds in candidates {
+        match assume(*seconds) {
+            Ok(result) => return Ok((*seconds, result)),
+            Err(e) if e.is::<DurationTooLong>() => {
+                debug!("{}, trying a shorter session", e);
+                last_error = Some(e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(last_error.expect("There is always a duration to try"))
+}
+
+// The longest duration first, the default last, so that `max` works even if
+// the cached maximum got lowered in the meantime
+fn max_candidates(cached: Option<i64>, session_limit: Option<i64>) -> Vec<i64> {
+    let longest = longest(cached, session_limit);
+
+    match longest > DEFAULT_SECONDS {
+        true => vec![longest, DEFAULT_SECONDS],
+        false => vec![longest],
+    }
+}
+
+// The longest session a role is known to allow
+fn longest(cached: Option<i64>, session_limit: Option<i64>) -> i64 {
+    cached
+        .or(session_limit)
+        .unwrap_or(MAX_SECONDS)
+        .min(MAX_SECONDS)
+}
+
+// Durations aren't secrets, but the keyring is the only place crowbar keeps
+// anything between runs
+fn service(role: &Role) -> String {
+    CredentialType::Duration.service(&role.role_arn)
+}
+
+fn cached_roles() -> Vec<String> {
+    keystore::get_cached(
+        &CredentialType::Duration.service(&ROLES_SERVICE),
+        ROLES_USERNAME,
+    )
+    .ok()
+    .and_then(|roles| serde_json::from_str(&roles).ok())
+    .unwrap_or_default()
+}
+
+fn store_cached_roles(roles: &[String]) -> Result<()> {
+    keystore::set_password(
+        &CredentialType::Duration.service(&ROLES_SERVICE),
+        ROLES_USERNAME,
+        &serde_json::to_string(roles)?,
+    )
+}
+
+fn cached_max(role: &Role) -> Option<i64> {
+    keystore::get_cached(&service(role), role.account_id())
+        .ok()
+        .and_then(|seconds| seconds.parse().ok())
+}
+
+pub fn remember_max(role: &Role, seconds: i64) {
+    if cached_max(role) != Some(seconds) {
+        let _ = keystore::set_password(&service(role), role.account_id(), &seconds.to_string())
+            .map_err(|e| debug!("Couldn't cache the session duration: {}", e));
+    }
+
+    let mut roles = cached_roles();
+    if !roles.contains(&role.role_arn) {
+        roles.push(role.role_arn.clone());
+        let _ = store_cached_roles(&roles)
+            .map_err(|e| debug!("Couldn't list the role of the session duration: {}", e));
+    }
+}
+
+/// Drops the cached maximum of every role. Returns whether there were any
+pub fn forget_all() -> bool {
+    let roles = cached_roles();
+
+    for role_arn in &roles {
+        let role = Role {
+            provider_arn: String::new(),
+            role_arn: role_arn.clone(),
+        };
+        let _ = keystore::delete_password(&service(&role), role.account_id())
+            .map_err(|e| debug!("Couldn't remove the session duration: {}", e));
+    }
+    let _ = keystore::delete_password(
+        &CredentialType::Duration.service(&ROLES_SERVICE),
+        ROLES_USERNAME,
+    );
+
+    !roles.is_empty()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::credentials::keystore::test::MemoryBackend;
+    use crate::prompt::test::ScriptedPrompter;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn parses_seconds_and_max() -> Result<()> {
+        #[derive(Deserialize)]
+        struct Profile {
+            duration: SessionDuration,
+        }
+
+        let seconds: Profile = toml::from_str("duration = 7200")?;
+        let max: Profile = toml::from_str(r#"duration = "max""#)?;
+
+        assert_eq!(SessionDuration::Seconds(7200), seconds.duration);
+        assert_eq!(SessionDuration::Max, max.duration);
+        assert!(toml::from_str::<Profile>(r#"duration = "forever""#).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_durations_sts_rejects() {
+        assert!(SessionDuration::Seconds(600).problem().is_some());
+        assert!(SessionDuration::Seconds(86400).problem().is_some());
+        assert_eq!(None, SessionDuration::Seconds(7200).problem());
+        assert_eq!(None, SessionDuration::Max.problem());
+    }
+
+    #[test]
+    fn resolves_max_to_cached_limit_of_role() -> Result<()> {
+        let role = create_role();
+
+        keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+            remember_max(&role, 14400);
+            let requested = RefCell::new(vec![]);
+
+            assume_with(&role, Some(SessionDuration::Max), Some(43200), |seconds| {
+                requested.borrow_mut().push(seconds);
+                Ok(())
+            })?;
+
+            assert_eq!(vec![Some(14400)], *requested.borrow());
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn falls_back_to_default_for_unknown_limit() -> Result<()> {
+        let role = create_role();
+
+        keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+            let requested = RefCell::new(vec![]);
+
+            assume_with(&role, Some(SessionDuration::Max), None, |seconds| {
+                requested.borrow_mut().push(seconds);
+                match seconds {
+                    Some(seconds) if seconds > 7200 => Err(too_long(&role).into()),
+                    _ => Ok(()),
+                }
+            })?;
+
+            assert_eq!(vec![Some(43200), Some(3600)], *requested.borrow());
+            assert_eq!(Some(3600), cached_max(&role));
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn only_falls_back_for_too_long_durations() {
+        let role = create_role();
+
+        keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+            let attempts = RefCell::new(0);
+
+            let result: Result<()> = assume_with(&role, Some(SessionDuration::Max), None, |_| {
+                *attempts.borrow_mut() += 1;
+                Err(anyhow::anyhow!("Access denied"))
+            });
+
+            assert!(result.is_err());
+            assert_eq!(1, *attempts.borrow());
+            assert_eq!(None, cached_max(&role));
+        })
+    }
+
+    #[test]
+    fn requests_configured_seconds_as_is() -> Result<()> {
+        let role = create_role();
+
+        assert_eq!(None, assume_with(&role, None, Some(43200), Ok)?);
+        assert_eq!(
+            Some(7200),
+            assume_with(&role, Some(SessionDuration::Seconds(7200)), None, Ok)?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn clamps_max_to_assertion_limit() {
+        assert_eq!(vec![21600, 3600], max_candidates(None, Some(21600)));
+        assert_eq!(vec![43200, 3600], max_candidates(None, Some(86400)));
+        assert_eq!(vec![7200, 3600], max_candidates(Some(7200), Some(21600)));
+        assert_eq!(vec![3600], max_candidates(Some(3600), None));
+    }
+
+    #[test]
+    fn maps_prompted_lengths_to_seconds() -> Result<()> {
+        let role = create_role();
+        let ask_with = |answers: &[&str], session_limit| {
+            let prompter = Rc::new(ScriptedPrompter::new(answers));
+            let duration = prompt::with_prompter(prompter.clone(), || ask(&role, session_limit))?;
+            assert!(prompter.is_done());
+            Ok::<_, anyhow::Error>(duration)
+        };
+
+        keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+            assert_eq!(
+                Some(SessionDuration::Seconds(3600)),
+                ask_with(&["0"], None)?
+            );
+            assert_eq!(
+                Some(SessionDuration::Seconds(28800)),
+                ask_with(&["2"], None)?
+            );
+            assert_eq!(
+                Some(SessionDuration::Seconds(5400)),
+                ask_with(&["4", "90m"], None)?
+            );
+            assert_eq!(
+                Some(SessionDuration::Seconds(7200)),
+                ask_with(&["4", "7200"], None)?
+            );
+            assert!(ask_with(&["4", "2 days"], None).is_err());
+
+            // Longer than the assertion or the cached maximum allow, or
+            // shorter than STS accepts
+            assert_eq!(
+                Some(SessionDuration::Seconds(21600)),
+                ask_with(&["3"], Some(21600))?
+            );
+            // Nothing to choose from
+            assert_eq!(None, ask_with(&[], Some(MIN_SECONDS))?);
+
+            remember_max(&role, 7200);
+            assert_eq!(
+                Some(SessionDuration::Seconds(7200)),
+                ask_with(&["1"], Some(21600))?
+            );
+            assert_eq!(
+                Some(SessionDuration::Seconds(MIN_SECONDS)),
+                ask_with(&["4", "5m"], None)?
+            );
+
+            Ok(())
+        })
+    }
+
+    fn too_long(role: &Role) -> DurationTooLong {
+        DurationTooLong {
+            role_arn: role.role_arn.clone(),
+            message:
+                "The requested DurationSeconds exceeds the MaxSessionDuration set for this role."
+                    .to_string(),
+        }
+    }
+
+    fn create_role() -> Role {
+        Role {
+            provider_arn: "arn:aws:iam::123456789012:saml-provider/okta-idp".to_string(),
+            role_arn: "arn:aws:iam::123456789012:role/role1".to_string(),
+        }
+    }
+}
@@ -1,13 +1,35 @@
-use anyhow::{anyhow, Error, Result};
+use crate::credentials::aws::AwsCredentials;
+use crate::error::{self, ErrorKind};
+use crate::har;
+use crate::timings;
+use crate::ui;
+use crate::utils;
+
+use anyhow::{anyhow, Context, Error, Result};
+use regex::Regex;
 use rusoto_core::request::HttpClient;
-use rusoto_core::Region;
+use rusoto_core::{DispatchSignedRequest, Region, RusotoError};
 use rusoto_credential::StaticProvider;
-use rusoto_sts::{AssumeRoleWithSAMLRequest, AssumeRoleWithSAMLResponse, Sts, StsClient};
+use rusoto_sts::{
+    AssumeRoleWithSAMLRequest, AssumeRoleWithSAMLResponse, GetCallerIdentityRequest,
+    GetFederationTokenRequest, Sts, StsClient,
+};
+use url::Url;
 
 use std::str::FromStr;
-use std::{fmt, str};
+use std::time::Duration;
+use std::{fmt, str, thread};
 use tokio::runtime::Runtime;
 
+// How often a throttled STS request is retried, waiting twice as long each time
+const STS_RETRIES: u32 = 2;
+const STS_BACKOFF: Duration = Duration::from_millis(500);
+// Federated users only get what both their IAM user and the session policy
+// allow, so allowing everything keeps the permissions of the IAM user
+const FEDERATION_POLICY: &str =
+    r#"{"Version":"2012-10-17","Statement":[{"Effect":"Allow","Action":"*","Resource":"*"}]}"#;
+const FEDERATION_NAME_LENGTH: usize = 32;
+
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub struct Role {
     pub provider_arn: String,
@@ -20,6 +42,52 @@ impl fmt::Display for Role {
     }
 }
 
+impl Role {
+    pub fn account_id(&self) -> &str {
+        self.role_arn.split(':').nth(4).unwrap_or_default()
+    }
+
+    /// The name of the role without its path
+    pub fn name(&self) -> &str {
+        self.role_arn.rsplit('/').next().unwrap_or_default()
+    }
+}
+
+/// A role given by its name rather than its ARN, e.g. `Admin`, or
+/// `Admin@123456789012` for the one of that account
+#[derive(Debug, PartialEq)]
+pub struct RoleName<'a> {
+    pub name: &'a str,
+    pub account_id: Option<&'a str>,
+}
+
+impl<'a> RoleName<'a> {
+    /// None for ARNs and anything else that can't be the name of an IAM role
+    pub fn parse(role: &'a str) -> Option<RoleName<'a>> {
+        let valid_name = Regex::new(r"^[\w+=,.@-]{1,64}$").unwrap();
+        // Role names may contain @ themselves, only an account id follows it
+        let (name, account_id) = match role.rsplit_once('@') {
+            Some((name, account_id))
+                if account_id.len() == 12 && account_id.chars().all(|c| c.is_ascii_digit()) =>
+            {
+                (name, Some(account_id))
+            }
+            _ => (role, None),
+        };
+
+        match valid_name.is_match(name) {
+            true => Some(RoleName { name, account_id }),
+            false => None,
+        }
+    }
+
+    /// IAM role names are unique regardless of case, so they match like that
+    pub fn matches(&self, role: &Role) -> bool {
+        role.name().eq_ignore_ascii_case(self.name)
+            && self.account_id.is_none_or(|id| id == role.account_id())
+    }
+}
+
 impl FromStr for Role {
     type Err = anyhow::Error;
 
@@ -38,39 +106,536 @@ impl FromStr for Role {
     }
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Partition {
+    Aws,
+    AwsUsGov,
+    AwsCn,
+}
+
+impl Partition {
+    pub fn from_arn(arn: &str) -> Result<Partition> {
+        match arn.split(':').nth(1) {
+            Some("aws") => Ok(Partition::Aws),
+            Some("aws-us-gov") => Ok(Partition::AwsUsGov),
+            Some("aws-cn") => Ok(Partition::AwsCn),
+            _ => Err(anyhow!("Unable to determine the AWS partition of {}", arn)),
+        }
+    }
+
+    pub fn of_region(region: &Region) -> Partition {
+        match region.name() {
+            name if name.starts_with("us-gov-") => Partition::AwsUsGov,
+            name if name.starts_with("cn-") => Partition::AwsCn,
+            _ => Partition::Aws,
+        }
+    }
+
+    pub fn default_region(self) -> Region {
+        match self {
+            Partition::Aws => Region::UsEast1,
+            Partition::AwsUsGov => Region::UsGovWest1,
+            Partition::AwsCn => Region::CnNorth1,
+        }
+    }
+}
+
+impl fmt::Display for Partition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Partition::Aws => write!(f, "aws"),
+            Partition::AwsUsGov => write!(f, "aws-us-gov"),
+            Partition::AwsCn => write!(f, "aws-cn"),
+        }
+    }
+}
+
+// STS only accepts SAML assertions for roles in its own partition, so a
+// GovCloud or China role has to be assumed against a region of that partition
+pub fn sts_region(role: &Role, configured: Region, explicit: bool) -> Result<Region> {
+    match partition_conflict(role, &configured, explicit)? {
+        Some((region, warning)) => {
+            eprintln!("{}", ui::warning(&warning));
+            Ok(region)
+        }
+        None => Ok(configured),
+    }
+}
+
+// A region given with --region wins unless it is outside the partition of
+// the role, which is worth explaining as the user asked for it explicitly
+fn partition_conflict(
+    role: &Role,
+    configured: &Region,
+    explicit: bool,
+) -> Result<Option<(Region, String)>> {
+    let partition = Partition::from_arn(&role.role_arn)?;
+
+    if Partition::of_region(configured) == partition {
+        return Ok(None);
+    }
+
+    let region = partition.default_region();
+    let warning = match explicit {
+        true => format!(
+            "Warning: --region {} is outside the partition {} of role {}, STS only accepts the role within its partition, using {} instead",
+            configured.name(),
+            partition,
+            role.role_arn,
+            region.name()
+        ),
+        false => format!(
+            "Warning: role {} is in partition {} but region {} is configured, using {} instead",
+            role.role_arn,
+            partition,
+            configured.name(),
+            region.name()
+        ),
+    };
+
+    Ok(Some((region, warning)))
+}
+
+// AWS recommends the regional STS endpoints, the global ones only exist for
+// the commercial and GovCloud partitions and are signed for their home region
+pub fn sts_endpoint(region: &Region, global: bool) -> Region {
+    let (name, endpoint) = match (Partition::of_region(region), global) {
+        (Partition::Aws, true) => ("us-east-1".to_string(), "sts.amazonaws.com".to_string()),
+        (Partition::AwsUsGov, true) => (
+            "us-gov-west-1".to_string(),
+            "sts.us-gov-west-1.amazonaws.com".to_string(),
+        ),
+        (Partition::AwsCn, _) => (
+            region.name().to_string(),
+            format!("sts.{}.amazonaws.com.cn", region.name()),
+        ),
+        (_, false) => (
+            region.name().to_string(),
+            format!("sts.{}.amazonaws.com", region.name()),
+        ),
+    };
+
+    Region::Custom {
+        name,
+        endpoint: format!("https://{}", endpoint),
+    }
+}
+
+/// The STS endpoint crowbar calls in `region`, or the default region, before
+/// the role and with it the partition are known
+pub fn sts_url(region: Option<&Region>, global: bool, endpoint_url: Option<&str>) -> Result<Url> {
+    let region = region.cloned().unwrap_or_default();
+
+    match endpoint_region(region, global, endpoint_url)? {
+        Region::Custom { endpoint, .. } => {
+            Url::parse(&endpoint).with_context(|| format!("Invalid STS endpoint {}", endpoint))
+        }
+        region => Err(anyhow!("No STS endpoint known for {}", region.name())),
+    }
+}
+
+// A configured endpoint, e.g. LocalStack or a private VPC endpoint, replaces
+// the public one. Requests are still signed for the role's region
+fn sts_client_region(
+    role: &Role,
+    region: Option<&Region>,
+    global: bool,
+    endpoint_url: Option<&str>,
+) -> Result<Region> {
+    let region = match region {
+        Some(region) => sts_region(role, region.clone(), true)?,
+        None => sts_region(role, Region::default(), false)?,
+    };
+
+    endpoint_region(region, global, endpoint_url)
+}
+
+fn endpoint_region(region: Region, global: bool, endpoint_url: Option<&str>) -> Result<Region> {
+    match endpoint_url {
+        Some(endpoint) => {
+            let url = Url::parse(endpoint)
+                .ok()
+                .filter(|u| ["http", "https"].contains(&u.scheme()) && u.has_host())
+                .with_context(|| format!("Invalid AWS endpoint URL {}", endpoint))?;
+
+            Ok(Region::Custom {
+                name: region.name().to_string(),
+                endpoint: url.as_str().trim_end_matches('/').to_string(),
+            })
+        }
+        None => Ok(sts_endpoint(&region, global)),
+    }
+}
+
+/// STS refused the role, usually because its trust policy doesn't allow the
+/// SAML provider of the assertion
+#[derive(Debug)]
+pub struct AccessDenied {
+    pub role_arn: String,
+    pub message: String,
+}
+
+impl fmt::Display for AccessDenied {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Access denied assuming role {}: {}",
+            self.role_arn, self.message
+        )
+    }
+}
+
+impl std::error::Error for AccessDenied {}
+
+/// STS refused the requested session duration as longer than the
+/// MaxSessionDuration of the role
+#[derive(Debug)]
+pub struct DurationTooLong {
+    pub role_arn: String,
+    pub message: String,
+}
+
+impl fmt::Display for DurationTooLong {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Session duration too long for role {}: {}",
+            self.role_arn, self.message
+        )
+    }
+}
+
+impl std::error::Error for DurationTooLong {}
+
+// rusoto doesn't model AccessDenied or ValidationError for
+// AssumeRoleWithSAML, so they only show up in the body of an unknown error
+fn access_denied(role: &Role, body: &str) -> Option<AccessDenied> {
+    if !body.contains("<Code>AccessDenied</Code>") {
+        return None;
+    }
+
+    Some(AccessDenied {
+        role_arn: role.role_arn.to_owned(),
+        message: error_message(body)
+            .unwrap_or("Not authorized to perform sts:AssumeRoleWithSAML")
+            .to_string(),
+    })
+}
+
+fn duration_too_long(role: &Role, body: &str) -> Option<DurationTooLong> {
+    if !body.contains("<Code>ValidationError</Code>") || !body.contains("MaxSessionDuration") {
+        return None;
+    }
+
+    Some(DurationTooLong {
+        role_arn: role.role_arn.to_owned(),
+        message: error_message(body)
+            .unwrap_or("The requested DurationSeconds exceeds the MaxSessionDuration")
+            .to_string(),
+    })
+}
+
+// STS answers bursts of requests with Throttling or RequestLimitExceeded,
+// which unlike a denied role is worth waiting out
+fn is_throttled(body: &str) -> bool {
+    body.contains("<Code>Throttling</Code>") || body.contains("<Code>RequestLimitExceeded</Code>")
+}
+
+fn error_message(body: &str) -> Option<&str> {
+    body.split("<Message>")
+        .nth(1)
+        .and_then(|m| m.split("</Message>").next())
+}
+
 pub fn assume_role(
-    Role {
-        provider_arn,
-        role_arn,
-    }: &Role,
+    role: &Role,
     saml_assertion: String,
+    duration_seconds: Option<i64>,
+    region: Option<&Region>,
+    global_sts: bool,
+    endpoint_url: Option<&str>,
 ) -> Result<AssumeRoleWithSAMLResponse, Error> {
+    let region = sts_client_region(role, region, global_sts, endpoint_url)?;
+    assume_role_with(
+        har::Dispatcher::new(HttpClient::new()?),
+        role,
+        saml_assertion,
+        duration_seconds,
+        region,
+    )
+}
+
+fn assume_role_with<D>(
+    dispatcher: D,
+    role: &Role,
+    saml_assertion: String,
+    duration_seconds: Option<i64>,
+    region: Region,
+) -> Result<AssumeRoleWithSAMLResponse, Error>
+where
+    D: DispatchSignedRequest + Send + Sync + 'static,
+{
     let req = AssumeRoleWithSAMLRequest {
-        duration_seconds: None,
+        duration_seconds,
         policy: None,
         policy_arns: None,
-        principal_arn: provider_arn.to_owned(),
-        role_arn: role_arn.to_owned(),
+        principal_arn: role.provider_arn.to_owned(),
+        role_arn: role.role_arn.to_owned(),
         saml_assertion,
     };
 
     let provider = StaticProvider::new_minimal(String::from(""), String::from(""));
-    let client = StsClient::new_with(HttpClient::new()?, provider, Region::default());
+    let client = StsClient::new_with(dispatcher, provider, region);
 
     trace!("Assuming role: {:?}", &req);
 
     let mut runtime = Runtime::new()?;
-    runtime.block_on(async {
-        client
-            .assume_role_with_saml(req)
-            .await
-            .map_err(|e| e.into())
+    let mut retries = 0;
+
+    loop {
+        match runtime.block_on(client.assume_role_with_saml(req.clone())) {
+            Err(RusotoError::Unknown(ref response))
+                if is_throttled(response.body_as_str()) && retries < STS_RETRIES =>
+            {
+                let backoff = STS_BACKOFF * 2u32.pow(retries);
+                retries += 1;
+                debug!(
+                    "STS throttled assuming role {}, retrying in {:?}",
+                    role, backoff
+                );
+                thread::sleep(timings::until_deadline(backoff));
+            }
+            result => {
+                return result.map_err(|e| match e {
+                    RusotoError::Unknown(ref response) => {
+                        let body = response.body_as_str();
+                        match access_denied(role, body) {
+                            Some(denied) => denied.into(),
+                            None => match duration_too_long(role, body) {
+                                Some(too_long) => too_long.into(),
+                                None => e.into(),
+                            },
+                        }
+                    }
+                    e => e.into(),
+                })
+            }
+        }
+    }
+}
+
+/// Who STS takes a set of credentials for
+#[derive(Debug, PartialEq)]
+pub struct CallerIdentity {
+    pub account: String,
+    pub arn: String,
+    pub user_id: String,
+}
+
+impl fmt::Display for CallerIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "account {}, ARN {}, user ID {}",
+            self.account, self.arn, self.user_id
+        )
+    }
+}
+
+/// Calls sts:GetCallerIdentity with the credentials, which fails unless they
+/// work, e.g. because of clock skew. Without a role at hand the endpoint is
+/// the one of the default region
+pub fn caller_identity(
+    credentials: &AwsCredentials,
+    global_sts: bool,
+    endpoint_url: Option<&str>,
+) -> Result<CallerIdentity> {
+    let region = endpoint_region(Region::default(), global_sts, endpoint_url)?;
+    caller_identity_with(
+        har::Dispatcher::new(HttpClient::new()?),
+        credentials,
+        region,
+    )
+}
+
+fn caller_identity_with<D>(
+    dispatcher: D,
+    credentials: &AwsCredentials,
+    region: Region,
+) -> Result<CallerIdentity>
+where
+    D: DispatchSignedRequest + Send + Sync + 'static,
+{
+    let provider = StaticProvider::new(
+        credentials.access_key_id.clone().unwrap_or_default(),
+        credentials.secret_access_key.clone().unwrap_or_default(),
+        credentials.session_token.clone(),
+        None,
+    );
+    let client = StsClient::new_with(dispatcher, provider, region);
+
+    let mut runtime = Runtime::new()?;
+    let response = runtime.block_on(client.get_caller_identity(GetCallerIdentityRequest {}))?;
+
+    Ok(CallerIdentity {
+        account: response.account.unwrap_or_default(),
+        arn: response.arn.unwrap_or_default(),
+        user_id: response.user_id.unwrap_or_default(),
+    })
+}
+
+/// Which STS call crowbar gets credentials with, see `sts_mode`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StsMode {
+    /// Assumes a role granted by the SAML assertion of the IdP
+    #[default]
+    AssumeRoleWithSaml,
+    /// Federates the IAM user whose long-term access keys are in the
+    /// environment, for legacy accounts without a SAML provider
+    FederationToken,
+}
+
+impl fmt::Display for StsMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StsMode::AssumeRoleWithSaml => write!(f, "assume_role_with_saml"),
+            StsMode::FederationToken => write!(f, "federation_token"),
+        }
+    }
+}
+
+/// The STS call to make along with what it's made with
+#[derive(Debug, PartialEq)]
+pub enum StsOperation {
+    AssumeRoleWithSaml(String),
+    GetFederationToken(AwsCredentials),
+}
+
+/// Picks the call of `mode`, failing if what it needs isn't at hand. STS
+/// refuses GetFederationToken with temporary credentials
+pub fn operation(
+    mode: StsMode,
+    saml_assertion: Option<String>,
+    access_keys: Option<AwsCredentials>,
+) -> Result<StsOperation> {
+    match (mode, saml_assertion, access_keys) {
+        (StsMode::AssumeRoleWithSaml, Some(assertion), _) => {
+            Ok(StsOperation::AssumeRoleWithSaml(assertion))
+        }
+        (StsMode::FederationToken, _, Some(keys)) if keys.session_token.is_none() => {
+            Ok(StsOperation::GetFederationToken(keys))
+        }
+        (StsMode::AssumeRoleWithSaml, None, _) => Err(error::error(
+            ErrorKind::Config,
+            "The sts_mode assume_role_with_saml needs a SAML assertion from the IdP",
+        )),
+        (StsMode::FederationToken, _, Some(_)) => Err(error::error(
+            ErrorKind::Config,
+            "The sts_mode federation_token needs long-term access keys of an IAM user, not temporary credentials with AWS_SESSION_TOKEN",
+        )),
+        (StsMode::FederationToken, _, None) => Err(error::error(
+            ErrorKind::Config,
+            "The sts_mode federation_token needs the access keys of an IAM user in AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY",
+        )),
+    }
+}
+
+/// The access keys in the environment, the way the AWS CLI reads them
+pub fn access_keys_from_env() -> Option<AwsCredentials> {
+    Some(AwsCredentials {
+        version: 1,
+        access_key_id: Some(utils::non_empty_env_var("AWS_ACCESS_KEY_ID")?),
+        secret_access_key: Some(utils::non_empty_env_var("AWS_SECRET_ACCESS_KEY")?),
+        session_token: utils::non_empty_env_var("AWS_SESSION_TOKEN"),
+        expiration: None,
     })
 }
 
+/// Calls sts:GetFederationToken with long-term access keys, naming the
+/// federated user after `name`. Without a role at hand the endpoint is the
+/// one of the given or the default region
+pub fn federation_token(
+    access_keys: &AwsCredentials,
+    name: &str,
+    duration_seconds: Option<i64>,
+    region: Option<&Region>,
+    global_sts: bool,
+    endpoint_url: Option<&str>,
+) -> Result<AwsCredentials> {
+    let region = endpoint_region(
+        region.cloned().unwrap_or_default(),
+        global_sts,
+        endpoint_url,
+    )?;
+    federation_token_with(
+        har::Dispatcher::new(HttpClient::new()?),
+        access_keys,
+        name,
+        duration_seconds,
+        region,
+    )
+}
+
+fn federation_token_with<D>(
+    dispatcher: D,
+    access_keys: &AwsCredentials,
+    name: &str,
+    duration_seconds: Option<i64>,
+    region: Region,
+) -> Result<AwsCredentials>
+where
+    D: DispatchSignedRequest + Send + Sync + 'static,
+{
+    let provider = StaticProvider::new_minimal(
+        access_keys.access_key_id.clone().unwrap_or_default(),
+        access_keys.secret_access_key.clone().unwrap_or_default(),
+    );
+    let client = StsClient::new_with(dispatcher, provider, region);
+    let req = GetFederationTokenRequest {
+        duration_seconds,
+        name: federated_user_name(name),
+        policy: Some(FEDERATION_POLICY.to_string()),
+        policy_arns: None,
+        tags: None,
+    };
+
+    trace!("Getting federation token: {:?}", &req);
+
+    let mut runtime = Runtime::new()?;
+    let response = runtime.block_on(client.get_federation_token(req))?;
+
+    Ok(AwsCredentials::from(response.credentials.with_context(
+        || "Error fetching credentials from the federation token response",
+    )?))
+}
+
+// STS only takes up to 32 letters, digits and _=,.@- as the name
+fn federated_user_name(name: &str) -> String {
+    let name = name
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || "_=,.@-".contains(*c))
+        .take(FEDERATION_NAME_LENGTH)
+        .collect::<String>();
+
+    match name.len() {
+        0 | 1 => format!("crowbar{}", name),
+        _ => name,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use claim::assert_err;
+    use reqwest::header::HeaderMap;
+    use reqwest::StatusCode;
+    use rusoto_core::request::{DispatchSignedRequestFuture, HttpResponse};
+    use rusoto_core::signature::{SignedRequest, SignedRequestPayload};
+    use rusoto_core::ByteStream;
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
 
     #[test]
     fn parse_attribute() {
@@ -90,6 +655,620 @@ mod tests {
         )
     }
 
+    #[test]
+    fn splits_role_arn() {
+        let role = Role {
+            provider_arn: "arn:aws:iam::123456789012:saml-provider/okta-idp".to_string(),
+            role_arn: "arn:aws:iam::123456789012:role/path/to/role1".to_string(),
+        };
+
+        assert_eq!("123456789012", role.account_id());
+        assert_eq!("role1", role.name());
+    }
+
+    #[test]
+    fn detects_access_denied() {
+        let body = "<ErrorResponse><Error><Type>Sender</Type><Code>AccessDenied</Code>\
+                    <Message>Not authorized to perform sts:AssumeRoleWithSAML</Message>\
+                    </Error></ErrorResponse>";
+
+        let denied = access_denied(&create_role(), body).unwrap();
+        assert_eq!(
+            "Access denied assuming role arn:aws:iam::123456789012:role/role1: \
+             Not authorized to perform sts:AssumeRoleWithSAML",
+            denied.to_string()
+        );
+        assert!(access_denied(&create_role(), "<Code>ExpiredTokenException</Code>").is_none());
+    }
+
+    #[test]
+    fn detects_too_long_duration() {
+        let body = "<ErrorResponse><Error><Type>Sender</Type><Code>ValidationError</Code>\
+                    <Message>The requested DurationSeconds exceeds the MaxSessionDuration set for this role.</Message>\
+                    </Error></ErrorResponse>";
+
+        let too_long = duration_too_long(&create_role(), body).unwrap();
+        assert_eq!(
+            "Session duration too long for role arn:aws:iam::123456789012:role/role1: \
+             The requested DurationSeconds exceeds the MaxSessionDuration set for this role.",
+            too_long.to_string()
+        );
+        assert!(access_denied(&create_role(), body).is_none());
+        assert!(duration_too_long(&create_role(), "<Code>ValidationError</Code>").is_none());
+    }
+
+    #[test]
+    fn parses_role_names() {
+        assert_eq!(
+            Some(RoleName {
+                name: "Admin",
+                account_id: Some("123456789012")
+            }),
+            RoleName::parse("Admin@123456789012")
+        );
+        assert_eq!(
+            Some(RoleName {
+                name: "ops@example.com",
+                account_id: None
+            }),
+            RoleName::parse("ops@example.com")
+        );
+        assert_eq!(
+            None,
+            RoleName::parse("arn:aws:iam::123456789012:role/Admin")
+        );
+        assert_eq!(None, RoleName::parse("Power User"));
+    }
+
+    #[test]
+    fn infers_partition_from_arn() -> Result<()> {
+        assert_eq!(
+            Partition::Aws,
+            Partition::from_arn("arn:aws:iam::123456789012:role/role1")?
+        );
+        assert_eq!(
+            Partition::AwsUsGov,
+            Partition::from_arn("arn:aws-us-gov:iam::123456789012:role/role1")?
+        );
+        assert_eq!(
+            Partition::AwsCn,
+            Partition::from_arn("arn:aws-cn:iam::123456789012:role/role1")?
+        );
+        assert_err!(Partition::from_arn("role1"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_configured_region_within_partition() -> Result<()> {
+        assert_eq!(
+            Region::EuCentral1,
+            sts_region(&create_role(), Region::EuCentral1, false)?
+        );
+        assert_eq!(
+            Region::UsGovEast1,
+            sts_region(
+                &create_partition_role("aws-us-gov"),
+                Region::UsGovEast1,
+                false
+            )?
+        );
+        assert_eq!(
+            Region::CnNorthwest1,
+            sts_region(
+                &create_partition_role("aws-cn"),
+                Region::CnNorthwest1,
+                false
+            )?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn switches_region_on_partition_mismatch() -> Result<()> {
+        assert_eq!(
+            Region::UsGovWest1,
+            sts_region(
+                &create_partition_role("aws-us-gov"),
+                Region::EuCentral1,
+                false
+            )?
+        );
+        assert_eq!(
+            Region::CnNorth1,
+            sts_region(&create_partition_role("aws-cn"), Region::UsEast1, false)?
+        );
+        assert_eq!(
+            Region::UsEast1,
+            sts_region(&create_role(), Region::CnNorth1, false)?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn explains_region_flag_outside_partition_of_role() -> Result<()> {
+        let (region, warning) =
+            partition_conflict(&create_partition_role("aws-us-gov"), &Region::UsEast1, true)?
+                .unwrap();
+
+        assert_eq!(Region::UsGovWest1, region);
+        assert!(warning.contains("--region us-east-1"), "{}", warning);
+        assert!(
+            warning.contains("using us-gov-west-1 instead"),
+            "{}",
+            warning
+        );
+
+        let (_, warning) = partition_conflict(
+            &create_partition_role("aws-us-gov"),
+            &Region::UsEast1,
+            false,
+        )?
+        .unwrap();
+        assert!(!warning.contains("--region"), "{}", warning);
+
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_region_flag_within_partition_of_role() -> Result<()> {
+        assert_eq!(
+            None,
+            partition_conflict(
+                &create_partition_role("aws-us-gov"),
+                &Region::UsGovEast1,
+                true
+            )?
+        );
+        assert_eq!(
+            Region::EuWest1,
+            sts_client_region(&create_role(), Some(&Region::EuWest1), false, None)?
+                .name()
+                .parse::<Region>()?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn builds_regional_sts_endpoints() {
+        let endpoints = [
+            (
+                Region::EuCentral1,
+                "eu-central-1",
+                "https://sts.eu-central-1.amazonaws.com",
+            ),
+            (
+                Region::UsEast1,
+                "us-east-1",
+                "https://sts.us-east-1.amazonaws.com",
+            ),
+            (
+                Region::UsGovEast1,
+                "us-gov-east-1",
+                "https://sts.us-gov-east-1.amazonaws.com",
+            ),
+            (
+                Region::CnNorthwest1,
+                "cn-northwest-1",
+                "https://sts.cn-northwest-1.amazonaws.com.cn",
+            ),
+        ];
+
+        for (region, name, endpoint) in endpoints.iter() {
+            assert_eq!(
+                Region::Custom {
+                    name: name.to_string(),
+                    endpoint: endpoint.to_string()
+                },
+                sts_endpoint(region, false)
+            );
+        }
+    }
+
+    #[test]
+    fn builds_global_sts_endpoints() {
+        let endpoints = [
+            (Region::EuCentral1, "us-east-1", "https://sts.amazonaws.com"),
+            (
+                Region::UsGovEast1,
+                "us-gov-west-1",
+                "https://sts.us-gov-west-1.amazonaws.com",
+            ),
+            (
+                Region::CnNorth1,
+                "cn-north-1",
+                "https://sts.cn-north-1.amazonaws.com.cn",
+            ),
+        ];
+
+        for (region, name, endpoint) in endpoints.iter() {
+            assert_eq!(
+                Region::Custom {
+                    name: name.to_string(),
+                    endpoint: endpoint.to_string()
+                },
+                sts_endpoint(region, true)
+            );
+        }
+    }
+
+    #[test]
+    fn assumes_role_against_configured_endpoint() -> Result<()> {
+        let dispatcher =
+            RecordingDispatcher::new("tests/fixtures/aws/assume_role_with_saml_response.xml")?;
+        let requests = dispatcher.requests.clone();
+        let region = sts_client_region(&create_role(), None, false, Some("http://localhost:4566"))?;
+
+        let response = assume_role_with(
+            dispatcher,
+            &create_role(),
+            "assertion".to_string(),
+            Some(7200),
+            region,
+        )?;
+        let requests = requests.lock().unwrap();
+        let request = requests.first().unwrap();
+        let parameters = request.body.split('&').collect::<Vec<&str>>();
+
+        assert_eq!(1, requests.len());
+        assert_eq!("http://localhost:4566/", request.endpoint);
+        assert!(parameters.contains(&"Action=AssumeRoleWithSAML"));
+        assert!(parameters.contains(&"RoleArn=arn%3Aaws%3Aiam%3A%3A123456789012%3Arole%2Frole1"));
+        assert!(parameters.contains(
+            &"PrincipalArn=arn%3Aaws%3Aiam%3A%3A123456789012%3Asaml-provider%2Fokta-idp"
+        ));
+        assert!(parameters.contains(&"SAMLAssertion=assertion"));
+        assert!(parameters.contains(&"DurationSeconds=7200"));
+        assert_eq!("ASIAEXAMPLE", response.credentials.unwrap().access_key_id);
+
+        Ok(())
+    }
+
+    #[test]
+    fn selects_sts_operation_by_mode() -> Result<()> {
+        let keys = AwsCredentials {
+            version: 1,
+            access_key_id: Some("AKIAEXAMPLE".to_string()),
+            secret_access_key: Some("secret-access-key".to_string()),
+            session_token: None,
+            expiration: None,
+        };
+
+        assert_eq!(
+            StsOperation::AssumeRoleWithSaml("assertion".to_string()),
+            operation(
+                StsMode::AssumeRoleWithSaml,
+                Some("assertion".to_string()),
+                Some(keys.clone())
+            )?
+        );
+        assert_eq!(
+            StsOperation::GetFederationToken(keys.clone()),
+            operation(
+                StsMode::FederationToken,
+                Some("assertion".to_string()),
+                Some(keys.clone())
+            )?
+        );
+        assert_eq!(StsMode::AssumeRoleWithSaml, StsMode::default());
+
+        // Each mode needs its own input
+        let missing = operation(StsMode::AssumeRoleWithSaml, None, Some(keys.clone())).unwrap_err();
+        assert_eq!(ErrorKind::Config, error::kind_of(&missing));
+        assert_err!(operation(
+            StsMode::FederationToken,
+            Some("assertion".to_string()),
+            None
+        ));
+
+        let temporary = AwsCredentials {
+            session_token: Some("session-token".to_string()),
+            ..keys
+        };
+        let error = operation(StsMode::FederationToken, None, Some(temporary)).unwrap_err();
+        assert!(error.to_string().contains("AWS_SESSION_TOKEN"), "{}", error);
+
+        Ok(())
+    }
+
+    #[test]
+    fn gets_federation_token_with_access_keys() -> Result<()> {
+        let dispatcher =
+            RecordingDispatcher::new("tests/fixtures/aws/get_federation_token_response.xml")?;
+        let requests = dispatcher.requests.clone();
+        let region = endpoint_region(Region::default(), false, Some("http://localhost:4566"))?;
+        let keys = AwsCredentials {
+            version: 1,
+            access_key_id: Some("AKIAEXAMPLE".to_string()),
+            secret_access_key: Some("secret-access-key".to_string()),
+            session_token: None,
+            expiration: None,
+        };
+
+        let credentials =
+            federation_token_with(dispatcher, &keys, "jdoe@example.com", Some(7200), region)?;
+        let requests = requests.lock().unwrap();
+        let request = requests.first().unwrap();
+        let parameters = request.body.split('&').collect::<Vec<&str>>();
+
+        assert!(parameters.contains(&"Action=GetFederationToken"));
+        assert!(parameters.contains(&"Name=jdoe%40example.com"));
+        assert!(parameters.contains(&"DurationSeconds=7200"));
+        assert!(parameters.iter().any(|p| p.starts_with("Policy=")));
+        assert!(request.authorization.contains("Credential=AKIAEXAMPLE/"));
+        assert_eq!(Some("ASIAFEDERATED".to_string()), credentials.access_key_id);
+        assert_eq!(
+            Some("2038-01-01T10:10:10Z".to_string()),
+            credentials.expiration
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn names_federated_users_within_limits() {
+        assert_eq!("jdoe@example.com", federated_user_name("jdoe@example.com"));
+        assert_eq!("JaneDoe", federated_user_name("Jane Doe"));
+        assert_eq!("crowbarj", federated_user_name("j"));
+        assert_eq!(32, federated_user_name(&"a".repeat(40)).len());
+    }
+
+    #[test]
+    fn assumes_roles_of_several_partitions_in_their_own() -> Result<()> {
+        let expected = [
+            ("aws", "https://sts.us-east-1.amazonaws.com/"),
+            ("aws-us-gov", "https://sts.us-gov-west-1.amazonaws.com/"),
+        ];
+
+        // As for assume-all, the same options apply to every role
+        for (partition, endpoint) in expected.iter() {
+            let role = create_partition_role(partition);
+            let dispatcher =
+                RecordingDispatcher::new("tests/fixtures/aws/assume_role_with_saml_response.xml")?;
+            let requests = dispatcher.requests.clone();
+            let region = sts_client_region(&role, Some(&Region::UsEast1), false, None)?;
+
+            assume_role_with(dispatcher, &role, "assertion".to_string(), None, region)?;
+
+            assert_eq!(*endpoint, requests.lock().unwrap()[0].endpoint);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn retries_throttled_role_assumption_once() -> Result<()> {
+        let dispatcher = RecordingDispatcher::with_responses(vec![
+            (StatusCode::BAD_REQUEST, throttling_response()),
+            (
+                StatusCode::OK,
+                fs::read_to_string("tests/fixtures/aws/assume_role_with_saml_response.xml")?,
+            ),
+        ]);
+        let requests = dispatcher.requests.clone();
+        let region = sts_client_region(&create_role(), None, false, Some("http://localhost:4566"))?;
+
+        let response = assume_role_with(
+            dispatcher,
+            &create_role(),
+            "assertion".to_string(),
+            None,
+            region,
+        )?;
+
+        assert_eq!(2, requests.lock().unwrap().len());
+        assert_eq!("ASIAEXAMPLE", response.credentials.unwrap().access_key_id);
+
+        Ok(())
+    }
+
+    #[test]
+    fn fails_fast_on_denied_role_assumption() -> Result<()> {
+        let dispatcher = RecordingDispatcher::with_responses(vec![(
+            StatusCode::FORBIDDEN,
+            "<ErrorResponse><Error><Type>Sender</Type><Code>AccessDenied</Code>\
+             <Message>Not authorized to perform sts:AssumeRoleWithSAML</Message>\
+             </Error></ErrorResponse>"
+                .to_string(),
+        )]);
+        let requests = dispatcher.requests.clone();
+        let region = sts_client_region(&create_role(), None, false, Some("http://localhost:4566"))?;
+
+        let error = assume_role_with(
+            dispatcher,
+            &create_role(),
+            "assertion".to_string(),
+            None,
+            region,
+        )
+        .unwrap_err();
+
+        assert!(error.is::<AccessDenied>());
+        assert_eq!(1, requests.lock().unwrap().len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn gives_up_on_lasting_throttling() -> Result<()> {
+        let dispatcher = RecordingDispatcher::with_responses(vec![(
+            StatusCode::BAD_REQUEST,
+            throttling_response(),
+        )]);
+        let requests = dispatcher.requests.clone();
+        let region = sts_client_region(&create_role(), None, false, Some("http://localhost:4566"))?;
+
+        let result = assume_role_with(
+            dispatcher,
+            &create_role(),
+            "assertion".to_string(),
+            None,
+            region,
+        );
+
+        assert_err!(result);
+        assert_eq!(1 + STS_RETRIES as usize, requests.lock().unwrap().len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_malformed_endpoint() {
+        let error = sts_client_region(&create_role(), None, false, Some("localhost:4566"))
+            .unwrap_err()
+            .to_string();
+
+        assert!(error.contains("Invalid AWS endpoint URL localhost:4566"));
+    }
+
+    #[test]
+    fn probes_sts_endpoint_of_region() -> Result<()> {
+        assert_eq!(
+            "https://sts.eu-west-1.amazonaws.com/",
+            sts_url(Some(&Region::EuWest1), false, None)?.as_str()
+        );
+        assert_eq!(
+            "https://sts.amazonaws.com/",
+            sts_url(Some(&Region::EuWest1), true, None)?.as_str()
+        );
+        assert_eq!(
+            "http://localhost:4566/",
+            sts_url(None, false, Some("http://localhost:4566"))?.as_str()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn verifies_credentials_with_caller_identity() -> Result<()> {
+        let dispatcher =
+            RecordingDispatcher::new("tests/fixtures/aws/get_caller_identity_response.xml")?;
+        let requests = dispatcher.requests.clone();
+        let region = endpoint_region(Region::EuCentral1, false, Some("http://localhost:4566"))?;
+        let credentials = AwsCredentials {
+            version: 1,
+            access_key_id: Some("ASIAEXAMPLE".to_string()),
+            secret_access_key: Some("secret-access-key".to_string()),
+            session_token: Some("session-token".to_string()),
+            expiration: Some("2038-01-01T10:10:10Z".to_string()),
+        };
+
+        let identity = caller_identity_with(dispatcher, &credentials, region)?;
+        let requests = requests.lock().unwrap();
+        let request = requests.first().unwrap();
+
+        assert_eq!(1, requests.len());
+        assert_eq!("http://localhost:4566/", request.endpoint);
+        assert!(request.body.contains("Action=GetCallerIdentity"));
+        assert!(request.authorization.contains("Credential=ASIAEXAMPLE/"));
+        assert!(request.authorization.contains("/eu-central-1/sts/"));
+        assert_eq!(
+            CallerIdentity {
+                account: "123456789012".to_string(),
+                arn: "arn:aws:sts::123456789012:assumed-role/role1/test@example.com".to_string(),
+                user_id: "AROAEXAMPLE:test@example.com".to_string(),
+            },
+            identity
+        );
+        assert_eq!(
+            "account 123456789012, ARN arn:aws:sts::123456789012:assumed-role/role1/test@example.com, \
+             user ID AROAEXAMPLE:test@example.com",
+            identity.to_string()
+        );
+
+        Ok(())
+    }
+
+    struct RecordedRequest {
+        endpoint: String,
+        body: String,
+        authorization: String,
+    }
+
+    // Stands in for STS, recording the endpoint, form body and signature of
+    // each request. The responses are given in order, the last one repeating
+    struct RecordingDispatcher {
+        requests: Arc<Mutex<Vec<RecordedRequest>>>,
+        responses: Mutex<Vec<(StatusCode, String)>>,
+    }
+
+    impl RecordingDispatcher {
+        fn new(response: &str) -> Result<RecordingDispatcher> {
+            Ok(RecordingDispatcher::with_responses(vec![(
+                StatusCode::OK,
+                fs::read_to_string(response)?,
+            )]))
+        }
+
+        fn with_responses(responses: Vec<(StatusCode, String)>) -> RecordingDispatcher {
+            RecordingDispatcher {
+                requests: Arc::new(Mutex::new(vec![])),
+                responses: Mutex::new(responses),
+            }
+        }
+    }
+
+    impl DispatchSignedRequest for RecordingDispatcher {
+        fn dispatch(
+            &self,
+            request: SignedRequest,
+            _timeout: Option<Duration>,
+        ) -> DispatchSignedRequestFuture {
+            let endpoint = format!(
+                "{}://{}{}",
+                request.scheme(),
+                request.hostname(),
+                request.path()
+            );
+            let body = match request.payload {
+                Some(SignedRequestPayload::Buffer(ref bytes)) => {
+                    String::from_utf8_lossy(bytes).to_string()
+                }
+                _ => String::new(),
+            };
+            let authorization = request
+                .headers()
+                .get("authorization")
+                .and_then(|values| values.first())
+                .map(|value| String::from_utf8_lossy(value).to_string())
+                .unwrap_or_default();
+            self.requests.lock().unwrap().push(RecordedRequest {
+                endpoint,
+                body,
+                authorization,
+            });
+
+            let mut responses = self.responses.lock().unwrap();
+            let (status, response) = match responses.len() {
+                1 => responses[0].clone(),
+                _ => responses.remove(0),
+            };
+            Box::pin(async move {
+                Ok(HttpResponse {
+                    status,
+                    body: ByteStream::from(response.into_bytes()),
+                    headers: HeaderMap::default(),
+                })
+            })
+        }
+    }
+
+    fn throttling_response() -> String {
+        "<ErrorResponse><Error><Type>Sender</Type><Code>Throttling</Code>\
+         <Message>Rate exceeded</Message></Error></ErrorResponse>"
+            .to_string()
+    }
+
+    fn create_partition_role(partition: &str) -> Role {
+        Role {
+            provider_arn: format!("arn:{}:iam::123456789012:saml-provider/okta-idp", partition),
+            role_arn: format!("arn:{}:iam::123456789012:role/role1", partition),
+        }
+    }
+
     fn create_role() -> Role {
         Role {
             provider_arn: "arn:aws:iam::123456789012:saml-provider/okta-idp".to_string(),
@@ -0,0 +1,210 @@
+//! Okta AWS app links found among the bookmarks of a browser, for `crowbar
+//! profiles import-bookmarks` to seed a profile with. Browsers export their
+//! bookmarks as Netscape bookmark HTML, Chrome and Firefox also keep them as
+//! JSON. Tile links are turned into embed links like `profiles add` expects.
+
+use crate::prompt;
+use crate::providers::okta;
+
+use anyhow::{anyhow, Context, Result};
+use reqwest::Url;
+use select::document::Document;
+use select::predicate::Name;
+use serde_json::Value;
+use std::fmt;
+use std::fs;
+
+/// A bookmarked link along with the title it was bookmarked under
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bookmark {
+    pub title: String,
+    pub url: Url,
+}
+
+impl fmt::Display for Bookmark {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.title, self.url)
+    }
+}
+
+/// The links of Okta AWS apps among the bookmarks in the export at `path`,
+/// each app once
+pub fn aws_apps(path: &str) -> Result<Vec<Bookmark>> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Unable to read bookmarks {}", path))?;
+    let mut apps: Vec<Bookmark> = vec![];
+
+    for bookmark in parse(&content)? {
+        if let Some(url) = aws_app_link(&bookmark.url) {
+            if !apps.iter().any(|app| app.url == url) {
+                apps.push(Bookmark { url, ..bookmark });
+            }
+        }
+    }
+
+    Ok(apps)
+}
+
+/// Asks which of the bookmarked apps to set up, unless there's only one
+pub fn pick(apps: Vec<Bookmark>) -> Result<Bookmark> {
+    let mut apps = apps;
+
+    match apps.len() {
+        0 => Err(anyhow!("The bookmarks contain no link of an Okta AWS app")),
+        1 => Ok(apps.remove(0)),
+        _ => {
+            let items = apps.iter().map(|app| app.to_string()).collect::<Vec<_>>();
+            let index = prompt::current().select("Which AWS app do you want to add?", &items)?;
+
+            Ok(apps.remove(index))
+        }
+    }
+}
+
+fn parse(content: &str) -> Result<Vec<Bookmark>> {
+    match content.trim_start().chars().next() {
+        Some('{') | Some('[') => {
+            let json = serde_json::from_str::<Value>(content)
+                .with_context(|| "Unable to parse the bookmarks as JSON")?;
+            let mut bookmarks = vec![];
+            collect_json(&json, &mut bookmarks);
+
+            Ok(bookmarks)
+        }
+        _ => Ok(Document::from(content)
+            .find(Name("a"))
+            .filter_map(|a| {
+                Some(Bookmark {
+                    title: a.text().trim().to_string(),
+                    url: Url::parse(a.attr("href")?).ok()?,
+                })
+            })
+            .collect()),
+    }
+}
+
+// Chrome names bookmarks with "name" and "url", Firefox with "title" and
+// "uri", both nest them in folders of "children"
+fn collect_json(value: &Value, bookmarks: &mut Vec<Bookmark>) {
+    match value {
+        Value::Object(object) => {
+            let url = object.get("url").or_else(|| object.get("uri"));
+            let title = object.get("name").or_else(|| object.get("title"));
+
+            match url.and_then(Value::as_str).map(Url::parse) {
+                Some(Ok(url)) => bookmarks.push(Bookmark {
+                    title: title
+                        .and_then(Value::as_str)
+                        .unwrap_or_default()
+                        .to_string(),
+                    url,
+                }),
+                _ => object
+                    .values()
+                    .for_each(|value| collect_json(value, bookmarks)),
+            }
+        }
+        Value::Array(values) => values
+            .iter()
+            .for_each(|value| collect_json(value, bookmarks)),
+        _ => (),
+    }
+}
+
+// Tile and embed links of the AWS app name it amazon_aws, embed links of the
+// `.../app/<label>/<id>/sso/saml` kind only in the label the admin chose,
+// which contains aws for the apps of the Okta Integration Network
+fn aws_app_link(url: &Url) -> Option<Url> {
+    let segments = url
+        .path_segments()?
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<&str>>();
+
+    let is_aws = match segments.as_slice() {
+        ["home", "amazon_aws", _, _] | ["app", "amazon_aws", _, _] => true,
+        ["app", label, _, "sso", "saml"] => label.contains("aws"),
+        _ => false,
+    };
+
+    if !is_aws || url.scheme() != "https" {
+        return None;
+    }
+
+    let mut link = okta::embed_link(url.clone()).ok()?;
+    link.set_query(None);
+    link.set_fragment(None);
+
+    Some(link)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::prompt::test::ScriptedPrompter;
+    use std::rc::Rc;
+
+    #[test]
+    fn finds_aws_apps_in_netscape_export() -> Result<()> {
+        assert_eq!(
+            vec![
+                bookmark(
+                    "AWS Production",
+                    "https://example.okta.com/home/amazon_aws/0oa1b2c3prod/272"
+                ),
+                bookmark(
+                    "AWS Staging",
+                    "https://example.okta.com/app/example_aws_2/0oa4d5e6stag/sso/saml"
+                ),
+            ],
+            aws_apps("tests/fixtures/bookmarks/bookmarks.html")?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn finds_aws_apps_in_json_bookmarks() -> Result<()> {
+        assert_eq!(
+            vec![
+                bookmark(
+                    "AWS Production",
+                    "https://example.okta.com/home/amazon_aws/0oa1b2c3prod/272"
+                ),
+                bookmark(
+                    "AWS Sandbox",
+                    "https://example.okta.com/app/example_aws_3/0oa7h8i9sand/sso/saml"
+                ),
+                bookmark(
+                    "AWS Staging",
+                    "https://example.okta.com/app/example_aws_2/0oa4d5e6stag/sso/saml"
+                ),
+            ],
+            aws_apps("tests/fixtures/bookmarks/bookmarks.json")?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn picks_bookmarked_app() -> Result<()> {
+        let apps = aws_apps("tests/fixtures/bookmarks/bookmarks.html")?;
+
+        let picked = prompt::with_prompter(Rc::new(ScriptedPrompter::new(&["1"])), || {
+            pick(apps.clone())
+        })?;
+        assert_eq!("AWS Staging", picked.title);
+
+        // A single app needs no question
+        assert_eq!(apps[0], pick(apps[..1].to_vec())?);
+        assert!(pick(vec![]).is_err());
+
+        Ok(())
+    }
+
+    fn bookmark(title: &str, url: &str) -> Bookmark {
+        Bookmark {
+            title: title.to_string(),
+            url: Url::parse(url).unwrap(),
+        }
+    }
+}
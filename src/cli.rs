@@ -1,12 +1,61 @@
+use crate::assume_all::DEFAULT_NAME_TEMPLATE;
 use crate::config::app::AppProfile;
-use crate::utils::LevelFilter;
-use anyhow::Result;
-use clap::{crate_description, crate_version, App, AppSettings, Arg, ArgMatches, SubCommand};
+use crate::notify::Channel;
+use crate::output::{OutputMode, SummaryFormat};
+use crate::providers::okta;
+use crate::providers::ProviderType;
+use crate::status;
+use crate::utils::{self, LevelFilter};
+use anyhow::{anyhow, Result};
+use clap::{
+    crate_description, crate_version, App, AppSettings, Arg, ArgMatches, Shell, SubCommand,
+};
+use console::Term;
+use rusoto_core::Region;
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
+use url::Url;
+
+pub const AWS_PROFILE: &str = "AWS_PROFILE";
+const DEFAULT_PROFILE: &str = "default";
 
 #[derive(Debug)]
 pub struct CliConfig {
     pub force: bool,
+    pub timings: bool,
+    pub explain: bool,
+    pub auto_fallback_role: bool,
+    pub no_keyring: bool,
+    pub no_cache: bool,
+    pub no_color: bool,
+    pub quiet_success: bool,
+    pub json_errors: bool,
+    pub bug_report: bool,
+    pub strict: bool,
+    pub mfa_factor: Option<String>,
+    pub mfa_code: Option<String>,
+    pub mfa_provider: Option<String>,
+    pub push_device: Option<String>,
+    pub factor_index: Option<usize>,
+    pub role_index: Option<usize>,
+    pub role_filter: Option<String>,
+    pub region: Option<String>,
+    pub sts_global: bool,
+    pub preflight: bool,
+    pub keep_session: bool,
+    pub forget_device: bool,
+    pub reauth: bool,
+    pub prompt_duration: bool,
+    pub deadline: Option<Duration>,
+    pub refresh_within: Option<Duration>,
+    pub max_idle_connections: Option<usize>,
+    pub metrics_file: Option<PathBuf>,
+    pub status_file: Option<PathBuf>,
+    pub notify: Option<Channel>,
+    pub trace_http: Option<PathBuf>,
     pub location: Option<String>,
+    pub ad_hoc: Option<AppProfile>,
     pub log_level: LevelFilter,
     pub action: CliAction,
 }
@@ -23,17 +72,99 @@ pub enum CliAction {
     Creds {
         profile: String,
         print: bool,
+        output: Option<OutputMode>,
+        verify: bool,
+    },
+    EnrollTotp {
+        profile: String,
+    },
+    FactorHealth {
+        profile: String,
+    },
+    TestFactor {
+        profile: String,
+        factor: Option<String>,
+    },
+    ListFactors {
+        profile: String,
+    },
+    InstallProcess {
+        profile: String,
+        write: bool,
+    },
+    Clean {
+        all: bool,
+    },
+    SetTotp {
+        profile: String,
+    },
+    Check {
+        location: Option<String>,
+    },
+    Get {
+        profile: String,
+        output: Option<OutputMode>,
+    },
+    Watch {
+        profile: String,
+    },
+    Serve {
+        profile: String,
+        socket: Option<String>,
+        prompt_once: bool,
+    },
+    PrintAssertionAttributes {
+        profile: String,
+    },
+    AssumeAll {
+        profile: String,
+        template: String,
+        summary: SummaryFormat,
+    },
+    Ensure {
+        profile: String,
+    },
+    Status {
+        summary: SummaryFormat,
+    },
+    ListProfiles,
+    ConfigPath,
+    Switch,
+    Completions {
+        shell: Shell,
     },
 }
 
 #[derive(Debug)]
 pub enum CliSubAction {
-    Add { profile: AppProfile },
-    Delete { profile_name: String },
+    Add {
+        profile: Box<AppProfile>,
+        region: Option<String>,
+    },
+    Delete {
+        profile_name: String,
+    },
+    ImportBookmarks {
+        path: String,
+        profile_name: String,
+        username: Option<String>,
+        role: Option<String>,
+    },
     List,
 }
 
+// Without any arguments at all a terminal gets the switcher, anything else
+// like a script the help
 fn get_matches() -> ArgMatches<'static> {
+    match env::args_os().len() == 1 && Term::stderr().is_term() {
+        true => app()
+            .unset_setting(AppSettings::SubcommandRequiredElseHelp)
+            .get_matches(),
+        false => app().get_matches(),
+    }
+}
+
+pub fn app() -> App<'static, 'static> {
     App::new("crowbar")
       .version(crate_version!())
       .about(crate_description!())
@@ -48,6 +179,212 @@ fn get_matches() -> ArgMatches<'static> {
               .long("force")
               .help("Forces re-entering of your Okta credentials"),
       )
+      .arg(
+          Arg::with_name("reauth")
+              .takes_value(false)
+              .long("reauth")
+              .help("Logs in to Okta again, ignoring the kept session and cached credentials, but keeps your stored password, MFA factor and role choices"),
+      )
+      .arg(
+          Arg::with_name("mfa-factor")
+              .long("mfa-factor")
+              .value_name("FACTOR")
+              .help("The Okta MFA factor to use, optionally qualified by its provider (e.g. push, totp or totp:google)")
+              .takes_value(true),
+      )
+      .arg(
+          Arg::with_name("mfa-provider")
+              .long("mfa-provider")
+              .value_name("PROVIDER")
+              .possible_values(&["okta", "duo", "google", "fido", "rsa", "symantec", "yubico"])
+              .help("Only offers the Okta MFA factors of this provider, e.g. to pick Duo's over Okta Verify. Combines with --mfa-factor")
+              .takes_value(true),
+      )
+      .arg(
+          Arg::with_name("push-device")
+              .long("push-device")
+              .value_name("DEVICE")
+              .help("Uses the Okta Verify Push factor of this device, given by its name or the end of the factor ID, if there are several")
+              .takes_value(true),
+      )
+      .arg(
+          Arg::with_name("mfa-code")
+              .long("mfa-code")
+              .value_name("CODE")
+              .help("Submits this code for a TOTP, token or SMS factor instead of prompting for it")
+              .takes_value(true),
+      )
+      .arg(
+          Arg::with_name("factor-index")
+              .long("factor-index")
+              .value_name("INDEX")
+              .help("Selects the MFA factor at this position of the menu, starting at 0, instead of prompting")
+              .takes_value(true),
+      )
+      .arg(
+          Arg::with_name("role-index")
+              .long("role-index")
+              .value_name("INDEX")
+              .help("Selects the role at this position of the menu, starting at 0, instead of prompting")
+              .takes_value(true),
+      )
+      .arg(
+          Arg::with_name("role-filter")
+              .long("role-filter")
+              .value_name("REGEX")
+              .help("Only offers roles whose ARN matches this regular expression, selecting the role right away if only one is left")
+              .takes_value(true),
+      )
+      .arg(
+          Arg::with_name("region")
+              .long("region")
+              .value_name("REGION")
+              .help("Assumes roles against STS in this region instead of the one of your AWS configuration, as long as it is in the partition of the role")
+              .takes_value(true),
+      )
+      .arg(
+          Arg::with_name("sts-global")
+              .takes_value(false)
+              .long("sts-global")
+              .help("Uses the global STS endpoint instead of the regional one of your AWS region"),
+      )
+      .arg(
+          Arg::with_name("keep-session")
+              .takes_value(false)
+              .long("keep-session")
+              .help("Keeps the Okta session in your keyring and extends it, so the next run with this flag skips the password and MFA"),
+      )
+      .arg(
+          Arg::with_name("forget-device")
+              .takes_value(false)
+              .long("forget-device")
+              .help("Forgets that Okta trusts this device, so the next login verifies MFA again"),
+      )
+      .arg(
+          Arg::with_name("prompt-duration")
+              .takes_value(false)
+              .long("prompt-duration")
+              .help("Asks for the length of the session in a terminal, unless the profile configures a duration"),
+      )
+      .arg(
+          Arg::with_name("preflight")
+              .takes_value(false)
+              .long("preflight")
+              .help("Checks that your IdP and STS can be reached before logging in, telling DNS, connection, TLS and proxy problems apart"),
+      )
+      .arg(
+          Arg::with_name("deadline")
+              .long("deadline")
+              .value_name("DURATION")
+              .help("Aborts if logging in, MFA, fetching the SAML assertion and assuming the role take longer than this, e.g. 90s or 2m")
+              .takes_value(true),
+      )
+      .arg(
+          Arg::with_name("refresh-if-expiring-within")
+              .long("refresh-if-expiring-within")
+              .value_name("DURATION")
+              .help("Only fetches new credentials if the cached ones have less than this left, e.g. 30m, instead of 15 minutes")
+              .takes_value(true),
+      )
+      .arg(
+          Arg::with_name("max-idle-connections")
+              .long("max-idle-connections")
+              .value_name("COUNT")
+              .help("Keeps up to this many idle connections to the IdP per host, 8 by default")
+              .takes_value(true),
+      )
+      .arg(
+          Arg::with_name("status-file")
+              .long("status-file")
+              .value_name("PATH")
+              .min_values(0)
+              .require_equals(true)
+              .help("Writes the profile, role, account and expiry of the credentials to ~/.cache/crowbar/status, or --status-file=PATH, after every run, e.g. for your shell prompt")
+              .takes_value(true),
+      )
+      .arg(
+          Arg::with_name("notify")
+              .long("notify")
+              .value_name("URL")
+              .min_values(0)
+              .require_equals(true)
+              .help("Shows a desktop notification when an Okta Verify push waits for approval, or posts it to the webhook at --notify=URL")
+              .takes_value(true),
+      )
+      .arg(
+          Arg::with_name("metrics-file")
+              .long("metrics-file")
+              .value_name("PATH")
+              .help("Writes Prometheus metrics about refreshing credentials to this file after every run, e.g. for node_exporter's textfile collector")
+              .takes_value(true),
+      )
+      .arg(
+          Arg::with_name("trace-http")
+              .long("trace-http")
+              .value_name("PATH")
+              .help("Records every request to the IdP and STS in this HAR file for your browser's devtools, masking passwords, tokens and credentials")
+              .takes_value(true),
+      )
+      .arg(
+          Arg::with_name("auto-fallback-role")
+              .takes_value(false)
+              .long("auto-fallback-role")
+              .help("Assumes the only granted role if the configured one isn't granted anymore"),
+      )
+      .arg(
+          Arg::with_name("no-color")
+              .takes_value(false)
+              .long("no-color")
+              .help("Disables colored messages, also possible by setting NO_COLOR"),
+      )
+      .arg(
+          Arg::with_name("quiet-success")
+              .takes_value(false)
+              .long("quiet-success")
+              .help("Leaves out success messages, e.g. for credential_process, still showing warnings, errors and prompts"),
+      )
+      .arg(
+          Arg::with_name("json-errors")
+              .takes_value(false)
+              .long("json-errors")
+              .help("Prints failures as a JSON object with error_kind, message and exit_code to stderr"),
+      )
+      .arg(
+          Arg::with_name("bug-report")
+              .takes_value(false)
+              .long("bug-report")
+              .help("Prints a summary to include in bug reports when crowbar fails, without sending anything anywhere"),
+      )
+      .arg(
+          Arg::with_name("strict")
+              .takes_value(false)
+              .long("strict")
+              .help("Fails instead of warning about problems with the configuration like profiles defined twice"),
+      )
+      .arg(
+          Arg::with_name("no-keyring")
+              .takes_value(false)
+              .long("no-keyring")
+              .help("Never reads or caches secrets in the system keyring, always prompting instead"),
+      )
+      .arg(
+          Arg::with_name("no-cache")
+              .takes_value(false)
+              .long("no-cache")
+              .help("Ignores cached credentials and the MFA factor used last, still caching the fresh ones"),
+      )
+      .arg(
+          Arg::with_name("explain")
+              .takes_value(false)
+              .long("explain")
+              .help("Describes each step of fetching credentials as it happens on stderr, e.g. which factor and role are used"),
+      )
+      .arg(
+          Arg::with_name("timings")
+              .takes_value(false)
+              .long("timings")
+              .help("Prints how long each step of fetching credentials took to stderr"),
+      )
       .arg(
           Arg::with_name("log-level")
               .short("l")
@@ -65,6 +402,37 @@ fn get_matches() -> ArgMatches<'static> {
               .value_name("CONFIG")
               .help("The location of the configuration file"),
       )
+      .arg(
+          Arg::with_name("app-url")
+              .long("app-url")
+              .value_name("URL")
+              .help("Logs into this Okta AWS app without a configuration file, using a profile that only exists for this run")
+              .takes_value(true),
+      )
+      .arg(
+          Arg::with_name("org")
+              .long("org")
+              .value_name("URL")
+              .requires("app-url")
+              .help("The URL of your Okta organization, which a relative --app-url is resolved against")
+              .takes_value(true),
+      )
+      .arg(
+          Arg::with_name("ad-hoc-username")
+              .long("username")
+              .value_name("USERNAME")
+              .requires("app-url")
+              .help("The username of the profile given with --app-url")
+              .takes_value(true),
+      )
+      .arg(
+          Arg::with_name("ad-hoc-role")
+              .long("role")
+              .value_name("ROLE")
+              .requires("app-url")
+              .help("The AWS role to assume for the profile given with --app-url")
+              .takes_value(true),
+      )
       .subcommand(
           SubCommand::with_name("profiles")
           .about("Add or delete profiles")
@@ -88,8 +456,8 @@ fn get_matches() -> ArgMatches<'static> {
                       .short("u")
                       .long("username")
                       .value_name("USERNAME")
-                      .required(true)
-                      .help("The username to use for logging into your IdP"),
+                      .required(false)
+                      .help("The username to use for logging into your IdP (Optional, defaults to $OKTA_USERNAME or your system username)"),
               )
               .arg(
                   Arg::with_name("url")
@@ -105,6 +473,40 @@ fn get_matches() -> ArgMatches<'static> {
                       .required(false)
                       .help("The AWS role to assume after a successful login (Optional)"),
               )
+              .arg(
+                  Arg::with_name("write-region")
+                      .long("write-region")
+                      .value_name("REGION")
+                      .required(false)
+                      .help("Also sets the region of the profile in the AWS CLI configuration (Optional)"),
+              )
+              .arg(
+                  Arg::with_name("profile").required(true).help("The name of the profile"),
+              ),
+          )
+          .subcommand(
+              SubCommand::with_name("import-bookmarks")
+              .about("Add an Okta profile for an AWS app found in a browser's bookmarks, exported as HTML or JSON")
+              .arg(
+                  Arg::with_name("username")
+                      .short("u")
+                      .long("username")
+                      .value_name("USERNAME")
+                      .required(false)
+                      .help("The username to use for logging into Okta (Optional, defaults to $OKTA_USERNAME or your system username)"),
+              )
+              .arg(
+                  Arg::with_name("role")
+                      .long("r")
+                      .value_name("ROLE")
+                      .required(false)
+                      .help("The AWS role to assume after a successful login (Optional)"),
+              )
+              .arg(
+                  Arg::with_name("bookmarks")
+                      .required(true)
+                      .help("The bookmark export of your browser, or e.g. Chrome's Bookmarks file"),
+              )
               .arg(
                   Arg::with_name("profile").required(true).help("The name of the profile"),
               ),
@@ -132,14 +534,214 @@ fn get_matches() -> ArgMatches<'static> {
               .help("Print credentials to stdout"),
           )
           .arg(
-              Arg::with_name("profile").required(true)
+              Arg::with_name("output")
+              .short("o")
+              .long("output")
+              .value_name("OUTPUT")
+              .help("The format to print credentials in, either json, ini for a section of a credentials file, field:<name> for a single value (e.g. field:SessionToken) or keyring to only store them for crowbar get. Defaults to the profile's output, otherwise json")
+              .takes_value(true),
+          )
+          .arg(
+              Arg::with_name("verify-credentials")
+              .takes_value(false)
+              .long("verify-credentials")
+              .help("Afterwards calls sts:GetCallerIdentity with the credentials and prints who they belong to, failing if they don't work"),
+          )
+          .arg(
+              Arg::with_name("factor-list-only")
+              .takes_value(false)
+              .long("factor-list-only")
+              .conflicts_with_all(&["print", "verify-credentials"])
+              .help("Only logs in with the password and prints the MFA factors to choose from as JSON, for picking one with --mfa-factor or --factor-index"),
+          )
+          .arg(
+              Arg::with_name("profile").help("The name of the profile, defaults to $AWS_PROFILE or default")
+          ),
+      )
+      .subcommand(
+          SubCommand::with_name("url")
+          .about("Logs into the Okta AWS app at the given link and prints credentials, without any configuration or profile")
+          .arg(
+              Arg::with_name("output")
+              .short("o")
+              .long("output")
+              .value_name("OUTPUT")
+              .help("The format to print credentials in, either json, ini for a section of a credentials file or field:<name> for a single value (e.g. field:SessionToken), json by default")
+              .takes_value(true),
+          )
+          .arg(
+              Arg::with_name("url")
+              .required(true)
+              .help("The full link of the app, e.g. https://example.okta.com/home/amazon_aws/0oa1b2c3/272")
+          ),
+      )
+      .subcommand(
+          SubCommand::with_name("get")
+          .about("Prints credentials stored with creds --output keyring if they are still valid, without logging in")
+          .arg(
+              Arg::with_name("output")
+              .short("o")
+              .long("output")
+              .value_name("OUTPUT")
+              .help("The format to print credentials in, either json, ini for a section of a credentials file or field:<name> for a single value (e.g. field:SessionToken). Defaults to the profile's output, otherwise json")
+              .takes_value(true),
+          )
+          .arg(
+              Arg::with_name("profile").help("The name of the profile, defaults to $AWS_PROFILE or default")
+          ),
+      )
+      .subcommand(
+          SubCommand::with_name("ensure")
+          .about("Makes sure a profile has valid cached credentials, logging in only if they are missing or about to expire")
+          .arg(
+              Arg::with_name("profile").help("The name of the profile, defaults to $AWS_PROFILE or default")
+          ),
+      )
+      .subcommand(
+          SubCommand::with_name("watch")
+          .about("Keeps the cached credentials of a profile valid by refreshing them shortly before they expire, until interrupted")
+          .arg(
+              Arg::with_name("profile").help("The name of the profile, defaults to $AWS_PROFILE or default")
+          ),
+      )
+      .subcommand(
+          SubCommand::with_name("serve")
+          .about("Serves the credentials of a profile to every connection of a Unix socket, sharing one login between shells and tools, until interrupted")
+          .arg(
+              Arg::with_name("profile").help("The name of the profile, defaults to $AWS_PROFILE or default")
+          )
+          .arg(
+              Arg::with_name("socket")
+              .long("socket")
+              .value_name("PATH")
+              .help("Where to listen, defaults to crowbar-<profile>.sock in your runtime directory")
+              .takes_value(true)
+          )
+          .arg(
+              Arg::with_name("prompt-once")
+              .long("prompt-once")
+              .conflicts_with("profile")
+              .help("Serves all profiles at crowbar.sock, each connection sending the name of its profile first, continuing one Okta session so MFA is only asked for once it ends")
+          ),
+      )
+      .subcommand(
+          SubCommand::with_name("print-assertion-attributes")
+          .about("Logs in and prints the attributes of the SAML assertion, e.g. to find out why a role is missing, without assuming a role")
+          .arg(
+              Arg::with_name("profile").help("The name of the profile, defaults to $AWS_PROFILE or default")
+          ),
+      )
+      .subcommand(
+          SubCommand::with_name("assume-all")
+          .about("Logs in once and stores credentials for every role granted, each under a profile of its own for crowbar get")
+          .arg(
+              Arg::with_name("name-template")
+              .long("name-template")
+              .value_name("TEMPLATE")
+              .default_value(DEFAULT_NAME_TEMPLATE)
+              .help("The name of the profile of each role, with {profile}, {account} or {account_alias} (the name from account_map, otherwise the id), {account_id} and {role} or {role_name} replaced. Names are lowercase, without spaces")
+              .takes_value(true),
+          )
+          .arg(
+              Arg::with_name("output")
+              .short("o")
+              .long("output")
+              .value_name("OUTPUT")
+              .possible_values(&["table", "json"])
+              .default_value("table")
+              .help("How to sum up the roles once they're stored, an aligned table or a JSON array")
+              .takes_value(true),
+          )
+          .arg(
+              Arg::with_name("profile").help("The name of the profile, defaults to $AWS_PROFILE or default")
+          ),
+      )
+      .subcommand(
+          SubCommand::with_name("check")
+          .about("Validates all profiles of a configuration file without logging in, failing if any is invalid")
+          .arg(
+              Arg::with_name("config-path").help("The configuration file to check, defaults to the one in use")
+          ),
+      )
+      .subcommand(
+          SubCommand::with_name("status")
+          .about("Lists whether the cached credentials of each profile are valid, expiring soon or expired, without logging in")
+          .arg(
+              Arg::with_name("output")
+              .short("o")
+              .long("output")
+              .value_name("OUTPUT")
+              .possible_values(&["table", "json"])
+              .default_value("table")
+              .help("An aligned table or a JSON array")
+              .takes_value(true),
+          ),
+      )
+      .subcommand(
+          SubCommand::with_name("clean")
+          .about("Removes cached AWS credentials, Okta sessions, device trust and other cached state of all profiles")
+          .arg(
+              Arg::with_name("all")
+              .short("a")
+              .takes_value(false)
+              .long("all")
+              .help("Also removes stored passwords and TOTP secrets"),
+          ),
+      )
+      .subcommand(
+          SubCommand::with_name("set-totp")
+          .about("Stores the secret of an already enrolled TOTP factor, given as base32 or otpauth:// URI, to generate codes automatically")
+          .arg(
+              Arg::with_name("profile").help("The name of the profile, defaults to $AWS_PROFILE or default")
+          ),
+      )
+      .subcommand(
+          SubCommand::with_name("factor-health")
+          .about("Logs into Okta without MFA and reports which of your MFA factors look unusable, e.g. ones pending activation")
+          .arg(
+              Arg::with_name("profile").help("The name of the profile, defaults to $AWS_PROFILE or default")
+          ),
+      )
+      .subcommand(
+          SubCommand::with_name("test-factor")
+          .about("Logs into Okta with one MFA factor and reports whether Okta accepted it, without fetching AWS credentials")
+          .arg(
+              Arg::with_name("factor")
+              .long("factor")
+              .value_name("FACTOR")
+              .help("The factor to test, optionally qualified by its provider (e.g. push, totp or totp:google), defaults to the profile's mfa_factor")
+              .takes_value(true),
+          )
+          .arg(
+              Arg::with_name("profile").help("The name of the profile, defaults to $AWS_PROFILE or default")
+          ),
+      )
+      .subcommand(
+          SubCommand::with_name("install-process")
+          .about("Prints the AWS CLI configuration that makes a profile get its credentials from crowbar as credential_process")
+          .arg(
+              Arg::with_name("write")
+              .short("w")
+              .takes_value(false)
+              .long("write")
+              .help("Writes the credential_process to the AWS CLI configuration instead, keeping all other settings"),
+          )
+          .arg(
+              Arg::with_name("profile").help("The name of the profile, defaults to $AWS_PROFILE or default")
+          ),
+      )
+      .subcommand(
+          SubCommand::with_name("enroll-totp")
+          .about("Enrolls a new software TOTP factor with Okta, for when your organization asks you to set up MFA")
+          .arg(
+              Arg::with_name("profile").help("The name of the profile, defaults to $AWS_PROFILE or default")
           ),
       )
       .subcommand(
         SubCommand::with_name("exec")
         .about("Exposed temporary credentials on the command line by executing a child process with environment variables")
         .arg(
-            Arg::with_name("profile").required(true)
+            Arg::with_name("profile").help("The name of the profile, defaults to $AWS_PROFILE or default")
         )
         .arg(
             Arg::with_name("command")
@@ -148,23 +750,200 @@ fn get_matches() -> ArgMatches<'static> {
             .multiple(true)
         ),
     )
-    .get_matches()
+    .subcommand(
+        SubCommand::with_name("list-profiles")
+        .about("Prints the names of all profiles, one per line, e.g. for shell completion")
+    )
+    .subcommand(
+        SubCommand::with_name("config-path")
+        .about("Prints which configuration files crowbar uses and where it caches, without reading them")
+    )
+    .subcommand(
+        SubCommand::with_name("completions")
+        .about("Prints a completion script for the given shell, completing profile names from your configuration")
+        .arg(
+            Arg::with_name("shell")
+            .required(true)
+            .possible_values(&["bash", "zsh", "fish"])
+        ),
+    )
 }
 
 pub fn config() -> Result<CliConfig> {
     let matches = get_matches();
     let cli_action = select_action(&matches);
-    let location = matches.value_of("config").map(|c| c.to_owned());
+    let location = matches.value_of("location").map(|c| c.to_owned());
     let log_level_from_matches = matches.value_of("log-level").unwrap();
 
     Ok(CliConfig {
         force: matches.is_present("force"),
+        timings: matches.is_present("timings"),
+        explain: matches.is_present("explain"),
+        no_keyring: matches.is_present("no-keyring"),
+        no_cache: matches.is_present("no-cache"),
+        no_color: matches.is_present("no-color"),
+        quiet_success: matches.is_present("quiet-success"),
+        json_errors: matches.is_present("json-errors"),
+        bug_report: matches.is_present("bug-report"),
+        strict: matches.is_present("strict"),
+        auto_fallback_role: matches.is_present("auto-fallback-role"),
+        mfa_factor: matches.value_of("mfa-factor").map(|f| f.to_owned()),
+        mfa_code: matches.value_of("mfa-code").map(|c| c.to_owned()),
+        mfa_provider: matches.value_of("mfa-provider").map(|p| p.to_owned()),
+        push_device: matches.value_of("push-device").map(|d| d.to_owned()),
+        factor_index: parse_index(&matches, "factor-index")?,
+        role_index: parse_index(&matches, "role-index")?,
+        role_filter: matches.value_of("role-filter").map(|f| f.to_owned()),
+        region: match matches.value_of("region") {
+            Some(region) => Some(parse_region(region)?),
+            None => None,
+        },
+        sts_global: matches.is_present("sts-global"),
+        preflight: matches.is_present("preflight"),
+        keep_session: matches.is_present("keep-session"),
+        forget_device: matches.is_present("forget-device"),
+        reauth: matches.is_present("reauth"),
+        prompt_duration: matches.is_present("prompt-duration"),
+        deadline: match matches.value_of("deadline") {
+            Some(deadline) => Some(parse_duration("deadline", deadline)?),
+            None => None,
+        },
+        refresh_within: match matches.value_of("refresh-if-expiring-within") {
+            Some(within) => Some(parse_duration("refresh-if-expiring-within", within)?),
+            None => None,
+        },
+        max_idle_connections: parse_index(&matches, "max-idle-connections")?,
+        metrics_file: matches.value_of("metrics-file").map(PathBuf::from),
+        status_file: status_file(&matches)?,
+        notify: notify_channel(&matches)?,
+        trace_http: matches.value_of("trace-http").map(PathBuf::from),
         location,
+        ad_hoc: ad_hoc_profile(&matches)?,
         log_level: select_log_level(log_level_from_matches),
         action: cli_action?,
     })
 }
 
+// --app-url and its companions describe a profile without any configuration
+// file, named like the profile the subcommand asks for
+fn ad_hoc_profile(matches: &ArgMatches) -> Result<Option<AppProfile>> {
+    if let ("url", Some(m)) = matches.subcommand() {
+        return Ok(Some(url_profile(m.value_of("url").unwrap())?));
+    }
+
+    let app_url = match matches.value_of("app-url") {
+        Some(app_url) => app_url,
+        None => return Ok(None),
+    };
+    let url = match matches.value_of("org") {
+        Some(org) => Url::parse(org)
+            .and_then(|org| org.join(app_url))
+            .map_err(|e| {
+                anyhow!(
+                    "Unable to resolve --app-url {} against --org {}: {}",
+                    app_url,
+                    org,
+                    e
+                )
+            })?
+            .to_string(),
+        None => app_url.to_owned(),
+    };
+    let name = match matches.subcommand() {
+        (_, Some(m)) => profile_name(m),
+        _ => DEFAULT_PROFILE.to_string(),
+    };
+
+    Ok(Some(AppProfile::new(
+        &name,
+        ProviderType::Okta,
+        &url,
+        matches.value_of("ad-hoc-username").unwrap_or_default(),
+        matches.value_of("ad-hoc-role"),
+    )))
+}
+
+// `crowbar url` names its profile after the org and the app, so passwords
+// and credentials are cached per app like for a configured profile
+fn url_profile(url: &str) -> Result<AppProfile> {
+    let (org, app_id) = okta::org_url(url)?;
+    let name = format!("{}-{}", org.host_str().unwrap_or_default(), app_id);
+
+    Ok(AppProfile::new(&name, ProviderType::Okta, url, "", None))
+}
+
+fn parse_output(matches: &ArgMatches) -> Result<Option<OutputMode>> {
+    match matches.value_of("output") {
+        Some(output) => Ok(Some(output.parse()?)),
+        None => Ok(None),
+    }
+}
+
+fn parse_index(matches: &ArgMatches, name: &str) -> Result<Option<usize>> {
+    match matches.value_of(name) {
+        Some(index) => Ok(Some(index.parse().map_err(|_| {
+            anyhow!(
+                "--{} needs to be a non-negative number, got {}",
+                name,
+                index
+            )
+        })?)),
+        None => Ok(None),
+    }
+}
+
+// A bare --status-file writes to the cache directory. The path needs an equals
+// sign, so the flag doesn't take the profile name for one
+fn status_file(matches: &ArgMatches) -> Result<Option<PathBuf>> {
+    match matches.value_of("status-file") {
+        Some(path) => Ok(Some(PathBuf::from(path))),
+        None if matches.is_present("status-file") => status::default_file()
+            .map(Some)
+            .ok_or_else(|| anyhow!("Unable to find your cache directory for --status-file")),
+        None => Ok(None),
+    }
+}
+
+// Like --status-file, a bare --notify notifies the desktop
+fn notify_channel(matches: &ArgMatches) -> Result<Option<Channel>> {
+    match matches.value_of("notify") {
+        Some(url) => Url::parse(url)
+            .map(|url| Some(Channel::Webhook(url)))
+            .map_err(|e| anyhow!("Invalid webhook URL {} for --notify: {}", url, e)),
+        None if matches.is_present("notify") => Ok(Some(Channel::Desktop)),
+        None => Ok(None),
+    }
+}
+
+// Plain numbers are seconds
+fn parse_duration(name: &str, s: &str) -> Result<Duration> {
+    let (value, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => s.split_at(index),
+        None => (s, "s"),
+    };
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        _ => 0,
+    };
+
+    match value.parse::<u64>() {
+        Ok(value) if seconds > 0 => Ok(Duration::from_secs(value * seconds)),
+        _ => Err(anyhow!(
+            "--{} needs to be a number of seconds, minutes or hours like 90s, 2m or 1h, got {}",
+            name,
+            s
+        )),
+    }
+}
+
+fn parse_region(s: &str) -> Result<String> {
+    s.parse::<Region>()
+        .map(|r| r.name().to_string())
+        .map_err(|_| anyhow!("Unknown AWS region {}", s))
+}
+
 fn select_action(matches: &ArgMatches) -> Result<CliAction> {
     match matches.subcommand() {
         ("exec", Some(m)) => {
@@ -175,29 +954,123 @@ fn select_action(matches: &ArgMatches) -> Result<CliAction> {
                 .collect();
             Ok(CliAction::Exec {
                 command: parts,
-                profile: m.value_of("profile").unwrap().to_owned(),
+                profile: profile_name(m),
             })
         }
+        ("creds", Some(m)) if m.is_present("factor-list-only") => Ok(CliAction::ListFactors {
+            profile: profile_name(m),
+        }),
         ("creds", Some(m)) => Ok(CliAction::Creds {
             print: m.is_present("print"),
-            profile: m.value_of("profile").unwrap().to_owned(),
+            profile: profile_name(m),
+            output: parse_output(m)?,
+            verify: m.is_present("verify-credentials"),
+        }),
+        ("url", Some(m)) => Ok(CliAction::Creds {
+            profile: url_profile(m.value_of("url").unwrap())?.name,
+            print: true,
+            output: parse_output(m)?,
+            verify: false,
+        }),
+        ("get", Some(m)) => Ok(CliAction::Get {
+            profile: profile_name(m),
+            output: parse_output(m)?,
+        }),
+        ("ensure", Some(m)) => Ok(CliAction::Ensure {
+            profile: profile_name(m),
+        }),
+        ("watch", Some(m)) => Ok(CliAction::Watch {
+            profile: profile_name(m),
+        }),
+        ("serve", Some(m)) => Ok(CliAction::Serve {
+            profile: profile_name(m),
+            socket: m.value_of("socket").map(|s| s.to_owned()),
+            prompt_once: m.is_present("prompt-once"),
+        }),
+        ("print-assertion-attributes", Some(m)) => Ok(CliAction::PrintAssertionAttributes {
+            profile: profile_name(m),
+        }),
+        ("assume-all", Some(m)) => Ok(CliAction::AssumeAll {
+            profile: profile_name(m),
+            template: m.value_of("name-template").unwrap().to_owned(),
+            summary: m.value_of("output").unwrap().parse()?,
+        }),
+        ("check", Some(m)) => Ok(CliAction::Check {
+            location: m.value_of("config-path").map(|c| c.to_owned()),
+        }),
+        ("status", Some(m)) => Ok(CliAction::Status {
+            summary: m.value_of("output").unwrap().parse()?,
+        }),
+        ("clean", Some(m)) => Ok(CliAction::Clean {
+            all: m.is_present("all"),
+        }),
+        ("set-totp", Some(m)) => Ok(CliAction::SetTotp {
+            profile: profile_name(m),
+        }),
+        ("enroll-totp", Some(m)) => Ok(CliAction::EnrollTotp {
+            profile: profile_name(m),
+        }),
+        ("factor-health", Some(m)) => Ok(CliAction::FactorHealth {
+            profile: profile_name(m),
+        }),
+        ("test-factor", Some(m)) => Ok(CliAction::TestFactor {
+            profile: profile_name(m),
+            factor: m.value_of("factor").map(|f| f.to_owned()),
+        }),
+        ("install-process", Some(m)) => Ok(CliAction::InstallProcess {
+            profile: profile_name(m),
+            write: m.is_present("write"),
+        }),
+        ("list-profiles", Some(_)) => Ok(CliAction::ListProfiles),
+        ("config-path", Some(_)) => Ok(CliAction::ConfigPath),
+        ("completions", Some(m)) => Ok(CliAction::Completions {
+            shell: m
+                .value_of("shell")
+                .unwrap()
+                .parse()
+                .map_err(|e| anyhow!("{}", e))?,
         }),
         ("profiles", Some(action)) => Ok(CliAction::Profiles {
             action: match action.subcommand() {
                 ("add", Some(action)) => CliSubAction::Add {
-                    profile: AppProfile::from(action),
+                    profile: Box::new(AppProfile::from(action)),
+                    region: match action.value_of("write-region") {
+                        Some(region) => Some(parse_region(region)?),
+                        None => None,
+                    },
                 },
                 ("delete", Some(action)) => CliSubAction::Delete {
                     profile_name: action.value_of("profile").unwrap().to_owned(),
                 },
+                ("import-bookmarks", Some(action)) => CliSubAction::ImportBookmarks {
+                    path: action.value_of("bookmarks").unwrap().to_owned(),
+                    profile_name: action.value_of("profile").unwrap().to_owned(),
+                    username: action.value_of("username").map(|u| u.to_owned()),
+                    role: action.value_of("role").map(|r| r.to_owned()),
+                },
                 ("list", Some(_action)) => CliSubAction::List,
                 _ => unreachable!(),
             },
         }),
+        ("", None) => Ok(CliAction::Switch),
         _ => unreachable!(),
     }
 }
 
+fn profile_name(matches: &ArgMatches) -> String {
+    resolve_profile_name(
+        matches.value_of("profile"),
+        utils::non_empty_env_var(AWS_PROFILE),
+    )
+}
+
+fn resolve_profile_name(argument: Option<&str>, env: Option<String>) -> String {
+    argument
+        .map(|p| p.to_owned())
+        .or(env)
+        .unwrap_or_else(|| DEFAULT_PROFILE.to_string())
+}
+
 fn select_log_level(selected_level: &str) -> LevelFilter {
     match selected_level {
         "trace" => LevelFilter::Trace,
@@ -217,4 +1090,142 @@ mod test {
         assert_eq!(LevelFilter::Trace, select_log_level("trace"));
         assert_eq!(LevelFilter::Info, select_log_level("something"))
     }
+
+    #[test]
+    fn parses_deadlines() -> Result<()> {
+        assert_eq!(Duration::from_secs(90), parse_duration("deadline", "90")?);
+        assert_eq!(Duration::from_secs(90), parse_duration("deadline", "90s")?);
+        assert_eq!(Duration::from_secs(120), parse_duration("deadline", "2m")?);
+        assert_eq!(Duration::from_secs(3600), parse_duration("deadline", "1h")?);
+
+        for invalid in &["", "m", "2d", "1.5m", "-1s"] {
+            assert!(
+                parse_duration("deadline", invalid).is_err(),
+                "{} was accepted",
+                invalid
+            );
+        }
+        assert!(parse_duration("refresh-if-expiring-within", "30 minutes")
+            .unwrap_err()
+            .to_string()
+            .starts_with("--refresh-if-expiring-within needs to be"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn parses_regions() -> Result<()> {
+        assert_eq!("eu-central-1", parse_region("eu-central-1")?);
+        assert!(parse_region("eu-middle-1").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn resolves_profile_name_by_precedence() {
+        let env = Some("from-env".to_string());
+
+        assert_eq!(
+            "explicit",
+            resolve_profile_name(Some("explicit"), env.clone())
+        );
+        assert_eq!("from-env", resolve_profile_name(None, env));
+        assert_eq!("default", resolve_profile_name(None, None));
+    }
+
+    #[test]
+    fn builds_ad_hoc_profile_like_configured_one() -> Result<()> {
+        let configured: AppProfile = toml::from_str(
+            r#"
+            name = "one-off"
+            provider = "okta"
+            url = "https://example.okta.com/home/amazon_aws/0oa/272"
+            username = "jdoe@example.com"
+            role = "arn:aws:iam::123456789012:role/role1"
+        "#,
+        )?;
+        let matches = app().get_matches_from_safe(vec![
+            "crowbar",
+            "--org",
+            "https://example.okta.com",
+            "--app-url",
+            "/home/amazon_aws/0oa/272",
+            "--username",
+            "jdoe@example.com",
+            "--role",
+            "arn:aws:iam::123456789012:role/role1",
+            "creds",
+            "one-off",
+        ])?;
+
+        assert_eq!(Some(configured), ad_hoc_profile(&matches)?);
+
+        let matches = app().get_matches_from_safe(vec!["crowbar", "creds", "one-off"])?;
+        assert_eq!(None, ad_hoc_profile(&matches)?);
+
+        let matches = app().get_matches_from_safe(vec![
+            "crowbar",
+            "url",
+            "https://example.okta.com/home/amazon_aws/0oa1b2c3prod/272?fromHome=true",
+        ])?;
+        let profile = ad_hoc_profile(&matches)?.unwrap();
+        assert_eq!("example.okta.com-0oa1b2c3prod", profile.name);
+        assert_eq!(
+            "https://example.okta.com/home/amazon_aws/0oa1b2c3prod/272?fromHome=true",
+            profile.url
+        );
+        assert!(app()
+            .get_matches_from_safe(vec!["crowbar", "--username", "jdoe", "creds"])
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn writes_status_file_to_cache_directory_by_default() -> Result<()> {
+        let matches =
+            app().get_matches_from_safe(vec!["crowbar", "--status-file", "creds", "prod"])?;
+        assert_eq!(status::default_file(), status_file(&matches)?);
+        assert_eq!(
+            Some("prod"),
+            matches
+                .subcommand_matches("creds")
+                .and_then(|m| m.value_of("profile"))
+        );
+
+        let matches = app().get_matches_from_safe(vec![
+            "crowbar",
+            "--status-file=/tmp/status",
+            "creds",
+            "prod",
+        ])?;
+        assert_eq!(Some(PathBuf::from("/tmp/status")), status_file(&matches)?);
+
+        let matches = app().get_matches_from_safe(vec!["crowbar", "creds", "prod"])?;
+        assert_eq!(None, status_file(&matches)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn notifies_desktop_or_webhook() -> Result<()> {
+        let notify = |args: &[&str]| -> Result<Option<Channel>> {
+            let mut argv = vec!["crowbar"];
+            argv.extend_from_slice(args);
+            argv.extend_from_slice(&["creds", "prod"]);
+            notify_channel(&app().get_matches_from_safe(argv)?)
+        };
+
+        assert_eq!(None, notify(&[])?);
+        assert_eq!(Some(Channel::Desktop), notify(&["--notify"])?);
+        assert_eq!(
+            Some(Channel::Webhook(Url::parse(
+                "https://hooks.slack.com/services/T000/B000/XXXX"
+            )?)),
+            notify(&["--notify=https://hooks.slack.com/services/T000/B000/XXXX"])?
+        );
+        assert!(notify(&["--notify=not a url"]).is_err());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,68 @@
+use chrono::{DateTime, Utc};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Where TOTP codes and expiry decisions take the current time from, so
+/// tests can pin it instead of depending on when they run
+pub trait Clock {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<Rc<dyn Clock>> = RefCell::new(Rc::new(SystemClock));
+}
+
+/// Runs `f` with the time told by `clock`, restoring the previous clock
+/// afterwards
+pub fn with_clock<T, F>(clock: Rc<dyn Clock>, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    let previous = CURRENT.with(|c| c.replace(clock));
+    let result = f();
+    CURRENT.with(|c| c.replace(previous));
+
+    result
+}
+
+pub fn now() -> DateTime<Utc> {
+    CURRENT.with(|c| c.borrow().now())
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    /// Always tells the same time
+    pub struct FixedClock(pub DateTime<Utc>);
+
+    impl FixedClock {
+        pub fn at(timestamp: i64) -> Rc<FixedClock> {
+            Rc::new(FixedClock(Utc.timestamp(timestamp, 0)))
+        }
+    }
+
+    impl Clock for FixedClock {
+        fn now(&self) -> DateTime<Utc> {
+            self.0
+        }
+    }
+
+    #[test]
+    fn restores_previous_clock() {
+        let pinned = with_clock(FixedClock::at(59), now);
+
+        assert_eq!(59, pinned.timestamp());
+        assert!(now().timestamp() > 59);
+    }
+}
@@ -0,0 +1,168 @@
+use crate::cli;
+
+use clap::Shell;
+
+// clap only generates static completions, so the profile arguments of these
+// commands are rewired to ask crowbar itself for the configured names
+const PROFILE_COMMANDS: &[&[&str]] = &[
+    &["creds"],
+    &["get"],
+    &["ensure"],
+    &["watch"],
+    &["serve"],
+    &["print-assertion-attributes"],
+    &["assume-all"],
+    &["set-totp"],
+    &["enroll-totp"],
+    &["factor-health"],
+    &["test-factor"],
+    &["install-process"],
+    &["exec"],
+    &["profiles", "delete"],
+];
+const LIST_PROFILES: &str = "crowbar list-profiles 2>/dev/null";
+const ZSH_PROFILE_NAMES: &str = "_crowbar_profile_names";
+
+/// The completion script of `shell`, completing profile arguments with the
+/// profiles of the configuration in use at completion time
+pub fn script(shell: Shell) -> String {
+    let mut generated = Vec::new();
+    cli::app().gen_completions_to("crowbar", shell, &mut generated);
+    let generated = String::from_utf8_lossy(&generated);
+
+    match shell {
+        Shell::Bash => complete_bash(&generated),
+        Shell::Zsh => complete_zsh(&generated),
+        Shell::Fish => complete_fish(&generated),
+        _ => generated.into_owned(),
+    }
+}
+
+fn leaf_commands() -> impl Iterator<Item = &'static str> {
+    PROFILE_COMMANDS
+        .iter()
+        .filter_map(|path| path.last().copied())
+}
+
+// Every command gets a `crowbar__<path>)` case, whose opts list the
+// positional arguments as `<name>`
+fn complete_bash(generated: &str) -> String {
+    let cases: Vec<String> = leaf_commands()
+        .map(|c| format!("__{})", c.replace('-', "__")))
+        .collect();
+    let mut completes_profile = false;
+
+    generated
+        .lines()
+        .map(|line| {
+            let trimmed = line.trim();
+            if trimmed.starts_with("crowbar") && trimmed.ends_with(')') {
+                completes_profile = cases.iter().any(|c| trimmed.ends_with(c.as_str()));
+            }
+
+            match completes_profile && trimmed.starts_with("opts=") {
+                true => line.replace("<profile>", &format!("$({})", LIST_PROFILES)),
+                false => line.to_string(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Every command gets a `(<name>)` case with a `:profile ...:_files` spec for
+// the positional argument. The helper has to be defined before the script
+// calls `_crowbar` at its very end
+fn complete_zsh(generated: &str) -> String {
+    let mut completes_profile = false;
+    let mut lines: Vec<String> = generated
+        .lines()
+        .map(|line| {
+            let trimmed = line.trim();
+            if trimmed.starts_with('(') && trimmed.ends_with(')') && !trimmed.contains(' ') {
+                let command = &trimmed[1..trimmed.len() - 1];
+                completes_profile = leaf_commands().any(|c| c == command);
+            }
+
+            match completes_profile
+                && (trimmed.starts_with("':profile") || trimmed.starts_with("'::profile"))
+            {
+                true => line.replace(":_files'", &format!(":{}'", ZSH_PROFILE_NAMES)),
+                false => line.to_string(),
+            }
+        })
+        .collect();
+
+    let call = lines
+        .iter()
+        .rposition(|l| l.trim() == "_crowbar \"$@\"")
+        .unwrap_or(lines.len());
+    lines.insert(
+        call,
+        format!(
+            "{}() {{\n    local -a profiles\n    profiles=(${{(f)\"$({})\"}})\n    _describe -t profiles 'crowbar profiles' profiles \"$@\"\n}}\n",
+            ZSH_PROFILE_NAMES, LIST_PROFILES
+        ),
+    );
+
+    lines.join("\n")
+}
+
+fn complete_fish(generated: &str) -> String {
+    format!(
+        "{}\ncomplete -c crowbar -n \"__fish_seen_subcommand_from {}\" -f -a \"({})\"\n",
+        generated.trim_end(),
+        leaf_commands().collect::<Vec<_>>().join(" "),
+        LIST_PROFILES
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn profile_commands_take_a_profile() {
+        for path in PROFILE_COMMANDS {
+            let mut args = vec!["crowbar"];
+            args.extend(path.iter());
+            args.push("some-profile");
+
+            let mut matches = cli::app().get_matches_from_safe(args).unwrap();
+            for command in path.iter() {
+                matches = matches.subcommand_matches(command).unwrap().clone();
+            }
+
+            assert_eq!(Some("some-profile"), matches.value_of("profile"));
+        }
+    }
+
+    #[test]
+    fn completes_profiles_from_configuration() {
+        for shell in &[Shell::Bash, Shell::Zsh, Shell::Fish] {
+            let script = script(*shell);
+
+            assert!(script.contains(LIST_PROFILES), "{}", shell);
+            assert!(script.contains("enroll-totp"), "{}", shell);
+        }
+    }
+
+    #[test]
+    fn leaves_new_profile_names_alone() {
+        let bash = script(Shell::Bash);
+        let add = bash
+            .lines()
+            .skip_while(|l| l.trim() != "crowbar__profiles__add)")
+            .nth(1)
+            .unwrap();
+        assert!(add.contains("<profile>"));
+        assert!(!bash.contains("opts=\" -h -V  --help --version  <profile> \""));
+
+        let zsh = script(Shell::Zsh);
+        assert!(zsh.contains("':profile -- The name of the profile:_files'"));
+        assert!(!zsh.contains(
+            "'::profile -- The name of the profile, defaults to $AWS_PROFILE or default:_files'"
+        ));
+        assert!(zsh.trim_end().ends_with("_crowbar \"$@\""));
+        assert!(zsh.find(ZSH_PROFILE_NAMES).unwrap() < zsh.rfind("_crowbar \"$@\"").unwrap());
+    }
+}
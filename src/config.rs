@@ -1,34 +1,90 @@
 pub mod app;
 pub mod aws;
 
-use crate::config::app::AppProfile;
-use anyhow::{anyhow, Result};
+use crate::config::app::{AppProfile, PROFILE_KEYS};
+use crate::error::{self, ErrorKind};
+use crate::report;
+use crate::ui;
+use crate::utils;
+use anyhow::{anyhow, Context, Result};
 use clap::crate_name;
-use std::default::Default;
+use directories::ProjectDirs;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use toml::Value;
+
+pub const CROWBAR_CONFIG: &str = "CROWBAR_CONFIG";
+const PROFILE_HEADER: &str = "[[profiles]]";
+// Profile keys that got a new name, the old one first, e.g.
+// `("role_arn", "role")`. Profiles still using an old name are read as if
+// they used the new one, and get the new one once crowbar writes the
+// configuration. No key has been renamed so far
+const RENAMED_KEYS: [(&str, &str); 0] = [];
+
+static STRICT: AtomicBool = AtomicBool::new(false);
+
+/// Makes reading the configuration fail on problems it otherwise warns about
+pub fn set_strict(enabled: bool) {
+    STRICT.store(enabled, Ordering::Relaxed);
+}
+
+/// Where the location of the configuration file in use was given
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfigSource {
+    Flag,
+    Environment,
+    Default,
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigSource::Flag => write!(f, "--config"),
+            ConfigSource::Environment => write!(f, "{}", CROWBAR_CONFIG),
+            ConfigSource::Default => write!(f, "default location"),
+        }
+    }
+}
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+/// The configuration file given with `--config`, otherwise the one in
+/// `CROWBAR_CONFIG`. Without either crowbar uses confy's default location
+pub fn resolve_location(
+    flag: Option<String>,
+    env: Option<String>,
+) -> (Option<String>, ConfigSource) {
+    match (flag, env) {
+        (Some(flag), _) => (Some(flag), ConfigSource::Flag),
+        (None, Some(env)) => (Some(env), ConfigSource::Environment),
+        (None, None) => (None, ConfigSource::Default),
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct CrowbarConfig {
     pub profiles: Vec<AppProfile>,
     pub location: Option<String>,
+    // Profiles given on the command line only live in memory
+    #[serde(skip)]
+    ad_hoc: bool,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Default)]
 struct AppProfiles {
     profiles: Vec<AppProfile>,
 }
 
-impl Default for CrowbarConfig {
-    fn default() -> Self {
-        Self {
-            profiles: vec![],
-            location: None,
-        }
-    }
+#[derive(Debug, PartialEq)]
+pub struct ProfileCheck {
+    pub name: String,
+    pub problems: Vec<String>,
 }
 
-impl Default for AppProfiles {
-    fn default() -> Self {
-        Self { profiles: vec![] }
+impl ProfileCheck {
+    pub fn is_valid(&self) -> bool {
+        self.problems.is_empty()
     }
 }
 
@@ -43,16 +99,109 @@ impl CrowbarConfig {
         config
     }
 
+    /// A configuration of just `profile`, which is never read from or written
+    /// to a file. Unlike the profiles loaded from the file, it's validated
+    /// up front, as it comes straight from the command line
+    pub fn ad_hoc(profile: AppProfile) -> Result<CrowbarConfig> {
+        profile.validate()?;
+
+        Ok(CrowbarConfig {
+            profiles: vec![profile],
+            location: None,
+            ad_hoc: true,
+        })
+    }
+
+    pub fn is_ad_hoc(&self) -> bool {
+        self.ad_hoc
+    }
+
     pub fn read(mut self) -> Result<CrowbarConfig> {
-        let app_profiles: AppProfiles = match &self.location {
-            Some(l) => confy::load_path(l)?,
-            _ => confy::load(crate_name!())?,
-        };
-        self.profiles = app_profiles.profiles;
+        if self.ad_hoc {
+            return Ok(self);
+        }
+
+        let path = self.path()?;
+        let mut app_profiles: AppProfiles = confy::load_path(&path)?;
+
+        if let Ok(content) = fs::read_to_string(&path) {
+            let mut config: Value = toml::from_str(&content)?;
+            let renamed = rename_keys(&mut config, &RENAMED_KEYS);
+
+            if !renamed.is_empty() {
+                app_profiles = config.try_into()?;
+            }
+            for (profile, old, new) in renamed {
+                eprintln!(
+                    "{}",
+                    ui::warning(&format!(
+                        "Profile {} in {} uses {}, which is now called {}. Please rename it",
+                        profile,
+                        path.display(),
+                        old,
+                        new
+                    ))
+                );
+            }
+
+            let duplicates = duplicate_profiles(&content)
+                .into_iter()
+                .map(|(name, lines)| {
+                    format!(
+                        "Profile {} is defined more than once in {}, on lines {}. Only the first one is used",
+                        name,
+                        path.display(),
+                        lines
+                            .iter()
+                            .map(|l| l.to_string())
+                            .collect::<Vec<String>>()
+                            .join(", ")
+                    )
+                })
+                .collect::<Vec<String>>();
+
+            if !duplicates.is_empty() && STRICT.load(Ordering::Relaxed) {
+                return Err(error::error(ErrorKind::Config, duplicates.join("\n")));
+            }
+            for duplicate in duplicates {
+                eprintln!("{}", ui::warning(&duplicate));
+            }
+        }
 
+        self.profiles = app_profiles.profiles;
         Ok(self)
     }
 
+    /// The configuration file in use, confy's default location unless
+    /// another one was given
+    pub fn path(&self) -> Result<PathBuf> {
+        match &self.location {
+            Some(l) => Ok(PathBuf::from(l)),
+            None => Ok(ProjectDirs::from("rs", "", crate_name!())
+                .ok_or_else(|| {
+                    error::error(
+                        ErrorKind::Config,
+                        format!(
+                            "Unable to find the configuration without a home directory, set HOME or pass the file with --config or {}",
+                            CROWBAR_CONFIG
+                        ),
+                    )
+                })?
+                .config_dir()
+                .join(format!("{}.toml", crate_name!()))),
+        }
+    }
+
+    /// Validates every profile of the configuration file without any
+    /// network calls
+    pub fn check(&self) -> Result<Vec<ProfileCheck>> {
+        let path = self.path()?;
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Unable to read configuration {}", path.display()))?;
+
+        check_profiles(&content, &RENAMED_KEYS)
+    }
+
     pub fn add_profile(mut self, profile: &AppProfile) -> Result<CrowbarConfig> {
         // We use our own function here instead of contains() to only
         // filter on the name attribute
@@ -85,21 +234,204 @@ impl CrowbarConfig {
         Ok(self)
     }
 
+    pub fn find_profile(&self, profile_name: &str) -> Result<AppProfile> {
+        self.profiles
+            .iter()
+            .find(|p| p.is_profile(profile_name))
+            .inspect(|p| report::set_org_url(&p.full_url()))
+            .cloned()
+            .ok_or_else(|| {
+                error::error(
+                    ErrorKind::Config,
+                    format!(
+                        "Profile {} not found, available profiles are: {}",
+                        profile_name,
+                        self.profiles
+                            .iter()
+                            .map(|p| p.name.clone())
+                            .collect::<Vec<String>>()
+                            .join(", ")
+                    ),
+                )
+            })
+    }
+
+    pub fn profile_names(&self) -> Vec<&str> {
+        self.profiles.iter().map(|p| p.name.as_str()).collect()
+    }
+
     pub fn list_profiles(&self) -> Result<()> {
         println!("{}", toml::ser::to_string_pretty(&self)?);
         Ok(())
     }
 
     pub fn write(self) -> Result<()> {
+        if self.ad_hoc {
+            return Err(error::error(
+                ErrorKind::Config,
+                "Profiles given with --app-url can't be changed, they only exist for this run",
+            ));
+        }
+
+        let path = self.path()?;
         let app_profiles = AppProfiles {
             profiles: self.profiles,
         };
+        let contents = toml::to_string_pretty(&app_profiles)?;
+
+        // Only the user may read it, as headers of profiles may be secrets
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        utils::write_secure(&path, contents.as_bytes())
+            .with_context(|| format!("Unable to write configuration {}", path.display()))
+    }
+}
+
+fn check_profiles(
+    content: &str,
+    renamed_keys: &[(&'static str, &'static str)],
+) -> Result<Vec<ProfileCheck>> {
+    let config: Value = toml::from_str(content)?;
+    let table = config
+        .as_table()
+        .with_context(|| "The configuration isn't a table")?;
+
+    if let Some(key) = table.keys().find(|k| *k != "profiles") {
+        return Err(anyhow!("Unknown configuration key {}", key));
+    }
+
+    let profiles = match table.get("profiles") {
+        Some(Value::Array(profiles)) => profiles.clone(),
+        Some(_) => return Err(anyhow!("profiles needs to be a list of profiles")),
+        None => vec![],
+    };
+
+    let mut names = HashSet::new();
+
+    Ok(profiles
+        .into_iter()
+        .enumerate()
+        .map(|(index, value)| {
+            let name = match value.get("name").and_then(|n| n.as_str()) {
+                Some(name) => name.to_owned(),
+                None => format!("#{}", index + 1),
+            };
+            let mut value = value;
+            let mut problems = vec![];
+
+            if let Some(profile) = value.as_table_mut() {
+                problems.extend(
+                    profile
+                        .keys()
+                        .filter(|k| !PROFILE_KEYS.contains(&k.as_str()))
+                        .filter(|k| !renamed_keys.iter().any(|(old, _)| old == k))
+                        .map(|k| format!("unknown key {}", k)),
+                );
+                problems.extend(
+                    rename_profile_keys(profile, renamed_keys)
+                        .into_iter()
+                        .map(|(old, new)| format!("the key {} is now called {}", old, new)),
+                );
+            }
+
+            match value.try_into::<AppProfile>() {
+                Ok(profile) => problems.extend(profile.problems()),
+                Err(e) => problems.push(e.to_string()),
+            }
+
+            if !names.insert(name.clone()) {
+                problems.push("the name is used by another profile".to_string());
+            }
+
+            ProfileCheck { name, problems }
+        })
+        .collect())
+}
+
+// Renames the old keys of every profile in `config`, returning the profiles
+// along with the keys renamed
+fn rename_keys(
+    config: &mut Value,
+    renamed_keys: &[(&'static str, &'static str)],
+) -> Vec<(String, &'static str, &'static str)> {
+    let profiles = match config.get_mut("profiles") {
+        Some(Value::Array(profiles)) => profiles,
+        _ => return vec![],
+    };
+
+    profiles
+        .iter_mut()
+        .filter_map(Value::as_table_mut)
+        .flat_map(|profile| {
+            let name = profile
+                .get("name")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string();
+
+            rename_profile_keys(profile, renamed_keys)
+                .into_iter()
+                .map(move |(old, new)| (name.clone(), old, new))
+        })
+        .collect()
+}
+
+// A key given under both names keeps the value of the new one
+fn rename_profile_keys(
+    profile: &mut toml::value::Table,
+    renamed_keys: &[(&'static str, &'static str)],
+) -> Vec<(&'static str, &'static str)> {
+    renamed_keys
+        .iter()
+        .filter_map(|&(old, new)| {
+            let value = profile.remove(old)?;
+            profile.entry(new).or_insert(value);
+
+            Some((old, new))
+        })
+        .collect()
+}
+
+/// The names given to more than one profile of the configuration `content`,
+/// along with the lines of the profiles' headers
+fn duplicate_profiles(content: &str) -> Vec<(String, Vec<usize>)> {
+    let mut profiles: Vec<(String, Vec<usize>)> = vec![];
+    // The header line of the profile whose keys the current line belongs to,
+    // until the profile's name was found
+    let mut header = None;
+
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+
+        if line.starts_with('[') {
+            header = match line.starts_with(PROFILE_HEADER) {
+                true => Some(index + 1),
+                // Tables like headers don't name the profile
+                false => None,
+            };
+            continue;
+        }
+
+        let name = match (header, toml::from_str::<Value>(line)) {
+            (Some(_), Ok(value)) => value
+                .get("name")
+                .and_then(|n| n.as_str())
+                .map(|n| n.to_owned()),
+            _ => None,
+        };
 
-        match self.location {
-            Some(l) => confy::store_path(l, app_profiles).map_err(|e| e.into()),
-            _ => confy::store(crate_name!(), app_profiles).map_err(|e| e.into()),
+        if let (Some(line_number), Some(name)) = (header, name) {
+            match profiles.iter_mut().find(|(n, _)| *n == name) {
+                Some((_, lines)) => lines.push(line_number),
+                None => profiles.push((name, vec![line_number])),
+            }
+            header = None;
         }
     }
+
+    profiles.retain(|(_, lines)| lines.len() > 1);
+    profiles
 }
 
 fn find_duplicate(vec: &[AppProfile], profile: &AppProfile) -> bool {
@@ -107,9 +439,9 @@ fn find_duplicate(vec: &[AppProfile], profile: &AppProfile) -> bool {
 }
 
 #[cfg(test)]
-
 mod test {
     use super::*;
+    use crate::config::app::Headers;
     use crate::providers::ProviderType;
     use claim::{assert_err, assert_ok};
 
@@ -138,11 +470,116 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn resolves_location_in_order_of_precedence() -> Result<()> {
+        let flag = || Some("flag.toml".to_string());
+        let env = || Some("env.toml".to_string());
+
+        assert_eq!(
+            (flag(), ConfigSource::Flag),
+            resolve_location(flag(), env())
+        );
+        assert_eq!(
+            (env(), ConfigSource::Environment),
+            resolve_location(None, env())
+        );
+        assert_eq!((None, ConfigSource::Default), resolve_location(None, None));
+
+        let default = CrowbarConfig::with_location(None).path()?;
+        assert!(default.ends_with("crowbar.toml"), "{}", default.display());
+
+        Ok(())
+    }
+
+    #[test]
+    fn tells_lines_of_duplicate_profiles() -> Result<()> {
+        let content = fs::read_to_string("tests/fixtures/duplicate_profiles_config.toml")?;
+
+        assert_eq!(
+            vec![("profile_a".to_string(), vec![1, 16])],
+            duplicate_profiles(&content)
+        );
+        assert!(
+            duplicate_profiles(&fs::read_to_string("tests/fixtures/valid_config.toml")?).is_empty()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn fails_on_duplicate_profiles_when_strict() -> Result<()> {
+        let location = || Some("tests/fixtures/duplicate_profiles_config.toml".to_string());
+
+        assert_eq!(
+            3,
+            CrowbarConfig::with_location(location())
+                .read()?
+                .profiles
+                .len()
+        );
+
+        set_strict(true);
+        let result = CrowbarConfig::with_location(location()).read();
+        set_strict(false);
+
+        let error = result.unwrap_err();
+        assert_eq!(ErrorKind::Config, error::kind_of(&error));
+        assert!(error.to_string().contains("lines 1, 16"), "{}", error);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reads_renamed_keys_under_their_new_name() -> Result<()> {
+        const RENAMED: [(&str, &str); 2] = [("old_role", "role"), ("old_mfa", "mfa_factor")];
+        let content = r#"
+            [[profiles]]
+            name = "old"
+            provider = "okta"
+            url = "https://example.okta.com/home/amazon_aws/0oa/272"
+            old_role = "arn:aws:iam::123456789012:role/role1"
+            old_mfa = "push"
+        "#;
+
+        let mut config: Value = toml::from_str(content)?;
+        assert_eq!(
+            vec![
+                ("old".to_string(), "old_role", "role"),
+                ("old".to_string(), "old_mfa", "mfa_factor"),
+            ],
+            rename_keys(&mut config, &RENAMED)
+        );
+        let profiles: AppProfiles = config.try_into()?;
+        assert_eq!(
+            Some("arn:aws:iam::123456789012:role/role1".to_string()),
+            profiles.profiles[0].role
+        );
+        assert_eq!(Some("push".to_string()), profiles.profiles[0].mfa_factor);
+
+        assert_eq!(
+            vec![
+                "the key old_role is now called role".to_string(),
+                "the key old_mfa is now called mfa_factor".to_string(),
+            ],
+            check_profiles(content, &RENAMED)?[0].problems
+        );
+        // Keys that weren't renamed are unknown
+        assert_eq!(
+            vec![
+                "unknown key old_mfa".to_string(),
+                "unknown key old_role".to_string(),
+            ],
+            check_profiles(content, &RENAMED_KEYS)?[0].problems
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn should_detect_profile_duplicate() {
         let profile_a_vec = vec![profile_a()];
-        assert_eq!(true, find_duplicate(&profile_a_vec, &profile_a()));
-        assert_eq!(false, find_duplicate(&profile_a_vec, &profile_b()))
+        assert!(find_duplicate(&profile_a_vec, &profile_a()));
+        assert!(!find_duplicate(&profile_a_vec, &profile_b()))
     }
 
     #[test]
@@ -162,6 +599,7 @@ mod test {
         let config = CrowbarConfig {
             profiles: vec![profile_a()],
             location: None,
+            ad_hoc: false,
         };
 
         let result = config.add_profile(&profile_a());
@@ -175,6 +613,7 @@ mod test {
         let config = CrowbarConfig {
             profiles: vec![profile_a(), profile_b()],
             location: None,
+            ad_hoc: false,
         };
 
         assert_eq!(2, config.profiles.len());
@@ -191,6 +630,7 @@ mod test {
         let config = CrowbarConfig {
             profiles: vec![profile_b()],
             location: None,
+            ad_hoc: false,
         };
 
         let profile = profile_a();
@@ -199,6 +639,78 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn finds_profile_by_name() -> Result<()> {
+        let config = CrowbarConfig {
+            profiles: vec![profile_a(), profile_b()],
+            location: None,
+            ad_hoc: false,
+        };
+
+        assert_eq!(profile_b(), config.find_profile("profile_b")?);
+        assert_err!(config.find_profile("profile_c"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn lists_profile_names_in_order() {
+        let config = CrowbarConfig {
+            profiles: vec![profile_b(), profile_a()],
+            location: None,
+            ad_hoc: false,
+        };
+
+        assert_eq!(vec!["profile_b", "profile_a"], config.profile_names());
+        assert!(CrowbarConfig::new().profile_names().is_empty());
+    }
+
+    #[test]
+    fn checks_all_profiles() -> Result<()> {
+        let content = r#"
+            [[profiles]]
+            name = "valid"
+            provider = "okta"
+            url = "https://example.okta.com/home/amazon_aws/0oa/272"
+            role = "arn:aws:iam::123456789012:role/role1"
+
+            [[profiles]]
+            name = "typo"
+            provider = "okta"
+            url = "https://example.okta.com/home/amazon_aws/0oa/272"
+            mfa_facto = "push"
+
+            [[profiles]]
+            name = "broken"
+            provider = "onelogin"
+            url = "https://example.okta.com/home/amazon_aws/0oa/272"
+
+            [[profiles]]
+            name = "valid"
+            provider = "jumpcloud"
+            url = "https://sso.jumpcloud.com/saml2/aws"
+        "#;
+
+        let checks = check_profiles(content, &RENAMED_KEYS)?;
+
+        assert_eq!(4, checks.len());
+        assert!(checks[0].is_valid());
+        assert_eq!(
+            vec!["unknown key mfa_facto".to_string()],
+            checks[1].problems
+        );
+        assert_eq!("broken", checks[2].name);
+        assert!(!checks[2].is_valid());
+        assert_eq!(
+            vec!["the name is used by another profile".to_string()],
+            checks[3].problems
+        );
+
+        assert_err!(check_profiles("profile = []", &RENAMED_KEYS));
+
+        Ok(())
+    }
+
     // Test helper functions
     fn profile_a() -> AppProfile {
         AppProfile {
@@ -206,7 +718,37 @@ mod test {
             username: "username_a".to_owned(),
             provider: ProviderType::Okta,
             url: "https://www.example.com/example/saml".to_owned(),
+            okta_subdomain: None,
+            okta_domain: None,
             role: None,
+            mfa_factor: None,
+            mfa_provider: None,
+            auto_fallback_role: false,
+            accept_language: None,
+            sts_regional: None,
+            headers: Headers::default(),
+            forwarded_for: None,
+            proxy_username: None,
+            proxy_password: None,
+            max_idle_connections: None,
+            tls_backend: None,
+            aws_endpoint_url: None,
+            role_filter: None,
+            allowed_roles: vec![],
+            denied_roles: vec![],
+            duration: None,
+            push_device: None,
+            mfa_fallback: vec![],
+            password_command: None,
+            app: None,
+            output: None,
+            account_map: None,
+            sts_mode: None,
+            region: None,
+            factor_index: None,
+            role_index: None,
+            mfa_code: None,
+            prompt_duration: false,
         }
     }
     fn profile_b() -> AppProfile {
@@ -215,7 +757,37 @@ mod test {
             username: "username_b".to_owned(),
             provider: ProviderType::Okta,
             url: "https://www.example.com/example/saml".to_owned(),
+            okta_subdomain: None,
+            okta_domain: None,
             role: None,
+            mfa_factor: None,
+            mfa_provider: None,
+            auto_fallback_role: false,
+            accept_language: None,
+            sts_regional: None,
+            headers: Headers::default(),
+            forwarded_for: None,
+            proxy_username: None,
+            proxy_password: None,
+            max_idle_connections: None,
+            tls_backend: None,
+            aws_endpoint_url: None,
+            role_filter: None,
+            allowed_roles: vec![],
+            denied_roles: vec![],
+            duration: None,
+            push_device: None,
+            mfa_fallback: vec![],
+            password_command: None,
+            app: None,
+            output: None,
+            account_map: None,
+            sts_mode: None,
+            region: None,
+            factor_index: None,
+            role_index: None,
+            mfa_code: None,
+            prompt_duration: false,
         }
     }
 }
@@ -1,18 +1,164 @@
+use crate::aws::accounts::{AccountMap, AccountNames};
+use crate::aws::duration::SessionDuration;
+use crate::aws::role::{Partition, RoleName, StsMode};
+use crate::credentials::aws::FetchOptions;
+use crate::http::TlsBackend;
+use crate::output::OutputMode;
+use crate::providers::okta::{
+    self,
+    factors::{FactorProvider, FactorSelector},
+};
 use crate::providers::ProviderType;
+use crate::utils;
 use anyhow::{anyhow, Result};
 use clap::ArgMatches;
+use regex::Regex;
+use reqwest::header::{HeaderName, HeaderValue};
 use sha2::Digest;
+use std::collections::BTreeMap;
 use std::fmt;
+use std::net::IpAddr;
 use std::str::FromStr;
 use url::Url;
 
+pub const OKTA_USERNAME: &str = "OKTA_USERNAME";
+
+/// All keys a profile in the configuration file may have
+pub const PROFILE_KEYS: [&str; 30] = [
+    "name",
+    "provider",
+    "username",
+    "url",
+    "okta_subdomain",
+    "okta_domain",
+    "role",
+    "mfa_factor",
+    "mfa_provider",
+    "auto_fallback_role",
+    "accept_language",
+    "sts_regional",
+    "headers",
+    "forwarded_for",
+    "proxy_username",
+    "proxy_password",
+    "max_idle_connections",
+    "tls_backend",
+    "aws_endpoint_url",
+    "role_filter",
+    "allowed_roles",
+    "denied_roles",
+    "duration",
+    "push_device",
+    "mfa_fallback",
+    "password_command",
+    "app",
+    "output",
+    "account_map",
+    "sts_mode",
+];
+
+/// Extra headers sent with every request to the IdP, e.g. for a WAF in front
+/// of it. Their values may be secrets, so they never show up in debug output
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(transparent)]
+pub struct Headers(pub BTreeMap<String, String>);
+
+impl Headers {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Debug for Headers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map()
+            .entries(self.0.keys().map(|name| (name, "<redacted>")))
+            .finish()
+    }
+}
+
+/// A value that never shows up in debug output, like the password of a proxy
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(transparent)]
+pub struct Secret(pub String);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<redacted>")
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct AppProfile {
     pub name: String,
     pub provider: ProviderType,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub username: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub url: String,
+    /// Give the organization as e.g. `mycompany` and `okta.com` instead of
+    /// its URL, for profiles looking up their `app`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub okta_subdomain: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub okta_domain: Option<String>,
     pub role: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mfa_factor: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mfa_provider: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub auto_fallback_role: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accept_language: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sts_regional: Option<bool>,
+    #[serde(default, skip_serializing_if = "Headers::is_empty")]
+    pub headers: Headers,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forwarded_for: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy_username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy_password: Option<Secret>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_idle_connections: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_backend: Option<TlsBackend>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aws_endpoint_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role_filter: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_roles: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub denied_roles: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<SessionDuration>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub push_device: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mfa_fallback: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_command: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub app: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account_map: Option<AccountMap>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sts_mode: Option<StsMode>,
+    #[serde(skip)]
+    pub region: Option<String>,
+    #[serde(skip)]
+    pub factor_index: Option<usize>,
+    #[serde(skip)]
+    pub role_index: Option<usize>,
+    #[serde(skip)]
+    pub mfa_code: Option<String>,
+    #[serde(skip)]
+    pub prompt_duration: bool,
 }
 
 impl fmt::Display for AppProfile {
@@ -31,19 +177,79 @@ impl fmt::Display for AppProfile {
 
 impl<'a> From<&ArgMatches<'a>> for AppProfile {
     fn from(action: &ArgMatches) -> AppProfile {
-        AppProfile {
-            name: action.value_of("profile").unwrap().to_owned(),
-            username: action.value_of("username").unwrap().to_owned(),
-            url: action.value_of("url").unwrap().to_owned(),
-            role: action.value_of("role").map(|r| r.to_owned()),
-            provider: ProviderType::from_str(action.value_of("provider").unwrap()).unwrap(),
-        }
+        AppProfile::new(
+            action.value_of("profile").unwrap(),
+            ProviderType::from_str(action.value_of("provider").unwrap()).unwrap(),
+            action.value_of("url").unwrap(),
+            action.value_of("username").unwrap_or_default(),
+            action.value_of("role"),
+        )
     }
 }
 
 impl AppProfile {
+    /// A profile with nothing but the settings every profile has
+    pub fn new(
+        name: &str,
+        provider: ProviderType,
+        url: &str,
+        username: &str,
+        role: Option<&str>,
+    ) -> AppProfile {
+        AppProfile {
+            name: name.to_owned(),
+            provider,
+            username: username.to_owned(),
+            url: url.to_owned(),
+            okta_subdomain: None,
+            okta_domain: None,
+            role: role.map(|r| r.to_owned()),
+            mfa_factor: None,
+            mfa_provider: None,
+            auto_fallback_role: false,
+            accept_language: None,
+            sts_regional: None,
+            headers: Headers::default(),
+            forwarded_for: None,
+            proxy_username: None,
+            proxy_password: None,
+            max_idle_connections: None,
+            tls_backend: None,
+            aws_endpoint_url: None,
+            role_filter: None,
+            allowed_roles: vec![],
+            denied_roles: vec![],
+            duration: None,
+            push_device: None,
+            mfa_fallback: vec![],
+            password_command: None,
+            app: None,
+            output: None,
+            account_map: None,
+            sts_mode: None,
+            region: None,
+            factor_index: None,
+            role_index: None,
+            mfa_code: None,
+            prompt_duration: false,
+        }
+    }
+
+    /// The URL of the profile, or else the organization assembled from
+    /// `okta_subdomain` and `okta_domain`, e.g. `https://mycompany.okta.com`
+    pub fn full_url(&self) -> String {
+        match (&self.okta_subdomain, &self.okta_domain) {
+            (Some(subdomain), Some(domain)) if self.url.is_empty() => format!(
+                "https://{}.{}",
+                subdomain.trim_matches('.'),
+                domain.trim_matches('.')
+            ),
+            _ => self.url.clone(),
+        }
+    }
+
     pub fn request_url(&self) -> Result<Url> {
-        let url = self.url.clone();
+        let url = self.full_url();
         match Url::parse(&url) {
             Ok(u) => Ok(u),
             Err(e) => Err(anyhow!("Cannot parse profile URL: {}", e)),
@@ -52,7 +258,13 @@ impl AppProfile {
 
     pub fn base_url(&self) -> Result<Url> {
         let url = self.request_url()?;
-        let base_url = &format!("{}://{}", url.scheme(), url.host().unwrap());
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow!("The profile URL {} has no host", url))?;
+        let base_url = &match url.port() {
+            Some(port) => format!("{}://{}:{}", url.scheme(), host, port),
+            None => format!("{}://{}", url.scheme(), host),
+        };
         match Url::from_str(base_url) {
             Ok(u) => Ok(u),
             Err(e) => Err(anyhow!("Unable to create base URL: {}", e)),
@@ -62,10 +274,260 @@ impl AppProfile {
     pub fn is_profile(&self, profile: &str) -> bool {
         self.name == profile
     }
+
+    /// Checks the profile for mistakes without talking to the IdP
+    pub fn validate(&self) -> Result<()> {
+        match self.problems().as_slice() {
+            [] => Ok(()),
+            problems => Err(anyhow!(
+                "Profile {} is invalid: {}",
+                self.name,
+                problems.join(", ")
+            )),
+        }
+    }
+
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+
+        if self.name.trim().is_empty() {
+            problems.push("the name is empty".to_string());
+        }
+
+        match (&self.okta_subdomain, &self.okta_domain) {
+            (Some(_), None) => problems.push("the okta_subdomain needs an okta_domain".to_string()),
+            (None, Some(_)) => problems.push("the okta_domain needs an okta_subdomain".to_string()),
+            _ => (),
+        }
+
+        let url = self.full_url();
+        match self.request_url() {
+            _ if url.is_empty() => problems.push("the URL is missing".to_string()),
+            Ok(url) if url.scheme() != "https" => {
+                problems.push(format!("the URL {} doesn't use https", url))
+            }
+            Ok(url) if url.host().is_none() => {
+                problems.push(format!("the URL {} has no host", url))
+            }
+            Ok(url) if self.provider == ProviderType::Okta && self.app.is_none() => {
+                if let Err(e) = okta::embed_link(url) {
+                    problems.push(e.to_string());
+                }
+            }
+            Ok(_) => (),
+            Err(_) => problems.push(format!("the URL {} can't be parsed", url)),
+        }
+
+        if self.app.is_some() && self.provider != ProviderType::Okta {
+            problems.push("the app can only be looked up for Okta profiles".to_string());
+        }
+
+        if let Some(role) = &self.role {
+            let role_arn = Regex::new(r"^arn:[a-z-]+:iam::\d{12}:role/.+$").unwrap();
+
+            if role.starts_with("arn:") {
+                if Partition::from_arn(role).is_err() || !role_arn.is_match(role) {
+                    problems.push(format!("the role {} isn't an IAM role ARN", role));
+                }
+            } else if RoleName::parse(role).is_none() {
+                problems.push(format!(
+                    "the role {} is neither an IAM role ARN nor a role name like Admin or Admin@123456789012",
+                    role
+                ));
+            }
+        }
+
+        if let Some(factor) = &self.mfa_factor {
+            if let Err(e) = factor.parse::<FactorSelector>() {
+                problems.push(e.to_string());
+            }
+        }
+
+        if let Some(provider) = &self.mfa_provider {
+            if let Err(e) = provider.parse::<FactorProvider>() {
+                problems.push(e.to_string());
+            }
+        }
+
+        for factor in &self.mfa_fallback {
+            if let Err(e) = factor.parse::<FactorSelector>() {
+                problems.push(format!("the mfa_fallback {}", e));
+            }
+        }
+
+        if let Some(map) = &self.account_map {
+            if let Err(e) = AccountNames::load(map) {
+                problems.push(format!("{:#}", e));
+            }
+        }
+
+        if let (Some(StsMode::FederationToken), Some(role)) = (self.sts_mode, &self.role) {
+            problems.push(format!(
+                "the sts_mode {} federates an IAM user instead of assuming the role {}",
+                StsMode::FederationToken,
+                role
+            ));
+        }
+
+        if let Some(output) = &self.output {
+            if let Err(e) = output.parse::<OutputMode>() {
+                problems.push(e.to_string());
+            }
+        }
+
+        if let Some(language) = &self.accept_language {
+            if HeaderValue::from_str(language).is_err() {
+                problems.push(format!("the accept_language {} is invalid", language));
+            }
+        }
+
+        if let Some(forwarded_for) = &self.forwarded_for {
+            if forwarded_for
+                .split(',')
+                .any(|ip| ip.trim().parse::<IpAddr>().is_err())
+            {
+                problems.push(format!(
+                    "the forwarded_for {} isn't a list of IP addresses",
+                    forwarded_for
+                ));
+            }
+        }
+
+        if self.proxy_password.is_some() && self.proxy_username.is_none() {
+            problems.push("the proxy_password needs a proxy_username".to_string());
+        }
+
+        if let Some(backend) = self.tls_backend.filter(|b| !b.is_built_in()) {
+            problems.push(format!(
+                "the tls_backend {} isn't built into this crowbar",
+                backend
+            ));
+        }
+
+        if let Some(endpoint) = &self.aws_endpoint_url {
+            match Url::parse(endpoint) {
+                Ok(url) if ["http", "https"].contains(&url.scheme()) && url.has_host() => (),
+                _ => problems.push(format!(
+                    "the aws_endpoint_url {} isn't an http(s) URL",
+                    endpoint
+                )),
+            }
+        }
+
+        if let Some(filter) = &self.role_filter {
+            if let Err(e) = Regex::new(filter) {
+                problems.push(format!(
+                    "the role_filter {} isn't a valid regular expression: {}",
+                    filter, e
+                ));
+            }
+        }
+
+        for (key, patterns) in &[
+            ("allowed_roles", &self.allowed_roles),
+            ("denied_roles", &self.denied_roles),
+        ] {
+            for pattern in patterns.iter() {
+                if let Err(e) = Regex::new(pattern) {
+                    problems.push(format!(
+                        "the {} pattern {} isn't a valid regular expression: {}",
+                        key, pattern, e
+                    ));
+                }
+            }
+        }
+
+        if let Some(problem) = self.duration.as_ref().and_then(SessionDuration::problem) {
+            problems.push(problem);
+        }
+
+        for (name, value) in &self.headers.0 {
+            if HeaderName::from_bytes(name.as_bytes()).is_err() {
+                problems.push(format!("the header name {} is invalid", name));
+            } else if HeaderValue::from_str(value).is_err() {
+                problems.push(format!("the value of header {} is invalid", name));
+            }
+        }
+
+        problems
+    }
+
+    /// Applies settings passed on the command line on top of the configured ones
+    pub fn with_options(mut self, options: &FetchOptions) -> AppProfile {
+        if let Some(ref factor) = options.mfa_factor {
+            self.mfa_factor = Some(factor.clone());
+        }
+
+        if options.auto_fallback_role {
+            self.auto_fallback_role = true;
+        }
+
+        if options.sts_global {
+            self.sts_regional = Some(false);
+        }
+
+        if let Some(ref filter) = options.role_filter {
+            self.role_filter = Some(filter.clone());
+        }
+
+        if let Some(ref provider) = options.mfa_provider {
+            self.mfa_provider = Some(provider.clone());
+        }
+
+        if let Some(ref device) = options.push_device {
+            self.push_device = Some(device.clone());
+        }
+
+        if let Some(connections) = options.max_idle_connections {
+            self.max_idle_connections = Some(connections);
+        }
+
+        if let Some(ref region) = options.region {
+            self.region = Some(region.clone());
+        }
+
+        self.factor_index = options.factor_index;
+        self.role_index = options.role_index;
+        self.mfa_code = options.mfa_code.clone();
+        self.prompt_duration = options.prompt_duration;
+
+        self
+    }
+
+    /// Fills in a missing username from, in order, the `OKTA_USERNAME`
+    /// environment variable, the system user or, as a last resort, a prompt
+    pub fn with_resolved_username(mut self) -> Result<AppProfile> {
+        let username = resolve_username(
+            &self.username,
+            utils::non_empty_env_var(OKTA_USERNAME),
+            Some(whoami::username()),
+        );
+
+        self.username = match username {
+            Some(username) => username,
+            None => utils::prompt_username(&self)?,
+        };
+
+        debug!("Using username {} for profile {}", self.username, self.name);
+
+        Ok(self)
+    }
+}
+
+fn resolve_username(
+    configured: &str,
+    env: Option<String>,
+    system: Option<String>,
+) -> Option<String> {
+    if !configured.is_empty() {
+        return Some(configured.to_owned());
+    }
+
+    env.or(system).filter(|u| !u.is_empty())
 }
 
 #[cfg(test)]
-mod test {
+pub mod test {
     use super::*;
 
     #[test]
@@ -95,6 +557,63 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn keeps_port_in_base_of_url() -> Result<()> {
+        let mut profile = short_profile();
+        profile.url = "https://example.com:8443/home/amazon_aws/0oa/272".to_string();
+        assert_eq!(
+            Url::from_str("https://example.com:8443")?,
+            profile.base_url()?
+        );
+
+        profile.url = "https://example.com:443/home/amazon_aws/0oa/272".to_string();
+        assert_eq!(Url::from_str("https://example.com")?, profile.base_url()?);
+
+        profile.url = "data:x".to_string();
+        assert_eq!(
+            "The profile URL data:x has no host",
+            profile.base_url().unwrap_err().to_string()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn resolves_username_in_order_of_precedence() {
+        let env = Some("env_user".to_string());
+        let system = Some("system_user".to_string());
+
+        assert_eq!(
+            Some("configured".to_string()),
+            resolve_username("configured", env.clone(), system.clone())
+        );
+        assert_eq!(
+            Some("env_user".to_string()),
+            resolve_username("", env, system.clone())
+        );
+        assert_eq!(
+            Some("system_user".to_string()),
+            resolve_username("", None, system)
+        );
+        assert_eq!(None, resolve_username("", None, Some("".to_string())));
+        assert_eq!(None, resolve_username("", None, None));
+    }
+
+    #[test]
+    fn deserializes_profile_without_username() {
+        let profile: AppProfile = toml::from_str(
+            r#"
+            name = "profile"
+            provider = "okta"
+            url = "https://example.com/example/url"
+        "#
+            .trim_start(),
+        )
+        .unwrap();
+
+        assert!(profile.username.is_empty());
+    }
+
     #[test]
     fn validates_profile_name() {
         assert_eq!("profile", short_profile().name)
@@ -114,6 +633,14 @@ mod test {
         .unwrap()
     }
 
+    /// The app link of `okta_profile` in most tests
+    pub const OKTA_URL: &str = "https://example.okta.com/home/amazon_aws/0oa1b2c3prod/272";
+
+    /// An Okta profile of user jdoe, for the tests of any module
+    pub fn okta_profile(name: &str, url: &str) -> AppProfile {
+        AppProfile::new(name, ProviderType::Okta, url, "jdoe", None)
+    }
+
     fn short_profile() -> AppProfile {
         toml::from_str(
             r#"
@@ -126,4 +653,161 @@ mod test {
         )
         .unwrap()
     }
+
+    #[test]
+    fn validates_profiles() -> Result<()> {
+        let mut profile = create_valid_profile();
+        assert!(profile.validate().is_ok());
+
+        profile.url = "http://example.com/home/amazon_aws/0oa/272".to_string();
+        profile.role = Some("role/1".to_string());
+        profile.mfa_factor = Some("carrier-pigeon".to_string());
+
+        let problems = profile.problems();
+        assert_eq!(3, problems.len());
+        assert!(problems[0].contains("doesn't use https"));
+        assert!(problems[1].contains("neither an IAM role ARN nor a role name"));
+        assert!(profile.validate().is_err());
+
+        profile.url = "not a url".to_string();
+        profile.role = Some("arn:aws:iam::123456789012:user/jdoe".to_string());
+        profile.mfa_factor = None;
+
+        let problems = profile.problems();
+        assert!(problems[0].contains("can't be parsed"));
+        assert!(problems[1].contains("isn't an IAM role ARN"));
+
+        profile.url = "https://example.okta.com/app/UserHome".to_string();
+        profile.role = None;
+
+        let problems = profile.problems();
+        assert_eq!(1, problems.len());
+        assert!(problems[0].contains("neither the embed link nor the tile link"));
+
+        profile.url = create_valid_profile().url;
+        profile.aws_endpoint_url = Some("http://localhost:4566".to_string());
+        assert!(profile.problems().is_empty());
+
+        profile.aws_endpoint_url = Some("localhost:4566".to_string());
+        let problems = profile.problems();
+        assert_eq!(1, problems.len());
+        assert!(problems[0].contains("isn't an http(s) URL"));
+
+        profile.aws_endpoint_url = None;
+        profile.role_filter = Some("prod-[0-9]+/(Admin".to_string());
+        let problems = profile.problems();
+        assert_eq!(1, problems.len());
+        assert!(problems[0].contains("isn't a valid regular expression"));
+
+        profile.role_filter = None;
+        profile.denied_roles = vec!["/Admin$".to_string(), "(prod".to_string()];
+        let problems = profile.problems();
+        assert_eq!(1, problems.len());
+        assert!(problems[0].contains("the denied_roles pattern (prod isn't a valid"));
+
+        profile.denied_roles = vec![];
+        profile.duration = Some(SessionDuration::Max);
+        assert!(profile.problems().is_empty());
+
+        profile.duration = Some(SessionDuration::Seconds(300));
+        let problems = profile.problems();
+        assert_eq!(1, problems.len());
+        assert!(problems[0].contains("isn't between 900 and 43200 seconds"));
+
+        profile.duration = None;
+        profile.sts_mode = Some(StsMode::FederationToken);
+        assert!(profile.problems().is_empty());
+
+        profile.role = Some("arn:aws:iam::123456789012:role/role1".to_string());
+        let problems = profile.problems();
+        assert_eq!(1, problems.len());
+        assert!(problems[0].contains("federates an IAM user"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn validates_headers() {
+        let mut profile = create_valid_profile();
+        profile
+            .headers
+            .0
+            .insert("X-Waf-Token".to_string(), "token".to_string());
+        assert!(profile.validate().is_ok());
+
+        profile
+            .headers
+            .0
+            .insert("Not a name".to_string(), "token".to_string());
+        profile
+            .headers
+            .0
+            .insert("X-Other".to_string(), "line\nbreak".to_string());
+
+        assert_eq!(
+            vec![
+                "the header name Not a name is invalid",
+                "the value of header X-Other is invalid"
+            ],
+            profile.problems()
+        );
+    }
+
+    #[test]
+    fn assembles_org_url_from_subdomain_and_domain() -> Result<()> {
+        let mut profile: AppProfile = toml::from_str(
+            r#"
+            name = "profile"
+            provider = "okta"
+            okta_subdomain = "mycompany"
+            okta_domain = "okta.com"
+            app = "AWS Production"
+        "#,
+        )?;
+        assert_eq!("https://mycompany.okta.com", profile.full_url());
+        assert_eq!(
+            Url::from_str("https://mycompany.okta.com")?,
+            profile.base_url()?
+        );
+        assert!(profile.problems().is_empty());
+
+        // A full URL wins
+        profile.url = "https://other.okta-emea.com".to_string();
+        assert_eq!(
+            Url::from_str("https://other.okta-emea.com")?,
+            profile.base_url()?
+        );
+
+        profile.url = String::new();
+        profile.okta_domain = None;
+        assert_eq!(
+            vec![
+                "the okta_subdomain needs an okta_domain",
+                "the URL is missing"
+            ],
+            profile.problems()
+        );
+
+        // Without an app the organization isn't enough
+        profile.okta_domain = Some("okta.com".to_string());
+        profile.app = None;
+        let problems = profile.problems();
+        assert_eq!(1, problems.len());
+        assert!(problems[0].contains("neither the embed link nor the tile link"));
+
+        Ok(())
+    }
+
+    fn create_valid_profile() -> AppProfile {
+        toml::from_str(
+            r#"
+            name = "profile"
+            provider = "okta"
+            url = "https://example.okta.com/home/amazon_aws/0oa/272"
+            role = "arn:aws-us-gov:iam::123456789012:role/role1"
+            mfa_factor = "totp:google"
+        "#,
+        )
+        .unwrap()
+    }
 }
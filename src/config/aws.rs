@@ -1,15 +1,16 @@
 use crate::config::app::AppProfile;
+use crate::error;
 use crate::utils;
 use anyhow::{anyhow, Context, Result};
 use dirs_next::home_dir;
 use ini::Ini;
-use std::fs;
-use std::fs::File;
-use std::io::ErrorKind;
-use std::path::PathBuf;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
 
 pub const AWS_CONFIG_FILE: &str = "AWS_CONFIG_FILE";
 pub const PROFILE_KEY: &str = "credential_process";
+pub const REGION_KEY: &str = "region";
 
 #[derive(Clone)]
 pub struct AwsConfig {
@@ -36,11 +37,17 @@ impl AwsConfig {
         Ok(AwsConfig { profiles, location })
     }
 
+    /// Writes the configuration, creating its directory if it's missing. Only
+    /// the user may read the file, like the AWS CLI does, as it may hold
+    /// secrets of other tools
     pub fn write(self) -> Result<AwsConfig> {
         let location = &self.location;
-        self.profiles
-            .write_to_file(location)
-            .with_context(|| format!("Unable to write AWS configuration at {:?}", location))?;
+        let mut contents = Vec::new();
+        self.profiles.write_to(&mut contents)?;
+
+        create_parent(location)
+            .and_then(|_| utils::write_secure(location, &contents))
+            .map_err(|e| write_error(location, e))?;
 
         Ok(self)
     }
@@ -56,6 +63,42 @@ impl AwsConfig {
         Ok(self)
     }
 
+    /// Wires a profile to fetch its credentials with `crowbar creds` as its
+    /// credential_process, keeping all other settings of the profile
+    pub fn install_process(mut self, profile_name: &str) -> Result<AwsConfig> {
+        self.profiles.set_to(
+            Some(format!("profile {}", profile_name)),
+            PROFILE_KEY.to_string(),
+            credential_process(profile_name),
+        );
+
+        Ok(self)
+    }
+
+    /// Points a profile at credentials stored by `crowbar assume-all`, which
+    /// `crowbar get` prints without logging in
+    pub fn install_stored(mut self, name: &str) -> Result<AwsConfig> {
+        self.profiles.set_to(
+            Some(format!("profile {}", name)),
+            PROFILE_KEY.to_string(),
+            format!("crowbar get {}", name),
+        );
+
+        Ok(self)
+    }
+
+    /// Sets the default region of a profile for tools reading the AWS CLI
+    /// configuration, keeping all other settings of the profile
+    pub fn set_region(mut self, profile_name: &str, region: &str) -> Result<AwsConfig> {
+        self.profiles.set_to(
+            Some(format!("profile {}", profile_name)),
+            REGION_KEY.to_string(),
+            region.to_string(),
+        );
+
+        Ok(self)
+    }
+
     pub fn delete_profile(mut self, profile_name: &str) -> Result<AwsConfig> {
         let profile_name = format!("profile {}", profile_name);
         self.profiles.delete_from(Some(profile_name), PROFILE_KEY);
@@ -64,21 +107,107 @@ impl AwsConfig {
     }
 }
 
+/// The credential_process of a profile, printing the credential_process JSON
+/// while prompts go to the terminal instead of the AWS CLI
+pub fn credential_process(profile_name: &str) -> String {
+    format!(
+        "sh -c 'crowbar creds {} -p --output json 2> /dev/tty'",
+        profile_name
+    )
+}
+
+/// The section `install_process` writes, to add to the AWS CLI
+/// configuration by hand
+pub fn process_snippet(profile_name: &str) -> Result<String> {
+    let mut snippet = Vec::new();
+    AwsConfig {
+        profiles: Ini::new(),
+        location: PathBuf::new(),
+    }
+    .install_process(profile_name)?
+    .profiles
+    .write_to(&mut snippet)?;
+
+    Ok(String::from_utf8(snippet)?)
+}
+
+fn create_parent(location: &Path) -> io::Result<()> {
+    match location.parent() {
+        Some(parent) => fs::create_dir_all(parent),
+        None => Ok(()),
+    }
+}
+
+fn write_error(location: &Path, e: io::Error) -> anyhow::Error {
+    let message = match e.kind() {
+        ErrorKind::PermissionDenied => format!(
+            "Unable to write AWS configuration at {}, permission denied. Check that you may write to {}",
+            location.display(),
+            location.parent().unwrap_or(location).display()
+        ),
+        _ => format!("Unable to write AWS configuration at {}", location.display()),
+    };
+
+    anyhow::Error::new(e).context(message)
+}
+
 fn default_config_location() -> Result<PathBuf> {
-    let env = utils::non_empty_env_var(AWS_CONFIG_FILE);
-    match env {
-        Some(path) => Ok(PathBuf::from(path)),
-        None => hardcoded_config_location(),
+    config_location(utils::non_empty_env_var(AWS_CONFIG_FILE), home_dir())
+}
+
+// Containers and CI runners may have no home directory, which only matters
+// unless the file is given explicitly
+fn config_location(env: Option<String>, home: Option<PathBuf>) -> Result<PathBuf> {
+    match (env, home) {
+        (Some(path), _) => Ok(PathBuf::from(path)),
+        (None, Some(home)) => Ok(home.join(".aws").join("config")),
+        (None, None) => Err(error::error(
+            error::ErrorKind::Config,
+            format!(
+                "Unable to find the AWS configuration without a home directory, set HOME or {} to the file",
+                AWS_CONFIG_FILE
+            ),
+        )),
     }
 }
 
-fn hardcoded_config_location() -> Result<PathBuf> {
-    match home_dir() {
-        Some(mut home_path) => {
-            home_path.push(".aws");
-            home_path.push("config");
-            Ok(home_path)
-        }
-        None => Err(anyhow!("Failed to determine home directory.")),
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn finds_config_without_home_directory_if_given() -> Result<()> {
+        assert_eq!(
+            PathBuf::from("/etc/aws/config"),
+            config_location(Some("/etc/aws/config".to_string()), None)?
+        );
+        assert_eq!(
+            PathBuf::from("/home/jdoe/.aws/config"),
+            config_location(None, Some(PathBuf::from("/home/jdoe")))?
+        );
+
+        let error = config_location(None, None).unwrap_err();
+        assert!(error.to_string().contains(AWS_CONFIG_FILE), "{}", error);
+
+        Ok(())
+    }
+
+    #[test]
+    fn explains_permission_problems() {
+        let location = Path::new("/etc/aws/config");
+
+        let message =
+            write_error(location, io::Error::from(ErrorKind::PermissionDenied)).to_string();
+        assert_eq!(
+            "Unable to write AWS configuration at /etc/aws/config, permission denied. \
+             Check that you may write to /etc/aws",
+            message
+        );
+
+        let message = write_error(location, io::Error::from(ErrorKind::Other)).to_string();
+        assert_eq!(
+            "Unable to write AWS configuration at /etc/aws/config",
+            message
+        );
     }
 }
@@ -1,13 +1,29 @@
 pub mod aws;
 pub mod config;
+pub mod keystore;
+pub mod totp;
+
+use crate::aws::duration;
+use crate::config::app::AppProfile;
+use crate::credentials::aws::AwsCredentials;
+use crate::credentials::config::ConfigCredentials;
+use crate::credentials::totp::TotpCredentials;
+use crate::providers::okta::auth::LastPush;
+use crate::providers::okta::{apps, devices, sessions};
 
 use anyhow::Result;
 use std::fmt;
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum CredentialType {
     Config,
     Aws,
+    Totp,
+    Session,
+    Device,
+    Push,
+    App,
+    Duration,
 }
 
 impl fmt::Display for CredentialType {
@@ -15,13 +31,173 @@ impl fmt::Display for CredentialType {
         match self {
             CredentialType::Config => write!(f, "config"),
             CredentialType::Aws => write!(f, "aws"),
+            CredentialType::Totp => write!(f, "totp"),
+            CredentialType::Session => write!(f, "session"),
+            CredentialType::Device => write!(f, "device"),
+            CredentialType::Push => write!(f, "push"),
+            CredentialType::App => write!(f, "app"),
+            CredentialType::Duration => write!(f, "duration"),
         }
     }
 }
 
+impl CredentialType {
+    /// The keyring service secrets of this type are stored under
+    pub fn service(&self, id: &dyn fmt::Display) -> String {
+        format!("crowbar::{}::{}", self, id)
+    }
+}
+
 pub trait Credential<T, U> {
     fn create(profile: &T) -> Result<U>;
     fn load(profile: &T) -> Result<U>;
     fn write(self, profile: &T) -> Result<U>;
     fn delete(self, profile: &T) -> Result<U>;
 }
+
+/// Removes everything crowbar cached for a profile: its AWS credentials,
+/// kept Okta session, the device trust of its Okta organization, the Push
+/// factor used last and the link of its app. With `all` its password and TOTP
+/// secret go as well. Returns the types that were actually deleted
+pub fn clean(profile: &AppProfile, all: bool) -> Result<Vec<CredentialType>> {
+    let mut deleted = vec![];
+
+    let aws_credentials = AwsCredentials::load(profile)?;
+    if aws_credentials.valid() {
+        aws_credentials.delete(profile)?;
+        deleted.push(CredentialType::Aws);
+    }
+
+    if sessions::kept_session(profile).is_some() {
+        sessions::forget(profile);
+        deleted.push(CredentialType::Session);
+    }
+
+    if devices::trusted_device(profile).is_some() {
+        devices::forget(profile);
+        deleted.push(CredentialType::Device);
+    }
+
+    let last_push = LastPush::new(profile);
+    if last_push.load().is_some() {
+        last_push.forget();
+        deleted.push(CredentialType::Push);
+    }
+
+    if apps::cached_app_link(profile).is_some() {
+        apps::forget(profile);
+        deleted.push(CredentialType::App);
+    }
+
+    if all {
+        if let Ok(credentials) = ConfigCredentials::load(profile) {
+            credentials.delete(profile)?;
+            deleted.push(CredentialType::Config);
+        }
+
+        if let Ok(credentials) = TotpCredentials::load(profile) {
+            credentials.delete(profile)?;
+            deleted.push(CredentialType::Totp);
+        }
+    }
+
+    Ok(deleted)
+}
+
+/// Removes what crowbar cached for roles rather than profiles, i.e. the
+/// longest sessions they allow. Returns the types that were actually deleted
+pub fn clean_shared() -> Vec<CredentialType> {
+    match duration::forget_all() {
+        true => vec![CredentialType::Duration],
+        false => vec![],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::aws::duration::SessionDuration;
+    use crate::aws::role::Role;
+    use crate::credentials::keystore::test::MemoryBackend;
+    use crate::providers::okta::apps::AppLink;
+    use crate::providers::ProviderType;
+    use std::rc::Rc;
+
+    #[test]
+    fn cleans_cached_state_and_only_with_all_secrets() -> Result<()> {
+        let mut profile = AppProfile::new(
+            "profile",
+            ProviderType::Okta,
+            "https://example.okta.com",
+            "jdoe",
+            None,
+        );
+        profile.app = Some("AWS Production".to_string());
+        let role = Role {
+            provider_arn: "arn:aws:iam::123456789012:saml-provider/okta-idp".to_string(),
+            role_arn: "arn:aws:iam::123456789012:role/Admin".to_string(),
+        };
+
+        keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+            AwsCredentials {
+                version: 1,
+                access_key_id: Some("ASIAEXAMPLE".to_string()),
+                secret_access_key: Some("secret".to_string()),
+                session_token: Some("token".to_string()),
+                expiration: Some("2038-01-01T10:10:10Z".to_string()),
+            }
+            .write(&profile)?;
+            sessions::adopt(&profile, "102bZDNFfWaQSyEZQuDgWt-uQ");
+            devices::trust(&profile, "DI0dZ5mFB7bQ2W8kJ3tNWK3Ag");
+            LastPush::new(&profile).store("opf3hkfocI4JTLAju0g4");
+            apps::app_link(&profile, "AWS Production", || {
+                Ok(vec![AppLink {
+                    label: "AWS Production".to_string(),
+                    link_url: "https://example.okta.com/home/amazon_aws/0oa1/272".to_string(),
+                    app_name: "amazon_aws".to_string(),
+                    app_instance_id: "0oa1".to_string(),
+                }])
+            })?;
+            duration::remember_max(&role, 14400);
+            ConfigCredentials::new("hunter2".to_string()).write(&profile)?;
+            TotpCredentials::new("JBSWY3DPEHPK3PXP".to_string()).write(&profile)?;
+
+            assert_eq!(
+                vec![
+                    CredentialType::Aws,
+                    CredentialType::Session,
+                    CredentialType::Device,
+                    CredentialType::Push,
+                    CredentialType::App,
+                ],
+                clean(&profile, false)?
+            );
+            assert_eq!(vec![CredentialType::Duration], clean_shared());
+
+            assert!(!AwsCredentials::load(&profile)?.valid());
+            assert_eq!(None, sessions::kept_session(&profile));
+            assert_eq!(None, devices::trusted_device(&profile));
+            assert_eq!(None, LastPush::new(&profile).load());
+            assert_eq!(None, apps::cached_app_link(&profile));
+            // Without a cached maximum, max starts out with the longest session
+            assert_eq!(
+                Some(Some(duration::MAX_SECONDS)),
+                duration::candidates(&role, Some(SessionDuration::Max), None)
+                    .first()
+                    .copied()
+            );
+            assert_eq!(Vec::<CredentialType>::new(), clean_shared());
+            assert!(ConfigCredentials::load(&profile).is_ok());
+            assert!(TotpCredentials::load(&profile).is_ok());
+
+            assert_eq!(
+                vec![CredentialType::Config, CredentialType::Totp],
+                clean(&profile, true)?
+            );
+            assert!(ConfigCredentials::load(&profile).is_err());
+            assert!(TotpCredentials::load(&profile).is_err());
+
+            Ok(())
+        })
+    }
+}
@@ -1,21 +1,40 @@
+use crate::api;
+use crate::aws::duration::SessionDuration;
+use crate::aws::role::{self, CallerIdentity, StsMode, StsOperation};
+use crate::clock;
 use crate::config::app::AppProfile;
 use crate::config::CrowbarConfig;
 use crate::credentials::config::ConfigCredentials;
+use crate::credentials::keystore;
 use crate::credentials::Credential;
 use crate::credentials::CredentialType;
+use crate::metrics;
+use crate::preflight;
+use crate::prompt;
 use crate::providers::adfs::AdfsProvider;
 use crate::providers::jumpcloud::JumpcloudProvider;
-use crate::providers::okta::OktaProvider;
+use crate::providers::okta::{devices, sessions, OktaProvider};
 use crate::providers::ProviderType;
+use crate::saml;
+use crate::status;
+use crate::timings::{self, Phase, Timings};
+use crate::ui;
+use crate::utils::RoleOptions;
 
-use anyhow::{anyhow, Result};
-use chrono::{DateTime, Utc};
-use keyring::Keyring;
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use rusoto_credential::{CredentialsError, ProvideAwsCredentials};
 use rusoto_sts::Credentials;
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::Duration;
 use std::{fmt, str};
 
-const SECONDS_TO_EXPIRATION: i64 = 900; // 15 minutes
+pub const SECONDS_TO_EXPIRATION: i64 = 900; // 15 minutes
+
+// Expirations without an offset, as some tools write them
+const NAIVE_EXPIRATION_FORMATS: [&str; 2] = ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"];
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Hash, Eq, Clone)]
 #[serde(rename_all = "PascalCase")]
@@ -27,17 +46,84 @@ pub struct AwsCredentials {
     pub expiration: Option<String>,
 }
 
+#[derive(Debug, Default, Clone)]
+pub struct FetchOptions {
+    pub force: bool,
+    pub mfa_factor: Option<String>,
+    pub auto_fallback_role: bool,
+    pub factor_index: Option<usize>,
+    pub role_index: Option<usize>,
+    pub role_filter: Option<String>,
+    pub mfa_code: Option<String>,
+    pub mfa_provider: Option<String>,
+    pub push_device: Option<String>,
+    pub region: Option<String>,
+    pub sts_global: bool,
+    pub preflight: bool,
+    pub keep_session: bool,
+    pub forget_device: bool,
+    /// Logs in to Okta anew, ignoring the kept session and the cached
+    /// credentials, but keeps the password, factor and role choices
+    pub reauth: bool,
+    pub prompt_duration: bool,
+    /// Fetches new credentials even if the cached ones are still valid, which
+    /// extends a kept Okta session as well
+    pub renew: bool,
+    /// Replaces cached credentials with less than this left instead of the
+    /// default buffer, see `--refresh-if-expiring-within`
+    pub refresh_within: Option<Duration>,
+    /// Idle connections the IdP clients keep per host, see `--max-idle-connections`
+    pub max_idle_connections: Option<usize>,
+}
+
+/// Whether usable credentials were cached or had to be fetched anew
+#[derive(Debug, PartialEq)]
+pub enum Ensured {
+    Valid(AwsCredentials),
+    Refreshed(AwsCredentials),
+}
+
+#[derive(Debug)]
+pub struct RunResult {
+    pub credentials: AwsCredentials,
+    pub timings: Timings,
+    /// When the Okta session kept with `--keep-session` expires, if known.
+    /// Fetching credentials after that needs the password and MFA again
+    pub session_expires_at: Option<DateTime<Utc>>,
+    /// The role assumed for new credentials, unknown for cached ones
+    pub role: Option<role::Role>,
+}
+
 impl AwsCredentials {
+    /// Credentials with an expiration that can't be parsed count as expired,
+    /// so they get replaced instead of used until STS rejects them
     pub fn is_expired(&self) -> bool {
-        match &self.expiration {
-            Some(dt) => {
-                let expiration = DateTime::parse_from_rfc3339(dt).unwrap();
-                expiration.signed_duration_since(Utc::now()).num_seconds() < SECONDS_TO_EXPIRATION
+        self.expires_within(None)
+    }
+
+    /// Whether the credentials have less than `within` left, or less than
+    /// the default buffer without one
+    pub fn expires_within(&self, within: Option<Duration>) -> bool {
+        let buffer = within.map_or(SECONDS_TO_EXPIRATION, |within| within.as_secs() as i64);
+
+        match (&self.expiration, self.expires_at()) {
+            (_, Some(expiration)) => {
+                expiration.signed_duration_since(clock::now()).num_seconds() < buffer
+            }
+            (Some(expiration), None) => {
+                debug!("Invalid expiration {} of the credentials", expiration);
+                true
             }
-            _ => false,
+            (None, None) => false,
         }
     }
 
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        self.expiration
+            .as_ref()
+            .and_then(|dt| parse_expiration(dt).ok())
+    }
+
     pub fn valid(&self) -> bool {
         self.access_key_id.is_some()
             && self.secret_access_key.is_some()
@@ -46,6 +132,21 @@ impl AwsCredentials {
     }
 }
 
+/// Parses the expiration of credentials, e.g. `2038-01-01T10:10:10Z`. STS
+/// always answers in UTC, so times without an offset are taken as UTC as
+/// well and never as the local time of the machine
+pub fn parse_expiration(expiration: &str) -> Result<DateTime<Utc>> {
+    if let Ok(expiration) = DateTime::parse_from_rfc3339(expiration) {
+        return Ok(expiration.with_timezone(&Utc));
+    }
+
+    NAIVE_EXPIRATION_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(expiration, format).ok())
+        .map(|expiration| DateTime::<Utc>::from_utc(expiration, Utc))
+        .with_context(|| format!("Invalid expiration {} of the credentials", expiration))
+}
+
 impl From<Credentials> for AwsCredentials {
     fn from(creds: Credentials) -> Self {
         AwsCredentials {
@@ -58,6 +159,47 @@ impl From<Credentials> for AwsCredentials {
     }
 }
 
+/// The credentials as rusoto takes them, for programs calling AWS with
+/// rusoto directly. Credentials without keys can't be converted
+impl TryFrom<AwsCredentials> for rusoto_credential::AwsCredentials {
+    type Error = anyhow::Error;
+
+    fn try_from(creds: AwsCredentials) -> Result<Self> {
+        let expires_at = creds
+            .expiration
+            .as_deref()
+            .map(parse_expiration)
+            .transpose()?;
+
+        match (creds.access_key_id, creds.secret_access_key) {
+            (Some(key), Some(secret)) => Ok(rusoto_credential::AwsCredentials::new(
+                key,
+                secret,
+                creds.session_token,
+                expires_at,
+            )),
+            _ => Err(anyhow!("The credentials have no access key")),
+        }
+    }
+}
+
+/// Lets credentials fetched by crowbar be handed to rusoto clients like
+/// `StsClient::new_with(dispatcher, credentials, region)`. Expired ones are
+/// refused, rusoto has no means to fetch new ones through crowbar
+#[async_trait]
+impl ProvideAwsCredentials for AwsCredentials {
+    async fn credentials(&self) -> Result<rusoto_credential::AwsCredentials, CredentialsError> {
+        if self.is_expired() {
+            return Err(CredentialsError::new(
+                "The credentials from crowbar expired, fetch new ones",
+            ));
+        }
+
+        rusoto_credential::AwsCredentials::try_from(self.clone())
+            .map_err(|e| CredentialsError::new(format!("{:#}", e)))
+    }
+}
+
 impl From<HashMap<String, Option<String>>> for AwsCredentials {
     fn from(mut map: HashMap<String, Option<String>>) -> Self {
         AwsCredentials {
@@ -109,26 +251,7 @@ impl Credential<AppProfile, AwsCredentials> for AwsCredentials {
     }
 
     fn load(profile: &AppProfile) -> Result<AwsCredentials> {
-        let default_map: HashMap<String, Option<String>> = AwsCredentials::default().into();
-        let mut credential_map: HashMap<String, Option<String>> = AwsCredentials::default().into();
-        let service = credentials_as_service(profile);
-
-        debug!("Trying to fetch cached AWS credentials for ID {}", &service);
-
-        for key in default_map.keys() {
-            let _res = credential_map.insert(
-                key.clone(),
-                match Keyring::new(&service, key).get_password() {
-                    Ok(s) => Some(s),
-                    Err(e) => {
-                        debug!("Error while fetching credentials: {}", e);
-                        break;
-                    }
-                },
-            );
-        }
-
-        Ok(AwsCredentials::from(credential_map))
+        Ok(load_service(&credentials_as_service(profile)))
     }
 
     fn write(self, profile: &AppProfile) -> Result<AwsCredentials> {
@@ -137,9 +260,7 @@ impl Credential<AppProfile, AwsCredentials> for AwsCredentials {
         debug!("Saving AWS credentials for {}", &service);
 
         for (key, secret) in credential_map.iter() {
-            Keyring::new(&service, key)
-                .set_password(&secret.clone().unwrap())
-                .map_err(|e| anyhow!("{}", e))?;
+            keystore::set_password(&service, key, &secret.clone().unwrap())?;
         }
 
         Ok(self)
@@ -150,91 +271,752 @@ impl Credential<AppProfile, AwsCredentials> for AwsCredentials {
         let service = credentials_as_service(profile);
 
         for (key, _) in credential_map.iter() {
-            let keyring = Keyring::new(&service, key);
-            let pass = keyring.get_password();
-
-            if pass.is_ok() {
-                debug!("Deleting secret for {} at service {}", key, &service);
-                keyring.delete_password().map_err(|e| anyhow!("{}", e))?
-            }
+            debug!("Deleting secret for {} at service {}", key, &service);
+            keystore::delete_password(&service, key)?;
         }
 
         Ok(self)
     }
 }
 
+fn load_service(service: &str) -> AwsCredentials {
+    let default_map: HashMap<String, Option<String>> = AwsCredentials::default().into();
+    let mut credential_map: HashMap<String, Option<String>> = AwsCredentials::default().into();
+
+    debug!("Trying to fetch cached AWS credentials for ID {}", service);
+
+    for key in default_map.keys() {
+        let _res = credential_map.insert(
+            key.clone(),
+            match keystore::get_cached(service, key) {
+                Ok(s) => Some(s),
+                Err(e) => {
+                    debug!("Error while fetching credentials: {}", e);
+                    break;
+                }
+            },
+        );
+    }
+
+    AwsCredentials::from(credential_map)
+}
+
 pub fn fetch_aws_credentials(
     profile: String,
     crowbar_config: CrowbarConfig,
-    force_new_credentials: bool,
-) -> Result<AwsCredentials> {
-    let profiles = crowbar_config
-        .read()?
-        .profiles
-        .into_iter()
-        .filter(|p| p.clone().is_profile(&profile))
-        .collect::<Vec<AppProfile>>();
-
-    if profiles.is_empty() {
-        return Err(anyhow!("No profiles available or empty configuration."));
-    }
+    options: &FetchOptions,
+) -> Result<RunResult> {
+    let result = fetch(&profile, crowbar_config, options);
+    metrics::record(&profile, &result);
+    status::record(&profile, &result);
 
-    let profile = match profiles.first() {
-        Some(profile) => Ok(profile),
-        None => Err(anyhow!("Unable to use parsed profile")),
-    }?;
+    result
+}
 
-    if force_new_credentials {
-        let _creds = ConfigCredentials::load(profile)
-            .map_err(|e| debug!("Couldn't reset credentials: {}", e))
-            .and_then(|creds| creds.delete(profile).map_err(|e| debug!("{}", e)));
+fn fetch(
+    profile: &str,
+    crowbar_config: CrowbarConfig,
+    options: &FetchOptions,
+) -> Result<RunResult> {
+    let crowbar_config = crowbar_config.read()?;
+
+    if crowbar_config.profiles.is_empty() {
+        return Err(anyhow!("No profiles available or empty configuration."));
     }
 
+    let profile = crowbar_config
+        .find_profile(profile)?
+        .with_resolved_username()?;
+    let profile = &profile.with_options(options);
+    forget_asked_for(profile, options);
+
     let mut aws_credentials = AwsCredentials::load(profile).unwrap_or_default();
+    let mut session_expires_at = None;
+
+    if needs_refresh(&aws_credentials, options) {
+        ui::explain(&match aws_credentials.valid() {
+            _ if options.reauth => format!(
+                "Logging in again for profile {} as asked by --reauth",
+                profile.name
+            ),
+            true => format!(
+                "The cached credentials of profile {} expired, fetching new ones",
+                profile.name
+            ),
+            false => format!(
+                "No cached credentials for profile {}, fetching new ones",
+                profile.name
+            ),
+        });
+
+        if options.preflight {
+            preflight::check(profile)?;
+        }
 
-    if !aws_credentials.valid() || aws_credentials.is_expired() {
-        aws_credentials = match profile.provider {
-            ProviderType::Okta => {
+        aws_credentials = match (profile.sts_mode.unwrap_or_default(), &profile.provider) {
+            (StsMode::FederationToken, _) => federation_credentials(profile)?,
+            (_, ProviderType::Okta) if options.keep_session => {
                 let mut provider = OktaProvider::new(profile)?;
-                provider.new_session()?;
-                provider.fetch_aws_credentials()?
+                if options.force || !provider.resume_session() {
+                    provider.new_session()?;
+                    provider.remember_login()?;
+                    provider.begin_kept_session()?;
+                }
+
+                let credentials = provider.fetch_aws_credentials()?;
+                session_expires_at = provider.keep_session();
+                credentials
+            }
+            (_, ProviderType::Okta) => {
+                let prompter = prompt::current();
+                let mut session = api::authenticate(profile, prompter.clone())?;
+                let credentials = api::get_aws_credentials(&mut session, profile, prompter);
+                // Also caches the login that replaced a stale session
+                session.remember_login(profile)?;
+                credentials?
             }
-            ProviderType::Jumpcloud => {
+            (_, ProviderType::Jumpcloud) => {
                 let mut provider = JumpcloudProvider::new(profile)?;
                 provider.new_session()?;
                 provider.fetch_aws_credentials()?
             }
-            ProviderType::Adfs => {
+            (_, ProviderType::Adfs) => {
                 let mut provider = AdfsProvider::new(profile)?;
                 provider.fetch_aws_credentials()?
             }
         };
 
-        aws_credentials = aws_credentials.write(profile)?;
+        aws_credentials =
+            store_unless_outdated(aws_credentials, profile, options.force || options.reauth)?;
+        ui::explain(&format!(
+            "Cached the credentials of profile {}, valid until {}",
+            profile.name,
+            aws_credentials.expiration.as_deref().unwrap_or("unknown")
+        ));
+    } else {
+        ui::explain(&format!(
+            "Using the cached credentials of profile {}, valid until {}",
+            profile.name,
+            aws_credentials.expiration.as_deref().unwrap_or("unknown")
+        ));
+
+        if options.keep_session && profile.provider == ProviderType::Okta {
+            session_expires_at = sessions::kept_session_expiry(profile);
+        }
     }
 
-    Ok(aws_credentials)
+    Ok(RunResult {
+        credentials: aws_credentials,
+        timings: timings::take(),
+        session_expires_at,
+        role: saml::take_assumed_role(),
+    })
+}
+
+// --force forgets the password, --forget-device the device trust and
+// --reauth only the kept Okta session
+fn forget_asked_for(profile: &AppProfile, options: &FetchOptions) {
+    if options.force {
+        let _creds = ConfigCredentials::load(profile)
+            .map_err(|e| debug!("Couldn't reset credentials: {}", e))
+            .and_then(|creds| creds.delete(profile).map_err(|e| debug!("{}", e)));
+    }
+
+    if options.forget_device {
+        devices::forget(profile);
+    }
+
+    if options.reauth {
+        sessions::forget(profile);
+    }
+}
+
+fn needs_refresh(credentials: &AwsCredentials, options: &FetchOptions) -> bool {
+    !credentials.valid()
+        || credentials.expires_within(options.refresh_within)
+        || options.renew
+        || options.reauth
+}
+
+// Legacy accounts without a SAML provider skip the IdP and federate the IAM
+// user of the access keys in the environment, named after the username
+fn federation_credentials(profile: &AppProfile) -> Result<AwsCredentials> {
+    let keys = match role::operation(StsMode::FederationToken, None, role::access_keys_from_env())?
+    {
+        StsOperation::GetFederationToken(keys) => keys,
+        operation => return Err(anyhow!("Unexpected STS operation {:?}", operation)),
+    };
+    let options = RoleOptions::from(profile);
+    let duration_seconds = match options.duration {
+        Some(SessionDuration::Seconds(seconds)) => Some(seconds),
+        // STS grants federated users twelve hours by default
+        Some(SessionDuration::Max) | None => None,
+    };
+
+    ui::explain(&format!(
+        "Getting a federation token for {} with the access keys {}…",
+        profile.username,
+        keys.access_key_id.as_deref().unwrap_or_default()
+    ));
+    timings::measure_bounded(Phase::RoleAssumption, || {
+        role::federation_token(
+            &keys,
+            &profile.username,
+            duration_seconds,
+            options.region.as_ref(),
+            options.global_sts,
+            options.sts_endpoint_url.as_deref(),
+        )
+    })
+    .with_context(|| "Error getting a federation token")
+}
+
+// A slow login mustn't replace credentials another crowbar, e.g. a watch,
+// stored in the meantime if those expire later, unless forced to
+fn store_unless_outdated(
+    credentials: AwsCredentials,
+    profile: &AppProfile,
+    force: bool,
+) -> Result<AwsCredentials> {
+    if !force {
+        let stored = AwsCredentials::load(profile).unwrap_or_default();
+
+        if let (true, Some(stored_expiration), Some(expiration)) = (
+            stored.valid(),
+            stored.expires_at(),
+            credentials.expires_at(),
+        ) {
+            if stored_expiration > expiration {
+                info!(
+                    "Not storing credentials of profile {} expiring at {}, the stored ones expire later at {}",
+                    profile.name, expiration, stored_expiration
+                );
+                return Ok(credentials);
+            }
+        }
+    }
+
+    credentials.write(profile)
+}
+
+/// Reads the credentials cached by an earlier run, never logging in
+pub fn stored_aws_credentials(profile: &AppProfile) -> Result<AwsCredentials> {
+    let credentials = AwsCredentials::load(profile)?;
+
+    if !credentials.valid() {
+        return Err(anyhow!(
+            "No credentials stored for profile {}, run crowbar creds --output keyring {} first",
+            profile.name,
+            profile.name
+        ));
+    }
+
+    if credentials.is_expired() {
+        return Err(anyhow!(
+            "The stored credentials of profile {} expired, run crowbar creds --output keyring {} to renew them",
+            profile.name,
+            profile.name
+        ));
+    }
+
+    Ok(credentials)
+}
+
+/// Reads the credentials `crowbar assume-all` stored under `name`, which
+/// isn't a profile of the configuration. None if there are none
+pub fn stored_role_credentials(name: &str) -> Result<Option<AwsCredentials>> {
+    let credentials = load_service(&CredentialType::Aws.service(&name));
+
+    if !credentials.valid() {
+        return Ok(None);
+    }
+
+    if credentials.is_expired() {
+        return Err(anyhow!(
+            "The stored credentials of {} expired, run crowbar assume-all again to renew them",
+            name
+        ));
+    }
+
+    Ok(Some(credentials))
+}
+
+/// Keeps the cached credentials of a profile if they are still valid beyond
+/// the expiration buffer, or `within` if given, and only calls `refresh` for
+/// new ones otherwise
+pub fn ensure_aws_credentials<F>(
+    profile: &AppProfile,
+    within: Option<Duration>,
+    refresh: F,
+) -> Result<Ensured>
+where
+    F: FnOnce() -> Result<AwsCredentials>,
+{
+    let credentials = AwsCredentials::load(profile).unwrap_or_default();
+
+    match credentials.valid() && !credentials.expires_within(within) {
+        true => Ok(Ensured::Valid(credentials)),
+        false => refresh().map(Ensured::Refreshed),
+    }
+}
+
+/// Makes sure credentials work by asking STS who they belong to, through the
+/// STS endpoint configured for the profile
+pub fn verify_aws_credentials(
+    profile: &AppProfile,
+    credentials: &AwsCredentials,
+) -> Result<CallerIdentity> {
+    let options = RoleOptions::from(profile);
+
+    role::caller_identity(
+        credentials,
+        options.global_sts,
+        options.sts_endpoint_url.as_deref(),
+    )
+    .with_context(|| format!("The credentials of profile {} don't work", profile.name))
 }
 
 pub fn credentials_as_service(profile: &AppProfile) -> String {
-    format!("crowbar::{}::{}", CredentialType::Aws, profile.name)
+    CredentialType::Aws.service(&profile.name)
 }
 
 #[cfg(test)]
-mod test {
+pub mod test {
     use super::*;
+    use crate::clock::test::FixedClock;
+    use crate::credentials::keystore::test::MemoryBackend;
+    use crate::prompt::{self, test::ScriptedPrompter};
+    use crate::ui;
+    use claim::assert_err;
+    use std::fs;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::rc::Rc;
+    use std::thread;
+
+    #[test]
+    fn explains_each_step_of_login() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let base = format!("http://{}", listener.local_addr()?);
+        let saml = fs::read_to_string("tests/fixtures/okta/saml_response.xml")?
+            .replace("2024-01-18T06:21:48Z", "2038-01-18T06:21:48Z");
+        let server = serve(
+            listener,
+            vec![
+                (
+                    "application/json",
+                    fs::read_to_string("tests/fixtures/okta/login_response_mfa_required.json")?
+                        .replace("https://example.okta.com", &base),
+                ),
+                (
+                    "application/json",
+                    r#"{"expiresAt":"2038-01-01T10:10:10.000Z","status":"SUCCESS","sessionToken":"session-token"}"#
+                        .to_string(),
+                ),
+                (
+                    "text/html",
+                    format!(
+                        r#"<form><input name="SAMLResponse" type="hidden" value="{}"/></form>"#,
+                        base64::encode(&saml)
+                    ),
+                ),
+                (
+                    "text/xml",
+                    fs::read_to_string("tests/fixtures/aws/assume_role_with_saml_response.xml")?,
+                ),
+            ],
+        );
+
+        let dir = tempfile::tempdir()?;
+        let location = dir.path().join("crowbar.toml");
+        fs::write(
+            &location,
+            format!(
+                r#"
+[[profiles]]
+name = "okta"
+provider = "okta"
+username = "jdoe@example.com"
+url = "{base}/home/amazon_aws/0oa1b2c3prod/272"
+role = "arn:aws:iam::123456789012:role/role1"
+aws_endpoint_url = "{base}"
+"#,
+                base = base
+            ),
+        )?;
+        let options = FetchOptions {
+            mfa_factor: Some("totp:google".to_string()),
+            mfa_code: Some("123456".to_string()),
+            ..FetchOptions::default()
+        };
+
+        let (result, explanations) = ui::capture_explanations(|| {
+            keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+                prompt::with_prompter(Rc::new(ScriptedPrompter::new(&["password"])), || {
+                    fetch_aws_credentials(
+                        "okta".to_string(),
+                        CrowbarConfig::with_location(Some(location.to_string_lossy().to_string())),
+                        &options,
+                    )
+                })
+            })
+        });
+        assert_eq!(
+            "2038-01-01T10:10:10Z",
+            result?.credentials.expiration.unwrap()
+        );
+        assert_eq!(
+            vec![
+                "POST /api/v1/authn",
+                "POST /api/v1/authn/factors/factor-id-totp-software/verify?rememberDevice=true",
+                "GET /home/amazon_aws/0oa1b2c3prod/272?onetimetoken=session-token",
+                "POST /",
+            ],
+            server.join().unwrap()
+        );
+
+        assert_eq!(
+            vec![
+                "No cached credentials for profile okta, fetching new ones".to_string(),
+                format!("Authenticating to {} as jdoe@example.com…", base),
+                "Selected factor: Software TOTP".to_string(),
+                format!(
+                    "Fetching the SAML assertion from app {}/home/amazon_aws/0oa1b2c3prod/272…",
+                    base
+                ),
+                "Assuming role arn:aws:iam::123456789012:role/role1 for 3600s…".to_string(),
+                "Cached the credentials of profile okta, valid until 2038-01-01T10:10:10Z"
+                    .to_string(),
+            ],
+            explanations
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn sends_login_cookies_with_saml_request() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let base = format!("http://{}", listener.local_addr()?);
+        let saml = fs::read_to_string("tests/fixtures/okta/saml_response.xml")?
+            .replace("2024-01-18T06:21:48Z", "2038-01-18T06:21:48Z");
+        let server = serve_with_cookies(
+            listener,
+            vec![
+                (
+                    "application/json",
+                    Some("sid=session-id; Path=/"),
+                    r#"{"expiresAt":"2038-01-01T10:10:10.000Z","status":"SUCCESS","sessionToken":"session-token"}"#
+                        .to_string(),
+                ),
+                (
+                    "text/html",
+                    None,
+                    format!(
+                        r#"<form><input name="SAMLResponse" type="hidden" value="{}"/></form>"#,
+                        base64::encode(&saml)
+                    ),
+                ),
+                (
+                    "text/xml",
+                    None,
+                    fs::read_to_string("tests/fixtures/aws/assume_role_with_saml_response.xml")?,
+                ),
+            ],
+        );
+
+        let dir = tempfile::tempdir()?;
+        let location = dir.path().join("crowbar.toml");
+        fs::write(
+            &location,
+            format!(
+                r#"
+[[profiles]]
+name = "okta"
+provider = "okta"
+username = "jdoe@example.com"
+url = "{base}/home/amazon_aws/0oa1b2c3prod/272"
+role = "arn:aws:iam::123456789012:role/role1"
+aws_endpoint_url = "{base}"
+"#,
+                base = base
+            ),
+        )?;
+
+        keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+            prompt::with_prompter(Rc::new(ScriptedPrompter::new(&["password"])), || {
+                fetch_aws_credentials(
+                    "okta".to_string(),
+                    CrowbarConfig::with_location(Some(location.to_string_lossy().to_string())),
+                    &FetchOptions::default(),
+                )
+            })
+        })?;
+
+        let requests = server.join().unwrap();
+        assert_eq!(
+            (
+                "GET /home/amazon_aws/0oa1b2c3prod/272?onetimetoken=session-token".to_string(),
+                Some("sid=session-id".to_string())
+            ),
+            requests[1]
+        );
+
+        Ok(())
+    }
+
+    // Answers one request after another like Okta and STS would, returning
+    // the method and path of each
+    pub fn serve(
+        listener: TcpListener,
+        responses: Vec<(&'static str, String)>,
+    ) -> thread::JoinHandle<Vec<String>> {
+        let responses = responses
+            .into_iter()
+            .map(|(content_type, body)| (content_type, None, body))
+            .collect();
+
+        thread::spawn(move || {
+            answer(listener, responses)
+                .into_iter()
+                .map(|(request, _)| request)
+                .collect()
+        })
+    }
+
+    // Like `serve`, also setting the cookie given with a response and
+    // returning the cookies each request sent
+    pub fn serve_with_cookies(
+        listener: TcpListener,
+        responses: Vec<(&'static str, Option<&'static str>, String)>,
+    ) -> thread::JoinHandle<Vec<(String, Option<String>)>> {
+        thread::spawn(move || answer(listener, responses))
+    }
+
+    fn answer(
+        listener: TcpListener,
+        responses: Vec<(&'static str, Option<&'static str>, String)>,
+    ) -> Vec<(String, Option<String>)> {
+        responses
+            .into_iter()
+            .map(|(content_type, set_cookie, body)| {
+                let mut reader = BufReader::new(listener.accept().unwrap().0);
+                let mut request_line = String::new();
+                let mut length = 0;
+                let mut cookie = None;
+                reader.read_line(&mut request_line).unwrap();
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    let lowercase = header.to_lowercase();
+                    if let Some(value) = lowercase.strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    } else if lowercase.starts_with("cookie:") {
+                        cookie = Some(header["cookie:".len()..].trim().to_string());
+                    } else if header.trim().is_empty() {
+                        break;
+                    }
+                }
+                reader.read_exact(&mut vec![0; length]).unwrap();
+
+                let set_cookie = set_cookie
+                    .map(|cookie| format!("Set-Cookie: {}\r\n", cookie))
+                    .unwrap_or_default();
+                write!(
+                    reader.into_inner(),
+                    "HTTP/1.1 200 OK\r\nContent-Type: {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    content_type,
+                    set_cookie,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+
+                let request = request_line
+                    .split_whitespace()
+                    .take(2)
+                    .collect::<Vec<&str>>()
+                    .join(" ");
+                (request, cookie)
+            })
+            .collect()
+    }
 
     #[test]
     fn shows_if_expired() {
-        assert_eq!(false, create_credentials().is_expired());
-        assert_eq!(true, create_expired_credentials().is_expired())
+        assert!(!create_credentials().is_expired());
+        assert!(create_expired_credentials().is_expired())
+    }
+
+    #[test]
+    fn ignores_cached_credentials_once_cache_is_bypassed() -> Result<()> {
+        let backend = Rc::new(MemoryBackend::default());
+        let profile = create_profile();
+
+        keystore::with_backend(backend.clone(), || {
+            create_credentials().write(&profile)?;
+            keystore::bypass_cache();
+
+            assert_eq!(AwsCredentials::default(), AwsCredentials::load(&profile)?);
+            assert_eq!(0, backend.reads());
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn logs_in_again_keeping_password_and_choices_on_reauth() -> Result<()> {
+        let profile = create_profile();
+        let options = FetchOptions {
+            reauth: true,
+            ..FetchOptions::default()
+        };
+
+        keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+            let credentials = create_credentials().write(&profile)?;
+            ConfigCredentials::new("hunter2".to_string()).write(&profile)?;
+            devices::trust(&profile, "DI0dZ5mFB7bQ2W8kJ3tNWK3Ag");
+            sessions::adopt(&profile, "102Vs5dhDrfS5ODmjTsSdDfXg");
+            assert!(!needs_refresh(&credentials, &FetchOptions::default()));
+
+            forget_asked_for(&profile, &options);
+
+            assert!(needs_refresh(&credentials, &options));
+            assert_eq!(None, sessions::kept_session(&profile));
+            assert_eq!("hunter2", ConfigCredentials::load(&profile)?.password);
+            assert_eq!(
+                Some("DI0dZ5mFB7bQ2W8kJ3tNWK3Ag".to_string()),
+                devices::trusted_device(&profile)
+            );
+
+            // Unlike --force, which forgets the password as well
+            forget_asked_for(
+                &profile,
+                &FetchOptions {
+                    force: true,
+                    ..FetchOptions::default()
+                },
+            );
+            assert!(ConfigCredentials::load(&profile).is_err());
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn expires_within_buffer_before_expiration() {
+        let expiration = create_credentials().expires_at().unwrap().timestamp();
+        let expired_at = |timestamp| {
+            clock::with_clock(FixedClock::at(timestamp), || {
+                create_credentials().is_expired()
+            })
+        };
+
+        assert!(!expired_at(expiration - SECONDS_TO_EXPIRATION - 1));
+        assert!(!expired_at(expiration - SECONDS_TO_EXPIRATION));
+        assert!(expired_at(expiration - SECONDS_TO_EXPIRATION + 1));
+        assert!(expired_at(expiration + 1));
+    }
+
+    #[test]
+    fn expires_within_given_threshold() {
+        let expiration = create_credentials().expires_at().unwrap().timestamp();
+        let expires_within = |timestamp, minutes: u64| {
+            clock::with_clock(FixedClock::at(timestamp), || {
+                create_credentials().expires_within(Some(Duration::from_secs(minutes * 60)))
+            })
+        };
+
+        assert!(!expires_within(expiration - 1801, 30));
+        assert!(!expires_within(expiration - 1800, 30));
+        assert!(expires_within(expiration - 1799, 30));
+        // Shorter than the default buffer as well
+        assert!(!expires_within(expiration - 120, 1));
+        assert!(expires_within(expiration - 59, 1));
+    }
+
+    #[test]
+    fn refreshes_when_expiring_within_threshold_when_ensuring() -> Result<()> {
+        let profile = create_profile();
+        let expiration = create_credentials().expires_at().unwrap().timestamp();
+        let within = Some(Duration::from_secs(1800));
+
+        keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+            create_credentials().write(&profile)?;
+
+            let ensured = clock::with_clock(FixedClock::at(expiration - 1801), || {
+                ensure_aws_credentials(&profile, within, || {
+                    panic!("Credentials beyond the threshold must not be refreshed")
+                })
+            })?;
+            assert_eq!(Ensured::Valid(create_credentials()), ensured);
+
+            let ensured = clock::with_clock(FixedClock::at(expiration - 1799), || {
+                ensure_aws_credentials(&profile, within, || Ok(create_credentials()))
+            })?;
+            assert_eq!(Ensured::Refreshed(create_credentials()), ensured);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn parses_expirations_as_utc() -> Result<()> {
+        let expected = parse_expiration("2038-01-01T10:10:10Z")?;
+
+        for expiration in &[
+            "2038-01-01T10:10:10+00:00",
+            "2038-01-01T12:10:10+02:00",
+            "2038-01-01T05:10:10-05:00",
+            "2038-01-01T10:10:10.000Z",
+            "2038-01-01T10:10:10",
+            "2038-01-01 10:10:10",
+        ] {
+            assert_eq!(expected, parse_expiration(expiration)?, "{}", expiration);
+        }
+        assert_err!(parse_expiration("next tuesday"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn decides_expiry_across_daylight_saving_change() {
+        // Clocks in New York went from 2:00 EST to 3:00 EDT at 7:00 UTC
+        let credentials = |expiration: &str| AwsCredentials {
+            expiration: Some(expiration.to_string()),
+            ..create_credentials()
+        };
+        let expired_at = |credentials: &AwsCredentials, now: &str| {
+            let now = parse_expiration(now).unwrap().timestamp();
+            clock::with_clock(FixedClock::at(now), || credentials.is_expired())
+        };
+
+        for expiration in &[
+            "2021-03-14T07:30:00Z",
+            "2021-03-14T03:30:00-04:00",
+            "2021-03-14T07:30:00",
+        ] {
+            let credentials = credentials(expiration);
+            assert!(
+                !expired_at(&credentials, "2021-03-14T07:14:59Z"),
+                "{}",
+                expiration
+            );
+            assert!(
+                expired_at(&credentials, "2021-03-14T07:15:01Z"),
+                "{}",
+                expiration
+            );
+        }
+
+        assert!(credentials("next tuesday").is_expired());
     }
 
     #[test]
     #[should_panic]
     fn shows_if_not_expired() {
-        assert_eq!(true, create_credentials().is_expired());
-        assert_eq!(false, create_credentials().is_expired())
+        assert!(create_credentials().is_expired());
+        assert!(!create_credentials().is_expired())
     }
 
     #[test]
@@ -251,6 +1033,36 @@ mod test {
         )
     }
 
+    #[test]
+    fn provides_credentials_to_rusoto_clients() -> Result<()> {
+        let converted = rusoto_credential::AwsCredentials::try_from(create_credentials())?;
+        assert_eq!("some_key", converted.aws_access_key_id());
+        assert_eq!("some_secret", converted.aws_secret_access_key());
+        assert_eq!(&Some("some_token".to_string()), converted.token());
+        assert_eq!(
+            &Some(parse_expiration("2038-01-01T10:10:10Z")?),
+            converted.expires_at()
+        );
+        assert!(rusoto_credential::AwsCredentials::try_from(AwsCredentials::default()).is_err());
+
+        let mut runtime = tokio::runtime::Runtime::new()?;
+        let provided = runtime.block_on(create_credentials().credentials())?;
+        assert_eq!(converted.aws_access_key_id(), provided.aws_access_key_id());
+        assert_eq!(converted.token(), provided.token());
+        assert!(runtime
+            .block_on(create_expired_credentials().credentials())
+            .is_err());
+
+        // Clients take the credentials like any other provider
+        let _client = rusoto_sts::StsClient::new_with(
+            rusoto_core::request::HttpClient::new()?,
+            create_credentials(),
+            rusoto_core::Region::EuCentral1,
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn parses_aws_credentials_to_hashmap() {
         let hash_map: HashMap<String, Option<String>> = create_credentials().into();
@@ -265,6 +1077,129 @@ mod test {
         );
     }
 
+    #[test]
+    fn reads_back_stored_credentials() -> Result<()> {
+        let profile = create_profile();
+
+        keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+            assert_err!(stored_aws_credentials(&profile));
+
+            create_credentials().write(&profile)?;
+            assert_eq!(create_credentials(), stored_aws_credentials(&profile)?);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn keeps_valid_credentials_when_ensuring() -> Result<()> {
+        let profile = create_profile();
+
+        keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+            create_credentials().write(&profile)?;
+
+            let ensured = ensure_aws_credentials(&profile, None, || {
+                panic!("Valid credentials must not be refreshed")
+            })?;
+            assert_eq!(Ensured::Valid(create_credentials()), ensured);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn refreshes_expired_or_missing_credentials_when_ensuring() -> Result<()> {
+        let profile = create_profile();
+
+        keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+            let ensured = ensure_aws_credentials(&profile, None, || Ok(create_credentials()))?;
+            assert_eq!(Ensured::Refreshed(create_credentials()), ensured);
+
+            create_expired_credentials().write(&profile)?;
+            let ensured = ensure_aws_credentials(&profile, None, || Ok(create_credentials()))?;
+            assert_eq!(Ensured::Refreshed(create_credentials()), ensured);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn refuses_expired_stored_credentials() -> Result<()> {
+        let profile = create_profile();
+
+        keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+            create_expired_credentials().write(&profile)?;
+
+            let error = stored_aws_credentials(&profile).unwrap_err().to_string();
+            assert!(error.contains("expired"));
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn prints_nothing_when_using_cached_credentials() -> Result<()> {
+        let config =
+            CrowbarConfig::with_location(Some("tests/fixtures/valid_config.toml".to_string()));
+        let profile = config.clone().read()?.find_profile("profile_a")?;
+
+        keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+            create_credentials().write(&profile)?;
+
+            let (result, printed) = ui::capture_successes(|| {
+                fetch_aws_credentials(
+                    "profile_a".to_string(),
+                    config.clone(),
+                    &FetchOptions::default(),
+                )
+            });
+
+            assert_eq!(create_credentials(), result?.credentials);
+            assert!(printed.is_empty(), "{:?}", printed);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn keeps_stored_credentials_expiring_later() -> Result<()> {
+        let profile = create_profile();
+        let mut earlier = create_credentials();
+        earlier.access_key_id = Some("earlier_key".to_string());
+        earlier.expiration = Some("2037-01-01T10:10:10Z".to_string());
+
+        keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+            create_credentials().write(&profile)?;
+
+            assert_eq!(
+                earlier,
+                store_unless_outdated(earlier.clone(), &profile, false)?
+            );
+            assert_eq!(create_credentials(), AwsCredentials::load(&profile)?);
+
+            store_unless_outdated(earlier.clone(), &profile, true)?;
+            assert_eq!(earlier, AwsCredentials::load(&profile)?);
+
+            // Later credentials replace earlier ones as always
+            store_unless_outdated(create_credentials(), &profile, false)?;
+            assert_eq!(create_credentials(), AwsCredentials::load(&profile)?);
+
+            Ok(())
+        })
+    }
+
+    fn create_profile() -> AppProfile {
+        toml::from_str(
+            r#"
+            name = "profile"
+            provider = "okta"
+            url = "https://example.com/example/url"
+            username = "username"
+        "#,
+        )
+        .unwrap()
+    }
+
     fn create_credentials() -> AwsCredentials {
         AwsCredentials {
             version: 1,
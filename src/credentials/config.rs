@@ -1,8 +1,10 @@
 use crate::config::app::AppProfile;
-use crate::credentials::{Credential, CredentialType};
+use crate::credentials::{keystore, Credential, CredentialType};
+use crate::error::{self, ErrorKind};
+use crate::exec;
 use crate::utils;
-use anyhow::{anyhow, Result};
-use keyring::Keyring;
+use anyhow::{Context, Result};
+use std::process::{Command, Stdio};
 
 #[derive(Clone)]
 pub struct ConfigCredentials {
@@ -10,6 +12,29 @@ pub struct ConfigCredentials {
     pub password: String,
 }
 
+impl ConfigCredentials {
+    pub fn new(password: String) -> ConfigCredentials {
+        ConfigCredentials {
+            credential_type: CredentialType::Config,
+            password,
+        }
+    }
+
+    /// The password of a profile from its `password_command`, or else from the
+    /// keyring or, as a last resort, a prompt
+    pub fn obtain(profile: &AppProfile) -> Result<ConfigCredentials> {
+        match &profile.password_command {
+            Some(command) => Ok(ConfigCredentials {
+                credential_type: CredentialType::Config,
+                password: run_password_command(command)?,
+            }),
+            None => {
+                ConfigCredentials::load(profile).or_else(|_| ConfigCredentials::create(profile))
+            }
+        }
+    }
+}
+
 impl Credential<AppProfile, ConfigCredentials> for ConfigCredentials {
     fn create(profile: &AppProfile) -> Result<ConfigCredentials> {
         let credential_type = CredentialType::Config;
@@ -23,14 +48,12 @@ impl Credential<AppProfile, ConfigCredentials> for ConfigCredentials {
 
     fn load(profile: &AppProfile) -> Result<ConfigCredentials> {
         let credential_type = CredentialType::Config;
-        let service = format!("crowbar::{}::{}", &credential_type, profile);
+        let service = credential_type.service(profile);
         let username = &profile.username;
 
         debug!("Trying to load credentials from ID {}", &service);
 
-        let password = Keyring::new(&service, username)
-            .get_password()
-            .map_err(|e| anyhow!("{}", e))?;
+        let password = keystore::get_password(&service, username)?;
 
         Ok(ConfigCredentials {
             credential_type,
@@ -39,7 +62,12 @@ impl Credential<AppProfile, ConfigCredentials> for ConfigCredentials {
     }
 
     fn write(self, profile: &AppProfile) -> Result<ConfigCredentials> {
-        let service = &format!("crowbar::{}::{}", self.credential_type, profile);
+        // The command stays in charge of the password
+        if profile.password_command.is_some() {
+            return Ok(self);
+        }
+
+        let service = self.credential_type.service(profile);
         let username = &profile.username;
         let password = &self.password;
 
@@ -48,26 +76,144 @@ impl Credential<AppProfile, ConfigCredentials> for ConfigCredentials {
             profile.base_url()?.host().unwrap()
         );
 
-        Keyring::new(service, username)
-            .set_password(password)
-            .map_err(|e| anyhow!("{}", e))?;
+        keystore::set_password(&service, username, password)?;
 
         Ok(self)
     }
 
     fn delete(self, profile: &AppProfile) -> Result<ConfigCredentials> {
-        let service = format!("crowbar::{}::{}", self.credential_type, profile);
+        let service = self.credential_type.service(profile);
         let username = &profile.username;
-        let keyring = Keyring::new(&service, username);
 
         debug!("Deleting credentials for {} at {}", username, &service);
 
-        let pass = keyring.get_password();
-
-        if pass.is_ok() {
-            keyring.delete_password().map_err(|e| anyhow!("{}", e))?
-        }
+        keystore::delete_password(&service, username)?;
 
         Ok(self)
     }
 }
+
+// Like git's credential helpers, e.g. `pass show okta`, the first line the
+// command prints is the password. It may ask for a passphrase on the terminal.
+// Output in another encoding than UTF-8 is refused rather than mangled into a
+// password Okta would reject
+fn run_password_command(command: &str) -> Result<String> {
+    let shell = exec::shell()?;
+    debug!("Running password_command {}", command);
+
+    let output = Command::new(&shell[0])
+        .arg(&shell[1])
+        .arg(command)
+        .stdin(Stdio::inherit())
+        .output()
+        .with_context(|| format!("Unable to run password_command {}", command))?;
+
+    if !output.status.success() {
+        return Err(error::error(
+            ErrorKind::Config,
+            format!(
+                "The password_command {} failed with {}: {}",
+                command,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ));
+    }
+
+    let stdout = String::from_utf8(output.stdout).map_err(|_| {
+        error::error(
+            ErrorKind::Config,
+            format!(
+                "The password_command {} printed a password that isn't valid UTF-8",
+                command
+            ),
+        )
+    })?;
+
+    match stdout.lines().next() {
+        Some(password) if !password.is_empty() => Ok(password.to_string()),
+        _ => Err(error::error(
+            ErrorKind::Config,
+            format!("The password_command {} printed no password", command),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::credentials::keystore::test::MemoryBackend;
+    use std::rc::Rc;
+
+    #[test]
+    fn reads_password_from_command() -> Result<()> {
+        let profile = create_profile("echo hunter2");
+
+        keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+            let credentials = ConfigCredentials::obtain(&profile)?;
+            assert_eq!("hunter2", credentials.password);
+
+            // The password never ends up in the keyring
+            credentials.write(&profile)?;
+            assert!(ConfigCredentials::load(&profile).is_err());
+
+            Ok(())
+        })
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn uses_first_line_of_command_output() -> Result<()> {
+        let profile = create_profile("printf 'hunter2\\nurl: example.com\\n'");
+
+        assert_eq!("hunter2", ConfigCredentials::obtain(&profile)?.password);
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reads_non_ascii_password_from_command() -> Result<()> {
+        let profile = create_profile("printf 'päss wörd-密码\\n'");
+        assert_eq!(
+            "päss wörd-密码",
+            ConfigCredentials::obtain(&profile)?.password
+        );
+
+        // "pässwörd" in Latin-1
+        let profile = create_profile("printf 'p\\344ssw\\366rd\\n'");
+        let error = ConfigCredentials::obtain(&profile).err().unwrap();
+        assert!(error.to_string().contains("isn't valid UTF-8"), "{}", error);
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fails_with_stderr_of_command() {
+        let profile = create_profile("echo 'gpg: decryption failed' >&2; exit 2");
+
+        let error = ConfigCredentials::obtain(&profile).err().unwrap();
+        assert_eq!(ErrorKind::Config, error::kind_of(&error));
+        assert!(
+            error.to_string().contains("gpg: decryption failed"),
+            "{}",
+            error
+        );
+    }
+
+    fn create_profile(password_command: &str) -> AppProfile {
+        let mut profile: AppProfile = toml::from_str(
+            r#"
+            name = "profile"
+            provider = "okta"
+            url = "https://example.com/example/url"
+            username = "username"
+        "#,
+        )
+        .unwrap();
+        profile.password_command = Some(password_command.to_string());
+
+        profile
+    }
+}
@@ -0,0 +1,242 @@
+use crate::ui;
+
+use anyhow::{anyhow, Result};
+use keyring::{Keyring, KeyringError};
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+/// Where secrets end up, the system keyring unless replaced with `with_backend`
+pub trait Backend {
+    fn get_password(&self, service: &str, username: &str) -> keyring::Result<String>;
+    fn set_password(&self, service: &str, username: &str, password: &str) -> keyring::Result<()>;
+    fn delete_password(&self, service: &str, username: &str) -> keyring::Result<()>;
+}
+
+#[derive(Debug, Default)]
+pub struct SystemBackend;
+
+impl Backend for SystemBackend {
+    fn get_password(&self, service: &str, username: &str) -> keyring::Result<String> {
+        Keyring::new(service, username).get_password()
+    }
+
+    fn set_password(&self, service: &str, username: &str, password: &str) -> keyring::Result<()> {
+        Keyring::new(service, username).set_password(password)
+    }
+
+    fn delete_password(&self, service: &str, username: &str) -> keyring::Result<()> {
+        Keyring::new(service, username).delete_password()
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<Rc<dyn Backend>> = RefCell::new(Rc::new(SystemBackend));
+
+    // Once the keyring failed (e.g. no Secret Service on a headless Linux box)
+    // there is no point in trying again for every secret of the same run
+    static UNAVAILABLE: Cell<bool> = const { Cell::new(false) };
+
+    // Whether cached values are ignored for the run, see --no-cache
+    static CACHE_BYPASSED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runs `f` with all secrets stored in `backend`, restoring the previous
+/// backend afterwards
+pub fn with_backend<T, F>(backend: Rc<dyn Backend>, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    let previous = CURRENT.with(|b| b.replace(backend));
+    let result = f();
+    CURRENT.with(|b| b.replace(previous));
+
+    result
+}
+
+fn current() -> Rc<dyn Backend> {
+    CURRENT.with(|b| b.borrow().clone())
+}
+
+pub fn disable() {
+    UNAVAILABLE.with(|u| u.set(true));
+}
+
+pub fn is_available() -> bool {
+    !UNAVAILABLE.with(|u| u.get())
+}
+
+/// Makes every read of a cached value miss for the rest of the run, while
+/// fresh values are still written. Secrets like passwords aren't cached
+/// values and are still read
+pub fn bypass_cache() {
+    CACHE_BYPASSED.with(|b| b.set(true));
+}
+
+/// Reads a value crowbar cached to save work, e.g. credentials or the MFA
+/// factor used last, as opposed to a secret the user stored
+pub fn get_cached(service: &str, username: &str) -> Result<String> {
+    if CACHE_BYPASSED.with(|b| b.get()) {
+        return Err(anyhow!("The cache is bypassed"));
+    }
+
+    get_password(service, username)
+}
+
+pub fn get_password(service: &str, username: &str) -> Result<String> {
+    if !is_available() {
+        return Err(anyhow!("Keyring is unavailable"));
+    }
+
+    handle(current().get_password(service, username))
+}
+
+pub fn set_password(service: &str, username: &str, password: &str) -> Result<()> {
+    if !is_available() {
+        return Ok(());
+    }
+
+    handle(current().set_password(service, username, password)).or_else(ignore_unavailable)
+}
+
+pub fn delete_password(service: &str, username: &str) -> Result<()> {
+    if !is_available() {
+        return Ok(());
+    }
+
+    let backend = current();
+
+    if handle(backend.get_password(service, username)).is_ok() {
+        handle(backend.delete_password(service, username)).or_else(ignore_unavailable)?
+    }
+
+    Ok(())
+}
+
+fn handle<T>(result: keyring::Result<T>) -> Result<T> {
+    result.map_err(|e| {
+        match e {
+            KeyringError::NoPasswordFound | KeyringError::Parse(_) => (),
+            _ => {
+                if is_available() {
+                    eprintln!(
+                        "{}",
+                        ui::warning(&format!(
+                            "Warning: keyring unavailable ({}), secrets won't be cached",
+                            e
+                        ))
+                    );
+                }
+                disable();
+            }
+        }
+        anyhow!("{}", e)
+    })
+}
+
+fn ignore_unavailable(e: anyhow::Error) -> Result<()> {
+    if is_available() {
+        Err(e)
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use claim::{assert_err, assert_ok};
+    use std::collections::HashMap;
+
+    /// Keeps secrets in memory for the duration of a test
+    #[derive(Default)]
+    pub struct MemoryBackend {
+        secrets: RefCell<HashMap<(String, String), String>>,
+        reads: Cell<usize>,
+    }
+
+    impl MemoryBackend {
+        /// How often a secret was read, found or not
+        pub fn reads(&self) -> usize {
+            self.reads.get()
+        }
+    }
+
+    impl Backend for MemoryBackend {
+        fn get_password(&self, service: &str, username: &str) -> keyring::Result<String> {
+            self.reads.set(self.reads.get() + 1);
+            self.secrets
+                .borrow()
+                .get(&(service.to_string(), username.to_string()))
+                .cloned()
+                .ok_or(KeyringError::NoPasswordFound)
+        }
+
+        fn set_password(
+            &self,
+            service: &str,
+            username: &str,
+            password: &str,
+        ) -> keyring::Result<()> {
+            self.secrets.borrow_mut().insert(
+                (service.to_string(), username.to_string()),
+                password.to_string(),
+            );
+            Ok(())
+        }
+
+        fn delete_password(&self, service: &str, username: &str) -> keyring::Result<()> {
+            self.secrets
+                .borrow_mut()
+                .remove(&(service.to_string(), username.to_string()))
+                .map(|_| ())
+                .ok_or(KeyringError::NoPasswordFound)
+        }
+    }
+
+    #[test]
+    fn stores_secrets_in_replaced_backend() -> Result<()> {
+        with_backend(Rc::new(MemoryBackend::default()), || {
+            set_password("crowbar::test", "user", "secret")?;
+            assert_eq!("secret", get_password("crowbar::test", "user")?);
+
+            delete_password("crowbar::test", "user")?;
+            assert_err!(get_password("crowbar::test", "user"));
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn misses_cache_once_bypassed() -> Result<()> {
+        let backend = Rc::new(MemoryBackend::default());
+
+        with_backend(backend.clone(), || {
+            set_password("crowbar::test", "user", "cached")?;
+            assert_eq!("cached", get_cached("crowbar::test", "user")?);
+
+            bypass_cache();
+            assert_err!(get_cached("crowbar::test", "user"));
+            assert_eq!(1, backend.reads());
+
+            // Fresh values are still written, and secrets still read
+            set_password("crowbar::test", "user", "fresh")?;
+            assert_eq!("fresh", get_password("crowbar::test", "user")?);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn degrades_after_backend_failure() {
+        assert_err!(handle::<String>(Err(KeyringError::NoPasswordFound)));
+        assert!(is_available());
+
+        assert_err!(handle::<String>(Err(KeyringError::NoBackendFound)));
+        assert!(!is_available());
+
+        // Callers fall back to prompting while writes become no-ops
+        assert_err!(get_password("crowbar::test", "user"));
+        assert_ok!(set_password("crowbar::test", "user", "secret"));
+        assert_ok!(delete_password("crowbar::test", "user"));
+    }
+}
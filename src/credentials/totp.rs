@@ -0,0 +1,399 @@
+use crate::clock;
+use crate::config::app::AppProfile;
+use crate::credentials::{keystore, Credential, CredentialType};
+use crate::ui;
+use crate::utils;
+use anyhow::{anyhow, Context, Result};
+use std::convert::TryFrom;
+use std::str::FromStr;
+use url::Url;
+
+const BASE32_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+const SHA1_BLOCK_SIZE: usize = 64;
+
+#[derive(Clone)]
+pub struct TotpCredentials {
+    credential_type: CredentialType,
+    pub secret: String,
+}
+
+impl TotpCredentials {
+    pub fn new(secret: String) -> TotpCredentials {
+        TotpCredentials {
+            credential_type: CredentialType::Totp,
+            secret,
+        }
+    }
+}
+
+/// Digits and period of the codes an authenticator generates
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TotpFormat {
+    pub digits: u32,
+    pub period: u64,
+}
+
+impl TotpFormat {
+    /// What Google Authenticator generates for secrets without parameters
+    pub const GOOGLE_AUTHENTICATOR: TotpFormat = TotpFormat {
+        digits: 6,
+        period: 30,
+    };
+}
+
+/// The RFC 6238 defaults
+impl Default for TotpFormat {
+    fn default() -> Self {
+        TotpFormat {
+            digits: 6,
+            period: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TotpParameters {
+    pub secret: Vec<u8>,
+    /// Given by an otpauth URI, the defaults apply otherwise
+    pub digits: Option<u32>,
+    pub period: Option<u64>,
+    defaults: TotpFormat,
+}
+
+impl TotpParameters {
+    /// The same secret generating codes of `defaults` unless it was given
+    /// with digits or a period of its own
+    pub fn with_defaults(&self, defaults: TotpFormat) -> TotpParameters {
+        TotpParameters {
+            defaults,
+            ..self.clone()
+        }
+    }
+
+    pub fn digits(&self) -> u32 {
+        self.digits.unwrap_or(self.defaults.digits)
+    }
+
+    pub fn period(&self) -> u64 {
+        self.period.unwrap_or(self.defaults.period)
+    }
+}
+
+/// Accepts either a raw base32 secret or a full `otpauth://totp/...` URI
+impl FromStr for TotpParameters {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let mut parameters = TotpParameters {
+            secret: vec![],
+            digits: None,
+            period: None,
+            defaults: TotpFormat::default(),
+        };
+
+        if !s.starts_with("otpauth://") {
+            parameters.secret = decode_base32(s)?;
+            return Ok(parameters);
+        }
+
+        let url = Url::parse(s)?;
+
+        if url.host_str() != Some("totp") {
+            return Err(anyhow!("Only otpauth://totp/ URIs are supported"));
+        }
+
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "secret" => parameters.secret = decode_base32(&value)?,
+                "digits" => parameters.digits = Some(value.parse()?),
+                "period" => parameters.period = Some(value.parse()?),
+                "algorithm" if !value.eq_ignore_ascii_case("SHA1") => {
+                    return Err(anyhow!("Unsupported TOTP algorithm {}", value))
+                }
+                _ => (),
+            }
+        }
+
+        if parameters.secret.is_empty() {
+            return Err(anyhow!("Missing secret in otpauth URI"));
+        }
+        if !(6..=8).contains(&parameters.digits()) {
+            return Err(anyhow!(
+                "Unsupported number of TOTP digits {}",
+                parameters.digits()
+            ));
+        }
+        if parameters.period() == 0 {
+            return Err(anyhow!("The TOTP period needs to be greater than zero"));
+        }
+
+        Ok(parameters)
+    }
+}
+
+/// Generates the RFC 6238 code for the given Unix timestamp
+/// The stored TOTP secret of the profile, if it has a usable one. A broken
+/// secret only means asking for the code, the password and all other inputs
+/// are still taken from where they are stored
+pub fn stored_parameters(profile: &AppProfile) -> Option<TotpParameters> {
+    let credentials = TotpCredentials::load(profile).ok()?;
+
+    match credentials.secret.parse() {
+        Ok(parameters) => Some(parameters),
+        Err(e) => {
+            eprintln!(
+                "{}",
+                ui::warning(&format!(
+                    "The stored TOTP secret of profile {} can't be used, asking for the code instead: {:#}",
+                    profile.name, e
+                ))
+            );
+            None
+        }
+    }
+}
+
+pub fn generate_totp(parameters: &TotpParameters, timestamp: u64) -> String {
+    let counter = timestamp / parameters.period();
+    let hash = hmac_sha1(&parameters.secret, &counter.to_be_bytes());
+    let offset = (hash[19] & 0xf) as usize;
+    let code = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+
+    format!(
+        "{:0width$}",
+        code % 10u32.pow(parameters.digits()),
+        width = parameters.digits() as usize
+    )
+}
+
+pub fn current_totp(parameters: &TotpParameters) -> Result<String> {
+    Ok(generate_totp(parameters, now()?))
+}
+
+/// The code of the time step next to the current one, the previous step in
+/// the first half of the period and the following one in the second half.
+/// It's the one most likely accepted where the clock is off by a little
+pub fn adjacent_totp(parameters: &TotpParameters) -> Result<String> {
+    let timestamp = now()?;
+    let period = parameters.period();
+    let adjacent = match timestamp % period < period / 2 {
+        true => timestamp.saturating_sub(period),
+        false => timestamp + period,
+    };
+
+    Ok(generate_totp(parameters, adjacent))
+}
+
+fn now() -> Result<u64> {
+    u64::try_from(clock::now().timestamp()).context("The system clock is set before 1970")
+}
+
+fn hmac_sha1(key: &[u8], message: &[u8]) -> [u8; 20] {
+    let mut block = [0u8; SHA1_BLOCK_SIZE];
+
+    if key.len() > SHA1_BLOCK_SIZE {
+        block[..20].copy_from_slice(&sha1::Sha1::from(key).digest().bytes());
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = sha1::Sha1::new();
+    inner.update(&block.iter().map(|b| b ^ 0x36).collect::<Vec<u8>>());
+    inner.update(message);
+
+    let mut outer = sha1::Sha1::new();
+    outer.update(&block.iter().map(|b| b ^ 0x5c).collect::<Vec<u8>>());
+    outer.update(&inner.digest().bytes());
+
+    outer.digest().bytes()
+}
+
+fn decode_base32(input: &str) -> Result<Vec<u8>> {
+    let mut bits: u64 = 0;
+    let mut bit_count = 0;
+    let mut output = vec![];
+
+    for c in input
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '=' && *c != '-')
+    {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|a| *a as char == c.to_ascii_uppercase())
+            .ok_or_else(|| anyhow!("Invalid character {} in base32 TOTP secret", c))?;
+
+        bits = (bits << 5) | value as u64;
+        bit_count += 5;
+
+        if bit_count >= 8 {
+            bit_count -= 8;
+            output.push((bits >> bit_count) as u8);
+        }
+    }
+
+    if output.is_empty() {
+        return Err(anyhow!("The TOTP secret is empty"));
+    }
+
+    Ok(output)
+}
+
+impl Credential<AppProfile, TotpCredentials> for TotpCredentials {
+    fn create(profile: &AppProfile) -> Result<TotpCredentials> {
+        Ok(TotpCredentials::new(utils::prompt_totp_secret(profile)?))
+    }
+
+    fn load(profile: &AppProfile) -> Result<TotpCredentials> {
+        let credential_type = CredentialType::Totp;
+        let service = credential_type.service(profile);
+
+        debug!("Trying to load TOTP secret from ID {}", &service);
+
+        let secret = keystore::get_password(&service, &profile.username)?;
+
+        Ok(TotpCredentials {
+            credential_type,
+            secret,
+        })
+    }
+
+    fn write(self, profile: &AppProfile) -> Result<TotpCredentials> {
+        let service = self.credential_type.service(profile);
+
+        debug!("Saving TOTP secret for {}", &profile.username);
+
+        keystore::set_password(&service, &profile.username, &self.secret)?;
+
+        Ok(self)
+    }
+
+    fn delete(self, profile: &AppProfile) -> Result<TotpCredentials> {
+        let service = self.credential_type.service(profile);
+
+        debug!("Deleting TOTP secret at {}", &service);
+
+        keystore::delete_password(&service, &profile.username)?;
+
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::test::FixedClock;
+    use claim::assert_err;
+
+    // The RFC 6238 test secret "12345678901234567890", base32 encoded
+    const RFC_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn parses_raw_base32_secret() -> Result<()> {
+        let parameters: TotpParameters = "gezd gnbv gy3t qojq gezd gnbv gy3t qojq".parse()?;
+
+        assert_eq!(b"12345678901234567890".to_vec(), parameters.secret);
+        assert_eq!(6, parameters.digits());
+        assert_eq!(30, parameters.period());
+
+        Ok(())
+    }
+
+    #[test]
+    fn generates_code_of_adjacent_step() -> Result<()> {
+        let parameters: TotpParameters = RFC_SECRET.parse()?;
+
+        // The periods start at 1111111080 and 1111111110
+        for (now, adjacent) in &[
+            (1_111_111_085, 1_111_111_055),
+            (1_111_111_134, 1_111_111_164),
+        ] {
+            let code = clock::with_clock(FixedClock::at(*now), || adjacent_totp(&parameters))?;
+            assert_eq!(generate_totp(&parameters, *adjacent), code);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn parses_otpauth_uris() -> Result<()> {
+        let uris = [
+            (
+                format!("otpauth://totp/Example:jdoe@example.com?secret={}&issuer=Example", RFC_SECRET),
+                6,
+                30,
+            ),
+            (
+                format!("otpauth://totp/jdoe?secret={}&period=60", RFC_SECRET),
+                6,
+                60,
+            ),
+            (
+                format!("otpauth://totp/Example%3Ajdoe?issuer=Example&digits=8&algorithm=SHA1&secret={}", RFC_SECRET),
+                8,
+                30,
+            ),
+            (
+                format!("otpauth://totp/?secret={}&digits=8&period=60", RFC_SECRET),
+                8,
+                60,
+            ),
+        ];
+
+        for (uri, digits, period) in uris.iter() {
+            let parameters: TotpParameters = uri.parse()?;
+
+            assert_eq!(b"12345678901234567890".to_vec(), parameters.secret);
+            assert_eq!(*digits, parameters.digits());
+            assert_eq!(*period, parameters.period());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_invalid_otpauth_uris() {
+        assert_err!("otpauth://hotp/jdoe?secret=GEZDGNBV".parse::<TotpParameters>());
+        assert_err!("otpauth://totp/jdoe?issuer=Example".parse::<TotpParameters>());
+        assert_err!(
+            "otpauth://totp/jdoe?secret=GEZDGNBV&algorithm=SHA256".parse::<TotpParameters>()
+        );
+        assert_err!("otpauth://totp/jdoe?secret=GEZDGNBV&digits=10".parse::<TotpParameters>());
+        assert_err!("not-base32!".parse::<TotpParameters>());
+    }
+
+    #[test]
+    fn generates_rfc_6238_codes() -> Result<()> {
+        let mut parameters: TotpParameters = RFC_SECRET.parse()?;
+        parameters.digits = Some(8);
+
+        assert_eq!("94287082", generate_totp(&parameters, 59));
+        assert_eq!("07081804", generate_totp(&parameters, 1_111_111_109));
+        assert_eq!("14050471", generate_totp(&parameters, 1_111_111_111));
+
+        parameters.digits = Some(6);
+        assert_eq!("287082", generate_totp(&parameters, 59));
+
+        Ok(())
+    }
+
+    #[test]
+    fn generates_current_code_from_clock() -> Result<()> {
+        let mut parameters: TotpParameters = RFC_SECRET.parse()?;
+        parameters.digits = Some(8);
+
+        let code =
+            |timestamp| clock::with_clock(FixedClock::at(timestamp), || current_totp(&parameters));
+        assert_eq!("07081804", code(1_111_111_109)?);
+        // The next period starts a second later
+        assert_eq!("14050471", code(1_111_111_111)?);
+        assert_err!(code(-1));
+
+        Ok(())
+    }
+}
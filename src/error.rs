@@ -0,0 +1,186 @@
+use crate::aws::role::AccessDenied;
+use crate::providers::okta::error::ErrorResponse;
+
+use serde_json::json;
+use std::fmt;
+
+const OKTA_AUTHENTICATION_FAILED: &str = "E0000004";
+pub const OKTA_INVALID_PASSCODE: &str = "E0000068";
+pub const OKTA_NOT_PERMITTED: &str = "E0000006";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Unknown,
+    Config,
+    Authentication,
+    MfaRejected,
+    MfaTimeout,
+    Network,
+    Okta,
+    Aws,
+    DeadlineExceeded,
+    /// Okta rejected the username or password, unlike other failures of
+    /// authentication
+    InvalidCredentials,
+}
+
+pub const ERROR_KINDS: [ErrorKind; 10] = [
+    ErrorKind::Unknown,
+    ErrorKind::Config,
+    ErrorKind::Authentication,
+    ErrorKind::MfaRejected,
+    ErrorKind::MfaTimeout,
+    ErrorKind::Network,
+    ErrorKind::Okta,
+    ErrorKind::Aws,
+    ErrorKind::DeadlineExceeded,
+    ErrorKind::InvalidCredentials,
+];
+
+impl ErrorKind {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorKind::Unknown => 1,
+            ErrorKind::Config => 2,
+            ErrorKind::Authentication => 3,
+            ErrorKind::MfaRejected => 4,
+            ErrorKind::MfaTimeout => 5,
+            ErrorKind::Network => 6,
+            ErrorKind::Okta => 7,
+            ErrorKind::Aws => 8,
+            ErrorKind::DeadlineExceeded => 9,
+            ErrorKind::InvalidCredentials => 10,
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::Unknown => write!(f, "unknown"),
+            ErrorKind::Config => write!(f, "config"),
+            ErrorKind::Authentication => write!(f, "authentication"),
+            ErrorKind::MfaRejected => write!(f, "mfa_rejected"),
+            ErrorKind::MfaTimeout => write!(f, "mfa_timeout"),
+            ErrorKind::Network => write!(f, "network"),
+            ErrorKind::Okta => write!(f, "okta"),
+            ErrorKind::Aws => write!(f, "aws"),
+            ErrorKind::DeadlineExceeded => write!(f, "deadline_exceeded"),
+            ErrorKind::InvalidCredentials => write!(f, "invalid_credentials"),
+        }
+    }
+}
+
+/// An error whose kind wrapping tools can rely on, see `--json-errors`
+#[derive(Debug)]
+pub struct CrowbarError {
+    pub kind: ErrorKind,
+    message: String,
+}
+
+impl fmt::Display for CrowbarError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for CrowbarError {}
+
+pub fn error(kind: ErrorKind, message: impl Into<String>) -> anyhow::Error {
+    anyhow::Error::new(CrowbarError {
+        kind,
+        message: message.into(),
+    })
+}
+
+/// Finds the most specific kind anywhere in the chain of causes
+pub fn kind_of(e: &anyhow::Error) -> ErrorKind {
+    for cause in e.chain() {
+        if let Some(e) = cause.downcast_ref::<CrowbarError>() {
+            return e.kind;
+        }
+        if let Some(e) = cause.downcast_ref::<ErrorResponse>() {
+            return match e.error_code.as_str() {
+                OKTA_AUTHENTICATION_FAILED => ErrorKind::InvalidCredentials,
+                OKTA_INVALID_PASSCODE => ErrorKind::MfaRejected,
+                _ => ErrorKind::Okta,
+            };
+        }
+        if cause.is::<reqwest::Error>() {
+            return ErrorKind::Network;
+        }
+        if cause.is::<confy::ConfyError>() || cause.is::<toml::de::Error>() {
+            return ErrorKind::Config;
+        }
+        if cause.is::<AccessDenied>()
+            || cause.is::<rusoto_core::RusotoError<rusoto_sts::AssumeRoleWithSAMLError>>()
+        {
+            return ErrorKind::Aws;
+        }
+    }
+
+    ErrorKind::Unknown
+}
+
+pub fn to_json(e: &anyhow::Error) -> String {
+    let kind = kind_of(e);
+
+    json!({
+        "error_kind": kind.to_string(),
+        "message": e.chain().map(|c| c.to_string()).collect::<Vec<String>>().join(": "),
+        "exit_code": kind.exit_code(),
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::anyhow;
+    use serde_json::Value;
+
+    #[test]
+    fn serializes_each_kind() -> anyhow::Result<()> {
+        for kind in ERROR_KINDS.iter() {
+            let e = error(*kind, "Something went wrong").context("While fetching credentials");
+            let json: Value = serde_json::from_str(&to_json(&e))?;
+
+            assert_eq!(
+                json!({
+                    "error_kind": kind.to_string(),
+                    "message": "While fetching credentials: Something went wrong",
+                    "exit_code": kind.exit_code(),
+                }),
+                json
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn classifies_untagged_errors() {
+        let okta_error = |code: &str| {
+            anyhow::Error::new(ErrorResponse {
+                error_code: code.to_string(),
+                error_summary: "Summary".to_string(),
+                error_causes: vec![],
+                remaining_attempts: None,
+            })
+        };
+
+        assert_eq!(
+            ErrorKind::InvalidCredentials,
+            kind_of(&okta_error("E0000004"))
+        );
+        assert_eq!(ErrorKind::MfaRejected, kind_of(&okta_error("E0000068")));
+        assert_eq!(ErrorKind::Okta, kind_of(&okta_error("E0000011")));
+        assert_eq!(
+            ErrorKind::Config,
+            kind_of(&anyhow::Error::new(
+                toml::from_str::<Value>("=").unwrap_err()
+            ))
+        );
+        assert_eq!(ErrorKind::Unknown, kind_of(&anyhow!("Something else")));
+    }
+}
@@ -61,7 +61,8 @@ impl Executor {
     }
 }
 
-fn shell() -> Result<Vec<String>> {
+/// The shell commands given as a single string run in
+pub fn shell() -> Result<Vec<String>> {
     if cfg!(windows) {
         Ok(vec!["cmd.exec".into(), "/C".into()])
     } else if let Ok(shell) = env::var("SHELL") {
@@ -1,15 +1,26 @@
+use crate::error;
+use crate::report;
+use crate::ui;
 use std::{
     io::{stderr, stdout, Write},
     process,
+    sync::atomic::{AtomicBool, Ordering},
 };
 
+static JSON_ERRORS: AtomicBool = AtomicBool::new(false);
+
+/// Makes `ok_or_exit` report failures as a single JSON object for wrapping tools
+pub fn set_json_errors(enabled: bool) {
+    JSON_ERRORS.store(enabled, Ordering::Relaxed);
+}
+
 pub fn print_causes(e: impl Into<anyhow::Error>, mut w: impl Write) {
     let e = e.into();
     let causes = e.chain().collect::<Vec<_>>();
     let num_causes = causes.len();
     for (index, cause) in causes.iter().enumerate() {
         if index == 0 {
-            writeln!(w, "{}", cause).ok();
+            writeln!(w, "{}", ui::error(&cause.to_string())).ok();
             if num_causes > 1 {
                 writeln!(w, "Caused by: ").ok();
             }
@@ -26,8 +37,23 @@ where
     match r {
         Ok(r) => r,
         Err(e) => {
+            let e = e.into();
             stdout().flush().ok();
+
+            if JSON_ERRORS.load(Ordering::Relaxed) {
+                eprintln!("{}", error::to_json(&e));
+                process::exit(error::kind_of(&e).exit_code());
+            }
+
+            let summary = match report::is_enabled() {
+                true => Some(report::error_summary(&e)),
+                false => None,
+            };
+
             print_causes(e, stderr());
+            if let Some(summary) = summary {
+                eprintln!("\n{}", summary);
+            }
             process::exit(1);
         }
     }
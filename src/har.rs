@@ -0,0 +1,790 @@
+//! Records every HTTP request to the IdP and STS in a HAR 1.2 file with
+//! `--trace-http`, to inspect in the network panel of a browser's devtools
+//! or share with support. Passwords, codes, tokens, session cookies, SAML
+//! assertions and AWS credentials are masked before anything is written.
+
+use crate::utils;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use reqwest::blocking::{Client as HttpClient, Request, RequestBuilder, Response};
+use reqwest::header::HeaderMap;
+use reqwest::{StatusCode, Url, Version};
+use rusoto_core::request::{
+    DispatchSignedRequest, DispatchSignedRequestFuture, HttpClient as AwsHttpClient, HttpResponse,
+};
+use rusoto_core::signature::{SignedRequest, SignedRequestPayload};
+use rusoto_core::ByteStream;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use url::form_urlencoded;
+
+const MASK: &str = "******";
+const SECRET_HEADERS: [&str; 4] = [
+    "authorization",
+    "cookie",
+    "set-cookie",
+    "x-amz-security-token",
+];
+// Along with any parameter whose name starts with pass, like the Password of
+// ADFS' login form
+const SECRET_PARAMETERS: [&str; 5] = [
+    "onetimetoken",
+    "token",
+    "SAMLResponse",
+    "SAMLAssertion",
+    "RelayState",
+];
+const SECRET_FIELDS: [&str; 11] = [
+    "password",
+    "passCode",
+    "answer",
+    "sessionToken",
+    "stateToken",
+    "sharedSecret",
+    "signatureData",
+    "clientData",
+    "authenticatorData",
+    "otp",
+    "token",
+];
+const SECRET_ELEMENTS: [&str; 3] = ["AccessKeyId", "SecretAccessKey", "SessionToken"];
+// Okta's sessions API answers with the id browsers send as the sid cookie
+const SESSIONS_PATH: &str = "/api/v1/sessions";
+
+// Requests are made from the threads of e.g. assume-all as well
+static TRACE: Mutex<Option<Trace>> = Mutex::new(None);
+
+#[derive(Debug)]
+struct Trace {
+    path: PathBuf,
+    entries: Vec<Entry>,
+}
+
+#[derive(Serialize, Debug)]
+struct Har<'a> {
+    log: Log<'a>,
+}
+
+#[derive(Serialize, Debug)]
+struct Log<'a> {
+    version: &'static str,
+    creator: Creator,
+    entries: &'a [Entry],
+}
+
+#[derive(Serialize, Debug)]
+struct Creator {
+    name: &'static str,
+    version: &'static str,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct Entry {
+    started_date_time: String,
+    time: f64,
+    request: HarRequest,
+    response: HarResponse,
+    cache: Cache,
+    timings: Timings,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct HarRequest {
+    method: String,
+    url: String,
+    http_version: String,
+    cookies: Vec<NameValue>,
+    headers: Vec<NameValue>,
+    query_string: Vec<NameValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    post_data: Option<PostData>,
+    headers_size: i64,
+    body_size: i64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct HarResponse {
+    status: u16,
+    status_text: String,
+    http_version: String,
+    cookies: Vec<NameValue>,
+    headers: Vec<NameValue>,
+    content: Content,
+    #[serde(rename = "redirectURL")]
+    redirect_url: String,
+    headers_size: i64,
+    body_size: i64,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+struct NameValue {
+    name: String,
+    value: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct PostData {
+    mime_type: String,
+    text: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct Content {
+    size: i64,
+    mime_type: String,
+    text: String,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+struct Cache {}
+
+#[derive(Serialize, Debug, Clone)]
+struct Timings {
+    send: f64,
+    wait: f64,
+    receive: f64,
+}
+
+/// Records the requests of this run to the HAR file at `path` from now on
+pub fn set_file(path: Option<PathBuf>) {
+    *TRACE.lock().unwrap() = path.map(|path| Trace {
+        path,
+        entries: vec![],
+    });
+}
+
+fn is_tracing() -> bool {
+    TRACE.lock().unwrap().is_some()
+}
+
+/// Sends the request of `builder` with `client`, recording it
+pub fn send(client: &HttpClient, builder: RequestBuilder) -> reqwest::Result<Response> {
+    execute(client, builder.build()?)
+}
+
+/// Executes `request` with `client`, recording it along with the response.
+/// The response body is read for that and handed on in a response of its
+/// own, as reqwest doesn't let it be read twice
+pub fn execute(client: &HttpClient, request: Request) -> reqwest::Result<Response> {
+    if !is_tracing() {
+        return client.execute(request);
+    }
+
+    let recorded = RecordedRequest {
+        method: request.method().to_string(),
+        url: request.url().clone(),
+        headers: headers(request.headers()),
+        body: request
+            .body()
+            .and_then(|b| b.as_bytes())
+            .map(|b| b.to_vec()),
+    };
+
+    let started_at = Utc::now();
+    let started = Instant::now();
+    let response = client.execute(request)?;
+    let waited = started.elapsed();
+
+    let status = response.status();
+    let version = response.version();
+    let response_headers = response.headers().clone();
+    let body = response.bytes()?.to_vec();
+
+    record(
+        &recorded,
+        RecordedResponse {
+            status,
+            version: format!("{:?}", version),
+            headers: headers(&response_headers),
+            body: &body,
+        },
+        started_at,
+        waited,
+        started.elapsed(),
+    );
+
+    let mut rebuilt = http::Response::builder().status(status).version(version);
+    if let Some(rebuilt_headers) = rebuilt.headers_mut() {
+        rebuilt_headers.extend(response_headers);
+    }
+
+    Ok(Response::from(
+        rebuilt
+            .body(body)
+            .expect("The parts of a received response are valid"),
+    ))
+}
+
+/// Dispatches the requests of rusoto's clients, recording them
+pub struct Dispatcher<D = AwsHttpClient> {
+    inner: D,
+}
+
+impl<D> Dispatcher<D> {
+    pub fn new(inner: D) -> Dispatcher<D> {
+        Dispatcher { inner }
+    }
+}
+
+impl<D: DispatchSignedRequest> DispatchSignedRequest for Dispatcher<D> {
+    fn dispatch(
+        &self,
+        request: SignedRequest,
+        timeout: Option<Duration>,
+    ) -> DispatchSignedRequestFuture {
+        if !is_tracing() {
+            return self.inner.dispatch(request, timeout);
+        }
+
+        let recorded = recorded_signed_request(&request);
+        let started_at = Utc::now();
+        let started = Instant::now();
+        let response = self.inner.dispatch(request, timeout);
+
+        Box::pin(async move {
+            let mut response = response.await?;
+            let waited = started.elapsed();
+            let buffered = response.buffer().await?;
+
+            record(
+                &recorded,
+                RecordedResponse {
+                    status: buffered.status,
+                    version: format!("{:?}", Version::HTTP_11),
+                    headers: buffered
+                        .headers
+                        .iter()
+                        .map(|(name, value)| (name.to_string(), value.clone(), false))
+                        .collect(),
+                    body: &buffered.body,
+                },
+                started_at,
+                waited,
+                started.elapsed(),
+            );
+
+            Ok(HttpResponse {
+                status: buffered.status,
+                body: ByteStream::from(buffered.body.to_vec()),
+                headers: buffered.headers,
+            })
+        })
+    }
+}
+
+struct RecordedRequest {
+    method: String,
+    url: Url,
+    // Names, values and whether they are sensitive
+    headers: Vec<(String, String, bool)>,
+    body: Option<Vec<u8>>,
+}
+
+struct RecordedResponse<'a> {
+    status: StatusCode,
+    version: String,
+    headers: Vec<(String, String, bool)>,
+    body: &'a [u8],
+}
+
+fn recorded_signed_request(request: &SignedRequest) -> RecordedRequest {
+    let query = match request.canonical_query_string() {
+        "" => String::new(),
+        query => format!("?{}", query),
+    };
+    let url = format!(
+        "{}://{}{}{}",
+        request.scheme(),
+        request.hostname(),
+        request.path(),
+        query
+    );
+
+    RecordedRequest {
+        method: request.method().to_string(),
+        url: Url::parse(&url).unwrap_or_else(|_| Url::parse("http://unknown").unwrap()),
+        headers: request
+            .headers()
+            .iter()
+            .flat_map(|(name, values)| {
+                values.iter().map(move |value| {
+                    (
+                        name.to_string(),
+                        String::from_utf8_lossy(value).to_string(),
+                        false,
+                    )
+                })
+            })
+            .collect(),
+        body: match request.payload {
+            Some(SignedRequestPayload::Buffer(ref bytes)) => Some(bytes.to_vec()),
+            _ => None,
+        },
+    }
+}
+
+fn headers(headers: &HeaderMap) -> Vec<(String, String, bool)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            (
+                name.to_string(),
+                String::from_utf8_lossy(value.as_bytes()).to_string(),
+                value.is_sensitive(),
+            )
+        })
+        .collect()
+}
+
+// Failing to write the trace only costs the trace, not the login
+fn record(
+    request: &RecordedRequest,
+    response: RecordedResponse,
+    started_at: DateTime<Utc>,
+    waited: Duration,
+    total: Duration,
+) {
+    let entry = entry(request, response, started_at, waited, total);
+    let mut trace = TRACE.lock().unwrap();
+
+    if let Some(trace) = trace.as_mut() {
+        trace.entries.push(entry);
+        if let Err(e) = write(&trace.path, &trace.entries) {
+            warn!(
+                "Unable to write the HTTP trace to {}: {:#}",
+                trace.path.display(),
+                e
+            );
+        }
+    }
+}
+
+fn entry(
+    request: &RecordedRequest,
+    response: RecordedResponse,
+    started_at: DateTime<Utc>,
+    waited: Duration,
+    total: Duration,
+) -> Entry {
+    let url = redact_url(&request.url);
+    let request_body = request.body.as_ref().map(|body| {
+        redact(
+            &String::from_utf8_lossy(body),
+            &header(&request.headers, "content-type"),
+            &request.url,
+        )
+    });
+    let response_body = redact(
+        &String::from_utf8_lossy(response.body),
+        &header(&response.headers, "content-type"),
+        &request.url,
+    );
+    let wait = milliseconds(waited);
+    let total = milliseconds(total);
+
+    Entry {
+        started_date_time: started_at.to_rfc3339(),
+        time: total,
+        request: HarRequest {
+            method: request.method.clone(),
+            url: url.to_string(),
+            http_version: "HTTP/1.1".to_string(),
+            cookies: vec![],
+            headers: redact_headers(&request.headers),
+            query_string: url
+                .query_pairs()
+                .map(|(name, value)| NameValue {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                })
+                .collect(),
+            post_data: request_body.as_ref().map(|text| PostData {
+                mime_type: header(&request.headers, "content-type"),
+                text: text.clone(),
+            }),
+            headers_size: -1,
+            body_size: request.body.as_ref().map_or(0, |body| body.len() as i64),
+        },
+        response: HarResponse {
+            status: response.status.as_u16(),
+            status_text: response
+                .status
+                .canonical_reason()
+                .unwrap_or_default()
+                .to_string(),
+            http_version: response.version,
+            cookies: vec![],
+            redirect_url: header(&response.headers, "location"),
+            content: Content {
+                size: response.body.len() as i64,
+                mime_type: header(&response.headers, "content-type"),
+                text: response_body,
+            },
+            headers: redact_headers(&response.headers),
+            headers_size: -1,
+            body_size: response.body.len() as i64,
+        },
+        cache: Cache::default(),
+        timings: Timings {
+            send: 0.0,
+            wait,
+            receive: (total - wait).max(0.0),
+        },
+    }
+}
+
+fn write(path: &Path, entries: &[Entry]) -> Result<()> {
+    let har = Har {
+        log: Log {
+            version: "1.2",
+            creator: Creator {
+                name: env!("CARGO_PKG_NAME"),
+                version: env!("CARGO_PKG_VERSION"),
+            },
+            entries,
+        },
+    };
+
+    Ok(utils::write_secure(
+        path,
+        &serde_json::to_vec_pretty(&har)?,
+    )?)
+}
+
+fn milliseconds(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn header(headers: &[(String, String, bool)], name: &str) -> String {
+    headers
+        .iter()
+        .find(|(n, _, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, value, _)| value.clone())
+        .unwrap_or_default()
+}
+
+// Headers marked sensitive are those of the profile, e.g. a WAF token
+fn redact_headers(headers: &[(String, String, bool)]) -> Vec<NameValue> {
+    headers
+        .iter()
+        .map(|(name, value, sensitive)| NameValue {
+            name: name.clone(),
+            value: match *sensitive || SECRET_HEADERS.contains(&name.to_lowercase().as_str()) {
+                true => MASK.to_string(),
+                false => value.clone(),
+            },
+        })
+        .collect()
+}
+
+fn redact_url(url: &Url) -> Url {
+    let mut redacted = url.clone();
+
+    if url.query().is_some() {
+        let pairs = url
+            .query_pairs()
+            .map(|(name, value)| redact_parameter(&name, &value))
+            .collect::<Vec<(String, String)>>();
+        redacted.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+
+    redacted
+}
+
+fn is_secret_parameter(name: &str) -> bool {
+    name.to_lowercase().starts_with("pass")
+        || SECRET_PARAMETERS
+            .iter()
+            .any(|p| p.eq_ignore_ascii_case(name))
+}
+
+fn redact_parameter(name: &str, value: &str) -> (String, String) {
+    match is_secret_parameter(name) {
+        true => (name.to_string(), MASK.to_string()),
+        false => (name.to_string(), value.to_string()),
+    }
+}
+
+/// Masks the secrets in a body of JSON, a form, HTML or XML. JSON and forms
+/// are parsed, so neither escaped quotes nor odd casing let a value through
+fn redact(text: &str, mime_type: &str, url: &Url) -> String {
+    if let Ok(mut json) = serde_json::from_str::<Value>(text) {
+        let sessions = url.path().starts_with(SESSIONS_PATH);
+        redact_json(&mut json, &|name| {
+            (sessions && name == "id") || SECRET_FIELDS.iter().any(|f| f.eq_ignore_ascii_case(name))
+        });
+        return json.to_string();
+    }
+
+    if mime_type.starts_with("application/x-www-form-urlencoded") {
+        return form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(
+                form_urlencoded::parse(text.as_bytes())
+                    .map(|(name, value)| redact_parameter(&name, &value)),
+            )
+            .finish();
+    }
+
+    let inputs = Regex::new(&format!(
+        r#"(?i)(<input[^>]*name="(?:{}|pass[^"]*)"[^>]*value=")[^"]*"#,
+        SECRET_PARAMETERS.join("|")
+    ))
+    .unwrap();
+    let xml = Regex::new(&format!(r"<({})>[^<]*<", SECRET_ELEMENTS.join("|"))).unwrap();
+
+    let text = inputs.replace_all(text, format!("${{1}}{}", MASK).as_str());
+    let text = xml.replace_all(&text, format!("<$1>{}<", MASK).as_str());
+
+    text.to_string()
+}
+
+fn redact_json(value: &mut Value, is_secret: &dyn Fn(&str) -> bool) {
+    match value {
+        Value::Object(fields) => {
+            for (name, value) in fields.iter_mut() {
+                match is_secret(name) && !value.is_null() {
+                    true => *value = Value::String(MASK.to_string()),
+                    false => redact_json(value, is_secret),
+                }
+            }
+        }
+        Value::Array(values) => values
+            .iter_mut()
+            .for_each(|value| redact_json(value, is_secret)),
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use reqwest::header::{HeaderValue, CONTENT_TYPE, COOKIE, SET_COOKIE};
+    use std::fs;
+
+    #[test]
+    fn writes_har_with_masked_secrets() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("trace.har");
+
+        let mut login_headers = HeaderMap::new();
+        login_headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        let mut waf_token = HeaderValue::from_static("waf-secret");
+        waf_token.set_sensitive(true);
+        login_headers.insert("x-waf-token", waf_token);
+        let login = RecordedRequest {
+            method: "POST".to_string(),
+            url: Url::parse("https://example.okta.com/api/v1/authn")?,
+            headers: headers(&login_headers),
+            body: Some(br#"{"username":"jdoe","password":"hunter2\"and more"}"#.to_vec()),
+        };
+
+        let mut saml_headers = HeaderMap::new();
+        saml_headers.insert(
+            COOKIE,
+            HeaderValue::from_static("sid=102bZDNFfWaQSyEZQuDgWt-uQ"),
+        );
+        let saml = RecordedRequest {
+            method: "GET".to_string(),
+            url: Url::parse(
+                "https://example.okta.com/home/amazon_aws/0oa/272?fromHome=true&onetimetoken=20111nHMj4dJ8Ns",
+            )?,
+            headers: headers(&saml_headers),
+            body: None,
+        };
+
+        let mut set_cookie = HeaderMap::new();
+        set_cookie.insert(CONTENT_TYPE, HeaderValue::from_static("text/html"));
+        set_cookie.insert(
+            SET_COOKIE,
+            HeaderValue::from_static("DT=DI0dZ5mFB7bQ2W8kJ3tNWK3Ag"),
+        );
+
+        let mut form_headers = HeaderMap::new();
+        form_headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/x-www-form-urlencoded"),
+        );
+        let adfs = RecordedRequest {
+            method: "POST".to_string(),
+            url: Url::parse(
+                "https://adfs.example.com/adfs/ls/IdpInitiatedSignOn.aspx?loginToRp=urn:amazon:webservices",
+            )?,
+            headers: headers(&form_headers),
+            body: Some(
+                b"UserName=EXAMPLE%5Cjdoe&Password=s3cr%26t&AuthMethod=FormsAuthentication"
+                    .to_vec(),
+            ),
+        };
+
+        let sts_body = fs::read_to_string("tests/fixtures/aws/assume_role_with_saml_response.xml")?;
+        let sts = RecordedRequest {
+            method: "POST".to_string(),
+            url: Url::parse("https://sts.amazonaws.com/")?,
+            headers: vec![
+                (
+                    "authorization".to_string(),
+                    "AWS4-HMAC-SHA256 Credential=...".to_string(),
+                    false,
+                ),
+                (
+                    "content-type".to_string(),
+                    "application/x-www-form-urlencoded".to_string(),
+                    false,
+                ),
+            ],
+            body: Some(
+                b"Action=AssumeRoleWithSAML&SAMLAssertion=PHNhbWw%2B&DurationSeconds=3600".to_vec(),
+            ),
+        };
+
+        let now = Utc::now();
+        let second = Duration::from_secs(1);
+        let entries = vec![
+            entry(
+                &login,
+                RecordedResponse {
+                    status: StatusCode::OK,
+                    version: "HTTP/1.1".to_string(),
+                    headers: vec![],
+                    body: br#"{"status":"SUCCESS","sessionToken":"20111nHMj4dJ8Ns"}"#,
+                },
+                now,
+                second,
+                second,
+            ),
+            entry(
+                &saml,
+                RecordedResponse {
+                    status: StatusCode::OK,
+                    version: "HTTP/1.1".to_string(),
+                    headers: headers(&set_cookie),
+                    body: br#"<form><input type="hidden" name="SAMLResponse" value="PHNhbWw+"/></form>"#,
+                },
+                now,
+                second,
+                second,
+            ),
+            entry(
+                &adfs,
+                RecordedResponse {
+                    status: StatusCode::OK,
+                    version: "HTTP/1.1".to_string(),
+                    headers: headers(&set_cookie),
+                    body: br#"<form><INPUT type="hidden" name="samlresponse" value="UEhOaGJXdw=="/></form>"#,
+                },
+                now,
+                second,
+                second,
+            ),
+            entry(
+                &sts,
+                RecordedResponse {
+                    status: StatusCode::OK,
+                    version: "HTTP/1.1".to_string(),
+                    headers: vec![],
+                    body: sts_body.as_bytes(),
+                },
+                now,
+                second,
+                second,
+            ),
+        ];
+        write(&path, &entries)?;
+
+        let content = fs::read_to_string(&path)?;
+        let har: serde_json::Value = serde_json::from_str(&content)?;
+        assert_eq!("1.2", har["log"]["version"]);
+        assert_eq!("crowbar", har["log"]["creator"]["name"]);
+
+        let entries = har["log"]["entries"].as_array().unwrap();
+        let urls = entries
+            .iter()
+            .map(|e| e["request"]["url"].as_str().unwrap())
+            .collect::<Vec<&str>>();
+        assert_eq!(
+            vec![
+                "https://example.okta.com/api/v1/authn",
+                "https://example.okta.com/home/amazon_aws/0oa/272?fromHome=true&onetimetoken=******",
+                "https://adfs.example.com/adfs/ls/IdpInitiatedSignOn.aspx?loginToRp=urn%3Aamazon%3Awebservices",
+                "https://sts.amazonaws.com/",
+            ],
+            urls
+        );
+        for entry in entries {
+            for field in &[
+                "startedDateTime",
+                "time",
+                "request",
+                "response",
+                "cache",
+                "timings",
+            ] {
+                assert!(!entry[field].is_null(), "{} missing in {}", field, entry);
+            }
+            assert!(entry["response"]["content"]["text"].is_string());
+            assert!(entry["request"]["headers"].is_array());
+        }
+        assert_eq!("POST", entries[0]["request"]["method"]);
+        assert_eq!(
+            "application/json",
+            entries[0]["request"]["postData"]["mimeType"]
+        );
+        assert_eq!(200, entries[3]["response"]["status"]);
+
+        for secret in &[
+            "hunter2",
+            "and more",
+            "s3cr",
+            "UEhOaGJXdw",
+            "waf-secret",
+            "20111nHMj4dJ8Ns",
+            "102bZDNFfWaQSyEZQuDgWt-uQ",
+            "DI0dZ5mFB7bQ2W8kJ3tNWK3Ag",
+            "PHNhbWw",
+            "AWS4-HMAC-SHA256",
+            "ASIAEXAMPLE",
+        ] {
+            assert!(!content.contains(secret), "{} is in the trace", secret);
+        }
+        // What isn't secret stays readable
+        assert!(content.contains(r#"\"username\":\"jdoe\""#), "{}", content);
+        assert!(content.contains("DurationSeconds=3600"), "{}", content);
+        assert!(
+            content
+                .contains("UserName=EXAMPLE%5Cjdoe&Password=******&AuthMethod=FormsAuthentication"),
+            "{}",
+            content
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn masks_session_ids_of_sessions_api_only() -> Result<()> {
+        let body = r#"{"id":"102bZDNFfWaQSyEZQuDgWt-uQ","userId":"00u1"}"#;
+
+        assert!(!redact(
+            body,
+            "application/json",
+            &Url::parse("https://example.okta.com/api/v1/sessions/me")?
+        )
+        .contains("102bZ"));
+        assert!(redact(
+            body,
+            "application/json",
+            &Url::parse("https://example.okta.com/api/v1/authn")?
+        )
+        .contains("102bZ"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+//! Settings shared by the HTTP clients talking to the IdP. Batch runs like
+//! `assume-all` keep many connections to the same host, so how many idle
+//! ones the pool keeps per host may be raised with `max_idle_connections` in
+//! the profile or `--max-idle-connections`.
+//!
+//! Some corporate proxies and middleboxes only complete a TLS handshake with
+//! one implementation, so profiles may pick the TLS backend with
+//! `tls_backend`, out of the ones crowbar was built with.
+
+use crate::config::app::AppProfile;
+use crate::proxy;
+
+use anyhow::{anyhow, Result};
+use reqwest::blocking::ClientBuilder;
+use std::fmt;
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls-tls")))]
+compile_error!("crowbar needs a TLS backend, build it with the native-tls or rustls-tls feature");
+
+/// Idle connections kept per host, enough for the requests of one login
+pub const DEFAULT_MAX_IDLE_CONNECTIONS: usize = 8;
+
+/// The TLS implementation the HTTP clients talking to the IdP use
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TlsBackend {
+    /// The system's TLS, i.e. OpenSSL, schannel or Secure Transport, and its
+    /// certificate store. Built in with the default `native-tls` feature
+    Native,
+    /// rustls with the Mozilla root certificates, ignoring the system's.
+    /// Built in with the `rustls-tls` feature
+    Rustls,
+}
+
+impl TlsBackend {
+    /// Whether this crowbar was built with the backend
+    pub fn is_built_in(self) -> bool {
+        match self {
+            TlsBackend::Native => cfg!(feature = "native-tls"),
+            TlsBackend::Rustls => cfg!(feature = "rustls-tls"),
+        }
+    }
+}
+
+impl fmt::Display for TlsBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TlsBackend::Native => write!(f, "native"),
+            TlsBackend::Rustls => write!(f, "rustls"),
+        }
+    }
+}
+
+/// Configures `builder` with the connection pool, proxies and TLS backend of
+/// the profile
+pub fn configure(builder: ClientBuilder, profile: &AppProfile) -> Result<ClientBuilder> {
+    proxy::configure(
+        with_tls_backend(
+            builder.max_idle_per_host(max_idle_connections(profile)),
+            profile.tls_backend,
+        )?,
+        profile,
+    )
+}
+
+// Without a backend in the profile, reqwest picks one of those built in,
+// the system's TLS if both are
+fn with_tls_backend(builder: ClientBuilder, backend: Option<TlsBackend>) -> Result<ClientBuilder> {
+    match backend {
+        None => Ok(builder),
+        #[cfg(feature = "native-tls")]
+        Some(TlsBackend::Native) => Ok(builder.use_native_tls()),
+        #[cfg(feature = "rustls-tls")]
+        Some(TlsBackend::Rustls) => Ok(builder.use_rustls_tls()),
+        // Only reachable with a single backend built in
+        #[allow(unreachable_patterns)]
+        Some(backend) => Err(anyhow!(
+            "The TLS backend {} isn't built into this crowbar",
+            backend
+        )),
+    }
+}
+
+fn max_idle_connections(profile: &AppProfile) -> usize {
+    profile
+        .max_idle_connections
+        .unwrap_or(DEFAULT_MAX_IDLE_CONNECTIONS)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::app::test::{okta_profile, OKTA_URL};
+    use crate::credentials::aws::FetchOptions;
+    use reqwest::blocking::Client;
+
+    #[test]
+    fn sizes_connection_pool_from_profile_or_flag() {
+        let mut profile = okta_profile("profile", OKTA_URL);
+        assert_eq!(DEFAULT_MAX_IDLE_CONNECTIONS, max_idle_connections(&profile));
+
+        profile.max_idle_connections = Some(32);
+        assert_eq!(32, max_idle_connections(&profile));
+
+        let options = FetchOptions {
+            max_idle_connections: Some(64),
+            ..FetchOptions::default()
+        };
+        assert_eq!(64, max_idle_connections(&profile.with_options(&options)));
+    }
+
+    #[test]
+    fn builds_client_with_selected_tls_backend() -> Result<()> {
+        let mut profile = okta_profile("profile", OKTA_URL);
+        configure(Client::builder(), &profile)?.build()?;
+
+        for backend in &[TlsBackend::Native, TlsBackend::Rustls] {
+            profile.tls_backend = Some(*backend);
+            let client = configure(Client::builder(), &profile).and_then(|b| Ok(b.build()?));
+
+            match backend.is_built_in() {
+                true => assert!(client.is_ok(), "{} fails: {:?}", backend, client.err()),
+                false => assert_eq!(
+                    format!("The TLS backend {} isn't built into this crowbar", backend),
+                    client.err().map(|e| e.to_string()).unwrap_or_default()
+                ),
+            }
+        }
+        assert!(TlsBackend::Native.is_built_in() || TlsBackend::Rustls.is_built_in());
+
+        Ok(())
+    }
+}
@@ -25,23 +25,57 @@ extern crate toml;
 extern crate walkdir;
 extern crate whoami;
 
+pub mod api;
+mod assume_all;
 mod aws;
+mod bookmarks;
 mod cli;
+pub mod clock;
+mod completions;
 pub mod config;
 pub mod credentials;
+pub mod error;
 mod exec;
 pub mod exit;
+mod har;
+mod http;
+mod metrics;
+mod notify;
+mod output;
+mod preflight;
+pub mod prompt;
 mod providers;
+mod proxy;
+mod report;
 mod saml;
+#[cfg(unix)]
+mod serve;
+mod status;
+mod switch;
+pub mod timings;
+pub mod ui;
 mod utils;
+mod watch;
 
 use crate::cli::{CliAction, CliSubAction};
-use crate::config::{aws::AwsConfig, CrowbarConfig};
+use crate::config::app::AppProfile;
+use crate::config::{aws::AwsConfig, ConfigSource, CrowbarConfig};
 use crate::credentials::aws as CredentialsProvider;
+use crate::credentials::aws::{Ensured, FetchOptions};
+use crate::credentials::keystore;
+use crate::credentials::totp::{TotpCredentials, TotpParameters};
+use crate::credentials::{Credential, CredentialType};
+use crate::error::ErrorKind;
 use crate::exec::Executor;
-use anyhow::Result;
+use crate::output::OutputMode;
+use crate::providers::jumpcloud::JumpcloudProvider;
+use crate::providers::okta::OktaProvider;
+use crate::providers::ProviderType;
+use anyhow::{anyhow, Result};
+use console::Term;
 use env_logger::{Builder, WriteStyle};
 use std::io::Write;
+use std::time::Instant;
 
 pub fn run() -> Result<()> {
     let cli = cli::config()?;
@@ -52,56 +86,548 @@ pub fn run() -> Result<()> {
         .format(|buf, record| writeln!(buf, "{}", record.args()))
         .init();
 
-    let force_new_credentials = cli.force;
+    ui::init(cli.no_color);
+    ui::set_quiet_success(cli.quiet_success);
+    ui::set_explain(cli.explain);
+    exit::set_json_errors(cli.json_errors);
+    config::set_strict(cli.strict);
+    metrics::set_file(cli.metrics_file);
+    har::set_file(cli.trace_http);
+    status::set_file(cli.status_file);
+    notify::set_channel(cli.notify);
+
+    if cli.bug_report {
+        report::enable();
+    }
+
+    if cli.no_keyring {
+        keystore::disable();
+    }
+
+    if cli.no_cache {
+        keystore::bypass_cache();
+    }
+
+    timings::set_deadline(cli.deadline.map(|d| Instant::now() + d));
+
+    let print_timings = cli.timings;
+    let fetch_options = FetchOptions {
+        force: cli.force,
+        mfa_factor: cli.mfa_factor,
+        auto_fallback_role: cli.auto_fallback_role,
+        factor_index: cli.factor_index,
+        role_index: cli.role_index,
+        role_filter: cli.role_filter,
+        mfa_code: cli.mfa_code,
+        mfa_provider: cli.mfa_provider,
+        push_device: cli.push_device,
+        region: cli.region,
+        sts_global: cli.sts_global,
+        preflight: cli.preflight,
+        keep_session: cli.keep_session,
+        forget_device: cli.forget_device,
+        reauth: cli.reauth,
+        // Scripts and the AWS CLI running crowbar can't answer the prompt
+        prompt_duration: cli.prompt_duration && Term::stderr().is_term(),
+        renew: false,
+        refresh_within: cli.refresh_within,
+        max_idle_connections: cli.max_idle_connections,
+    };
     let cli_action = cli.action;
-    let location = cli.location;
-    let crowbar_config = CrowbarConfig::with_location(location).read()?;
-    let aws_config = AwsConfig::new()?;
+    let (location, config_source) = config::resolve_location(
+        cli.location,
+        utils::non_empty_env_var(config::CROWBAR_CONFIG),
+    );
+    let crowbar_config = match cli.ad_hoc {
+        Some(profile) => CrowbarConfig::ad_hoc(profile)?,
+        None => CrowbarConfig::with_location(location),
+    };
+
+    // Checking has to work for configurations that fail to load, and
+    // completion scripts don't depend on them at all
+    let crowbar_config = match cli_action {
+        CliAction::Check { .. } | CliAction::ConfigPath | CliAction::Completions { .. } => {
+            crowbar_config
+        }
+        _ => crowbar_config.read()?,
+    };
     let executor = Executor::default();
 
     match cli_action {
+        CliAction::Check { location } => match location {
+            Some(_) => check_config(CrowbarConfig::with_location(location)),
+            None => check_config(crowbar_config),
+        },
         CliAction::Profiles { action } => {
             match action {
-                CliSubAction::Add { profile } => {
-                    crowbar_config.add_profile(&profile)?.write()?;
-                    aws_config.add_profile(&profile)?.write()?;
-                    println!("Profile {} added successfully!", profile.name)
+                CliSubAction::Add { profile, region } => {
+                    add_profile(crowbar_config, &profile, region)?
+                }
+                CliSubAction::ImportBookmarks {
+                    path,
+                    profile_name,
+                    username,
+                    role,
+                } => {
+                    let app = bookmarks::pick(bookmarks::aws_apps(&path)?)?;
+                    let profile = AppProfile::new(
+                        &profile_name,
+                        ProviderType::Okta,
+                        app.url.as_str(),
+                        username.as_deref().unwrap_or_default(),
+                        role.as_deref(),
+                    );
+
+                    add_profile(crowbar_config, &profile, None)?
                 }
                 CliSubAction::Delete { profile_name } => {
                     crowbar_config.delete_profile(&profile_name)?.write()?;
-                    aws_config.delete_profile(&profile_name)?.write()?;
-                    println!("Profile {} deleted successfully", profile_name)
+                    AwsConfig::new()?.delete_profile(&profile_name)?.write()?;
+                    println!(
+                        "{}",
+                        ui::success(&format!("Profile {} deleted successfully", profile_name))
+                    )
                 }
-                CliSubAction::List {} => crowbar_config.list_profiles()?,
+                CliSubAction::List => crowbar_config.list_profiles()?,
+            }
+            Ok(())
+        }
+        CliAction::Status { summary } => {
+            status::print(&status::profile_statuses(&crowbar_config.profiles), summary)
+        }
+        CliAction::ListProfiles => {
+            for name in crowbar_config.profile_names() {
+                println!("{}", name);
             }
             Ok(())
         }
+        CliAction::ConfigPath => {
+            print_config_paths(&crowbar_config, config_source, &AwsConfig::new()?)
+        }
+        CliAction::Completions { shell } => {
+            println!("{}", completions::script(shell));
+            Ok(())
+        }
         CliAction::Exec { command, profile } => {
-            let credentials = CredentialsProvider::fetch_aws_credentials(
+            let result = CredentialsProvider::fetch_aws_credentials(
                 profile,
                 crowbar_config,
-                force_new_credentials,
+                &fetch_options,
             )?;
 
-            let exec = executor.set_command(command).set_credentials(credentials);
+            if print_timings {
+                eprintln!("{}", result.timings);
+            }
+
+            let exec = executor
+                .set_command(command)
+                .set_credentials(result.credentials);
             let _exit = exec.run()?.wait();
 
             Ok(())
         }
-        CliAction::Creds { profile, print } => {
-            let aws_credentials = CredentialsProvider::fetch_aws_credentials(
-                profile,
+        CliAction::Clean { all } => {
+            for profile in crowbar_config.profiles {
+                let profile = profile.with_resolved_username()?;
+
+                for credential_type in credentials::clean(&profile, all)? {
+                    println!(
+                        "Deleted {} secrets of profile {}",
+                        credential_type, profile.name
+                    );
+                }
+            }
+
+            for credential_type in credentials::clean_shared() {
+                println!("Deleted {} secrets of all profiles", credential_type);
+            }
+
+            Ok(())
+        }
+        CliAction::SetTotp { profile } => {
+            let profile = crowbar_config
+                .find_profile(&profile)?
+                .with_resolved_username()?;
+            let credentials = TotpCredentials::create(&profile)?;
+            let _parameters: TotpParameters = credentials.secret.parse()?;
+
+            credentials.write(&profile)?;
+            ui::print_success(&format!("TOTP secret saved for profile {}", profile.name));
+
+            Ok(())
+        }
+        CliAction::EnrollTotp { profile } => {
+            let profile = crowbar_config
+                .find_profile(&profile)?
+                .with_resolved_username()?;
+
+            match profile.provider {
+                ProviderType::Okta => OktaProvider::new(&profile)?.enroll_totp()?,
+                _ => {
+                    return Err(anyhow!(
+                        "TOTP enrollment is only supported for Okta profiles"
+                    ))
+                }
+            };
+
+            ui::print_success(&format!(
+                "TOTP factor enrolled successfully for profile {}",
+                profile.name
+            ));
+
+            Ok(())
+        }
+        CliAction::FactorHealth { profile } => {
+            let profile = crowbar_config
+                .find_profile(&profile)?
+                .with_resolved_username()?;
+
+            let health = match profile.provider {
+                ProviderType::Okta => OktaProvider::new(&profile)?.factor_health()?,
+                _ => {
+                    return Err(anyhow!(
+                        "Checking MFA factors is only supported for Okta profiles"
+                    ))
+                }
+            };
+
+            match health {
+                Some(health) => {
+                    for factor in health {
+                        if factor.is_healthy() {
+                            println!("{} {}", ui::success("PASS"), factor.factor);
+                        } else {
+                            println!("{} {}", ui::error("FAIL"), factor.factor);
+                            for problem in &factor.problems {
+                                println!("  - {}", problem);
+                            }
+                        }
+                    }
+                }
+                None => println!(
+                    "Okta doesn't ask for MFA when logging into profile {}",
+                    profile.name
+                ),
+            }
+
+            Ok(())
+        }
+        CliAction::TestFactor { profile, factor } => {
+            let mut profile = crowbar_config
+                .find_profile(&profile)?
+                .with_resolved_username()?
+                .with_options(&fetch_options);
+            profile.mfa_factor = factor.or(profile.mfa_factor);
+
+            match profile.provider {
+                ProviderType::Okta => OktaProvider::new(&profile)?.test_factor()?,
+                _ => {
+                    return Err(anyhow!(
+                        "Testing MFA factors is only supported for Okta profiles"
+                    ))
+                }
+            };
+
+            ui::print_success(&format!(
+                "Okta accepted the {} factor of profile {}, no AWS credentials were fetched",
+                profile.mfa_factor.unwrap_or_default(),
+                profile.name
+            ));
+
+            Ok(())
+        }
+        CliAction::ListFactors { profile } => {
+            let profile = crowbar_config
+                .find_profile(&profile)?
+                .with_resolved_username()?
+                .with_options(&fetch_options);
+
+            let factors = match profile.provider {
+                ProviderType::Okta => OktaProvider::new(&profile)?
+                    .menu_factors()?
+                    .unwrap_or_default(),
+                _ => {
+                    return Err(anyhow!(
+                        "Listing MFA factors is only supported for Okta profiles"
+                    ))
+                }
+            };
+
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&providers::okta::auth::list_factors(&factors))?
+            );
+
+            Ok(())
+        }
+        CliAction::InstallProcess { profile, write } => {
+            let profile = crowbar_config.find_profile(&profile)?;
+
+            if write {
+                let aws_config = AwsConfig::new()?.install_process(&profile.name)?.write()?;
+                ui::print_success(&format!(
+                    "Profile {} now gets its credentials from crowbar, see {}",
+                    profile.name,
+                    aws_config.location.display()
+                ));
+            } else {
+                print!("{}", config::aws::process_snippet(&profile.name)?);
+            }
+
+            Ok(())
+        }
+        CliAction::Creds {
+            profile,
+            print,
+            output,
+            verify,
+        } => {
+            let app_profile = crowbar_config
+                .find_profile(&profile)?
+                .with_options(&fetch_options);
+            let output = output::resolve(output, Some(&app_profile))?;
+            let result = CredentialsProvider::fetch_aws_credentials(
+                profile.clone(),
                 crowbar_config,
-                force_new_credentials,
+                &fetch_options,
             )?;
 
-            if print {
-                println!("{}", aws_credentials);
-            } else {
-                info!("Please run with the -p switch to print the credentials to stdout")
+            if print_timings {
+                eprintln!("{}", result.timings);
             }
 
+            match output {
+                // Fetched credentials are always cached in the keyring, so
+                // there is nothing left to do as long as it is available
+                OutputMode::Keyring => {
+                    if !keystore::is_available() {
+                        return Err(anyhow!(
+                            "The keyring is unavailable, unable to store credentials in it"
+                        ));
+                    }
+
+                    ui::print_success(&format!(
+                        "Credentials of profile {} stored in the keyring as {}",
+                        profile,
+                        CredentialType::Aws.service(&profile)
+                    ));
+                }
+                _ if print => println!(
+                    "{}",
+                    output::render(&result.credentials, &output, &profile)?
+                ),
+                _ => info!("Please run with the -p switch to print the credentials to stdout"),
+            }
+
+            if verify {
+                let identity =
+                    CredentialsProvider::verify_aws_credentials(&app_profile, &result.credentials)?;
+                ui::print_success(&format!("Credentials verified: {}", identity));
+            }
+
+            Ok(())
+        }
+        CliAction::Ensure { profile } => {
+            let app_profile = crowbar_config.find_profile(&profile)?;
+            let within = fetch_options.refresh_within;
+            let ensured =
+                CredentialsProvider::ensure_aws_credentials(&app_profile, within, || {
+                    Ok(CredentialsProvider::fetch_aws_credentials(
+                        profile.clone(),
+                        crowbar_config.clone(),
+                        &fetch_options,
+                    )?
+                    .credentials)
+                })?;
+
+            let message = match ensured {
+                Ensured::Valid(credentials) => format!(
+                    "Credentials of profile {} are valid until {}",
+                    profile,
+                    credentials.expiration.unwrap_or_default()
+                ),
+                Ensured::Refreshed(credentials) => format!(
+                    "Refreshed credentials of profile {}, valid until {}",
+                    profile,
+                    credentials.expiration.unwrap_or_default()
+                ),
+            };
+            ui::print_success(&message);
+
+            Ok(())
+        }
+        CliAction::Watch { profile } => watch::watch(profile, crowbar_config, &fetch_options),
+        CliAction::Switch => switch::switch(crowbar_config, &fetch_options),
+        #[cfg(unix)]
+        CliAction::Serve {
+            socket,
+            prompt_once: true,
+            ..
+        } => serve::serve_all(socket, crowbar_config, &fetch_options),
+        #[cfg(unix)]
+        CliAction::Serve {
+            profile, socket, ..
+        } => serve::serve(profile, socket, crowbar_config, &fetch_options),
+        #[cfg(not(unix))]
+        CliAction::Serve { .. } => Err(anyhow!(
+            "Serving credentials needs Unix sockets, which aren't available on this platform"
+        )),
+        CliAction::PrintAssertionAttributes { profile } => {
+            let profile = crowbar_config
+                .find_profile(&profile)?
+                .with_resolved_username()?
+                .with_options(&fetch_options);
+
+            let input = fetch_saml(&profile, &fetch_options, "Printing assertion attributes")?;
+            let attributes = saml::extract_attributes(&input)?;
+            if attributes.is_empty() {
+                eprintln!(
+                    "{}",
+                    ui::warning("The SAML assertion doesn't contain any attributes")
+                );
+            }
+
+            for attribute in attributes {
+                println!("{}", attribute);
+            }
+
+            Ok(())
+        }
+        CliAction::AssumeAll {
+            profile,
+            template,
+            summary,
+        } => {
+            let profile = crowbar_config
+                .find_profile(&profile)?
+                .with_resolved_username()?
+                .with_options(&fetch_options);
+            assume_all::check_template(&template)?;
+
+            let input = fetch_saml(&profile, &fetch_options, "Assuming all roles")?;
+            let assumed = assume_all::assume_all(&profile, &input, &template)?;
+
+            assume_all::store(&profile, assumed, AwsConfig::new()?, summary)
+        }
+        CliAction::Get { profile, output } => {
+            let (credentials, output) = match crowbar_config.find_profile(&profile) {
+                Ok(app_profile) => (
+                    CredentialsProvider::stored_aws_credentials(&app_profile)?,
+                    output::resolve(output, Some(&app_profile))?,
+                ),
+                // Roles assumed with assume-all are stored under names of
+                // their own
+                Err(e) => (
+                    CredentialsProvider::stored_role_credentials(&profile)?.ok_or(e)?,
+                    output::resolve(output, None)?,
+                ),
+            };
+
+            println!("{}", output::render(&credentials, &output, &profile)?);
+
             Ok(())
         }
     }
 }
+
+// Logs in and fetches the HTML form carrying the SAML assertion, which
+// isn't possible for ADFS whose provider assumes the role right away
+fn fetch_saml(profile: &AppProfile, options: &FetchOptions, purpose: &str) -> Result<String> {
+    if options.preflight {
+        preflight::check(profile)?;
+    }
+
+    match profile.provider {
+        ProviderType::Okta => {
+            let mut provider = OktaProvider::new(profile)?;
+            provider.new_session()?;
+            provider.remember_login()?;
+            provider.fetch_saml()
+        }
+        ProviderType::Jumpcloud => {
+            let mut provider = JumpcloudProvider::new(profile)?;
+            provider.new_session()?;
+            provider.fetch_saml()
+        }
+        ProviderType::Adfs => Err(anyhow!("{} isn't supported for ADFS profiles", purpose)),
+    }
+}
+
+fn add_profile(
+    crowbar_config: CrowbarConfig,
+    profile: &AppProfile,
+    region: Option<String>,
+) -> Result<()> {
+    crowbar_config.add_profile(profile)?.write()?;
+
+    let aws_config = AwsConfig::new()?.add_profile(profile)?;
+    match region {
+        Some(region) => aws_config.set_region(&profile.name, &region)?,
+        None => aws_config,
+    }
+    .write()?;
+    println!(
+        "{}",
+        ui::success(&format!("Profile {} added successfully!", profile.name))
+    );
+
+    Ok(())
+}
+
+fn print_config_paths(
+    crowbar_config: &CrowbarConfig,
+    source: ConfigSource,
+    aws_config: &AwsConfig,
+) -> Result<()> {
+    let existence = |path: &std::path::Path| match path.exists() {
+        true => "exists",
+        false => "doesn't exist",
+    };
+
+    if crowbar_config.is_ad_hoc() {
+        println!("crowbar configuration: none, the profile was given with --app-url");
+    } else {
+        let path = crowbar_config.path()?;
+        println!(
+            "crowbar configuration: {} (from {}, {})",
+            path.display(),
+            source,
+            existence(&path)
+        );
+    }
+    println!(
+        "AWS configuration: {} ({})",
+        aws_config.location.display(),
+        existence(&aws_config.location)
+    );
+    match keystore::is_available() {
+        true => println!("Cache: system keyring, services starting with crowbar::"),
+        false => println!("Cache: none, the keyring is disabled"),
+    }
+
+    Ok(())
+}
+
+fn check_config(crowbar_config: CrowbarConfig) -> Result<()> {
+    let checks = crowbar_config.check()?;
+    let invalid = checks.iter().filter(|c| !c.is_valid()).count();
+
+    for check in &checks {
+        if check.is_valid() {
+            println!("{} {}", ui::success("PASS"), check.name);
+        } else {
+            println!("{} {}", ui::error("FAIL"), check.name);
+            for problem in &check.problems {
+                println!("  - {}", problem);
+            }
+        }
+    }
+
+    match invalid {
+        0 => Ok(()),
+        _ => Err(error::error(
+            ErrorKind::Config,
+            format!("{} of {} profiles are invalid", invalid, checks.len()),
+        )),
+    }
+}
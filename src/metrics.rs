@@ -0,0 +1,372 @@
+//! Metrics about refreshing credentials in the textfile format of Prometheus,
+//! see `--metrics-file`. They are written after every run and every refresh
+//! of `watch` or `serve`, for node_exporter's textfile collector to pick up.
+//! Counters carry on from the file a previous run wrote and other profiles'
+//! metrics are kept, so scheduled runs of several profiles can share a file.
+
+use crate::clock;
+use crate::credentials::aws::RunResult;
+use crate::timings::{Phase, Timings};
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::cell::RefCell;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const LAST_REFRESH: &str = "crowbar_last_refresh_timestamp_seconds";
+const REFRESHES: &str = "crowbar_refreshes_total";
+const EXPIRY: &str = "crowbar_credentials_expiry_timestamp_seconds";
+const DURATION: &str = "crowbar_refresh_duration_seconds";
+
+// Name, type and help of every metric in the order they are written
+const METRICS: [(&str, &str, &str); 4] = [
+    (
+        LAST_REFRESH,
+        "gauge",
+        "When the credentials of the profile were last refreshed successfully",
+    ),
+    (
+        REFRESHES,
+        "counter",
+        "Runs fetching the credentials of the profile by their result",
+    ),
+    (
+        EXPIRY,
+        "gauge",
+        "When the current credentials of the profile expire",
+    ),
+    (
+        DURATION,
+        "gauge",
+        "How long each phase of the last successful refresh of the profile took",
+    ),
+];
+const PHASES: [Phase; 4] = [
+    Phase::Authentication,
+    Phase::MfaWait,
+    Phase::SamlFetch,
+    Phase::RoleAssumption,
+];
+
+thread_local! {
+    static FILE: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Sample {
+    name: String,
+    labels: Vec<(String, String)>,
+    value: f64,
+}
+
+impl Sample {
+    fn new(name: &str, labels: &[(&str, &str)], value: f64) -> Sample {
+        Sample {
+            name: name.to_string(),
+            labels: labels
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            value,
+        }
+    }
+
+    fn label(&self, name: &str) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Writes metrics to `path` after every run from now on
+pub fn set_file(path: Option<PathBuf>) {
+    FILE.with(|f| f.replace(path));
+}
+
+/// Updates the metrics of `profile` with the outcome of fetching its
+/// credentials. Failing to write them only warns, the credentials are
+/// still good
+pub fn record(profile: &str, result: &Result<RunResult>) {
+    if let Some(path) = FILE.with(|f| f.borrow().clone()) {
+        if let Err(e) = write(&path, profile, result) {
+            warn!("Unable to write metrics to {}: {:#}", path.display(), e);
+        }
+    }
+}
+
+fn write(path: &Path, profile: &str, result: &Result<RunResult>) -> Result<()> {
+    let previous = match fs::read_to_string(path) {
+        Ok(content) => parse(&content),
+        Err(_) => vec![],
+    };
+    let content = render(&update(previous, profile, result));
+
+    // The collector may read the file at any time, so it's replaced at once
+    let temporary = path.with_extension("prom.tmp");
+    fs::write(&temporary, content)
+        .with_context(|| format!("Unable to write {}", temporary.display()))?;
+    fs::rename(&temporary, path).with_context(|| format!("Unable to replace {}", path.display()))
+}
+
+// Failures count and keep the gauges of the last success, as the credentials
+// cached then are still the ones in use
+fn update(mut samples: Vec<Sample>, profile: &str, result: &Result<RunResult>) -> Vec<Sample> {
+    let outcome = match result {
+        Ok(_) => "success",
+        Err(_) => "failure",
+    };
+    let count = samples
+        .iter()
+        .find(|s| {
+            s.name == REFRESHES
+                && s.label("profile") == Some(profile)
+                && s.label("result") == Some(outcome)
+        })
+        .map_or(0.0, |s| s.value);
+
+    samples.retain(|s| {
+        !(s.label("profile") == Some(profile)
+            && (s.name == REFRESHES && s.label("result") == Some(outcome)
+                || result.is_ok() && s.name != REFRESHES))
+    });
+    samples.push(Sample::new(
+        REFRESHES,
+        &[("profile", profile), ("result", outcome)],
+        count + 1.0,
+    ));
+
+    if let Ok(result) = result {
+        samples.push(Sample::new(
+            LAST_REFRESH,
+            &[("profile", profile)],
+            clock::now().timestamp() as f64,
+        ));
+        if let Some(expires_at) = result.credentials.expires_at() {
+            samples.push(Sample::new(
+                EXPIRY,
+                &[("profile", profile)],
+                expires_at.timestamp() as f64,
+            ));
+        }
+        samples.extend(durations(profile, &result.timings));
+    }
+
+    samples
+}
+
+fn durations(profile: &str, timings: &Timings) -> Vec<Sample> {
+    PHASES
+        .iter()
+        .map(|phase| {
+            Sample::new(
+                DURATION,
+                &[("profile", profile), ("phase", phase_label(*phase))],
+                timings.get(*phase).as_secs_f64(),
+            )
+        })
+        .collect()
+}
+
+fn phase_label(phase: Phase) -> &'static str {
+    match phase {
+        Phase::Authentication => "authentication",
+        Phase::MfaWait => "mfa_wait",
+        Phase::SamlFetch => "saml_fetch",
+        Phase::RoleAssumption => "role_assumption",
+    }
+}
+
+fn render(samples: &[Sample]) -> String {
+    let mut content = String::new();
+
+    for (name, kind, help) in METRICS.iter() {
+        let mut samples = samples
+            .iter()
+            .filter(|s| s.name == *name)
+            .collect::<Vec<_>>();
+        if samples.is_empty() {
+            continue;
+        }
+        samples.sort_by(|a, b| a.labels.cmp(&b.labels));
+
+        let _ = writeln!(content, "# HELP {} {}", name, help);
+        let _ = writeln!(content, "# TYPE {} {}", name, kind);
+        for sample in samples {
+            let labels = sample
+                .labels
+                .iter()
+                .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+                .collect::<Vec<String>>()
+                .join(",");
+            let _ = writeln!(content, "{}{{{}}} {}", sample.name, labels, sample.value);
+        }
+    }
+
+    content
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// Reads back the samples crowbar wrote, skipping anything else
+fn parse(content: &str) -> Vec<Sample> {
+    let sample = Regex::new(r"^([a-zA-Z_:][a-zA-Z0-9_:]*)\{(.*)\} (\S+)$").unwrap();
+    let label = Regex::new(r#"([a-zA-Z_][a-zA-Z0-9_]*)="((?:[^"\\]|\\.)*)""#).unwrap();
+
+    content
+        .lines()
+        .filter_map(|line| sample.captures(line))
+        .filter_map(|c| {
+            Some(Sample {
+                name: c[1].to_string(),
+                labels: label
+                    .captures_iter(&c[2])
+                    .map(|l| (l[1].to_string(), unescape(&l[2])))
+                    .collect(),
+                value: c[3].parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        unescaped.push(match c {
+            '\\' => match chars.next() {
+                Some('n') => '\n',
+                Some(c) => c,
+                None => '\\',
+            },
+            c => c,
+        });
+    }
+
+    unescaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::test::FixedClock;
+    use crate::credentials::aws::AwsCredentials;
+    use anyhow::anyhow;
+    use std::collections::BTreeSet;
+    use std::time::Duration;
+
+    #[test]
+    fn writes_prometheus_text_format() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("crowbar.prom");
+
+        clock::with_clock(FixedClock::at(2_145_866_400), || -> Result<()> {
+            write(&path, "prod \"admin\"", &Ok(run_result()))?;
+            write(&path, "staging", &Err(anyhow!("Okta responded with 401")))?;
+            write(&path, "staging", &Ok(run_result()))
+        })?;
+
+        let content = fs::read_to_string(&path)?;
+        let sample = Regex::new(
+            r#"^([a-zA-Z_:][a-zA-Z0-9_:]*)\{[a-zA-Z_][a-zA-Z0-9_]*="(?:[^"\\]|\\.)*"(?:,[a-zA-Z_][a-zA-Z0-9_]*="(?:[^"\\]|\\.)*")*\} -?[0-9.e+-]+$"#,
+        )?;
+        let mut typed = BTreeSet::new();
+        let mut names = BTreeSet::new();
+
+        for line in content.lines() {
+            let words = line.splitn(4, ' ').collect::<Vec<&str>>();
+            match words[..] {
+                ["#", "HELP", _, _] => (),
+                ["#", "TYPE", name, kind] => {
+                    assert!(["counter", "gauge"].contains(&kind), "{}", line);
+                    assert!(typed.insert(name.to_string()), "{} typed twice", name);
+                }
+                _ => {
+                    let name = &sample
+                        .captures(line)
+                        .unwrap_or_else(|| panic!("Invalid sample {}", line))[1];
+                    assert!(typed.contains(name), "{} has no type", name);
+                    names.insert(name.to_string());
+                }
+            }
+        }
+
+        let expected = [LAST_REFRESH, REFRESHES, EXPIRY, DURATION]
+            .iter()
+            .map(|n| n.to_string())
+            .collect::<BTreeSet<String>>();
+        assert_eq!(expected, names);
+        assert!(content.contains(
+            "crowbar_credentials_expiry_timestamp_seconds{profile=\"prod \\\"admin\\\"\"} 2145916800"
+        ));
+        assert!(content
+            .contains("crowbar_last_refresh_timestamp_seconds{profile=\"staging\"} 2145866400"));
+        assert!(content.contains(
+            "crowbar_refresh_duration_seconds{profile=\"staging\",phase=\"mfa_wait\"} 1.5"
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn counts_on_from_previous_runs() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("crowbar.prom");
+
+        write(&path, "prod", &Ok(run_result()))?;
+        write(&path, "prod", &Ok(run_result()))?;
+        write(&path, "prod", &Err(anyhow!("Okta responded with 401")))?;
+        write(&path, "staging", &Err(anyhow!("Okta responded with 401")))?;
+
+        let samples = parse(&fs::read_to_string(&path)?);
+        let count = |profile: &str, result: &str| {
+            samples
+                .iter()
+                .find(|s| {
+                    s.name == REFRESHES
+                        && s.label("profile") == Some(profile)
+                        && s.label("result") == Some(result)
+                })
+                .map(|s| s.value)
+        };
+
+        assert_eq!(Some(2.0), count("prod", "success"));
+        assert_eq!(Some(1.0), count("prod", "failure"));
+        assert_eq!(Some(1.0), count("staging", "failure"));
+        assert_eq!(None, count("staging", "success"));
+
+        // The failure kept the expiry of the credentials still in use
+        assert!(samples
+            .iter()
+            .any(|s| s.name == EXPIRY && s.label("profile") == Some("prod")));
+
+        Ok(())
+    }
+
+    fn run_result() -> RunResult {
+        let mut timings = Timings::default();
+        timings.record(Phase::Authentication, Duration::from_secs(3));
+        timings.record(Phase::MfaWait, Duration::from_millis(1500));
+
+        RunResult {
+            credentials: AwsCredentials {
+                version: 1,
+                access_key_id: Some("ASIA".to_string()),
+                secret_access_key: Some("secret".to_string()),
+                session_token: Some("token".to_string()),
+                expiration: Some("2038-01-01T00:00:00Z".to_string()),
+            },
+            timings,
+            session_expires_at: None,
+            role: None,
+        }
+    }
+}
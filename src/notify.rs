@@ -0,0 +1,170 @@
+//! Notifications about MFA waiting on the user, see `--notify`, for terminals
+//! that are hidden while a Push has to be approved on the phone. They go to
+//! the desktop or, for `--notify=URL`, to a webhook like one of Slack.
+
+use crate::config::app::AppProfile;
+use crate::http;
+
+use anyhow::{anyhow, Context, Result};
+use reqwest::blocking::Client as HttpClient;
+use reqwest::Url;
+use std::cell::RefCell;
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+use std::time::Duration;
+
+const TITLE: &str = "crowbar";
+// Logging in would rather go on without a notification than wait for it
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Sends notifications about the login of a profile
+pub trait Notifier {
+    fn notify(&self, profile: &AppProfile, message: &str) -> Result<()>;
+}
+
+/// Where `--notify` sends notifications
+#[derive(Debug, Clone, PartialEq)]
+pub enum Channel {
+    Desktop,
+    Webhook(Url),
+}
+
+impl Channel {
+    fn notifier(self) -> Rc<dyn Notifier> {
+        match self {
+            Channel::Desktop => Rc::new(DesktopNotifier),
+            Channel::Webhook(url) => Rc::new(WebhookNotifier { url }),
+        }
+    }
+}
+
+/// Shows notifications with the tools of the platform, `notify-send` or
+/// `osascript`
+#[derive(Debug, Default)]
+pub struct DesktopNotifier;
+
+impl Notifier for DesktopNotifier {
+    fn notify(&self, _profile: &AppProfile, message: &str) -> Result<()> {
+        let mut command = desktop_command(message)?;
+        let status = command
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .status()
+            .with_context(|| format!("Unable to run {:?}", command))?;
+
+        if !status.success() {
+            return Err(anyhow!("{:?} failed with {}", command, status));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn desktop_command(message: &str) -> Result<Command> {
+    let mut command = Command::new("osascript");
+    command.arg("-e").arg(format!(
+        "display notification {:?} with title {:?}",
+        message, TITLE
+    ));
+
+    Ok(command)
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn desktop_command(message: &str) -> Result<Command> {
+    let mut command = Command::new("notify-send");
+    command.arg(TITLE).arg(message);
+
+    Ok(command)
+}
+
+#[cfg(not(unix))]
+fn desktop_command(_message: &str) -> Result<Command> {
+    Err(anyhow!(
+        "Desktop notifications aren't supported on this platform, use --notify=URL"
+    ))
+}
+
+/// Posts notifications as `{"text": "…"}`, which Slack, Mattermost and most
+/// chat webhooks take, through the proxy and TLS backend of the profile
+#[derive(Debug)]
+pub struct WebhookNotifier {
+    url: Url,
+}
+
+impl Notifier for WebhookNotifier {
+    fn notify(&self, profile: &AppProfile, message: &str) -> Result<()> {
+        http::configure(HttpClient::builder().timeout(WEBHOOK_TIMEOUT), profile)?
+            .build()?
+            .post(self.url.clone())
+            .json(&serde_json::json!({ "text": message }))
+            .send()?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Rc<dyn Notifier>>> = const { RefCell::new(None) };
+}
+
+/// Sends notifications to `channel` from now on, or none at all
+pub fn set_channel(channel: Option<Channel>) {
+    CURRENT.with(|n| n.replace(channel.map(Channel::notifier)));
+}
+
+/// Tells the user about `message` where `--notify` asks for it. A notification
+/// that doesn't make it only warns, the terminal still shows the same
+pub fn notify(profile: &AppProfile, message: &str) {
+    if let Some(notifier) = CURRENT.with(|n| n.borrow().clone()) {
+        if let Err(e) = notifier.notify(profile, message) {
+            warn!("Unable to send the notification: {:#}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::config::app::test::{okta_profile, OKTA_URL};
+
+    /// Runs `f` with all notifications going to `notifier`, restoring the
+    /// previous notifier afterwards
+    pub fn with_notifier<T, F>(notifier: Rc<dyn Notifier>, f: F) -> T
+    where
+        F: FnOnce() -> T,
+    {
+        let previous = CURRENT.with(|n| n.replace(Some(notifier)));
+        let result = f();
+        CURRENT.with(|n| n.replace(previous));
+
+        result
+    }
+
+    /// Keeps the messages instead of showing them
+    #[derive(Debug, Default)]
+    pub struct RecordingNotifier {
+        pub messages: RefCell<Vec<String>>,
+    }
+
+    impl Notifier for RecordingNotifier {
+        fn notify(&self, _profile: &AppProfile, message: &str) -> Result<()> {
+            self.messages.borrow_mut().push(message.to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn sends_notifications_only_when_asked_to() {
+        let notifier = Rc::new(RecordingNotifier::default());
+        let profile = okta_profile("profile", OKTA_URL);
+
+        notify(&profile, "Nobody listens");
+        with_notifier(notifier.clone(), || notify(&profile, "Approve the push"));
+        notify(&profile, "Nobody listens again");
+
+        assert_eq!(vec!["Approve the push"], *notifier.messages.borrow());
+    }
+}
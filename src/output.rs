@@ -0,0 +1,299 @@
+use crate::config::app::AppProfile;
+use crate::credentials::aws::AwsCredentials;
+use crate::error::{self, ErrorKind};
+
+use anyhow::{anyhow, Result};
+use ini::Ini;
+use std::fmt;
+use std::str::FromStr;
+
+const FIELD_PREFIX: &str = "field:";
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum OutputMode {
+    #[default]
+    Json,
+    Field(CredentialField),
+    Ini,
+    Keyring,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CredentialField {
+    Version,
+    AccessKeyId,
+    SecretAccessKey,
+    SessionToken,
+    Expiration,
+}
+
+const FIELDS: [CredentialField; 5] = [
+    CredentialField::Version,
+    CredentialField::AccessKeyId,
+    CredentialField::SecretAccessKey,
+    CredentialField::SessionToken,
+    CredentialField::Expiration,
+];
+
+/// How batch commands like `assume-all` and `status` print their summary
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SummaryFormat {
+    Table,
+    Json,
+}
+
+impl FromStr for SummaryFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<SummaryFormat> {
+        match s {
+            "table" => Ok(SummaryFormat::Table),
+            "json" => Ok(SummaryFormat::Json),
+            _ => Err(error::error(
+                ErrorKind::Config,
+                format!("Unknown output {}, use table or json", s),
+            )),
+        }
+    }
+}
+
+impl FromStr for OutputMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(OutputMode::Json),
+            "ini" => Ok(OutputMode::Ini),
+            "keyring" => Ok(OutputMode::Keyring),
+            s if s.starts_with(FIELD_PREFIX) => {
+                Ok(OutputMode::Field(s[FIELD_PREFIX.len()..].parse()?))
+            }
+            _ => Err(anyhow!(
+                "Unknown output mode {}, use either json, ini, keyring or field:<name>",
+                s
+            )),
+        }
+    }
+}
+
+impl FromStr for CredentialField {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match FIELDS.iter().find(|f| f.to_string() == s) {
+            Some(field) => Ok(*field),
+            None => Err(anyhow!(
+                "Unknown credentials field {}, valid fields are: {}",
+                s,
+                FIELDS
+                    .iter()
+                    .map(|f| f.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for CredentialField {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CredentialField::Version => write!(f, "Version"),
+            CredentialField::AccessKeyId => write!(f, "AccessKeyId"),
+            CredentialField::SecretAccessKey => write!(f, "SecretAccessKey"),
+            CredentialField::SessionToken => write!(f, "SessionToken"),
+            CredentialField::Expiration => write!(f, "Expiration"),
+        }
+    }
+}
+
+/// The mode given with `--output`, otherwise the profile's `output` and
+/// JSON for profiles without one
+pub fn resolve(flag: Option<OutputMode>, profile: Option<&AppProfile>) -> Result<OutputMode> {
+    match (flag, profile.and_then(|p| p.output.as_ref())) {
+        (Some(mode), _) => Ok(mode),
+        (None, Some(output)) => output.parse(),
+        (None, None) => Ok(OutputMode::default()),
+    }
+}
+
+/// Renders the credentials of `profile`, which names the section of the INI
+/// fragment
+pub fn render(credentials: &AwsCredentials, mode: &OutputMode, profile: &str) -> Result<String> {
+    match mode {
+        OutputMode::Json => Ok(credentials.to_string()),
+        OutputMode::Field(field) => required_field(credentials, *field),
+        OutputMode::Ini => ini_fragment(credentials, profile),
+        OutputMode::Keyring => Err(anyhow!(
+            "Credentials are stored in the keyring instead of printed with --output keyring"
+        )),
+    }
+}
+
+fn field(credentials: &AwsCredentials, field: CredentialField) -> Option<String> {
+    match field {
+        CredentialField::Version => Some(credentials.version.to_string()),
+        CredentialField::AccessKeyId => credentials.access_key_id.clone(),
+        CredentialField::SecretAccessKey => credentials.secret_access_key.clone(),
+        CredentialField::SessionToken => credentials.session_token.clone(),
+        CredentialField::Expiration => credentials.expiration.clone(),
+    }
+}
+
+fn required_field(credentials: &AwsCredentials, field: CredentialField) -> Result<String> {
+    self::field(credentials, field)
+        .ok_or_else(|| anyhow!("The credentials are missing the field {}", field))
+}
+
+// A section as in the shared credentials file. The SDKs ignore
+// x_security_token_expires, but tools managing the file refresh by it
+fn ini_fragment(credentials: &AwsCredentials, profile: &str) -> Result<String> {
+    let mut fragment = Ini::new();
+    fragment
+        .with_section(Some(profile))
+        .set(
+            "aws_access_key_id",
+            required_field(credentials, CredentialField::AccessKeyId)?,
+        )
+        .set(
+            "aws_secret_access_key",
+            required_field(credentials, CredentialField::SecretAccessKey)?,
+        );
+
+    let optional = [
+        ("aws_session_token", CredentialField::SessionToken),
+        ("x_security_token_expires", CredentialField::Expiration),
+    ];
+    for (key, credential_field) in optional.iter() {
+        if let Some(value) = field(credentials, *credential_field) {
+            fragment.with_section(Some(profile)).set(*key, value);
+        }
+    }
+
+    let mut rendered = Vec::new();
+    fragment.write_to(&mut rendered)?;
+
+    Ok(String::from_utf8(rendered)?.trim_end().to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::providers::ProviderType;
+    use claim::assert_err;
+
+    #[test]
+    fn renders_each_field() -> Result<()> {
+        let credentials = create_credentials();
+        let expected = [
+            ("Version", "1"),
+            ("AccessKeyId", "some_key"),
+            ("SecretAccessKey", "some_secret"),
+            ("SessionToken", "some_token"),
+            ("Expiration", "2038-01-01T10:10:10Z"),
+        ];
+
+        for (name, value) in expected.iter() {
+            let mode: OutputMode = format!("field:{}", name).parse()?;
+            assert_eq!(*value, render(&credentials, &mode, "profile")?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn renders_json_by_default() -> Result<()> {
+        let credentials = create_credentials();
+        assert_eq!(
+            credentials.to_string(),
+            render(&credentials, &OutputMode::default(), "profile")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn prefers_flag_over_output_of_profile() -> Result<()> {
+        let mut profile = AppProfile::new(
+            "serverless-app",
+            ProviderType::Okta,
+            "https://example.okta.com/home/amazon_aws/0oa1b2c3prod/272",
+            "jdoe",
+            None,
+        );
+
+        assert_eq!(OutputMode::Json, resolve(None, Some(&profile))?);
+        assert_eq!(OutputMode::Json, resolve(None, None)?);
+
+        profile.output = Some("ini".to_string());
+        assert_eq!(OutputMode::Ini, resolve(None, Some(&profile))?);
+        assert_eq!(
+            OutputMode::Keyring,
+            resolve(Some(OutputMode::Keyring), Some(&profile))?
+        );
+
+        profile.output = Some("yaml".to_string());
+        assert_err!(resolve(None, Some(&profile)));
+        assert_eq!(
+            OutputMode::Json,
+            resolve(Some(OutputMode::Json), Some(&profile))?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_unknown_fields_and_modes() {
+        assert_err!("field:AccessKey".parse::<OutputMode>());
+        assert_err!("field:".parse::<OutputMode>());
+        assert_err!("yaml".parse::<OutputMode>());
+    }
+
+    #[test]
+    fn renders_ini_fragment_of_profile() -> Result<()> {
+        let mode: OutputMode = "ini".parse()?;
+        let fragment = render(&create_credentials(), &mode, "my-profile")?;
+
+        assert!(fragment.starts_with("[my-profile]\n"), "{}", fragment);
+
+        let parsed = Ini::load_from_str(&fragment)?;
+        let section = parsed
+            .section(Some("my-profile"))
+            .expect("The fragment has no section of the profile");
+        assert_eq!(Some("some_key"), section.get("aws_access_key_id"));
+        assert_eq!(Some("some_secret"), section.get("aws_secret_access_key"));
+        assert_eq!(Some("some_token"), section.get("aws_session_token"));
+        assert_eq!(
+            Some("2038-01-01T10:10:10Z"),
+            section.get("x_security_token_expires")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn errors_on_missing_field_value() {
+        let credentials = AwsCredentials::default();
+        let mode = OutputMode::Field(CredentialField::SessionToken);
+        assert_err!(render(&credentials, &mode, "profile"));
+    }
+
+    #[test]
+    fn parses_keyring_mode_without_rendering_it() -> Result<()> {
+        let mode: OutputMode = "keyring".parse()?;
+
+        assert_eq!(OutputMode::Keyring, mode);
+        assert_err!(render(&create_credentials(), &mode, "profile"));
+
+        Ok(())
+    }
+
+    fn create_credentials() -> AwsCredentials {
+        AwsCredentials {
+            version: 1,
+            access_key_id: Some("some_key".to_string()),
+            secret_access_key: Some("some_secret".to_string()),
+            session_token: Some("some_token".to_string()),
+            expiration: Some("2038-01-01T10:10:10Z".to_string()),
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use anyhow::{Context, Result};
+use console::Term;
+use dialoguer::{theme::SimpleTheme, Input, PasswordInput, Select};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Everything crowbar needs to ask the user, or show them while waiting on
+/// them, goes through a prompter, so embedding applications can answer
+/// without a terminal
+pub trait Prompter {
+    fn input(&self, prompt: &str) -> Result<String>;
+    fn password(&self, prompt: &str) -> Result<String>;
+    fn select(&self, prompt: &str, items: &[String]) -> Result<usize>;
+
+    /// Like `select`, preselecting the item at `default` where possible
+    fn select_with_default(
+        &self,
+        prompt: &str,
+        items: &[String],
+        _default: usize,
+    ) -> Result<usize> {
+        self.select(prompt, items)
+    }
+
+    /// Whether someone answers the prompts, e.g. to type a rejected password again
+    fn is_interactive(&self) -> bool {
+        true
+    }
+
+    /// Shows how a wait, e.g. for a push notification, goes, replacing the
+    /// previous progress message
+    fn progress(&self, _message: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Tells the user something they need to go on, e.g. a warning or the
+    /// secret of a newly enrolled TOTP factor
+    fn message(&self, _message: &str) {}
+}
+
+#[derive(Debug, Default)]
+pub struct TerminalPrompter;
+
+impl Prompter for TerminalPrompter {
+    fn input(&self, prompt: &str) -> Result<String> {
+        Input::new()
+            .with_prompt(prompt)
+            .interact()
+            .with_context(|| format!("Failed to get input for: {}", prompt))
+    }
+
+    fn password(&self, prompt: &str) -> Result<String> {
+        PasswordInput::new()
+            .with_prompt(prompt)
+            .interact()
+            .map_err(|e| e.into())
+    }
+
+    fn select(&self, prompt: &str, items: &[String]) -> Result<usize> {
+        self.select_with_default(prompt, items, 0)
+    }
+
+    fn select_with_default(&self, prompt: &str, items: &[String], default: usize) -> Result<usize> {
+        Select::with_theme(&SimpleTheme)
+            .with_prompt(prompt)
+            .default(default)
+            .items(items)
+            .interact()
+            .map_err(|e| e.into())
+    }
+
+    fn is_interactive(&self) -> bool {
+        Term::stderr().is_term()
+    }
+
+    fn progress(&self, message: &str) -> Result<()> {
+        let term = Term::stderr();
+        term.clear_last_lines(1)?;
+        term.write_line(message)?;
+
+        Ok(())
+    }
+
+    fn message(&self, message: &str) {
+        eprintln!("{}", message);
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<Rc<dyn Prompter>> = RefCell::new(Rc::new(TerminalPrompter));
+}
+
+/// Runs `f` with all prompts answered by `prompter`, restoring the
+/// previous prompter afterwards
+pub fn with_prompter<T, F>(prompter: Rc<dyn Prompter>, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    let previous = CURRENT.with(|p| p.replace(prompter));
+    let result = f();
+    CURRENT.with(|p| p.replace(previous));
+
+    result
+}
+
+pub fn current() -> Rc<dyn Prompter> {
+    CURRENT.with(|p| p.borrow().clone())
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// Answers prompts from a script, failing on anything unexpected
+    pub struct ScriptedPrompter {
+        answers: RefCell<VecDeque<String>>,
+        messages: RefCell<Vec<String>>,
+    }
+
+    impl ScriptedPrompter {
+        pub fn new(answers: &[&str]) -> ScriptedPrompter {
+            ScriptedPrompter {
+                answers: RefCell::new(answers.iter().map(|a| a.to_string()).collect()),
+                messages: RefCell::new(vec![]),
+            }
+        }
+
+        /// The messages shown so far
+        pub fn messages(&self) -> Vec<String> {
+            self.messages.borrow().clone()
+        }
+
+        /// Whether every answer was asked for
+        pub fn is_done(&self) -> bool {
+            self.answers.borrow().is_empty()
+        }
+
+        fn next(&self, prompt: &str) -> Result<String> {
+            self.answers
+                .borrow_mut()
+                .pop_front()
+                .with_context(|| format!("Unexpected prompt: {}", prompt))
+        }
+    }
+
+    impl Prompter for ScriptedPrompter {
+        fn input(&self, prompt: &str) -> Result<String> {
+            self.next(prompt)
+        }
+
+        fn password(&self, prompt: &str) -> Result<String> {
+            self.next(prompt)
+        }
+
+        fn select(&self, prompt: &str, _items: &[String]) -> Result<usize> {
+            Ok(self.next(prompt)?.parse()?)
+        }
+
+        fn message(&self, message: &str) {
+            self.messages.borrow_mut().push(message.to_string());
+        }
+    }
+
+    #[test]
+    fn restores_previous_prompter() -> Result<()> {
+        let answer = with_prompter(Rc::new(ScriptedPrompter::new(&["outer"])), || {
+            let inner = with_prompter(Rc::new(ScriptedPrompter::new(&["inner"])), || {
+                current().input("inner")
+            });
+            assert_eq!("inner", inner?);
+            current().input("outer")
+        })?;
+
+        assert_eq!("outer", answer);
+
+        Ok(())
+    }
+}
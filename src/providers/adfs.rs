@@ -1,9 +1,11 @@
 use crate::config::app::AppProfile;
 use crate::credentials::aws::AwsCredentials;
 use crate::credentials::config::ConfigCredentials;
-use crate::credentials::Credential;
 use crate::providers::adfs::client::Client;
 use crate::saml;
+use crate::timings::{self, Phase};
+use crate::ui;
+use crate::utils::RoleOptions;
 
 use anyhow::{anyhow, Context, Result};
 use regex::Regex;
@@ -46,7 +48,7 @@ impl Default for AdfsResponse {
 impl AdfsProvider {
     pub fn new(profile: &AppProfile) -> Result<Self> {
         Ok(AdfsProvider {
-            client: Client::new()?,
+            client: Client::new(profile)?,
             profile: profile.clone(),
         })
     }
@@ -54,25 +56,28 @@ impl AdfsProvider {
     pub fn fetch_aws_credentials(&mut self) -> Result<AwsCredentials> {
         let profile = &self.profile;
 
-        let config_credentials =
-            ConfigCredentials::load(profile).or_else(|_| ConfigCredentials::create(profile))?;
+        let config_credentials = ConfigCredentials::obtain(profile)?;
 
         let username = self.profile.username.clone();
         let password = config_credentials.password;
         let mut url = self.profile.url.clone();
         url.push_str(ADFS_URL_SUFFIX);
 
-        let response = self
-            .client
-            .get(&url)
-            .with_context(|| "Unable to reach login form")?;
+        ui::explain(&format!("Authenticating to {} as {}…", url, username));
+        let client = &self.client;
+        // ADFS hands out the SAML assertion in response to the login form
+        let response = timings::measure_bounded(Phase::Authentication, || -> Result<String> {
+            let response = client
+                .get(&url)
+                .with_context(|| "Unable to reach login form")?;
 
-        let document = Document::from(response.text()?.as_str());
-        let form_content = build_login_form_elements(&username, &password, &document);
-        let submit_url = fetch_submit_url(&document);
+            let document = Document::from(response.text()?.as_str());
+            let form_content = build_login_form_elements(&username, &password, &document);
+            let submit_url = fetch_submit_url(&document);
 
-        let response = self.client.post(submit_url, &form_content)?;
-        let adfs_response = evaluate_response_state(response.text()?, profile.role.clone())?;
+            Ok(client.post(submit_url, &form_content)?.text()?)
+        })?;
+        let adfs_response = evaluate_response_state(response, &profile.into())?;
 
         let credentials = match adfs_response.state {
             ResponseState::Success => adfs_response.credentials.unwrap(),
@@ -129,10 +134,10 @@ fn fetch_submit_url(document: &Document) -> &str {
     url.expect("Missing submission URL for authentication form")
 }
 
-fn evaluate_response_state(response: String, role: Option<String>) -> Result<AdfsResponse> {
+fn evaluate_response_state(response: String, options: &RoleOptions) -> Result<AdfsResponse> {
     let mut adfs_response = AdfsResponse::default();
 
-    match saml::get_credentials_from_saml(response.clone(), role) {
+    match saml::get_credentials_from_saml(response.clone(), options) {
         Ok(credentials) => {
             adfs_response.credentials = Some(credentials);
             adfs_response.state = ResponseState::Success;
@@ -224,7 +229,7 @@ mod test {
         "#
         .to_string();
 
-        let adfs_response = evaluate_response_state(response, None)?;
+        let adfs_response = evaluate_response_state(response, &RoleOptions::default())?;
         assert_eq!(adfs_response.state, ResponseState::MfaPrompt);
 
         let response = r#"
@@ -232,7 +237,7 @@ mod test {
         "#
         .to_string();
 
-        let adfs_response = evaluate_response_state(response, None)?;
+        let adfs_response = evaluate_response_state(response, &RoleOptions::default())?;
         assert_eq!(adfs_response.state, ResponseState::MfaWait);
 
         let response = r#"
@@ -240,7 +245,7 @@ mod test {
         "#
         .to_string();
 
-        let adfs_response = evaluate_response_state(response, None)?;
+        let adfs_response = evaluate_response_state(response, &RoleOptions::default())?;
         assert_eq!(adfs_response.state, ResponseState::MfaWait);
 
         let response = r#"
@@ -248,7 +253,7 @@ mod test {
         "#
         .to_string();
 
-        let adfs_response = evaluate_response_state(response, None)?;
+        let adfs_response = evaluate_response_state(response, &RoleOptions::default())?;
         assert_eq!(adfs_response.state, ResponseState::MfaPrompt);
 
         let response = r#"
@@ -256,7 +261,7 @@ mod test {
         "#
         .to_string();
 
-        let adfs_response = evaluate_response_state(response, None)?;
+        let adfs_response = evaluate_response_state(response, &RoleOptions::default())?;
         assert_eq!(adfs_response.state, ResponseState::Error);
 
         Ok(())
@@ -1,3 +1,7 @@
+use crate::config::app::AppProfile;
+use crate::har;
+use crate::http;
+
 use anyhow::Result;
 use reqwest::blocking::Client as HttpClient;
 use reqwest::blocking::Response;
@@ -9,16 +13,14 @@ pub struct Client {
 }
 
 impl Client {
-    pub fn new() -> Result<Self> {
+    pub fn new(profile: &AppProfile) -> Result<Self> {
         Ok(Client {
-            client: HttpClient::builder().cookie_store(true).build()?,
+            client: http::configure(HttpClient::builder().cookie_store(true), profile)?.build()?,
         })
     }
 
     pub fn get<U: IntoUrl>(&self, url: U) -> Result<Response> {
-        self.client
-            .get(url)
-            .send()?
+        har::send(&self.client, self.client.get(url))?
             .error_for_status()
             .map_err(|e| e.into())
     }
@@ -27,10 +29,6 @@ impl Client {
     where
         I: Serialize,
     {
-        self.client
-            .post(url)
-            .form(form_content)
-            .send()?
-            .error_for_status()
+        har::send(&self.client, self.client.post(url).form(form_content))?.error_for_status()
     }
 }
@@ -6,6 +6,8 @@ use crate::credentials::config::ConfigCredentials;
 use crate::credentials::Credential;
 use crate::providers::jumpcloud::client::Client;
 use crate::saml;
+use crate::timings::{self, Phase};
+use crate::ui;
 use crate::utils;
 
 use anyhow::{anyhow, Context, Result};
@@ -57,7 +59,7 @@ pub struct JumpcloudProvider {
 impl JumpcloudProvider {
     pub fn new(profile: &AppProfile) -> Result<Self> {
         Ok(JumpcloudProvider {
-            client: Client::new()?,
+            client: Client::new(profile)?,
             profile: profile.clone(),
             redirect_to: None,
         })
@@ -68,39 +70,45 @@ impl JumpcloudProvider {
     pub fn new_session(&mut self) -> Result<&Self> {
         let profile = &self.profile;
 
-        let config_credentials =
-            ConfigCredentials::load(profile).or_else(|_| ConfigCredentials::create(profile))?;
-
-        let response: XsrfResponse = self
-            .client
-            .get(Url::parse(XSRF_URL)?)
-            .with_context(|| "Unable to obtain XSRF token")?
-            .json()?;
-
-        let token = response.xsrf;
-        let username = &profile.username;
-        let password = &config_credentials.password;
-        let redirect_to = create_redirect_to(&profile.url)?;
-        let mut login_request =
-            LoginRequest::from_credentials(username.clone(), password.clone(), redirect_to);
-
-        debug!("Login request: {:?}", login_request);
-
-        let login_response: Result<LoginResponse, _> =
-            self.client
-                .post(Url::parse(AUTH_SUBMIT_URL)?, &login_request, &token);
-
-        let content: LoginResponse = match login_response {
-            Ok(r) => r,
-            Err(e) => match e.status() {
-                Some(StatusCode::UNAUTHORIZED) if login_request.otp.is_empty() => {
-                    login_request.otp = utils::prompt_mfa()?;
-                    self.client
-                        .post(Url::parse(AUTH_SUBMIT_URL)?, &login_request, &token)?
+        let config_credentials = ConfigCredentials::obtain(profile)?;
+
+        ui::explain(&format!(
+            "Authenticating to JumpCloud as {}…",
+            profile.username
+        ));
+        let client = &self.client;
+        let content =
+            timings::measure_bounded(Phase::Authentication, || -> Result<LoginResponse> {
+                let response: XsrfResponse = client
+                    .get(Url::parse(XSRF_URL)?)
+                    .with_context(|| "Unable to obtain XSRF token")?
+                    .json()?;
+
+                let token = response.xsrf;
+                let username = &profile.username;
+                let password = &config_credentials.password;
+                let redirect_to = create_redirect_to(&profile.url)?;
+                let mut login_request =
+                    LoginRequest::from_credentials(username.clone(), password.clone(), redirect_to);
+
+                debug!("Login request: {:?}", login_request);
+
+                let login_response: Result<LoginResponse, _> =
+                    client.post(Url::parse(AUTH_SUBMIT_URL)?, &login_request, &token);
+
+                match login_response {
+                    Ok(r) => Ok(r),
+                    Err(e) => match e.status() {
+                        Some(StatusCode::UNAUTHORIZED) if login_request.otp.is_empty() => {
+                            login_request.otp = utils::prompt_mfa()?;
+                            client
+                                .post(Url::parse(AUTH_SUBMIT_URL)?, &login_request, &token)
+                                .map_err(|e| e.into())
+                        }
+                        _ => Err(anyhow!("Unable to login: {}", e)),
+                    },
                 }
-                _ => return Err(anyhow!("Unable to login: {}", e)),
-            },
-        };
+            })?;
 
         config_credentials.write(profile)?;
 
@@ -109,21 +117,33 @@ impl JumpcloudProvider {
     }
 
     pub fn fetch_aws_credentials(&self) -> Result<AwsCredentials> {
+        let profile = &self.profile;
+        let input = self.fetch_saml()?;
+        let credentials = saml::get_credentials_from_saml(input, &profile.into())?;
+
+        trace!("Credentials: {:#?}", credentials);
+        Ok(credentials)
+    }
+
+    /// Fetches the HTML form carrying the SAML assertion of the profile's app
+    pub fn fetch_saml(&self) -> Result<String> {
         let profile = &self.profile;
         let url = self.redirect_to.clone().expect("Missing SAML redirect URL");
+        ui::explain(&format!("Fetching the SAML assertion from app {}…", url));
 
-        let input = self
-            .client
-            .get(Url::parse(&url)?)
-            .with_context(|| format!("Error getting SAML response for profile {}", profile.name))?
-            .text()?;
+        let input = timings::measure_bounded(Phase::SamlFetch, || -> Result<String> {
+            self.client
+                .get(Url::parse(&url)?)
+                .with_context(|| {
+                    format!("Error getting SAML response for profile {}", profile.name)
+                })?
+                .text()
+                .map_err(|e| e.into())
+        })?;
 
         debug!("Text for SAML response: {:#?}", input);
 
-        let credentials = saml::get_credentials_from_saml(input, profile.role.clone())?;
-
-        trace!("Credentials: {:#?}", credentials);
-        Ok(credentials)
+        Ok(input)
     }
 }
 
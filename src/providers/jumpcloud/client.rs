@@ -1,3 +1,7 @@
+use crate::config::app::AppProfile;
+use crate::har;
+use crate::http;
+
 use anyhow::Result;
 use reqwest::blocking::Client as HttpClient;
 use reqwest::blocking::Response;
@@ -13,16 +17,14 @@ pub struct Client {
 }
 
 impl Client {
-    pub fn new() -> Result<Self> {
+    pub fn new(profile: &AppProfile) -> Result<Self> {
         Ok(Client {
-            client: HttpClient::builder().cookie_store(true).build()?,
+            client: http::configure(HttpClient::builder().cookie_store(true), profile)?.build()?,
         })
     }
 
     pub fn get(&self, url: Url) -> Result<Response> {
-        self.client
-            .get(url)
-            .send()?
+        har::send(&self.client, self.client.get(url))?
             .error_for_status()
             .map_err(|e| e.into())
     }
@@ -33,13 +35,15 @@ impl Client {
         O: DeserializeOwned,
     {
         let json = HeaderValue::from_static("application/json");
-        self.client
-            .post(url)
-            .json(body)
-            .header(ACCEPT, &json)
-            .header(TOKEN, token)
-            .send()?
-            .error_for_status()?
-            .json()
+        har::send(
+            &self.client,
+            self.client
+                .post(url)
+                .json(body)
+                .header(ACCEPT, &json)
+                .header(TOKEN, token),
+        )?
+        .error_for_status()?
+        .json()
     }
 }
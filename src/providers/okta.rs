@@ -1,25 +1,120 @@
+pub mod apps;
 pub mod auth;
 pub mod client;
+pub mod devices;
+pub mod enroll;
+pub mod error;
 pub mod factors;
+pub mod health;
 pub mod login;
 pub mod response;
+pub mod security_key;
+pub mod sessions;
+pub mod step_up;
 pub mod verification;
 
 use crate::config::app::AppProfile;
 use crate::credentials::aws::AwsCredentials;
 use crate::credentials::config::ConfigCredentials;
+use crate::credentials::totp::{self, TotpCredentials};
 use crate::credentials::Credential;
-use crate::providers::okta::client::Client;
+use crate::error::ErrorKind;
+use crate::prompt;
+use crate::providers::okta::client::{Client, SessionKind};
+use crate::providers::okta::enroll::EnrollAction;
+use crate::providers::okta::error::StatusError;
+use crate::providers::okta::factors::Factor;
+use crate::providers::okta::health::FactorHealth;
 use crate::providers::okta::login::LoginRequest;
+use crate::providers::okta::response::{Response, Status};
+use crate::providers::okta::sessions::Validation;
 use crate::saml;
+use crate::timings::{self, Phase};
+use crate::ui;
+use crate::utils;
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use reqwest::{StatusCode, Url};
+use std::fmt;
+use std::rc::Rc;
 
 const API_AUTHN_PATH: &str = "api/v1/authn";
+const LOGIN_PAGE_MARKERS: [&str; 2] = ["id=\"okta-sign-in\"", "id=\"okta-signin-username\""];
+// Well below the 10 failed logins after which Okta locks accounts by default
+const PASSWORD_ATTEMPTS: usize = 3;
+
+/// An authenticated Okta session, usable until Okta expires it
+#[derive(Clone)]
+pub struct OktaSession {
+    pub session_token: String,
+    login: Option<AcceptedLogin>,
+    // The provider that logged in, whose cookie store holds the sid and DT
+    // cookies Okta set meanwhile
+    provider: Option<Rc<OktaProvider>>,
+}
+
+impl OktaSession {
+    pub fn new(session_token: String) -> OktaSession {
+        OktaSession {
+            session_token,
+            login: None,
+            provider: None,
+        }
+    }
+
+    /// A provider for fetching the credentials of `profile` with the session,
+    /// the one that logged in if it was for the same profile
+    pub fn provider(&self, profile: &AppProfile) -> Result<Rc<OktaProvider>> {
+        match &self.provider {
+            Some(provider) if provider.profile == *profile => Ok(provider.clone()),
+            _ => Ok(Rc::new(OktaProvider::with_session(profile, self)?)),
+        }
+    }
+
+    /// Caches the password the session was logged in with and the device
+    /// trust Okta granted, so the next login of the profile needn't ask again
+    pub fn remember_login(&mut self, profile: &AppProfile) -> Result<()> {
+        match self.login.take() {
+            Some(login) => login.remember(profile),
+            None => Ok(()),
+        }
+    }
+}
+
+// Leaves out the password of the login
+impl fmt::Debug for OktaSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OktaSession")
+            .field("session_token", &self.session_token)
+            .finish()
+    }
+}
+
+// What a login leaves to cache, which only the caller writes to the keyring
+#[derive(Clone)]
+struct AcceptedLogin {
+    credentials: ConfigCredentials,
+    device_token: Option<String>,
+}
+
+impl AcceptedLogin {
+    fn remember(self, profile: &AppProfile) -> Result<()> {
+        self.credentials.write(profile)?;
+        if let Some(token) = self.device_token {
+            devices::trust(profile, &token);
+        }
+
+        Ok(())
+    }
+}
 
 pub struct OktaProvider {
     client: Client,
     profile: AppProfile,
+    login: Option<AcceptedLogin>,
+    // When the session kept with `--keep-session` expires, if Okta told
+    session_expires_at: Option<String>,
 }
 
 impl OktaProvider {
@@ -27,49 +122,890 @@ impl OktaProvider {
         Ok(OktaProvider {
             client: Client::new(profile.clone())?,
             profile: profile.clone(),
+            login: None,
+            session_expires_at: None,
         })
     }
 
+    pub fn with_session(profile: &AppProfile, session: &OktaSession) -> Result<OktaProvider> {
+        let mut provider = OktaProvider::new(profile)?;
+        provider.client.totp = totp::stored_parameters(profile);
+        provider.client.device_token = devices::trusted_device(profile);
+        provider.client.session_token = Some(session.session_token.clone());
+
+        Ok(provider)
+    }
+
+    /// The session of the last login, carrying what `remember_login` caches.
+    /// It keeps the provider, so later requests share its cookies
+    pub fn into_session(mut self) -> Option<OktaSession> {
+        let session_token = self.client.session_token.clone()?;
+        let login = self.login.take();
+
+        Some(OktaSession {
+            session_token,
+            login,
+            provider: Some(Rc::new(self)),
+        })
+    }
+
+    /// Caches the password of the last login and the device trust Okta
+    /// granted, which `new_session` leaves to its caller
+    pub fn remember_login(&mut self) -> Result<()> {
+        match self.login.take() {
+            Some(login) => login.remember(&self.profile),
+            None => Ok(()),
+        }
+    }
+
+    /// Logs in with the password and MFA. Nothing gets cached until
+    /// `remember_login`
     pub fn new_session(&mut self) -> Result<&Self> {
         let profile = &self.profile;
-        let config_credentials =
-            ConfigCredentials::load(profile).or_else(|_| ConfigCredentials::create(profile))?;
+
+        self.client.totp = totp::stored_parameters(profile);
+        self.client.device_token = devices::trusted_device(profile);
+
+        let mut config_credentials = ConfigCredentials::obtain(profile)?;
 
         let username = &profile.username;
-        let password = &config_credentials.password;
-        let login_response = self
-            .client
-            .login(&LoginRequest::from_credentials(
-                username.clone(),
-                password.clone(),
-            ))
-            .with_context(|| "Unable to login")?;
+        let client = &self.client;
+        let interactive = prompt::current().is_interactive();
 
-        trace!("Login response: {:?}", login_response);
+        ui::explain(&format!(
+            "Authenticating to {} as {}…",
+            client.base_url.as_str().trim_end_matches('/'),
+            username
+        ));
+        let session_token =
+            timings::measure_bounded(Phase::Authentication, || -> Result<String> {
+                let (login_response, accepted) = login_with_password(
+                    profile,
+                    config_credentials.clone(),
+                    interactive,
+                    |password| {
+                        client
+                            .login(&LoginRequest::from_credentials(
+                                username.clone(),
+                                password.to_string(),
+                            ))
+                            .with_context(|| "Unable to login")
+                    },
+                )?;
+                config_credentials = accepted;
 
-        let session_token = self.client.get_session_token(login_response)?;
+                trace!("Login response: {:?}", login_response);
 
-        config_credentials.write(profile)?;
+                let login_response = match login_response.status {
+                    Status::MfaEnroll => match enroll::enroll_action(
+                        &login_response,
+                        client.factor_selector.as_ref(),
+                    ) {
+                        EnrollAction::Totp => {
+                            enroll_totp_factor(client, profile, login_response)?.0
+                        }
+                        EnrollAction::Explain(_) => login_response,
+                    },
+                    _ => login_response,
+                };
+
+                client.get_session_token(login_response)
+            })?;
 
+        self.login = Some(AcceptedLogin {
+            credentials: config_credentials,
+            device_token: self.client.new_device_token(),
+        });
         self.client.session_token = Some(session_token);
         Ok(self)
     }
 
+    /// Continues the session an earlier run kept with `--keep-session`, if
+    /// Okta still accepts it, instead of logging in
+    pub fn resume_session(&mut self) -> bool {
+        let id = match sessions::kept_session(&self.profile) {
+            Some(id) => id,
+            None => return false,
+        };
+
+        self.client.session_id = Some(id);
+        ui::explain("Checking whether Okta still accepts the session kept by an earlier run…");
+        match self.client.validate_session() {
+            Ok(Validation::Valid(expires_at)) => {
+                self.session_expires_at = expires_at;
+                true
+            }
+            Ok(Validation::NotPermitted) => {
+                debug!("Okta doesn't permit checking the kept session, using it as is");
+                self.session_expires_at =
+                    sessions::kept_session_expiry(&self.profile).map(|expiry| expiry.to_rfc3339());
+                true
+            }
+            Ok(Validation::Invalid) => {
+                debug!("Logging in again, the kept Okta session ended");
+                sessions::forget(&self.profile);
+                self.client.session_id = None;
+                false
+            }
+            // The session may well outlive e.g. a network problem, so it's
+            // kept for the next run
+            Err(e) => {
+                debug!(
+                    "Logging in again, unable to check the kept Okta session: {:#}",
+                    e
+                );
+                self.client.session_id = None;
+                false
+            }
+        }
+    }
+
+    /// Turns the session token of a login into a session that outlives this
+    /// run, which later requests use instead of the token
+    pub fn begin_kept_session(&mut self) -> Result<()> {
+        if self.client.session_id.is_some() {
+            return Ok(());
+        }
+
+        let token = self
+            .client
+            .session_token
+            .take()
+            .with_context(|| "Okta didn't return a session token")?;
+        let session = self.client.create_session(token)?;
+        self.client.session_id = Some(session.id);
+        self.session_expires_at = session.expires_at;
+
+        Ok(())
+    }
+
+    /// Extends the session and caches it for the next run, returning when it
+    /// expires if Okta told
+    pub fn keep_session(&self) -> Option<DateTime<Utc>> {
+        let id = self.client.session_id.as_ref()?;
+
+        sessions::keep(&self.profile, id, self.session_expires_at.clone(), || {
+            self.client.refresh_session()
+        })
+    }
+
+    pub fn enroll_totp(&self) -> Result<TotpCredentials> {
+        let profile = &self.profile;
+        let (login_response, config_credentials) = self.password_login()?;
+
+        if login_response.status == Status::Unauthenticated {
+            return Err(crate::error::error(
+                ErrorKind::Authentication,
+                "Username or password wrong. Please check them and try again",
+            ));
+        }
+
+        let (_, totp_credentials) = enroll_totp_factor(&self.client, profile, login_response)?;
+        config_credentials.write(profile)?;
+
+        Ok(totp_credentials)
+    }
+
+    /// Logs in without MFA and checks the factors Okta offers, or returns
+    /// none if Okta doesn't ask for MFA at all
+    pub fn factor_health(&self) -> Result<Option<Vec<FactorHealth>>> {
+        Ok(self
+            .mfa_factors()?
+            .map(|factors| health::check_factors(&factors)))
+    }
+
+    /// Logs in without MFA and returns the factors the menu would offer, in
+    /// the order `--factor-index` counts them, or none if Okta doesn't ask
+    /// for MFA at all
+    pub fn menu_factors(&self) -> Result<Option<Vec<Factor>>> {
+        match self.mfa_factors()? {
+            Some(factors) => Ok(Some(auth::menu_factors(
+                factors,
+                self.client.factor_provider.as_ref(),
+                self.profile.push_device.as_deref(),
+            )?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Logs in and verifies the factor the profile selects, e.g. with
+    /// `--factor push`, but stops short of fetching the SAML assertion. The
+    /// device isn't trusted for the login, so Okta asks for the factor
+    pub fn test_factor(&self) -> Result<()> {
+        if self.client.factor_selector.is_none() {
+            return Err(anyhow!("Name the factor to test, e.g. --factor push"));
+        }
+
+        let (login_response, config_credentials) = self.password_login()?;
+        if login_response.status == Status::Success {
+            return Err(crate::error::error(
+                ErrorKind::Authentication,
+                "Okta logged you in without asking for MFA, there's no factor to test",
+            ));
+        }
+
+        self.client.get_session_token(login_response)?;
+        config_credentials.write(&self.profile)?;
+
+        Ok(())
+    }
+
+    // Logs in with the password of the profile, without going on with MFA
+    fn password_login(&self) -> Result<(Response, ConfigCredentials)> {
+        let profile = &self.profile;
+
+        login_with_password(
+            profile,
+            ConfigCredentials::obtain(profile)?,
+            prompt::current().is_interactive(),
+            |password| {
+                self.client
+                    .login(&LoginRequest::from_credentials(
+                        profile.username.clone(),
+                        password.to_string(),
+                    ))
+                    .with_context(|| "Unable to login")
+            },
+        )
+    }
+
+    fn mfa_factors(&self) -> Result<Option<Vec<Factor>>> {
+        let profile = &self.profile;
+        let (login_response, config_credentials) = self.password_login()?;
+
+        let factors = match login_response.status {
+            Status::Unauthenticated => {
+                return Err(crate::error::error(
+                    ErrorKind::Authentication,
+                    "Username or password wrong. Please check them and try again",
+                ))
+            }
+            Status::MfaRequired => Some(login_response.factors()?),
+            Status::Success => None,
+            Status::MfaEnroll => {
+                return Err(crate::error::error(
+                    ErrorKind::Authentication,
+                    enroll::enrollment_required(&login_response),
+                ))
+            }
+            ref status => {
+                return Err(anyhow!(
+                    "Okta answered the login with {:?} instead of the MFA factors",
+                    status
+                ))
+            }
+        };
+        config_credentials.write(profile)?;
+
+        Ok(factors)
+    }
+
     pub fn fetch_aws_credentials(&self) -> Result<AwsCredentials> {
         let profile = &self.profile;
         debug!("Requesting temporary STS credentials for {}", &profile.name);
 
-        let url = profile.clone().request_url().unwrap();
-        let input = self
-            .client
-            .get(url)
-            .with_context(|| format!("Error getting SAML response for profile {}", profile.name))?
-            .text()?;
+        let input = self.fetch_saml()?;
+        let credentials = saml::get_credentials_from_saml(input, &profile.into())?;
+        trace!("Credentials: {:?}", credentials);
+        Ok(credentials)
+    }
+
+    /// Fetches the HTML form carrying the SAML assertion of the profile's app.
+    /// Apps configured by label or id are looked up among the apps assigned
+    /// to the user
+    pub fn fetch_saml(&self) -> Result<String> {
+        match &self.profile.app {
+            Some(app) => apps::with_app_link(
+                &self.profile,
+                app,
+                || self.client.app_links(),
+                |url| self.fetch_saml_from(url),
+            ),
+            None => self.fetch_saml_from(embed_link(self.profile.request_url()?)?),
+        }
+    }
+
+    fn fetch_saml_from(&self, url: Url) -> Result<String> {
+        let profile = &self.profile;
+        ui::explain(&format!("Fetching the SAML assertion from app {}…", url));
+        let fetch = |session| {
+            timings::measure_bounded(Phase::SamlFetch, || -> Result<String> {
+                self.client
+                    .fetch_saml_assertion(url.clone(), session)
+                    .with_context(|| {
+                        format!("Error getting SAML response for profile {}", profile.name)
+                    })?
+                    .text()
+                    .map_err(|e| e.into())
+            })
+        };
+        let input = step_up::satisfy(
+            fetch(self.client.session_kind())?,
+            |state_token| self.client.step_up(state_token),
+            |session_token| fetch(SessionKind::OneTime(session_token)),
+        )?;
 
         debug!("Text for SAML response: {:#?}", input);
 
-        let credentials = saml::get_credentials_from_saml(input, profile.role.clone())?;
-        trace!("Credentials: {:?}", credentials);
-        Ok(credentials)
+        check_app_page(&input)?;
+
+        Ok(input)
+    }
+}
+
+/// Logs in with the password of `credentials`. A stored password Okta rejects
+/// is forgotten, so the next run doesn't fail with it again, and where someone
+/// can answer it's asked for anew a few times. Returns the password that
+/// worked along with the login response
+fn login_with_password<T, F>(
+    profile: &AppProfile,
+    mut credentials: ConfigCredentials,
+    interactive: bool,
+    login: F,
+) -> Result<(T, ConfigCredentials)>
+where
+    F: Fn(&str) -> Result<T>,
+{
+    for attempt in 1.. {
+        match login(&credentials.password) {
+            Err(e) if crate::error::kind_of(&e) == ErrorKind::InvalidCredentials => {
+                let _ = ConfigCredentials::load(profile)
+                    .and_then(|stored| stored.delete(profile))
+                    .map_err(|e| debug!("No stored password to forget: {}", e));
+
+                if !interactive
+                    || profile.password_command.is_some()
+                    || attempt >= PASSWORD_ATTEMPTS
+                {
+                    return Err(e);
+                }
+
+                prompt::current().message(&ui::warning(&format!(
+                    "Okta rejected the password of {}, please try again",
+                    profile.username
+                )));
+                credentials = ConfigCredentials::create(profile)?;
+            }
+            result => return result.map(|response| (response, credentials)),
+        }
+    }
+
+    unreachable!("The attempts never run out")
+}
+
+/// Enrolls and activates a software TOTP factor for a login Okta answered
+/// with MFA_ENROLL, storing its secret. The activation response carries the
+/// session token of the login
+fn enroll_totp_factor(
+    client: &Client,
+    profile: &AppProfile,
+    login_response: Response,
+) -> Result<(Response, TotpCredentials)> {
+    let enrollment = client.enroll_totp(login_response)?;
+    let activation = enroll::totp_activation(&enrollment)?;
+    let issuer = profile
+        .base_url()?
+        .host_str()
+        .unwrap_or_default()
+        .to_owned();
+    let uri = enroll::otpauth_uri(&issuer, &profile.username, &activation)?;
+
+    prompt::current().message(&format!(
+        "Add the following secret to your authenticator app:\n  Secret: {}\n  URI: {}",
+        activation.shared_secret, uri
+    ));
+
+    keep_activated_secret(profile, activation.shared_secret, || {
+        client.activate_totp(&enrollment, utils::prompt_mfa()?)
+    })
+}
+
+// Codes of a secret Okta didn't activate are rejected, so it's only written
+// once the activation succeeded, not when e.g. a mistyped code failed it
+fn keep_activated_secret<F>(
+    profile: &AppProfile,
+    shared_secret: String,
+    activate: F,
+) -> Result<(Response, TotpCredentials)>
+where
+    F: FnOnce() -> Result<Response>,
+{
+    let activation_response = activate().with_context(|| "Unable to activate TOTP factor")?;
+
+    match activation_response.status {
+        Status::Success => {
+            let totp_credentials = TotpCredentials::new(shared_secret).write(profile)?;
+            Ok((activation_response, totp_credentials))
+        }
+        status => Err(anyhow!(
+            "Activating the TOTP factor failed with status {:?}",
+            status
+        )),
+    }
+}
+
+/// Turns the URL of an AWS app tile, `.../app/amazon_aws/<id>/<label>`, into
+/// the embed link `.../app/<label>/<id>/sso/saml` answering with the SAML form.
+/// Embed links, including the `.../home/amazon_aws/<id>/<number>` kind, pass as is
+pub fn embed_link(url: Url) -> Result<Url> {
+    let segments = url
+        .path_segments()
+        .map(|s| s.filter(|s| !s.is_empty()).collect::<Vec<&str>>())
+        .unwrap_or_default();
+
+    let path = match segments.as_slice() {
+        ["home", _, _, _] | ["app", _, _, "sso", "saml"] => return Ok(url),
+        ["app", "amazon_aws", id, label] => format!("app/{}/{}/sso/saml", label, id),
+        _ => {
+            return Err(anyhow!(
+                "The profile URL {} is neither the embed link nor the tile link of an Okta AWS app. \
+                 Copy the link of the AWS tile on your Okta dashboard or the \"App Embed Link\" on the \
+                 General tab of the AWS app in the Okta admin console",
+                url
+            ))
+        }
+    };
+
+    let mut link = url.clone();
+    link.set_path(&path);
+
+    Ok(link)
+}
+
+/// The Okta organization of the AWS app at `app_url`, which needs to be the
+/// full link of the app, along with the app's id
+pub fn org_url(app_url: &str) -> Result<(Url, String)> {
+    let url = Url::parse(app_url).map_err(|e| {
+        anyhow!(
+            "{} needs to be the full link of the app like https://example.okta.com/home/amazon_aws/0oa1b2c3/272: {}",
+            app_url,
+            e
+        )
+    })?;
+
+    if url.scheme() != "https" || url.host_str().is_none() {
+        return Err(anyhow!("The app link {} needs to be an https URL", app_url));
+    }
+
+    // Tile and embed links alike carry the app's id as their third segment
+    let link = embed_link(url)?;
+    let app_id = link
+        .path_segments()
+        .and_then(|mut s| s.nth(2))
+        .unwrap_or_default()
+        .to_string();
+    let mut org = link;
+    org.set_path("/");
+    org.set_query(None);
+    org.set_fragment(None);
+
+    Ok((org, app_id))
+}
+
+/// Okta answers a URL that isn't the app's embed link, e.g. an SP-initiated
+/// or ACS URL, as well as an expired session with its sign-in page
+#[derive(Debug)]
+pub struct SignInPage;
+
+impl fmt::Display for SignInPage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Okta returned its sign-in page instead of a SAML response, so the profile URL is most likely not \
+             the embed link of your AWS app. crowbar needs the IdP-initiated embed link, which looks like \
+             https://<org>.okta.com/home/amazon_aws/<app-id>/272. You can find it under \"App Embed Link\" \
+             on the General tab of the AWS app in the Okta admin console, or by copying the link of the AWS \
+             tile on your Okta dashboard"
+        )
+    }
+}
+
+impl std::error::Error for SignInPage {}
+
+/// Whether fetching the SAML assertion failed because Okta wants a new login
+pub fn needs_login(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        cause.is::<SignInPage>()
+            || cause
+                .downcast_ref::<StatusError>()
+                .is_some_and(|e| e.status == StatusCode::UNAUTHORIZED)
+    })
+}
+
+fn check_app_page(html: &str) -> Result<()> {
+    if html.contains("SAMLResponse") || !LOGIN_PAGE_MARKERS.iter().any(|m| html.contains(m)) {
+        return Ok(());
+    }
+
+    Err(SignInPage.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::app::test::{okta_profile, OKTA_URL};
+    use crate::credentials::aws::test::serve;
+    use crate::credentials::keystore::{self, test::MemoryBackend};
+    use crate::prompt::{self, test::ScriptedPrompter};
+    use crate::providers::okta::error::{okta_error, ErrorResponse};
+    use crate::providers::ProviderType;
+    use claim::assert_ok;
+    use std::cell::RefCell;
+    use std::fs;
+    use std::net::TcpListener;
+    use std::rc::Rc;
+
+    #[test]
+    fn tests_factor_without_fetching_credentials() -> Result<()> {
+        let test_factor = |base: &str, factor: &str| {
+            let mut profile = AppProfile::new(
+                "okta",
+                ProviderType::Okta,
+                &format!("{}/home/amazon_aws/0oa1b2c3prod/272", base),
+                "jdoe@example.com",
+                None,
+            );
+            profile.mfa_factor = Some(factor.to_string());
+            profile.mfa_code = Some("123456".to_string()).filter(|_| factor != "push");
+
+            keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+                prompt::with_prompter(Rc::new(ScriptedPrompter::new(&["password"])), || {
+                    OktaProvider::new(&profile)?.test_factor()
+                })
+            })
+        };
+        let mfa_required = |base: &str| -> Result<String> {
+            Ok(
+                fs::read_to_string("tests/fixtures/okta/login_response_mfa_required.json")?
+                    .replace("https://example.okta.com", base),
+            )
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let base = format!("http://{}", listener.local_addr()?);
+        let server = serve(
+            listener,
+            vec![
+                ("application/json", mfa_required(&base)?),
+                (
+                    "application/json",
+                    r#"{"expiresAt":"2038-01-01T10:10:10.000Z","status":"SUCCESS","sessionToken":"session-token"}"#
+                        .to_string(),
+                ),
+            ],
+        );
+        test_factor(&base, "totp:google")?;
+        assert_eq!(
+            vec![
+                "POST /api/v1/authn",
+                "POST /api/v1/authn/factors/factor-id-totp-software/verify?rememberDevice=true",
+            ],
+            server.join().unwrap()
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let base = format!("http://{}", listener.local_addr()?);
+        let challenge = fs::read_to_string("tests/fixtures/okta/challenge_response_push.json")?
+            .replace("https://thoughtworks.okta.com", &base);
+        let rejected = challenge.replace("WAITING", "REJECTED");
+        let server = serve(
+            listener,
+            vec![
+                ("application/json", mfa_required(&base)?),
+                ("application/json", challenge),
+                ("application/json", rejected.clone()),
+                ("application/json", rejected),
+            ],
+        );
+        let e = test_factor(&base, "push").unwrap_err();
+        assert_eq!(ErrorKind::MfaRejected, crate::error::kind_of(&e));
+        // Neither the app nor STS were asked for anything
+        assert!(server
+            .join()
+            .unwrap()
+            .iter()
+            .all(|request| request.starts_with("POST /api/v1/authn")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn caches_login_only_once_remembered() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let base = format!("http://{}", listener.local_addr()?);
+        let server = serve(
+            listener,
+            vec![(
+                "application/json",
+                r#"{"expiresAt":"2038-01-01T10:10:10.000Z","status":"SUCCESS","sessionToken":"session-token"}"#
+                    .to_string(),
+            )],
+        );
+        let profile = AppProfile::new(
+            "okta",
+            ProviderType::Okta,
+            &format!("{}/home/amazon_aws/0oa1b2c3prod/272", base),
+            "jdoe@example.com",
+            None,
+        );
+
+        keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+            let mut session =
+                crate::api::authenticate(&profile, Rc::new(ScriptedPrompter::new(&["hunter2"])))?;
+            assert_eq!(vec!["POST /api/v1/authn"], server.join().unwrap());
+
+            assert_eq!("session-token", session.session_token);
+            assert!(!format!("{:?}", session).contains("hunter2"));
+            assert!(ConfigCredentials::load(&profile).is_err());
+
+            session.remember_login(&profile)?;
+            assert_eq!("hunter2", ConfigCredentials::load(&profile)?.password);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn remembers_password_and_device_of_login() -> Result<()> {
+        let profile = okta_profile("profile", OKTA_URL);
+        let mut session = OktaSession {
+            session_token: "session-token".to_string(),
+            login: Some(AcceptedLogin {
+                credentials: ConfigCredentials::new("hunter2".to_string()),
+                device_token: Some("device-token".to_string()),
+            }),
+            provider: None,
+        };
+        assert!(!format!("{:?}", session).contains("hunter2"));
+
+        keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+            session.clone().remember_login(&profile)?;
+            assert_eq!("hunter2", ConfigCredentials::load(&profile)?.password);
+            assert_eq!(
+                Some("device-token".to_string()),
+                devices::trusted_device(&profile)
+            );
+
+            Ok::<_, anyhow::Error>(())
+        })?;
+
+        keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+            OktaSession::new("session-token".to_string()).remember_login(&profile)?;
+            assert!(ConfigCredentials::load(&profile).is_err());
+
+            session.remember_login(&profile)?;
+            session.remember_login(&profile)?;
+            assert!(ConfigCredentials::load(&profile).is_ok());
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn explains_login_page_instead_of_app() -> Result<()> {
+        let html = fs::read_to_string("tests/fixtures/okta/login_page.html")?;
+        let error = check_app_page(&html).unwrap_err().to_string();
+
+        assert!(error.contains("not the embed link of your AWS app"));
+        assert!(error.contains("App Embed Link"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn builds_embed_link_from_tile_url() -> Result<()> {
+        assert_eq!(
+            "https://example.okta.com/app/example_aws_1/0oa1b2c3/sso/saml",
+            embed_link(Url::parse(
+                "https://example.okta.com/app/amazon_aws/0oa1b2c3/example_aws_1"
+            )?)?
+            .as_str()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn passes_embed_links_through() -> Result<()> {
+        for link in &[
+            "https://example.okta.com/app/example_aws_1/0oa1b2c3/sso/saml",
+            "https://example.okta.com/home/amazon_aws/0oa1b2c3/272",
+            "https://example.okta.com/home/amazon_aws/0oa1b2c3/272?fromHome=true",
+        ] {
+            assert_eq!(*link, embed_link(Url::parse(link)?)?.as_str());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn derives_org_from_app_links() -> Result<()> {
+        for (link, org, app_id) in &[
+            (
+                "https://example.okta.com/home/amazon_aws/0oa1b2c3/272?fromHome=true",
+                "https://example.okta.com/",
+                "0oa1b2c3",
+            ),
+            (
+                "https://example.okta.com/app/amazon_aws/0oa1b2c3/aws-production",
+                "https://example.okta.com/",
+                "0oa1b2c3",
+            ),
+            (
+                "https://example.oktapreview.com/app/example_aws_1/0oa4d5e6/sso/saml#top",
+                "https://example.oktapreview.com/",
+                "0oa4d5e6",
+            ),
+            (
+                "https://login.example.com:8443/home/amazon_aws/0oa1b2c3/272",
+                "https://login.example.com:8443/",
+                "0oa1b2c3",
+            ),
+        ] {
+            let (derived_org, derived_id) = org_url(link)?;
+            assert_eq!(*org, derived_org.as_str(), "{}", link);
+            assert_eq!(*app_id, derived_id, "{}", link);
+        }
+
+        for link in &[
+            "/home/amazon_aws/0oa1b2c3/272",
+            "example.okta.com/home/amazon_aws/0oa1b2c3/272",
+            "http://example.okta.com/home/amazon_aws/0oa1b2c3/272",
+            "https://example.okta.com/app/UserHome",
+        ] {
+            assert!(org_url(link).is_err(), "{} was accepted", link);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn explains_malformed_app_urls() -> Result<()> {
+        for url in &[
+            "https://example.okta.com/",
+            "https://example.okta.com/app/UserHome",
+            "https://example.okta.com/login/login.htm",
+        ] {
+            let error = embed_link(Url::parse(url)?).unwrap_err().to_string();
+            assert!(error.contains("neither the embed link nor the tile link"));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn recognizes_stale_sessions() {
+        assert!(needs_login(&SignInPage.into()));
+        assert!(needs_login(
+            &anyhow::Error::new(StatusError {
+                status: StatusCode::UNAUTHORIZED
+            })
+            .context("Error getting SAML response")
+        ));
+        assert!(!needs_login(&anyhow::Error::new(StatusError {
+            status: StatusCode::INTERNAL_SERVER_ERROR
+        })));
+    }
+
+    #[test]
+    fn accepts_saml_form() {
+        let html = r#"<form><input name="SAMLResponse" value="abc"/></form>"#;
+        assert_ok!(check_app_page(html));
+    }
+
+    #[test]
+    fn forgets_password_okta_rejected() -> Result<()> {
+        let profile = okta_profile("profile", OKTA_URL);
+
+        keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+            ConfigCredentials::new("hunter2".to_string()).write(&profile)?;
+
+            let error = login_with_password(
+                &profile,
+                ConfigCredentials::load(&profile)?,
+                false,
+                |_| -> Result<()> { Err(authentication_failed()?) },
+            )
+            .err()
+            .unwrap();
+
+            assert_eq!(ErrorKind::InvalidCredentials, crate::error::kind_of(&error));
+            assert!(ConfigCredentials::load(&profile).is_err());
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn asks_again_for_password_okta_rejected() -> Result<()> {
+        let profile = okta_profile("profile", OKTA_URL);
+        let passwords = RefCell::new(vec![]);
+
+        let prompter = Rc::new(ScriptedPrompter::new(&["correct"]));
+
+        keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+            prompt::with_prompter(prompter.clone(), || {
+                let (_, credentials) = login_with_password(
+                    &profile,
+                    ConfigCredentials::new("hunter2".to_string()),
+                    true,
+                    |password| {
+                        passwords.borrow_mut().push(password.to_string());
+                        match password {
+                            "correct" => Ok(()),
+                            _ => Err(authentication_failed()?),
+                        }
+                    },
+                )?;
+
+                assert_eq!("correct", credentials.password);
+                assert_eq!(vec!["hunter2", "correct"], *passwords.borrow());
+                assert_eq!(1, prompter.messages().len());
+
+                Ok(())
+            })
+        })
+    }
+
+    #[test]
+    fn keeps_totp_secret_only_once_activated() -> Result<()> {
+        let profile = okta_profile("profile", OKTA_URL);
+        let secret = "JBSWY3DPEHPK3PXP";
+        let invalid_passcode: ErrorResponse = serde_json::from_str(&fs::read_to_string(
+            "tests/fixtures/okta/error_response_invalid_passcode.json",
+        )?)?;
+        let pending: Response = serde_json::from_str(&fs::read_to_string(
+            "tests/fixtures/okta/enroll_response_totp_activate.json",
+        )?)?;
+        let activated: Response = serde_json::from_str(
+            r#"{"expiresAt":"2038-01-01T10:10:10.000Z","status":"SUCCESS","sessionToken":"session-token"}"#,
+        )?;
+
+        keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+            // A mistyped code is rejected with 403
+            assert!(keep_activated_secret(&profile, secret.to_string(), || {
+                Err(okta_error(StatusCode::FORBIDDEN, Ok(invalid_passcode)))
+            })
+            .is_err());
+            assert!(TotpCredentials::load(&profile).is_err());
+
+            assert!(keep_activated_secret(&profile, secret.to_string(), || Ok(pending)).is_err());
+            assert!(TotpCredentials::load(&profile).is_err());
+
+            let (_, credentials) =
+                keep_activated_secret(&profile, secret.to_string(), || Ok(activated))?;
+            assert_eq!(secret, credentials.secret);
+            assert_eq!(secret, TotpCredentials::load(&profile)?.secret);
+
+            Ok(())
+        })
+    }
+
+    fn authentication_failed() -> Result<anyhow::Error> {
+        let body: ErrorResponse = serde_json::from_str(&fs::read_to_string(
+            "tests/fixtures/okta/error_response_authentication_failed.json",
+        )?)?;
+
+        Ok(okta_error(StatusCode::UNAUTHORIZED, Ok(body)))
     }
 }
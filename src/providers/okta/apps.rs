@@ -0,0 +1,320 @@
+//! AWS apps referenced by their label or id with `app = "AWS Production"`
+//! instead of by their embed link. Okta lists the apps assigned to the user
+//! along with their links, so the link is looked up once and cached. A cached
+//! link Okta no longer knows, e.g. of an app that was recreated, is looked up
+//! again.
+
+use crate::config::app::AppProfile;
+use crate::credentials::keystore;
+use crate::credentials::CredentialType;
+use crate::error::{self, ErrorKind};
+use crate::providers::okta::client::Client;
+use crate::providers::okta::error::StatusError;
+
+use anyhow::{Context, Result};
+use reqwest::{StatusCode, Url};
+
+const API_APP_LINKS_PATH: &str = "api/v1/users/me/appLinks";
+const AWS_APP_NAME: &str = "amazon_aws";
+
+/// An app assigned to the user, as listed by appLinks
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AppLink {
+    pub label: String,
+    pub link_url: String,
+    pub app_name: String,
+    pub app_instance_id: String,
+}
+
+impl Client {
+    pub fn app_links(&self) -> Result<Vec<AppLink>> {
+        self.get_with_session_cookie(self.base_url.join(API_APP_LINKS_PATH)?)?
+            .json()
+            .with_context(|| "Unable to read the apps assigned to the user")
+    }
+}
+
+/// The embed link of the app of the profile, asking Okta for it only if it
+/// isn't cached yet
+pub fn app_link<F>(profile: &AppProfile, app: &str, fetch: F) -> Result<Url>
+where
+    F: FnOnce() -> Result<Vec<AppLink>>,
+{
+    if let Some(link) = cached_link(profile, app) {
+        return Ok(link);
+    }
+
+    let link = find_app_link(&fetch()?, app, &profile.username)?;
+    remember_link(profile, app, &link);
+
+    Ok(link)
+}
+
+/// Fetches from the embed link of the app of the profile. If the cached link
+/// turns out to be stale, it's looked up again and fetched from once more
+pub fn with_app_link<T, L, F>(profile: &AppProfile, app: &str, links: L, fetch: F) -> Result<T>
+where
+    L: Fn() -> Result<Vec<AppLink>>,
+    F: Fn(Url) -> Result<T>,
+{
+    let cached = cached_link(profile, app).is_some();
+
+    match fetch(app_link(profile, app, &links)?) {
+        Err(e) if cached && is_stale_link(&e) => {
+            debug!(
+                "The cached link of the app {} looks stale, looking it up again: {}",
+                app, e
+            );
+            forget_link(profile, app);
+            fetch(app_link(profile, app, &links)?)
+        }
+        result => result,
+    }
+}
+
+// Okta answers links of apps that were deleted, or are no longer assigned to
+// the user, with a web page of these statuses
+fn is_stale_link(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        cause
+            .downcast_ref::<StatusError>()
+            .is_some_and(|e| e.status == StatusCode::NOT_FOUND || e.status == StatusCode::FORBIDDEN)
+    })
+}
+
+/// Finds the app by its instance id or, ignoring case, its label
+pub fn find_app_link(links: &[AppLink], app: &str, username: &str) -> Result<Url> {
+    let matching = links
+        .iter()
+        .filter(|l| l.app_instance_id == app || l.label.eq_ignore_ascii_case(app))
+        .collect::<Vec<&AppLink>>();
+
+    let link = match matching.as_slice() {
+        [link] => link,
+        [] => {
+            let assigned = links
+                .iter()
+                .filter(|l| l.app_name == AWS_APP_NAME)
+                .map(|l| format!("{} ({})", l.label, l.app_instance_id))
+                .collect::<Vec<String>>();
+
+            return Err(error::error(
+                ErrorKind::Config,
+                match assigned.as_slice() {
+                    [] => format!(
+                        "The app {} isn't assigned to {}, and neither is any other AWS app",
+                        app, username
+                    ),
+                    assigned => format!(
+                        "The app {} isn't assigned to {}, the AWS apps assigned are {}",
+                        app,
+                        username,
+                        assigned.join(", ")
+                    ),
+                },
+            ));
+        }
+        _ => {
+            return Err(error::error(
+                ErrorKind::Config,
+                format!(
+                "Several apps assigned to {} are labeled {}, please use the id of one of them: {}",
+                username,
+                app,
+                matching
+                    .iter()
+                    .map(|l| l.app_instance_id.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(", ")
+            ),
+            ))
+        }
+    };
+
+    Url::parse(&link.link_url).with_context(|| {
+        format!(
+            "Okta sent the invalid link {} for the app {}",
+            link.link_url, app
+        )
+    })
+}
+
+// Links aren't secrets, but the keyring is the only place crowbar keeps
+// anything between runs
+fn service(profile: &AppProfile, app: &str) -> String {
+    CredentialType::App.service(&format!("{}::{}", profile.name, app))
+}
+
+/// The link an earlier run cached for the app of the profile
+pub fn cached_app_link(profile: &AppProfile) -> Option<Url> {
+    cached_link(profile, profile.app.as_deref()?)
+}
+
+/// Drops the cached link of the app of the profile
+pub fn forget(profile: &AppProfile) {
+    if let Some(app) = &profile.app {
+        forget_link(profile, app);
+    }
+}
+
+fn cached_link(profile: &AppProfile, app: &str) -> Option<Url> {
+    keystore::get_cached(&service(profile, app), &profile.username)
+        .ok()
+        .and_then(|link| Url::parse(&link).ok())
+}
+
+fn forget_link(profile: &AppProfile, app: &str) {
+    let _ = keystore::delete_password(&service(profile, app), &profile.username)
+        .map_err(|e| debug!("Couldn't forget the link of the app: {}", e));
+}
+
+fn remember_link(profile: &AppProfile, app: &str, link: &Url) {
+    let _ = keystore::set_password(&service(profile, app), &profile.username, link.as_str())
+        .map_err(|e| debug!("Couldn't cache the link of the app: {}", e));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::credentials::keystore::test::MemoryBackend;
+    use crate::providers::ProviderType;
+    use std::cell::{Cell, RefCell};
+    use std::fs;
+    use std::rc::Rc;
+
+    #[test]
+    fn resolves_label_to_embed_link() -> Result<()> {
+        let links = read_app_links()?;
+
+        for app in &["AWS Staging", "aws staging", "0oa1b2c3stag"] {
+            assert_eq!(
+                "https://example.okta.com/home/amazon_aws/0oa1b2c3stag/272",
+                find_app_link(&links, app, "jdoe")?.as_str()
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn explains_unassigned_app() -> Result<()> {
+        let error = find_app_link(&read_app_links()?, "AWS Sandbox", "jdoe").unwrap_err();
+
+        assert_eq!(ErrorKind::Config, error::kind_of(&error));
+        assert_eq!(
+            "The app AWS Sandbox isn't assigned to jdoe, the AWS apps assigned are \
+             AWS Production (0oa1b2c3prod), AWS Staging (0oa1b2c3stag)",
+            error.to_string()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_ambiguous_label() -> Result<()> {
+        let mut links = read_app_links()?;
+        links[1].label = "AWS Production".to_string();
+
+        let error = find_app_link(&links, "AWS Production", "jdoe")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("0oa1b2c3prod, 0oa1b2c3stag"), "{}", error);
+        assert!(find_app_link(&links, "0oa1b2c3stag", "jdoe").is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn caches_resolved_link() -> Result<()> {
+        let profile = AppProfile::new(
+            "profile",
+            ProviderType::Okta,
+            "https://example.okta.com",
+            "jdoe",
+            None,
+        );
+        let fetched = Cell::new(0);
+        let fetch = || {
+            fetched.set(fetched.get() + 1);
+            read_app_links()
+        };
+
+        keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+            let first = app_link(&profile, "AWS Production", fetch)?;
+            let second = app_link(&profile, "AWS Production", fetch)?;
+
+            assert_eq!(first, second);
+            assert_eq!(1, fetched.get());
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn looks_up_stale_link_again() -> Result<()> {
+        let profile = AppProfile::new(
+            "profile",
+            ProviderType::Okta,
+            "https://example.okta.com",
+            "jdoe",
+            None,
+        );
+        let original = "https://example.okta.com/home/amazon_aws/0oa1b2c3prod/272";
+        let recreated_link = "https://example.okta.com/home/amazon_aws/0oa9z8y7prod/272";
+        let recreated = Cell::new(false);
+        let lookups = Cell::new(0);
+        let links = || {
+            lookups.set(lookups.get() + 1);
+            let mut links = read_app_links()?;
+            if recreated.get() {
+                links[0].link_url = recreated_link.to_string();
+            }
+            Ok(links)
+        };
+        let fetched = RefCell::new(vec![]);
+        let fetch = |url: Url| match recreated.get() && url.as_str() == original {
+            true => Err(anyhow::Error::new(StatusError {
+                status: StatusCode::NOT_FOUND,
+            })
+            .context("Error getting SAML response for profile profile")),
+            false => {
+                fetched.borrow_mut().push(url.to_string());
+                Ok(())
+            }
+        };
+
+        keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+            with_app_link(&profile, "AWS Production", links, fetch)?;
+            with_app_link(&profile, "AWS Production", links, fetch)?;
+            assert_eq!(1, lookups.get());
+
+            // Okta no longer knows the cached link once the app is recreated
+            recreated.set(true);
+            with_app_link(&profile, "AWS Production", links, fetch)?;
+            with_app_link(&profile, "AWS Production", links, fetch)?;
+            assert_eq!(2, lookups.get());
+            assert_eq!(
+                vec![original, original, recreated_link, recreated_link],
+                fetched.take()
+            );
+
+            // Other failures keep the cached link
+            let error = with_app_link(&profile, "AWS Production", links, |_| -> Result<()> {
+                Err(anyhow::Error::new(StatusError {
+                    status: StatusCode::INTERNAL_SERVER_ERROR,
+                }))
+            });
+            assert!(error.is_err());
+            assert_eq!(2, lookups.get());
+
+            Ok(())
+        })
+    }
+
+    fn read_app_links() -> Result<Vec<AppLink>> {
+        Ok(serde_json::from_str(&fs::read_to_string(
+            "tests/fixtures/okta/app_links.json",
+        )?)?)
+    }
+}
@@ -1,28 +1,85 @@
+use crate::config::app::AppProfile;
+use crate::credentials::keystore;
+use crate::credentials::totp::{self, TotpFormat, TotpParameters};
+use crate::credentials::CredentialType;
+use crate::error::{self, ErrorKind};
+use crate::notify;
+use crate::prompt;
 use crate::providers::okta::client::Client;
-use crate::providers::okta::factors::Factor;
+use crate::providers::okta::enroll;
+use crate::providers::okta::error::{explain_invalid_passcode, is_invalid_passcode};
+use crate::providers::okta::factors::{Factor, FactorProvider, FactorSelector, ListedFactor};
 use crate::providers::okta::response::{FactorResult, Links, Response, Status};
+use crate::providers::okta::security_key;
 use crate::providers::okta::verification::VerificationRequest;
+use crate::timings::{self, Phase};
+use crate::ui;
 use crate::utils;
 
 use anyhow::{anyhow, Context, Result};
 use chrono::{DateTime, Utc};
-use console::Term;
+use reqwest::Url;
 use std::collections::HashMap;
 use std::{thread, time::Duration};
 
 const BACKOFF_TIMEOUT: Duration = Duration::from_secs(2);
+// Bounds of the poll interval Okta may recommend, so a bogus hint neither
+// runs into its rate limits nor misses the push
+const MIN_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(10);
 pub const PUSH_WAIT_TIMEOUT: i64 = 60;
+// The last item of the menu offering other factors
+const GIVE_UP: &str = "None, give up";
+// How much of a factor ID tells apart factors that look the same otherwise
+const ID_SUFFIX_LENGTH: usize = 6;
+// Where users enroll factors in the Okta dashboard
+const ENROLLMENT_PATH: &str = "/enduser/settings";
+// Digits of the security codes Symantec VIP tokens show
+const VIP_CODE_LENGTH: usize = 6;
+
+/// The Push factor used last, preselected when there are several, e.g. for
+/// Okta Verify on both a phone and a watch
+pub struct LastPush {
+    service: String,
+    username: String,
+}
+
+impl LastPush {
+    pub fn new(profile: &AppProfile) -> LastPush {
+        LastPush {
+            service: CredentialType::Push.service(profile),
+            username: profile.username.clone(),
+        }
+    }
+
+    pub fn load(&self) -> Option<String> {
+        keystore::get_cached(&self.service, &self.username).ok()
+    }
+
+    pub fn forget(&self) {
+        let _ = keystore::delete_password(&self.service, &self.username)
+            .map_err(|e| debug!("Couldn't forget the Push factor: {}", e));
+    }
+
+    pub fn store(&self, factor_id: &str) {
+        if self.load().as_deref() != Some(factor_id) {
+            let _ = keystore::set_password(&self.service, &self.username, factor_id)
+                .map_err(|e| debug!("Couldn't remember the Push factor: {}", e));
+        }
+    }
+}
 
 impl Client {
     pub fn get_session_token(&self, response: Response) -> Result<String> {
         trace!("Session token response input: {:?}", response);
 
         match response.status {
-            Status::Unauthenticated => Err(anyhow!(
-                "Username or password wrong. Please check them and try again"
+            Status::Unauthenticated => Err(error::error(
+                ErrorKind::Authentication,
+                "Username or password wrong. Please check them and try again",
             )),
             Status::Success => {
-                eprintln!("Authentication successful!");
+                ui::print_success("Authentication successful!");
                 Ok(response
                     .session_token
                     .expect("The session token is missing from the success response"))
@@ -32,72 +89,70 @@ impl Client {
                     .state_token
                     .clone()
                     .with_context(|| "Missing state token in response")?;
-                let factors = filter_factors(
-                    response
-                        .embedded
-                        .expect("Missing embedded information for MFA challenge")
-                        .factors
-                        .expect("Missing factor for MFA challenge"),
-                );
+                let factors = filter_provider(
+                    available_factors(&response, &self.base_url)?,
+                    self.factor_provider.as_ref(),
+                    self.factor_selector.as_ref(),
+                )?;
+                if let Some(user) = response.user() {
+                    debug!("MFA required for {}", user.profile.login);
+                }
 
-                let factor = select_factor(factors)?;
+                // A factor chosen explicitly wins over the fallback order
+                if !self.mfa_fallback.is_empty()
+                    && self.factor_selector.is_none()
+                    && self.factor_index.is_none()
+                    && self.mfa_code.is_none()
+                {
+                    let factors = match self.push_device.as_deref() {
+                        Some(device) => select_push_device(factors, device)?,
+                        None => factors,
+                    };
 
-                let verification_request = match factor {
-                    Factor::Sms { .. } => VerificationRequest::Sms {
-                        state_token,
-                        pass_code: None,
-                    },
-                    Factor::Totp { .. } => {
-                        let mfa_code = utils::prompt_mfa()?;
+                    return verify_with_fallback(&factors, &self.mfa_fallback, |factor| {
+                        self.verify_factor(factor, state_token.clone())
+                    });
+                }
 
-                        VerificationRequest::Totp {
-                            state_token,
-                            pass_code: mfa_code,
-                        }
-                    }
-                    Factor::Push { .. } => VerificationRequest::Push { state_token },
-                    Factor::WebAuthn { .. } => VerificationRequest::WebAuthn {
-                        state_token,
-                        authenticator_data: None,
-                        signature_data: None,
-                        client_data: None,
-                    },
-                    _ => return Err(anyhow!("The selected factor isn't implemented")),
-                };
+                let factor = select_factor(
+                    factors.clone(),
+                    self.factor_selector.as_ref(),
+                    self.factor_index,
+                    self.push_device.as_deref(),
+                    self.last_push.load().as_deref(),
+                )?;
+                check_mfa_code(&factor, self.mfa_code.as_deref())?;
 
-                debug!("Verification request: {:#?}", &verification_request);
+                // Only a factor picked from the menu may be swapped for another
+                if self.factor_selector.is_none()
+                    && self.factor_index.is_none()
+                    && self.mfa_code.is_none()
+                {
+                    return verify_switching_factors(factors, factor, |factor| {
+                        self.verify_factor(factor, state_token.clone())
+                    });
+                }
 
-                let verification_response = self.verify(&factor, &verification_request)?;
-                self.get_session_token(verification_response)
+                self.verify_factor(&factor, state_token)
             }
             Status::MfaChallenge => {
                 let state_token = response
                     .state_token
                     .clone()
                     .with_context(|| "Missing state token in response")?;
-                let factor = response
-                    .embedded
-                    .expect("Missing embedded information for MFA challenge")
-                    .factor
-                    .expect("Missing factor for MFA challenge");
+                let factor = response.factor()?;
                 let links = response
                     .links
                     .clone()
                     .expect("Missing verification links for factor");
 
-                if let Some(fr) = response.factor_result {
-                    match fr {
-                        FactorResult::Rejected | FactorResult::Timeout => {
-                            eprintln!("{}", fr);
-                            return Err(anyhow!("Authentication failed"));
-                        }
-                        _ => (),
-                    }
-                };
+                if let Some(fr) = &response.factor_result {
+                    check_factor_result(fr)?;
+                }
 
                 let factor_verification_request = match factor {
                     Factor::Sms { .. } => {
-                        let mfa_code = utils::prompt_mfa()?;
+                        let mfa_code = mfa_code(self.mfa_code.as_deref(), None)?;
 
                         VerificationRequest::Sms {
                             state_token,
@@ -105,12 +160,18 @@ impl Client {
                         }
                     }
                     Factor::Push { .. } => VerificationRequest::Push { state_token },
-                    // Factor::WebAuthn { .. } => {
-                    //     unimplemented!()
-                    //     let challenge =
-                    //     embedded.expect("Missing embedded challenge for WebAuthn factor");
-                    //     get_webauthn_verification_request(&challenge)?
-                    // }
+                    Factor::WebAuthn { .. } | Factor::U2f { .. } => {
+                        let key = security_key::platform_key()?;
+                        let challenge = security_key::key_challenge(&factor)?;
+
+                        timings::measure_bounded(Phase::MfaWait, || {
+                            security_key::verification_request(
+                                state_token,
+                                &challenge,
+                                key.as_ref(),
+                            )
+                        })?
+                    }
                     _ => return Err(anyhow!("Unknown challenge received for MFA type")),
                 };
 
@@ -120,72 +181,349 @@ impl Client {
                 );
 
                 let verification_response = match factor {
-                    Factor::Push { .. } => {
-                        self.poll_for_push_result(&links, &factor_verification_request)?
+                    Factor::Push { .. } => timings::measure_bounded(Phase::MfaWait, || {
+                        ui::explain("Waiting for the push notification to be approved…");
+                        self.poll_for_push_result(&links, &factor_verification_request)
+                    })?,
+                    // The challenged factor comes without links, the signature
+                    // goes to the response's next link
+                    Factor::WebAuthn { .. } | Factor::U2f { .. } => {
+                        self.poll(&links, &factor_verification_request)?
                     }
-                    _ => self.verify(&factor, &factor_verification_request)?,
+                    _ => self
+                        .verify(&factor, &factor_verification_request)
+                        .map_err(explain_invalid_passcode)?,
                 };
 
                 trace!("Factor Verification Response: {:?}", verification_response);
 
                 self.get_session_token(verification_response)
             }
+            Status::MfaEnroll => Err(error::error(
+                ErrorKind::Authentication,
+                enroll::enrollment_required(&response),
+            )),
             _ => Err(anyhow!("Unknown response status received, bailing!")),
         }
     }
 
+    fn verify_factor(&self, factor: &Factor, state_token: String) -> Result<String> {
+        ui::explain(&format!("Selected factor: {}", factor));
+
+        if let Factor::Push { ref id, .. } = factor {
+            self.last_push.store(id);
+        }
+
+        // SMS, Push and security keys are challenged first and verified
+        // once Okta answers with MFA_CHALLENGE, TOTP is verified right away
+        match factor {
+            Factor::Sms { .. } | Factor::Push { .. } => {
+                let challenge_response = self.challenge(factor, state_token)?;
+                self.get_session_token(challenge_response)
+            }
+            Factor::WebAuthn { .. } | Factor::U2f { .. } => {
+                security_key::platform_key()?;
+                let challenge_response = self.challenge(factor, state_token)?;
+                self.get_session_token(challenge_response)
+            }
+            Factor::Totp { provider, .. } => {
+                let verify = |pass_code| {
+                    let verification_request = VerificationRequest::Totp {
+                        state_token: state_token.clone(),
+                        pass_code,
+                    };
+                    debug!("Verification request: {:#?}", &verification_request);
+                    self.verify(factor, &verification_request)
+                };
+
+                let verification_response = match (self.mfa_code.as_deref(), self.totp.as_ref()) {
+                    (None, Some(parameters)) => verify_generated_totp(
+                        &parameters.with_defaults(totp_format(provider)),
+                        verify,
+                    ),
+                    (given, _) => verify(vip_code(provider, mfa_code(given, None)?)?),
+                }
+                .map_err(explain_invalid_passcode)?;
+                self.get_session_token(verification_response)
+            }
+            Factor::Token { provider, .. } => {
+                // Okta verifies tokens with the same payload as TOTP
+                let verify = |state_token, pass_code| {
+                    let verification_request = VerificationRequest::Totp {
+                        state_token,
+                        pass_code,
+                    };
+                    debug!("Verification request: {:#?}", &verification_request);
+                    self.verify(factor, &verification_request)
+                };
+
+                let verification_response = verify_token(
+                    state_token,
+                    vip_code(provider, mfa_code(self.mfa_code.as_deref(), None)?)?,
+                    verify,
+                    prompt_next_tokencode,
+                )
+                .map_err(explain_invalid_passcode)?;
+                self.get_session_token(verification_response)
+            }
+            _ => Err(anyhow!("The selected factor isn't implemented")),
+        }
+    }
+
     fn poll_for_push_result(
         &self,
         links: &HashMap<String, Links>,
         req: &VerificationRequest,
     ) -> Result<Response> {
-        let mut verification_response = self.poll(links, req)?;
-        let time_at_execution = Utc::now();
-        let mut tick = String::new();
-        let term = Term::stderr();
-
-        while timeout_not_reached(time_at_execution) {
-            verification_response = self.poll(links, req)?;
-            term.clear_last_lines(1)?;
-
-            match verification_response.factor_result.clone() {
-                Some(r) if r == FactorResult::Waiting || r == FactorResult::Challenge => {
-                    let answer = fetch_correct_push_answer(&verification_response);
-
-                    if let Some(a) = answer {
-                        let message = format!("The correct answer is: {}. {}{}", a, r, tick);
-                        term.write_line(&message)?;
-                    } else {
-                        let message = format!("{}{}", r, tick);
-                        term.write_line(&message)?;
-                    };
+        wait_for_push(&self.profile, || self.poll(links, req))
+    }
+}
+
+fn wait_for_push<F>(profile: &AppProfile, poll: F) -> Result<Response>
+where
+    F: Fn() -> Result<Response>,
+{
+    let mut verification_response = poll()?;
+    let mut interval = poll_interval(&verification_response, BACKOFF_TIMEOUT);
+    let time_at_execution = Utc::now();
+    let mut tick = String::new();
+    let mut notified = false;
+    let prompter = prompt::current();
+
+    while timeout_not_reached(time_at_execution) {
+        timings::check_deadline(Phase::MfaWait)?;
+        verification_response = poll()?;
+
+        // The factor result tells how the push ended more reliably than the
+        // status, which stays MFA_CHALLENGE unless the push succeeded
+        match verification_response.factor_result.clone() {
+            Some(r) if r == FactorResult::Waiting || r == FactorResult::Challenge => {
+                let answer = fetch_correct_push_answer(&verification_response);
 
-                    tick.push('.');
-                    thread::sleep(BACKOFF_TIMEOUT);
-                    continue;
+                if !notified {
+                    notify::notify(profile, &push_notification(answer));
+                    notified = true;
                 }
-                _ => break,
+
+                if let Some(a) = answer {
+                    let message = format!(
+                        "The correct answer is: {}. {}{}",
+                        ui::emphasis(&a.to_string()),
+                        r,
+                        tick
+                    );
+                    prompter.progress(&message)?;
+                } else {
+                    let message = format!("{}{}", r, tick);
+                    prompter.progress(&message)?;
+                };
+
+                tick.push('.');
+                interval = poll_interval(&verification_response, interval);
+                thread::sleep(timings::until_deadline(interval));
+                continue;
             }
+            Some(r) => {
+                check_factor_result(&r)?;
+                break;
+            }
+            None => break,
+        }
+    }
+
+    Ok(verification_response)
+}
+
+// The interval Okta recommends in `response`, otherwise the `current` one
+fn poll_interval(response: &Response, current: Duration) -> Duration {
+    match response.poll_interval() {
+        Some(interval) => interval.clamp(MIN_POLL_INTERVAL, MAX_POLL_INTERVAL),
+        None => current,
+    }
+}
+
+// Fails for factor results ending the verification without success
+fn check_factor_result(result: &FactorResult) -> Result<()> {
+    let kind = match result {
+        FactorResult::Rejected | FactorResult::Cancelled => ErrorKind::MfaRejected,
+        FactorResult::Timeout => ErrorKind::MfaTimeout,
+        FactorResult::Error => ErrorKind::Okta,
+        FactorResult::Waiting | FactorResult::Challenge | FactorResult::Success => return Ok(()),
+    };
+
+    Err(error::error(
+        kind,
+        format!("Authentication failed: {}", result),
+    ))
+}
+
+// A code given with --mfa-code wins over a stored TOTP secret and the prompt
+fn mfa_code(given: Option<&str>, totp: Option<&TotpParameters>) -> Result<String> {
+    match (given, totp) {
+        (Some(code), _) => Ok(code.to_owned()),
+        (None, Some(parameters)) => totp::current_totp(parameters),
+        (None, None) => utils::prompt_mfa(),
+    }
+}
+
+// Secrets stored without parameters generate the codes the factor's
+// authenticator app would. Okta Verify sticks to the RFC 6238 defaults
+fn totp_format(provider: &FactorProvider) -> TotpFormat {
+    match provider {
+        FactorProvider::Google => TotpFormat::GOOGLE_AUTHENTICATOR,
+        _ => TotpFormat::default(),
+    }
+}
+
+// Symantec VIP shows 6 digit security codes. Anything else, like the
+// credential ID printed on the token, is refused before Okta counts it as a
+// failed attempt. Codes of other providers go to Okta as they are
+fn vip_code(provider: &FactorProvider, code: String) -> Result<String> {
+    let digits = code.split_whitespace().collect::<String>();
+
+    match provider {
+        FactorProvider::Symantec
+            if digits.len() != VIP_CODE_LENGTH || !digits.chars().all(|c| c.is_ascii_digit()) =>
+        {
+            Err(anyhow!(
+                "Symantec VIP security codes are {} digits, please enter the code the token shows \
+                 rather than its credential ID",
+                VIP_CODE_LENGTH
+            ))
         }
+        FactorProvider::Symantec => Ok(digits),
+        _ => Ok(code),
+    }
+}
+
+// RSA SecurID may ask for the next tokencode to resynchronize the token.
+// Okta tells so with a CHALLENGE result instead of the session, and the next
+// code is verified like the first, once
+fn verify_token<V, N>(
+    state_token: String,
+    code: String,
+    verify: V,
+    next_code: N,
+) -> Result<Response>
+where
+    V: Fn(String, String) -> Result<Response>,
+    N: FnOnce() -> Result<String>,
+{
+    let response = verify(state_token.clone(), code)?;
+    if !asks_for_next_code(&response) {
+        return Ok(response);
+    }
 
-        Ok(verification_response)
+    debug!("Okta asks for the next tokencode to resynchronize the token");
+    let state_token = response.state_token.unwrap_or(state_token);
+    let response = verify(state_token, next_code()?)?;
+
+    match asks_for_next_code(&response) {
+        true => Err(error::error(
+            ErrorKind::MfaRejected,
+            "The token is still out of sync after the next tokencode, \
+             please ask your administrator to resynchronize it",
+        )),
+        false => Ok(response),
     }
 }
 
-fn select_factor(factors: Vec<Factor>) -> Result<Factor> {
-    let factor = match factors.len() {
-        0 => return Err(anyhow!("MFA required, and no available factors")),
-        1 => {
+fn asks_for_next_code(response: &Response) -> bool {
+    response.status == Status::MfaChallenge
+        && response.factor_result == Some(FactorResult::Challenge)
+}
+
+fn prompt_next_tokencode() -> Result<String> {
+    timings::measure_bounded(Phase::MfaWait, || {
+        prompt::current()
+            .input("Wait for the token to change, then enter the next tokencode")
+            .with_context(|| "Failed to get MFA input")
+    })
+}
+
+// A code generated from the stored secret may be rejected because the local
+// clock is off, so the code of the adjacent time step is tried once before
+// giving up. Codes the user entered are never retried
+fn verify_generated_totp<T, V>(parameters: &TotpParameters, verify: V) -> Result<T>
+where
+    V: Fn(String) -> Result<T>,
+{
+    match verify(totp::current_totp(parameters)?) {
+        Err(e) if is_invalid_passcode(&e) => {
+            debug!("Okta rejected the generated TOTP code, trying the adjacent time step in case the clock is off");
+            verify(totp::adjacent_totp(parameters)?)
+        }
+        result => result,
+    }
+}
+
+// Only factors asking for a code can make use of --mfa-code
+fn check_mfa_code(factor: &Factor, mfa_code: Option<&str>) -> Result<()> {
+    match mfa_code {
+        Some(_) if !factor.requires_code() => Err(anyhow!(
+            "--mfa-code only works with TOTP, token or SMS factors, but the selected factor is {}",
+            factor
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn select_factor(
+    factors: Vec<Factor>,
+    selector: Option<&FactorSelector>,
+    index: Option<usize>,
+    push_device: Option<&str>,
+    last_push: Option<&str>,
+) -> Result<Factor> {
+    let factors = match selector {
+        Some(selector) => {
+            let matching = factors
+                .iter()
+                .filter(|f| selector.matches(f))
+                .cloned()
+                .collect::<Vec<Factor>>();
+
+            if matching.is_empty() {
+                return Err(anyhow!(
+                    "No MFA factor matching {} available, available factors are: {}",
+                    selector,
+                    menu_labels(&factors).join(", ")
+                ));
+            }
+
+            matching
+        }
+        None => factors,
+    };
+    let factors = match push_device {
+        Some(device) => select_push_device(factors, device)?,
+        None => factors,
+    };
+
+    let factor = match (factors.len(), index) {
+        (0, _) => return Err(anyhow!("MFA required, and no available factors")),
+        (len, Some(index)) => factors[utils::select_index(index, len, "Factor")?].clone(),
+        (1, None) => {
             info!("Only one factor available, using it");
             factors[0].clone()
         }
         _ => {
-            eprintln!("Please select the factor to use:");
-            let mut menu = dialoguer::Select::new();
-            for factor in &factors {
-                menu.item(&factor.to_string());
-            }
-            factors[menu.interact()?].clone()
+            let labels = menu_labels(&factors);
+            let default = last_push
+                .and_then(|id| {
+                    factors
+                        .iter()
+                        .position(|f| f.kind() == Some("push") && f.id() == Some(id))
+                })
+                .unwrap_or(0);
+            factors[timings::measure_bounded(Phase::MfaWait, || {
+                prompt::current().select_with_default(
+                    "Please select the factor to use:",
+                    &labels,
+                    default,
+                )
+            })?]
+            .clone()
         }
     };
 
@@ -194,18 +532,289 @@ fn select_factor(factors: Vec<Factor>) -> Result<Factor> {
     Ok(factor)
 }
 
-// fn get_webauthn_verification_request(challenge: &FactorChallenge) -> Result<VerificationRequest> {}
+// Tries the first available factor of each kind in `order`, e.g. Push and
+// then TOTP. A factor that times out or can't be verified on this machine
+// makes way for the next one, a rejected one ends the login
+fn verify_with_fallback<T, F>(
+    factors: &[Factor],
+    order: &[FactorSelector],
+    mut verify: F,
+) -> Result<T>
+where
+    F: FnMut(&Factor) -> Result<T>,
+{
+    let mut last_error = None;
+
+    for selector in order {
+        let factor = match factors.iter().find(|f| selector.matches(f)) {
+            Some(factor) => factor,
+            None => {
+                debug!("No {} factor available, falling back", selector);
+                continue;
+            }
+        };
+
+        if let (Factor::WebAuthn { .. }, Err(e)) | (Factor::U2f { .. }, Err(e)) =
+            (factor, security_key::platform_key())
+        {
+            debug!("{}, falling back", e);
+            continue;
+        }
+
+        match verify(factor) {
+            Ok(result) => return Ok(result),
+            Err(e) if error::kind_of(&e) == ErrorKind::MfaTimeout => {
+                prompt::current().message(&ui::warning(&format!(
+                    "{} timed out, trying the next MFA factor",
+                    factor
+                )));
+                last_error = Some(e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| {
+        anyhow!(
+            "None of the MFA factors {} is available, available factors are: {}",
+            order
+                .iter()
+                .map(|s| s.to_string())
+                .collect::<Vec<String>>()
+                .join(", "),
+            menu_labels(factors).join(", ")
+        )
+    }))
+}
+
+// Offers the other factors once the chosen one times out or gets rejected,
+// e.g. Push to a phone with a dead battery, continuing the same login
+fn verify_switching_factors<T, F>(factors: Vec<Factor>, factor: Factor, mut verify: F) -> Result<T>
+where
+    F: FnMut(&Factor) -> Result<T>,
+{
+    let mut factors = factors;
+    let mut factor = factor;
+
+    loop {
+        let e = match verify(&factor) {
+            Ok(result) => return Ok(result),
+            Err(e) => e,
+        };
+
+        factors.retain(|f| f != &factor);
+        match error::kind_of(&e) {
+            ErrorKind::MfaTimeout | ErrorKind::MfaRejected if !factors.is_empty() => (),
+            _ => return Err(e),
+        }
+
+        prompt::current().message(&ui::warning(&format!("{} failed: {}", factor, e)));
+        let mut labels = menu_labels(&factors);
+        labels.push(GIVE_UP.to_string());
+
+        let index = timings::measure_bounded(Phase::MfaWait, || {
+            prompt::current().select("Please select another factor to use:", &labels)
+        })?;
+        factor = match factors.get(index) {
+            Some(factor) => factor.clone(),
+            None => return Err(e),
+        };
+    }
+}
+
+// Leaves the other factors alone, so that a --push-device from the profile
+// doesn't get in the way of choosing e.g. TOTP
+fn select_push_device(factors: Vec<Factor>, device: &str) -> Result<Vec<Factor>> {
+    let pushes = factors
+        .iter()
+        .filter(|f| f.kind() == Some("push"))
+        .cloned()
+        .collect::<Vec<Factor>>();
+
+    if !pushes.is_empty() && !pushes.iter().any(|f| f.is_push_device(device)) {
+        return Err(anyhow!(
+            "No Okta Verify Push factor of device {} available, available Push factors are: {}",
+            device,
+            menu_labels(&pushes).join(", ")
+        ));
+    }
+
+    Ok(factors
+        .into_iter()
+        .filter(|f| f.kind() != Some("push") || f.is_push_device(device))
+        .collect())
+}
+
+/// Leaves the factors of `provider`, e.g. Duo's where Okta Verify is enrolled
+/// as well, failing if none of them is the one `selector` picks
+fn filter_provider(
+    factors: Vec<Factor>,
+    provider: Option<&FactorProvider>,
+    selector: Option<&FactorSelector>,
+) -> Result<Vec<Factor>> {
+    let provider = match provider {
+        Some(provider) => provider,
+        None => return Ok(factors),
+    };
+
+    let matching = factors
+        .iter()
+        .filter(|f| f.provider() == Some(provider))
+        .cloned()
+        .collect::<Vec<Factor>>();
+
+    let wanted = match selector {
+        Some(selector) if !matching.iter().any(|f| selector.matches(f)) => {
+            format!("{} factor of provider {}", selector.kind, provider)
+        }
+        None if matching.is_empty() => format!("factor of provider {}", provider),
+        _ => return Ok(matching),
+    };
+
+    Err(anyhow!(
+        "No MFA {} available, available factors are: {}",
+        wanted,
+        menu_labels(&factors).join(", ")
+    ))
+}
+
+/// The factors the menu offers without a `--mfa-factor`, in its order
+pub fn menu_factors(
+    factors: Vec<Factor>,
+    provider: Option<&FactorProvider>,
+    push_device: Option<&str>,
+) -> Result<Vec<Factor>> {
+    let factors = filter_provider(filter_factors(factors), provider, None)?;
+
+    match push_device {
+        Some(device) => select_push_device(factors, device),
+        None => Ok(factors),
+    }
+}
+
+/// The factors the menu offers, as listed by `creds --factor-list-only`
+pub fn list_factors(factors: &[Factor]) -> Vec<ListedFactor<'_>> {
+    factors
+        .iter()
+        .zip(menu_labels(factors))
+        .enumerate()
+        .filter_map(|(index, (factor, label))| {
+            Some(ListedFactor {
+                index,
+                selector: format!("{}:{}", factor.kind()?, factor.provider()?),
+                label,
+                factor,
+            })
+        })
+        .collect()
+}
+
+// Factors of the same type from different providers (e.g. Okta Verify and
+// Google Authenticator TOTP) get the matching --mfa-factor selector appended.
+// Factors that still look the same, e.g. Push to two devices of the same
+// name, get the end of their ID appended, which --push-device accepts
+fn menu_labels(factors: &[Factor]) -> Vec<String> {
+    let labels = factors
+        .iter()
+        .map(|factor| {
+            let shared_kind = factors
+                .iter()
+                .filter(|f| f.kind() == factor.kind() && f.provider() != factor.provider())
+                .count()
+                > 0;
+
+            match (shared_kind, factor.kind(), factor.provider()) {
+                (true, Some(kind), Some(provider)) => {
+                    format!("{} ({}:{})", factor, kind, provider)
+                }
+                _ => factor.to_string(),
+            }
+        })
+        .collect::<Vec<String>>();
+
+    labels
+        .iter()
+        .zip(factors)
+        .map(|(label, factor)| {
+            match (
+                labels.iter().filter(|l| *l == label).count() > 1,
+                factor.id(),
+            ) {
+                (true, Some(id)) => format!("{} (ID …{})", label, id_suffix(id)),
+                _ => label.clone(),
+            }
+        })
+        .collect()
+}
+
+fn id_suffix(id: &str) -> String {
+    let chars = id.chars().collect::<Vec<char>>();
+    chars[chars.len().saturating_sub(ID_SUFFIX_LENGTH)..]
+        .iter()
+        .collect()
+}
 
 fn timeout_not_reached(time: DateTime<Utc>) -> bool {
     time.signed_duration_since(Utc::now()).num_seconds() < PUSH_WAIT_TIMEOUT
 }
 
+// The menu, --factor-index and --factor-list-only count the factors in this
+// order. Okta lists them in no particular order, so they're sorted by priority
+// and then by id, which keeps indices stable for scripts answering the menu.
+// The provider only tells apart factors Okta gave the same id
 fn filter_factors(factors: Vec<Factor>) -> Vec<Factor> {
+    let mut factors = factors
+        .into_iter()
+        .filter(|f| *f != Factor::Unimplemented)
+        .collect::<Vec<Factor>>();
+    factors.sort_by_cached_key(|f| {
+        (
+            f.priority(),
+            f.id().map(str::to_string),
+            f.provider().map(|p| p.to_string()),
+        )
+    });
+
     factors
-        .iter()
-        .filter(|f| **f != Factor::Unimplemented)
-        .cloned()
-        .collect()
+}
+
+// Factors Okta asks for with MFA_REQUIRED, failing if a policy demands MFA
+// without any factor enrolled that crowbar could verify
+fn available_factors(response: &Response, base_url: &Url) -> Result<Vec<Factor>> {
+    let enrolled = response.factors().unwrap_or_default();
+    let factors = filter_factors(enrolled.clone());
+
+    if !factors.is_empty() {
+        return Ok(factors);
+    }
+
+    let problem = match enrolled.len() {
+        0 => "no factors are enrolled".to_string(),
+        n => format!(
+            "none of the {} enrolled factors is supported by crowbar, which supports \
+             Okta Verify Push, TOTP, Symantec VIP, SMS and security keys",
+            n
+        ),
+    };
+
+    Err(error::error(
+        ErrorKind::Authentication,
+        format!(
+            "Your org requires MFA but {}. Enroll a factor at {} and try again",
+            problem,
+            base_url.join(ENROLLMENT_PATH)?
+        ),
+    ))
+}
+
+fn push_notification(answer: Option<u64>) -> String {
+    match answer {
+        Some(a) => format!(
+            "Approve the Okta Verify push on your phone by choosing {}",
+            a
+        ),
+        None => "Approve the Okta Verify push on your phone".to_string(),
+    }
 }
 
 fn fetch_correct_push_answer(response: &Response) -> Option<u64> {
@@ -228,10 +837,23 @@ fn fetch_correct_push_answer(response: &Response) -> Option<u64> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::clock::{self, test::FixedClock};
+    use crate::config::app::test::{okta_profile, OKTA_URL};
+    use crate::credentials::config::ConfigCredentials;
+    use crate::credentials::keystore::test::MemoryBackend;
+    use crate::credentials::totp::TotpCredentials;
+    use crate::credentials::Credential;
+    use crate::notify::test::RecordingNotifier;
+    use crate::prompt::test::ScriptedPrompter;
+    use crate::prompt::Prompter;
+    use crate::providers::okta::error::ErrorResponse;
     use crate::providers::okta::factors::FactorProvider;
     use crate::providers::okta::factors::{Factor, SmsFactorProfile};
     use chrono::NaiveDateTime;
+    use claim::{assert_err, assert_ok};
+    use std::cell::{Cell, RefCell};
     use std::fs;
+    use std::rc::Rc;
 
     #[test]
     fn should_reach_timeout() -> Result<()> {
@@ -239,7 +861,7 @@ mod test {
             NaiveDateTime::parse_from_str("2038-01-01T10:10:10", "%Y-%m-%dT%H:%M:%S")?,
             Utc,
         );
-        assert_eq!(false, timeout_not_reached(dt));
+        assert!(!timeout_not_reached(dt));
         Ok(())
     }
 
@@ -247,21 +869,50 @@ mod test {
     fn should_not_reach_timeout() -> Result<()> {
         let dt = Utc::now();
         thread::sleep(Duration::from_secs(3));
-        assert_eq!(true, timeout_not_reached(dt));
+        assert!(timeout_not_reached(dt));
+        Ok(())
+    }
+
+    #[test]
+    fn explains_mfa_required_without_usable_factors() -> Result<()> {
+        let base_url = Url::parse("https://example.okta.com")?;
+        let mut response: Response = serde_json::from_str(&fs::read_to_string(
+            "tests/fixtures/okta/login_response_mfa_required.json",
+        )?)?;
+        assert!(!available_factors(&response, &base_url)?.is_empty());
+
+        let enrolled = response
+            .embedded
+            .as_mut()
+            .unwrap()
+            .factors
+            .as_mut()
+            .unwrap();
+        *enrolled = vec![Factor::Unimplemented, Factor::Unimplemented];
+        let e = available_factors(&response, &base_url).unwrap_err();
+        assert_eq!(ErrorKind::Authentication, crate::error::kind_of(&e));
+        assert_eq!(
+            "Your org requires MFA but none of the 2 enrolled factors is supported by crowbar, \
+             which supports Okta Verify Push, TOTP, Symantec VIP, SMS and security keys. \
+             Enroll a factor at https://example.okta.com/enduser/settings and try again",
+            e.to_string()
+        );
+
+        response.embedded.as_mut().unwrap().factors = Some(vec![]);
+        assert_eq!(
+            "Your org requires MFA but no factors are enrolled. \
+             Enroll a factor at https://example.okta.com/enduser/settings and try again",
+            available_factors(&response, &base_url)
+                .unwrap_err()
+                .to_string()
+        );
+
         Ok(())
     }
 
     #[test]
     fn filters_unknown_factors() -> Result<()> {
-        let sms_factor = Factor::Sms {
-            id: "id".to_string(),
-            links: None,
-            profile: SmsFactorProfile {
-                phone_number: "12345".to_string(),
-            },
-            status: None,
-            provider: FactorProvider::Okta,
-        };
+        let sms_factor = sms_factor();
 
         let factors = vec![
             Factor::Unimplemented,
@@ -278,6 +929,331 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn selects_factor_by_qualified_selector() -> Result<()> {
+        let factors = login_factors()?;
+
+        let selector: FactorSelector = "totp:google".parse()?;
+        let factor = select_factor(factors.clone(), Some(&selector), None, None, None)?;
+        assert_eq!(Some(&FactorProvider::Google), factor.provider());
+        assert_eq!(Some("totp"), factor.kind());
+
+        let selector: FactorSelector = "sms".parse()?;
+        let factor = select_factor(factors.clone(), Some(&selector), None, None, None)?;
+        assert_eq!(Some("sms"), factor.kind());
+
+        let selector: FactorSelector = "totp:okta".parse()?;
+        let factor = select_factor(factors.clone(), Some(&selector), None, None, None)?;
+        assert_eq!(Some(&FactorProvider::Okta), factor.provider());
+
+        let selector: FactorSelector = "sms:google".parse()?;
+        assert_err!(select_factor(factors, Some(&selector), None, None, None));
+
+        Ok(())
+    }
+
+    #[test]
+    fn filters_factors_by_provider_before_selection() -> Result<()> {
+        let duo_push: Factor = serde_json::from_value(serde_json::json!({
+            "factorType": "push",
+            "id": "duo-push",
+            "provider": "DUO",
+        }))?;
+        let factors = vec![
+            push_factor("okta-push", "iPhone"),
+            duo_push.clone(),
+            totp_factor(FactorProvider::Okta),
+            totp_factor(FactorProvider::Google),
+        ];
+        let push: FactorSelector = "push".parse()?;
+
+        let duo = filter_provider(factors.clone(), Some(&FactorProvider::Duo), Some(&push))?;
+        assert_eq!(vec![duo_push.clone()], duo);
+        assert_eq!(duo_push, select_factor(duo, Some(&push), None, None, None)?);
+
+        let okta = filter_provider(factors.clone(), Some(&FactorProvider::Okta), Some(&push))?;
+        assert_eq!(
+            Some("okta-push"),
+            select_factor(okta, Some(&push), None, None, None)?.id()
+        );
+
+        assert_eq!(
+            factors,
+            filter_provider(factors.clone(), None, Some(&push))?
+        );
+
+        let error = filter_provider(
+            factors.clone(),
+            Some(&FactorProvider::Duo),
+            Some(&"totp".parse()?),
+        )
+        .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("No MFA totp factor of provider duo available"),
+            "{}",
+            error
+        );
+
+        let error = filter_provider(factors, Some(&FactorProvider::Rsa), None).unwrap_err();
+        assert!(
+            error.to_string().contains("factor of provider rsa"),
+            "{}",
+            error
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn selects_factor_by_index() -> Result<()> {
+        let factors = vec![totp_factor(FactorProvider::Okta), sms_factor()];
+
+        assert_eq!(
+            sms_factor(),
+            select_factor(factors.clone(), None, Some(1), None, None)?
+        );
+        assert_eq!(
+            "Factor index 2 is out of range, valid indices are 0 to 1",
+            select_factor(factors, None, Some(2), None, None)
+                .unwrap_err()
+                .to_string()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn selects_single_required_factor_without_menu() -> Result<()> {
+        let response = serde_json::from_str::<Response>(&fs::read_to_string(
+            "tests/fixtures/okta/login_response_mfa_required_single_factor.json",
+        )?)?;
+        let factors = filter_factors(response.factors()?);
+        assert_eq!(1, factors.len());
+
+        let prompter = Rc::new(ScriptedPrompter::new(&[]));
+        let factor =
+            prompt::with_prompter(prompter, || select_factor(factors, None, None, None, None))?;
+        assert_eq!(Some("factor-id-push"), factor.id());
+
+        Ok(())
+    }
+
+    #[test]
+    fn orders_menu_regardless_of_okta_order() -> Result<()> {
+        let response: serde_json::Value = serde_json::from_str(&fs::read_to_string(
+            "tests/fixtures/okta/login_response_mfa_required.json",
+        )?)?;
+        let listed = response["_embedded"]["factors"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+
+        let labels = |order: Vec<serde_json::Value>| -> Result<Vec<String>> {
+            let mut response = response.clone();
+            response["_embedded"]["factors"] = serde_json::Value::Array(order);
+            let response: Response = serde_json::from_value(response)?;
+
+            Ok(menu_labels(&filter_factors(response.factors()?)))
+        };
+
+        let expected = vec![
+            "Okta Verify Push to Android-Phone",
+            "Software TOTP (totp:google)",
+            "Okta Verify TOTP (totp:okta)",
+            "WebAuthn with YubiKey",
+            "Okta SMS to +49XXXXXXX1234",
+        ];
+        for shift in 0..listed.len() {
+            let mut order = listed.clone();
+            order.rotate_left(shift);
+            assert_eq!(expected, labels(order.clone())?, "shifted by {}", shift);
+
+            order.reverse();
+            assert_eq!(expected, labels(order)?, "reversed, shifted by {}", shift);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn disambiguates_factors_sharing_a_type() {
+        let factors = vec![
+            totp_factor(FactorProvider::Okta),
+            totp_factor(FactorProvider::Google),
+            sms_factor(),
+        ];
+
+        assert_eq!(
+            vec![
+                "Okta Verify TOTP (totp:okta)",
+                "Software TOTP (totp:google)",
+                "Okta SMS to 12345"
+            ],
+            menu_labels(&factors)
+        );
+    }
+
+    #[test]
+    fn tells_push_devices_apart_in_menu() -> Result<()> {
+        let factors = vec![
+            push_factor("factor-id-phone", "iPhone"),
+            push_factor("factor-id-watch", "Apple Watch"),
+            sms_factor(),
+        ];
+        assert_eq!(
+            vec![
+                "Okta Verify Push to iPhone",
+                "Okta Verify Push to Apple Watch",
+                "Okta SMS to 12345"
+            ],
+            menu_labels(&factors)
+        );
+
+        let prompter = Rc::new(MenuPrompter::default());
+        let factor = prompt::with_prompter(prompter.clone(), || {
+            select_factor(
+                vec![
+                    push_factor("factor-id-a1b2c3", "iPhone"),
+                    push_factor("factor-id-d4e5f6", "iPhone"),
+                ],
+                Some(&"push".parse()?),
+                None,
+                None,
+                None,
+            )
+        })?;
+
+        assert_eq!(push_factor("factor-id-a1b2c3", "iPhone"), factor);
+        assert_eq!(
+            vec![
+                "Okta Verify Push to iPhone (ID …a1b2c3)",
+                "Okta Verify Push to iPhone (ID …d4e5f6)"
+            ],
+            *prompter.items.borrow()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn selects_push_device() -> Result<()> {
+        let factors = vec![
+            push_factor("factor-id-phone", "iPhone"),
+            push_factor("factor-id-watch", "Apple Watch"),
+        ];
+
+        assert_eq!(
+            factors[1],
+            select_factor(factors.clone(), None, None, Some("apple watch"), None)?
+        );
+        assert_eq!(
+            factors[0],
+            select_factor(factors.clone(), None, None, Some("phone"), None)?
+        );
+
+        let error = select_factor(factors, None, None, Some("iPad"), None)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("No Okta Verify Push factor of device iPad"));
+        assert!(error.contains("Okta Verify Push to Apple Watch"));
+
+        // A device doesn't get in the way of factors other than Push
+        let totp = totp_factor(FactorProvider::Okta);
+        assert_eq!(
+            totp,
+            select_factor(vec![totp.clone()], None, None, Some("iPad"), None)?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn preselects_last_used_push_device() -> Result<()> {
+        let factors = vec![
+            sms_factor(),
+            push_factor("factor-id-phone", "iPhone"),
+            push_factor("factor-id-watch", "Apple Watch"),
+        ];
+        let prompter = Rc::new(MenuPrompter::default());
+
+        let factor = prompt::with_prompter(prompter, || {
+            select_factor(factors.clone(), None, None, None, Some("factor-id-watch"))
+        })?;
+        assert_eq!(factors[2], factor);
+
+        let factor = prompt::with_prompter(Rc::new(MenuPrompter::default()), || {
+            select_factor(factors.clone(), None, None, None, Some("factor-id-gone"))
+        })?;
+        assert_eq!(factors[0], factor);
+
+        Ok(())
+    }
+
+    #[test]
+    fn remembers_last_used_push_device() {
+        let profile: AppProfile = toml::from_str(
+            r#"
+            name = "profile"
+            provider = "okta"
+            url = "https://example.okta.com/home/amazon_aws/0oa1b2c3d4/272"
+            username = "username"
+        "#,
+        )
+        .unwrap();
+
+        keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+            let last_push = LastPush::new(&profile);
+            assert_eq!(None, last_push.load());
+
+            last_push.store("factor-id-watch");
+            assert_eq!(
+                Some("factor-id-watch".to_string()),
+                LastPush::new(&profile).load()
+            );
+        });
+    }
+
+    #[test]
+    fn prompts_only_for_inputs_not_stored() -> Result<()> {
+        let profile: AppProfile = toml::from_str(
+            r#"
+            name = "profile"
+            provider = "okta"
+            url = "https://example.okta.com/home/amazon_aws/0oa1b2c3d4/272"
+            username = "username"
+        "#,
+        )?;
+
+        keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+            ConfigCredentials::new("password".to_string()).write(&profile)?;
+            let prompter = Rc::new(ScriptedPrompter::new(&["123456"]));
+
+            let (password, code) = prompt::with_prompter(prompter.clone(), || -> Result<_> {
+                let password = ConfigCredentials::obtain(&profile)?.password;
+                let code = mfa_code(None, totp::stored_parameters(&profile).as_ref())?;
+                Ok((password, code))
+            })?;
+
+            assert_eq!(("password", "123456"), (password.as_str(), code.as_str()));
+            assert!(prompter.is_done());
+
+            // A broken secret only costs the code prompt, not the password's
+            TotpCredentials::new("not base32!".to_string()).write(&profile)?;
+            let prompter = Rc::new(ScriptedPrompter::new(&["654321"]));
+            let code = prompt::with_prompter(prompter.clone(), || {
+                ConfigCredentials::obtain(&profile)?;
+                mfa_code(None, totp::stored_parameters(&profile).as_ref())
+            })?;
+
+            assert_eq!("654321", code);
+            assert!(prompter.is_done());
+
+            Ok(())
+        })
+    }
+
     #[test]
     fn parses_push_challenge_response() -> Result<()> {
         let response = serde_json::de::from_str::<Response>(&fs::read_to_string(
@@ -288,4 +1264,584 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn stops_waiting_for_push_at_deadline() -> Result<()> {
+        let challenge = fs::read_to_string("tests/fixtures/okta/challenge_response_push.json")?;
+        timings::set_deadline(Some(std::time::Instant::now() + Duration::from_millis(50)));
+
+        let result = wait_for_push(&okta_profile("profile", OKTA_URL), || {
+            Ok(serde_json::de::from_str::<Response>(&challenge)?)
+        });
+        timings::set_deadline(None);
+
+        assert_eq!(
+            "Deadline exceeded during MFA wait",
+            result.unwrap_err().to_string()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn stops_waiting_for_push_at_final_factor_result() -> Result<()> {
+        let results = vec![
+            ("REJECTED", Some(ErrorKind::MfaRejected)),
+            ("CANCELLED", Some(ErrorKind::MfaRejected)),
+            ("TIMEOUT", Some(ErrorKind::MfaTimeout)),
+            ("ERROR", Some(ErrorKind::Okta)),
+            ("SUCCESS", None),
+        ];
+
+        for (result, kind) in results {
+            let polls = Cell::new(0);
+            let outcome = wait_for_push(&okta_profile("profile", OKTA_URL), || {
+                polls.set(polls.get() + 1);
+                push_response(result)
+            });
+
+            match kind {
+                Some(kind) => assert_eq!(kind, error::kind_of(&outcome.unwrap_err())),
+                None => assert_eq!(Status::Success, outcome?.status),
+            }
+            assert_eq!(2, polls.get(), "{}", result);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn notifies_once_when_push_waits_for_approval() -> Result<()> {
+        let mut waiting: serde_json::Value = serde_json::from_str(&fs::read_to_string(
+            "tests/fixtures/okta/challenge_response_push.json",
+        )?)?;
+        waiting["_links"]["next"]["hints"]["refresh"] = serde_json::json!(0);
+
+        let notifier = Rc::new(RecordingNotifier::default());
+        let polls = Cell::new(0);
+        notify::test::with_notifier(notifier.clone(), || {
+            wait_for_push(&okta_profile("profile", OKTA_URL), || {
+                polls.set(polls.get() + 1);
+                match polls.get() {
+                    1..=3 => Ok(serde_json::from_value(waiting.clone())?),
+                    _ => push_response("SUCCESS"),
+                }
+            })
+        })?;
+
+        assert_eq!(
+            vec!["Approve the Okta Verify push on your phone by choosing 44"],
+            *notifier.messages.borrow()
+        );
+        assert_eq!(
+            "Approve the Okta Verify push on your phone",
+            push_notification(None)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn polls_push_at_interval_recommended_by_okta() -> Result<()> {
+        let recommending = |refresh: u64| -> Result<Response> {
+            let mut response: serde_json::Value = serde_json::from_str(&fs::read_to_string(
+                "tests/fixtures/okta/challenge_response_push.json",
+            )?)?;
+            response["_links"]["next"]["hints"]["refresh"] = serde_json::json!(refresh);
+            Ok(serde_json::from_value(response)?)
+        };
+
+        assert_eq!(
+            Duration::from_secs(1),
+            poll_interval(&recommending(1000)?, BACKOFF_TIMEOUT)
+        );
+        assert_eq!(
+            MIN_POLL_INTERVAL,
+            poll_interval(&recommending(0)?, BACKOFF_TIMEOUT)
+        );
+        assert_eq!(
+            BACKOFF_TIMEOUT,
+            poll_interval(&push_response("WAITING")?, BACKOFF_TIMEOUT)
+        );
+
+        // A later response without the hint keeps the recommended interval
+        let polls = Cell::new(0);
+        let started = std::time::Instant::now();
+        wait_for_push(&okta_profile("profile", OKTA_URL), || {
+            polls.set(polls.get() + 1);
+            match polls.get() {
+                1 => recommending(500),
+                2 | 3 => push_response("WAITING"),
+                _ => push_response("SUCCESS"),
+            }
+        })?;
+        assert_eq!(4, polls.get());
+        assert!(
+            started.elapsed() < BACKOFF_TIMEOUT,
+            "Waited {:?}",
+            started.elapsed()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_waiting_for_push_while_pending() -> Result<()> {
+        let polls = Cell::new(0);
+        let outcome = wait_for_push(&okta_profile("profile", OKTA_URL), || {
+            polls.set(polls.get() + 1);
+            match polls.get() {
+                1 => push_response("WAITING"),
+                2 => push_response("CHALLENGE"),
+                _ => push_response("SUCCESS"),
+            }
+        })?;
+
+        assert_eq!(Some(FactorResult::Success), outcome.factor_result);
+        assert_eq!(3, polls.get());
+
+        Ok(())
+    }
+
+    #[test]
+    fn submits_given_mfa_code() -> Result<()> {
+        let factors = login_factors()?;
+        let totp = select_factor(factors, Some(&"totp:google".parse()?), None, None, None)?;
+        let parameters: TotpParameters = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ".parse()?;
+
+        check_mfa_code(&totp, Some("123456"))?;
+        assert_eq!("123456", mfa_code(Some("123456"), Some(&parameters))?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn verifies_symantec_vip_token_with_code() -> Result<()> {
+        let vip: Factor = serde_json::from_value(serde_json::json!({
+            "factorType": "token",
+            "id": "factor-id-vip",
+            "provider": "SYMANTEC",
+            "profile": { "credentialId": "VSMT12345678" },
+            "_links": {
+                "verify": {
+                    "href": "https://example.okta.com/api/v1/authn/factors/factor-id-vip/verify"
+                }
+            }
+        }))?;
+        let mut factors = login_factors()?;
+        factors.push(vip.clone());
+        let factors = filter_factors(factors);
+
+        assert_eq!("Symantec VIP Token", vip.to_string());
+        assert_eq!(
+            vip,
+            select_factor(factors, Some(&"token:symantec".parse()?), None, None, None)?
+        );
+        assert_ok!(check_mfa_code(&vip, Some("123456")));
+        assert_eq!(
+            "https://example.okta.com/api/v1/authn/factors/factor-id-vip/verify",
+            crate::providers::okta::verification::verify_url(&vip)?.as_str()
+        );
+
+        assert_eq!(
+            "123456",
+            vip_code(&FactorProvider::Symantec, "123 456".to_string())?
+        );
+        let error = vip_code(&FactorProvider::Symantec, "VSMT12345678".to_string()).unwrap_err();
+        assert!(error.to_string().contains("6 digits"), "{}", error);
+        assert_err!(vip_code(&FactorProvider::Symantec, "12345".to_string()));
+        assert_eq!(
+            "12345",
+            vip_code(&FactorProvider::Rsa, "12345".to_string())?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn verifies_rsa_securid_tokencode() -> Result<()> {
+        let attempts = RefCell::new(vec![]);
+        let verify = |state_token: String, code: String| {
+            attempts.borrow_mut().push((state_token, code));
+            success()
+        };
+
+        // PIN and tokencode go to Okta together, as entered
+        let response = verify_token(
+            "state-token".to_string(),
+            "1234987654".to_string(),
+            verify,
+            || Err(anyhow!("Unexpected prompt for the next tokencode")),
+        )?;
+        assert_eq!(Some("session-token".to_string()), response.session_token);
+        assert_eq!(
+            vec![("state-token".to_string(), "1234987654".to_string())],
+            attempts.take()
+        );
+        assert_eq!(
+            "1234987654",
+            vip_code(&FactorProvider::Rsa, "1234987654".to_string())?
+        );
+
+        let token: Factor = serde_json::from_value(serde_json::json!({
+            "factorType": "token",
+            "id": "factor-id-rsa",
+            "provider": "RSA",
+        }))?;
+        assert_eq!("RSA SecurID", token.to_string());
+
+        Ok(())
+    }
+
+    #[test]
+    fn resynchronizes_rsa_securid_with_next_tokencode() -> Result<()> {
+        let attempts = RefCell::new(vec![]);
+        let verify = |_: String, code: String| {
+            attempts.borrow_mut().push(code);
+            match attempts.borrow().len() {
+                1 => next_tokencode(),
+                _ => success(),
+            }
+        };
+
+        let response = verify_token(
+            "state-token".to_string(),
+            "987654".to_string(),
+            verify,
+            || Ok("123456".to_string()),
+        )?;
+        assert_eq!(Some("session-token".to_string()), response.session_token);
+        assert_eq!(vec!["987654", "123456"], attempts.take());
+
+        // Okta asking once more means the token can't be resynchronized
+        let error = verify_token(
+            "state-token".to_string(),
+            "987654".to_string(),
+            |_, _| next_tokencode(),
+            || Ok("123456".to_string()),
+        )
+        .unwrap_err();
+        assert_eq!(ErrorKind::MfaRejected, crate::error::kind_of(&error));
+
+        Ok(())
+    }
+
+    fn next_tokencode() -> Result<Response> {
+        Ok(serde_json::from_str(&fs::read_to_string(
+            "tests/fixtures/okta/challenge_response_rsa_next_tokencode.json",
+        )?)?)
+    }
+
+    fn success() -> Result<Response> {
+        Ok(serde_json::from_str(
+            r#"{"expiresAt":"2038-01-01T10:10:10.000Z","status":"SUCCESS","sessionToken":"session-token"}"#,
+        )?)
+    }
+
+    #[test]
+    fn generates_totp_of_factor_provider() -> Result<()> {
+        // Google Authenticator shows secrets in lowercase groups, padded
+        let google: TotpParameters = "gezd gnbv gy3t qojq gezd gnbv gy3t qojq====".parse()?;
+        let google = google.with_defaults(totp_format(&FactorProvider::Google));
+        assert_eq!("287082", totp::generate_totp(&google, 59));
+
+        // Parameters given with the secret win over the defaults
+        let okta: TotpParameters =
+            "otpauth://totp/jdoe?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&digits=8&period=60"
+                .parse()?;
+        let okta = okta.with_defaults(totp_format(&FactorProvider::Okta));
+        assert_eq!("84755224", totp::generate_totp(&okta, 59));
+        let overridden = okta.with_defaults(totp_format(&FactorProvider::Google));
+        assert_eq!("84755224", totp::generate_totp(&overridden, 59));
+
+        Ok(())
+    }
+
+    #[test]
+    fn retries_generated_totp_with_adjacent_step() -> Result<()> {
+        let parameters: TotpParameters = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ".parse()?;
+        let incorrect_code = || -> Result<ErrorResponse> {
+            Ok(serde_json::from_str(&fs::read_to_string(
+                "tests/fixtures/okta/error_response_invalid_passcode.json",
+            )?)?)
+        };
+
+        // The local clock is 10 seconds behind Okta's, which is a step ahead
+        let okta_code = totp::generate_totp(&parameters, 1_111_111_142);
+        let attempts = RefCell::new(vec![]);
+        let accepted = clock::with_clock(FixedClock::at(1_111_111_132), || {
+            verify_generated_totp(&parameters, |code| {
+                attempts.borrow_mut().push(code.clone());
+                match code == okta_code {
+                    true => Ok(code),
+                    false => Err(anyhow::Error::new(incorrect_code()?)),
+                }
+            })
+        })?;
+        assert_eq!(okta_code, accepted);
+        assert_eq!(2, attempts.borrow().len());
+
+        // Other failures and a second rejection aren't retried
+        let attempts = Cell::new(0);
+        let error = clock::with_clock(FixedClock::at(1_111_111_132), || {
+            verify_generated_totp(&parameters, |_| -> Result<String> {
+                attempts.set(attempts.get() + 1);
+                Err(anyhow::Error::new(incorrect_code()?))
+            })
+        })
+        .unwrap_err();
+        assert!(is_invalid_passcode(&error));
+        assert_eq!(2, attempts.get());
+
+        let attempts = Cell::new(0);
+        assert_err!(verify_generated_totp(&parameters, |_| -> Result<String> {
+            attempts.set(attempts.get() + 1);
+            Err(anyhow!("Okta responded with 500 Internal Server Error"))
+        }));
+        assert_eq!(1, attempts.get());
+
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_mfa_code_for_push() -> Result<()> {
+        let factors = login_factors()?;
+        let push = select_factor(factors, Some(&"push".parse()?), None, None, None)?;
+
+        assert_ok!(check_mfa_code(&push, None));
+        let error = check_mfa_code(&push, Some("123456")).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("only works with TOTP, token or SMS factors"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn falls_back_to_totp_once_push_times_out() -> Result<()> {
+        let factors = login_factors()?;
+        let order = vec!["push".parse()?, "totp:google".parse()?];
+        let attempts = RefCell::new(vec![]);
+
+        let token = verify_with_fallback(&factors, &order, |factor| {
+            attempts
+                .borrow_mut()
+                .push(factor.kind().unwrap().to_string());
+            match factor {
+                Factor::Push { .. } => {
+                    wait_for_push(&okta_profile("profile", OKTA_URL), || {
+                        push_response("TIMEOUT")
+                    })?;
+                    Ok("push-token")
+                }
+                _ => Ok("totp-token"),
+            }
+        })?;
+
+        assert_eq!("totp-token", token);
+        assert_eq!(vec!["push", "totp"], *attempts.borrow());
+
+        Ok(())
+    }
+
+    #[test]
+    fn stops_falling_back_once_rejected() -> Result<()> {
+        let factors = login_factors()?;
+        let order = vec!["webauthn".parse()?, "push".parse()?, "totp".parse()?];
+        let attempts = RefCell::new(vec![]);
+
+        let error = verify_with_fallback(&factors, &order, |factor| -> Result<()> {
+            attempts
+                .borrow_mut()
+                .push(factor.kind().unwrap().to_string());
+            wait_for_push(&okta_profile("profile", OKTA_URL), || {
+                push_response("REJECTED")
+            })?;
+            Ok(())
+        })
+        .unwrap_err();
+
+        assert_eq!(ErrorKind::MfaRejected, error::kind_of(&error));
+        // Security keys aren't supported yet, so they are skipped unasked
+        assert_eq!(vec!["push"], *attempts.borrow());
+
+        Ok(())
+    }
+
+    #[test]
+    fn explains_unavailable_fallback_factors() -> Result<()> {
+        let factors = vec![sms_factor()];
+        let order = vec!["push".parse()?, "totp".parse()?];
+
+        let error = verify_with_fallback(&factors, &order, |_| Ok(())).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("None of the MFA factors push, totp"),
+            "{}",
+            error
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn switches_to_other_factor_once_push_times_out() -> Result<()> {
+        let factors = login_factors()?;
+        let push = factors.iter().find(|f| f.kind() == Some("push")).cloned();
+        let totp = factors
+            .iter()
+            .filter(|f| f.kind() != Some("push"))
+            .position(|f| f.kind() == Some("totp"))
+            .unwrap()
+            .to_string();
+        let prompter = Rc::new(ScriptedPrompter::new(&[&totp]));
+        let attempts = RefCell::new(vec![]);
+
+        let token = prompt::with_prompter(prompter, || {
+            verify_switching_factors(factors.clone(), push.unwrap(), |factor| {
+                attempts
+                    .borrow_mut()
+                    .push(factor.kind().unwrap().to_string());
+                match factor {
+                    Factor::Push { .. } => {
+                        wait_for_push(&okta_profile("profile", OKTA_URL), || {
+                            push_response("TIMEOUT")
+                        })?;
+                        Ok("push-token")
+                    }
+                    _ => Ok("totp-token"),
+                }
+            })
+        })?;
+
+        assert_eq!("totp-token", token);
+        assert_eq!(vec!["push", "totp"], *attempts.borrow());
+
+        Ok(())
+    }
+
+    #[test]
+    fn gives_up_switching_factors_when_asked() -> Result<()> {
+        let factors = vec![sms_factor(), totp_factor(FactorProvider::Google)];
+        let prompter = Rc::new(ScriptedPrompter::new(&["1"]));
+
+        let error = prompt::with_prompter(prompter, || {
+            verify_switching_factors(factors.clone(), factors[0].clone(), |_| -> Result<()> {
+                Err(error::error(ErrorKind::MfaRejected, "Incorrect code"))
+            })
+        })
+        .unwrap_err();
+        assert_eq!("Incorrect code", error.to_string());
+
+        // Other failures end the login right away
+        let error =
+            verify_switching_factors(factors.clone(), factors[0].clone(), |_| -> Result<()> {
+                Err(anyhow!("Okta is down"))
+            })
+            .unwrap_err();
+        assert_eq!("Okta is down", error.to_string());
+
+        Ok(())
+    }
+
+    #[test]
+    fn lists_factors_that_read_back_as_factors() -> Result<()> {
+        let factors = menu_factors(login_factors()?, None, None)?;
+        let json = serde_json::to_string(&list_factors(&factors))?;
+
+        let listed: Vec<serde_json::Value> = serde_json::from_str(&json)?;
+        assert_eq!(factors.len(), listed.len());
+        for (index, entry) in listed.iter().enumerate() {
+            assert_eq!(index as u64, entry["index"]);
+            let selector: FactorSelector = entry["selector"].as_str().unwrap().parse()?;
+            assert!(selector.matches(&factors[index]));
+        }
+
+        assert_eq!(factors, serde_json::from_str::<Vec<Factor>>(&json)?);
+
+        Ok(())
+    }
+
+    fn push_response(factor_result: &str) -> Result<Response> {
+        let mut response: serde_json::Value = serde_json::from_str(&fs::read_to_string(
+            "tests/fixtures/okta/challenge_response_push.json",
+        )?)?;
+        response["factorResult"] = serde_json::json!(factor_result);
+        if factor_result == "SUCCESS" {
+            response["status"] = serde_json::json!("SUCCESS");
+        }
+
+        Ok(serde_json::from_value(response)?)
+    }
+
+    fn login_factors() -> Result<Vec<Factor>> {
+        let response = serde_json::de::from_str::<Response>(&fs::read_to_string(
+            "tests/fixtures/okta/login_response_mfa_required.json",
+        )?)?;
+
+        Ok(filter_factors(response.embedded.unwrap().factors.unwrap()))
+    }
+
+    // Accepts the preselected item, recording the menu
+    #[derive(Default)]
+    struct MenuPrompter {
+        items: RefCell<Vec<String>>,
+    }
+
+    impl Prompter for MenuPrompter {
+        fn input(&self, prompt: &str) -> Result<String> {
+            Err(anyhow!("Unexpected prompt: {}", prompt))
+        }
+
+        fn password(&self, prompt: &str) -> Result<String> {
+            Err(anyhow!("Unexpected prompt: {}", prompt))
+        }
+
+        fn select(&self, prompt: &str, items: &[String]) -> Result<usize> {
+            self.select_with_default(prompt, items, 0)
+        }
+
+        fn select_with_default(
+            &self,
+            _prompt: &str,
+            items: &[String],
+            default: usize,
+        ) -> Result<usize> {
+            self.items.replace(items.to_vec());
+            Ok(default)
+        }
+    }
+
+    fn push_factor(id: &str, device: &str) -> Factor {
+        serde_json::from_value(serde_json::json!({
+            "factorType": "push",
+            "id": id,
+            "provider": "OKTA",
+            "profile": { "name": device }
+        }))
+        .unwrap()
+    }
+
+    fn sms_factor() -> Factor {
+        Factor::Sms {
+            id: "id".to_string(),
+            links: None,
+            profile: SmsFactorProfile {
+                phone_number: "12345".to_string(),
+            },
+            status: None,
+            provider: FactorProvider::Okta,
+        }
+    }
+
+    fn totp_factor(provider: FactorProvider) -> Factor {
+        serde_json::from_value(serde_json::json!({
+            "factorType": "token:software:totp",
+            "id": format!("factor-id-{}", provider),
+            "provider": provider.to_string().to_uppercase(),
+            "profile": { "credentialId": "jdoe@example.com" }
+        }))
+        .unwrap()
+    }
 }
@@ -1,37 +1,167 @@
 use crate::config::app::AppProfile;
+use crate::credentials::totp::TotpParameters;
+use crate::har;
+use crate::http;
+use crate::providers::okta::auth::LastPush;
+use crate::providers::okta::devices::{self, DEVICE_COOKIE};
+use crate::providers::okta::error::check_okta_response;
+use crate::providers::okta::factors::{FactorProvider, FactorSelector};
+use crate::utils;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use reqwest::blocking::Client as HttpClient;
-use reqwest::blocking::Response;
-use reqwest::header::{HeaderValue, ACCEPT};
+use reqwest::blocking::{Request, Response};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, ACCEPT, ACCEPT_LANGUAGE, COOKIE};
 use reqwest::Url;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
+use std::cell::{Cell, RefCell};
+
+const LOCALE_VARIABLES: [&str; 3] = ["LC_ALL", "LC_MESSAGES", "LANG"];
+pub const OKTA_FORWARDED_FOR: &str = "OKTA_FORWARDED_FOR";
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const SESSION_COOKIE_REDIRECT_PATH: &str = "login/sessionCookieRedirect";
+
+/// How the request for the SAML assertion is authenticated
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionKind {
+    /// The session token of a fresh login, which the app embed link takes
+    /// once as `onetimetoken`
+    OneTime(String),
+    /// A session Okta knows by its cookie, kept from an earlier run or set
+    /// while the token was spent
+    Cookie,
+}
 
 pub struct Client {
     client: HttpClient,
+    accept_language: Option<String>,
+    profile_headers: HeaderMap,
+    forwarded_for: Option<String>,
     pub base_url: Url,
     pub session_token: Option<String>,
+    // The session kept from an earlier run, sent as the sid cookie
+    pub session_id: Option<String>,
+    // Whether the session token got exchanged for a session cookie
+    session_cookie: Cell<bool>,
+    // The device trust of an earlier login, sent as the DT cookie
+    pub device_token: Option<String>,
+    // The DT cookie Okta set during this login
+    new_device_token: RefCell<Option<String>>,
+    pub factor_selector: Option<FactorSelector>,
+    pub factor_provider: Option<FactorProvider>,
+    pub factor_index: Option<usize>,
+    pub mfa_fallback: Vec<FactorSelector>,
+    pub mfa_code: Option<String>,
+    pub push_device: Option<String>,
+    pub last_push: LastPush,
+    pub totp: Option<TotpParameters>,
+    pub profile: AppProfile,
 }
 
 impl Client {
     pub fn new(profile: AppProfile) -> Result<Client> {
         Ok(Client {
-            client: HttpClient::builder().cookie_store(true).build()?,
+            // The session cookies Okta sets while logging in, e.g. sid and
+            // DT, have to reach the app embed link along with the session
+            // token, so every request of a login shares this cookie store
+            client: http::configure(HttpClient::builder().cookie_store(true), &profile)?.build()?,
+            accept_language: accept_language(
+                profile.accept_language.as_deref(),
+                LOCALE_VARIABLES
+                    .iter()
+                    .find_map(|v| utils::non_empty_env_var(v)),
+            ),
+            profile_headers: profile_headers(&profile)?,
+            forwarded_for: profile
+                .forwarded_for
+                .clone()
+                .or_else(|| utils::non_empty_env_var(OKTA_FORWARDED_FOR)),
             base_url: profile.base_url()?,
             session_token: None,
+            session_id: None,
+            session_cookie: Cell::new(false),
+            device_token: None,
+            new_device_token: RefCell::new(None),
+            factor_selector: match profile.mfa_factor {
+                Some(ref factor) => Some(factor.parse()?),
+                None => None,
+            },
+            factor_provider: match profile.mfa_provider {
+                Some(ref provider) => Some(provider.parse()?),
+                None => None,
+            },
+            factor_index: profile.factor_index,
+            mfa_fallback: profile
+                .mfa_fallback
+                .iter()
+                .map(|factor| factor.parse())
+                .collect::<Result<_>>()?,
+            mfa_code: profile.mfa_code.clone(),
+            push_device: profile.push_device.clone(),
+            last_push: LastPush::new(&profile),
+            totp: None,
+            profile,
         })
     }
 
-    pub fn get(&self, mut url: Url) -> Result<Response> {
-        if let Some(token) = &self.session_token {
-            url.query_pairs_mut().append_pair("sessionToken", token);
+    /// Gets `url` without turning an unsuccessful response into an error, for
+    /// telling apart what Okta answered
+    pub fn get_unchecked(&self, url: Url) -> Result<Response> {
+        Ok(har::send(
+            &self.client,
+            self.client.get(url).headers(self.headers()),
+        )?)
+    }
+
+    /// Gets `url` authenticated by the session cookie, for APIs that don't
+    /// take the session token, e.g. appLinks. Okta sets the cookie while
+    /// redirecting there with the token, which spends the token, so all later
+    /// requests rely on the cookie as well
+    pub fn get_with_session_cookie(&self, url: Url) -> Result<Response> {
+        let url = match &self.session_token {
+            Some(token) if !self.session_cookie.get() => {
+                session_cookie_redirect(&self.base_url, token, &url)?
+            }
+            _ => url,
+        };
+        let response = check_okta_response(har::send(
+            &self.client,
+            self.client.get(url).headers(self.headers()),
+        )?)?;
+        self.session_cookie.set(self.session_token.is_some());
+
+        Ok(response)
+    }
+
+    /// The session the next SAML fetch relies on. The token is only sent
+    /// until Okta exchanged it for a session cookie
+    pub fn session_kind(&self) -> SessionKind {
+        match &self.session_token {
+            Some(token) if !self.session_cookie.get() => SessionKind::OneTime(token.clone()),
+            _ => SessionKind::Cookie,
         }
-        self.client
-            .get(url)
-            .send()?
-            .error_for_status()
-            .map_err(|e| e.into())
+    }
+
+    /// Gets the app embed link `url`, which answers with the SAML form
+    pub fn fetch_saml_assertion(&self, url: Url, session: SessionKind) -> Result<Response> {
+        let response = check_okta_response(har::execute(
+            &self.client,
+            self.saml_request(url, &session)?,
+        )?)?;
+        if let SessionKind::OneTime(_) = session {
+            self.session_cookie.set(true);
+        }
+
+        Ok(response)
+    }
+
+    fn saml_request(&self, mut url: Url, session: &SessionKind) -> Result<Request> {
+        if let SessionKind::OneTime(token) = session {
+            url.query_pairs_mut().append_pair("onetimetoken", token);
+        }
+
+        Ok(self.client.get(url).headers(self.headers()).build()?)
     }
 
     pub fn post<I, O>(&self, url: Url, body: &I) -> Result<O>
@@ -39,13 +169,346 @@ impl Client {
         I: Serialize,
         O: DeserializeOwned,
     {
-        self.client
-            .post(url)
-            .json(body)
-            .header(ACCEPT, HeaderValue::from_static("application/json"))
-            .send()?
-            .error_for_status()?
-            .json()
-            .map_err(|e| e.into())
+        self.post_with_headers(url, body, self.headers())
+    }
+
+    pub fn post_with_headers<I, O>(&self, url: Url, body: &I, headers: HeaderMap) -> Result<O>
+    where
+        I: Serialize,
+        O: DeserializeOwned,
+    {
+        let response = har::send(
+            &self.client,
+            self.client
+                .post(url)
+                .json(body)
+                .headers(headers)
+                .header(ACCEPT, HeaderValue::from_static("application/json")),
+        )?;
+
+        if let Some(token) = devices::device_cookie(response.headers()) {
+            self.new_device_token.replace(Some(token));
+        }
+
+        check_okta_response(response)?.json().map_err(|e| e.into())
+    }
+
+    /// The device trust Okta granted during this login, if it differs from
+    /// the one sent
+    pub fn new_device_token(&self) -> Option<String> {
+        self.new_device_token
+            .borrow()
+            .clone()
+            .filter(|token| Some(token) != self.device_token.as_ref())
+    }
+
+    /// Headers of the primary authentication, which Okta evaluates network
+    /// zones and device trust for. Passing on the client's address lets
+    /// policies see the user behind a jump host instead of the host itself
+    pub fn authn_headers(&self) -> Result<HeaderMap> {
+        let mut headers = self.headers();
+
+        if let Some(token) = &self.device_token {
+            let cookie = match headers.get(COOKIE).and_then(|c| c.to_str().ok()) {
+                Some(cookies) => format!("{}; {}={}", cookies, DEVICE_COOKIE, token),
+                None => format!("{}={}", DEVICE_COOKIE, token),
+            };
+            match HeaderValue::from_str(&cookie) {
+                Ok(value) => {
+                    headers.insert(COOKIE, value);
+                }
+                Err(_) => debug!("Ignoring invalid Okta device cookie"),
+            }
+        }
+
+        if let Some(forwarded_for) = &self.forwarded_for {
+            headers.insert(
+                X_FORWARDED_FOR,
+                HeaderValue::from_str(forwarded_for)
+                    .with_context(|| format!("Invalid X-Forwarded-For {}", forwarded_for))?,
+            );
+        }
+
+        Ok(headers)
+    }
+
+    // Okta localizes its error summaries based on Accept-Language
+    fn headers(&self) -> HeaderMap {
+        let mut headers = self.profile_headers.clone();
+
+        if let Some(language) = &self.accept_language {
+            match HeaderValue::from_str(language) {
+                Ok(value) => {
+                    headers.insert(ACCEPT_LANGUAGE, value);
+                }
+                Err(_) => debug!("Ignoring invalid Accept-Language {}", language),
+            }
+        }
+
+        if let Some(id) = &self.session_id {
+            match HeaderValue::from_str(&format!("sid={}", id)) {
+                Ok(value) => {
+                    headers.insert(COOKIE, value);
+                }
+                Err(_) => debug!("Ignoring invalid Okta session id"),
+            }
+        }
+
+        headers
+    }
+}
+
+fn session_cookie_redirect(base_url: &Url, token: &str, target: &Url) -> Result<Url> {
+    let mut url = base_url.join(SESSION_COOKIE_REDIRECT_PATH)?;
+    url.query_pairs_mut()
+        .append_pair("token", token)
+        .append_pair("redirectUrl", target.as_str());
+
+    Ok(url)
+}
+
+fn profile_headers(profile: &AppProfile) -> Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+
+    for (name, value) in &profile.headers.0 {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .with_context(|| format!("Invalid header name {}", name))?;
+        let mut value = HeaderValue::from_str(value)
+            .with_context(|| format!("Invalid value of header {}", name))?;
+        // Keeps the value out of debug output of the requests
+        value.set_sensitive(true);
+
+        headers.insert(name, value);
+    }
+
+    Ok(headers)
+}
+
+// Turns a POSIX locale like de_DE.UTF-8 into the de-DE language tag
+fn accept_language(configured: Option<&str>, locale: Option<String>) -> Option<String> {
+    if let Some(language) = configured {
+        return Some(language.to_owned());
+    }
+
+    let locale = locale?;
+    let language = locale
+        .split(['.', '@'])
+        .next()
+        .unwrap_or_default()
+        .replace('_', "-");
+
+    match language.as_str() {
+        "" | "C" | "POSIX" => None,
+        _ => Some(language),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::credentials::keystore::{self, test::MemoryBackend};
+    use std::rc::Rc;
+
+    #[test]
+    fn redirects_to_target_with_session_cookie() -> Result<()> {
+        let base_url = Url::parse("https://example.okta.com")?;
+        let target = base_url.join("api/v1/users/me/appLinks")?;
+
+        assert_eq!(
+            "https://example.okta.com/login/sessionCookieRedirect?token=session-token\
+             &redirectUrl=https%3A%2F%2Fexample.okta.com%2Fapi%2Fv1%2Fusers%2Fme%2FappLinks",
+            session_cookie_redirect(&base_url, "session-token", &target)?.as_str()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn prefers_configured_accept_language() {
+        assert_eq!(
+            Some("fr-CA".to_string()),
+            accept_language(Some("fr-CA"), Some("de_DE.UTF-8".to_string()))
+        );
+    }
+
+    #[test]
+    fn derives_accept_language_from_locale() {
+        assert_eq!(
+            Some("de-DE".to_string()),
+            accept_language(None, Some("de_DE.UTF-8".to_string()))
+        );
+        assert_eq!(
+            Some("sr-RS".to_string()),
+            accept_language(None, Some("sr_RS@latin".to_string()))
+        );
+        assert_eq!(None, accept_language(None, Some("C.UTF-8".to_string())));
+        assert_eq!(None, accept_language(None, None));
+    }
+
+    #[test]
+    fn sets_accept_language_header_from_config() -> Result<()> {
+        let mut profile: AppProfile = toml::from_str(
+            r#"
+            name = "profile"
+            provider = "okta"
+            url = "https://example.okta.com/home/amazon_aws/0oa/272"
+            accept_language = "ja-JP"
+        "#,
+        )?;
+        let client = Client::new(profile.clone())?;
+
+        assert_eq!("ja-JP", client.headers()[ACCEPT_LANGUAGE]);
+
+        profile.accept_language = Some("not valid\n".to_string());
+        assert!(Client::new(profile)?.headers().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn sends_kept_session_as_cookie() -> Result<()> {
+        let profile: AppProfile = toml::from_str(
+            r#"
+            name = "profile"
+            provider = "okta"
+            url = "https://example.okta.com/home/amazon_aws/0oa/272"
+        "#,
+        )?;
+        let mut client = Client::new(profile)?;
+        assert!(!client.headers().contains_key(COOKIE));
+
+        client.session_id = Some("102bZDNFfWaQSyEZQuDgWt-uQ".to_string());
+        assert_eq!("sid=102bZDNFfWaQSyEZQuDgWt-uQ", client.headers()[COOKIE]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn fetches_saml_with_one_time_token() -> Result<()> {
+        let mut client = Client::new(create_profile()?)?;
+        client.session_token = Some("20111nHMj4dJ8Ns".to_string());
+        let url = Url::parse("https://example.okta.com/home/amazon_aws/0oa/272?fromHome=true")?;
+
+        let kind = client.session_kind();
+        assert_eq!(SessionKind::OneTime("20111nHMj4dJ8Ns".to_string()), kind);
+
+        let request = client.saml_request(url, &kind)?;
+        assert_eq!(
+            "https://example.okta.com/home/amazon_aws/0oa/272?fromHome=true&onetimetoken=20111nHMj4dJ8Ns",
+            request.url().as_str()
+        );
+        assert!(!request.headers().contains_key(COOKIE));
+
+        Ok(())
+    }
+
+    #[test]
+    fn fetches_saml_with_session_cookie() -> Result<()> {
+        let mut client = Client::new(create_profile()?)?;
+        client.session_id = Some("102bZDNFfWaQSyEZQuDgWt-uQ".to_string());
+        let url = Url::parse("https://example.okta.com/home/amazon_aws/0oa/272")?;
+
+        let kind = client.session_kind();
+        assert_eq!(SessionKind::Cookie, kind);
+
+        let request = client.saml_request(url.clone(), &kind)?;
+        assert_eq!(&url, request.url());
+        assert_eq!("sid=102bZDNFfWaQSyEZQuDgWt-uQ", request.headers()[COOKIE]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn forwards_client_address_on_authn_only() -> Result<()> {
+        let profile: AppProfile = toml::from_str(
+            r#"
+            name = "profile"
+            provider = "okta"
+            url = "https://example.okta.com/home/amazon_aws/0oa/272"
+            forwarded_for = "203.0.113.7"
+        "#,
+        )?;
+        let client = Client::new(profile)?;
+
+        assert_eq!("203.0.113.7", client.authn_headers()?[X_FORWARDED_FOR]);
+        assert!(!client.headers().contains_key(X_FORWARDED_FOR));
+
+        Ok(())
+    }
+
+    #[test]
+    fn sends_device_trusted_by_earlier_login() -> Result<()> {
+        let profile = create_profile()?;
+
+        keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+            devices::trust(&profile, "DI0dZ5mFB7bQ2W8kJ3tNWK3Ag");
+
+            let mut client = Client::new(profile.clone())?;
+            client.device_token = devices::trusted_device(&profile);
+            assert_eq!(
+                "DT=DI0dZ5mFB7bQ2W8kJ3tNWK3Ag",
+                client.authn_headers()?[COOKIE]
+            );
+            // Only a cookie Okta set anew is worth caching
+            assert_eq!(None, client.new_device_token());
+
+            devices::forget(&profile);
+            client.device_token = devices::trusted_device(&profile);
+            assert!(!client.authn_headers()?.contains_key(COOKIE));
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn sends_trusted_device_on_authn_only() -> Result<()> {
+        let mut client = Client::new(create_profile()?)?;
+        assert!(!client.authn_headers()?.contains_key(COOKIE));
+
+        client.device_token = Some("DI0dZ5mFB7bQ2W8kJ3tNWK3Ag".to_string());
+        assert_eq!(
+            "DT=DI0dZ5mFB7bQ2W8kJ3tNWK3Ag",
+            client.authn_headers()?[COOKIE]
+        );
+        assert!(!client.headers().contains_key(COOKIE));
+
+        client.session_id = Some("102bZDNFfWaQSyEZQuDgWt-uQ".to_string());
+        assert_eq!(
+            "sid=102bZDNFfWaQSyEZQuDgWt-uQ; DT=DI0dZ5mFB7bQ2W8kJ3tNWK3Ag",
+            client.authn_headers()?[COOKIE]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn sends_profile_headers() -> Result<()> {
+        let profile: AppProfile = toml::from_str(
+            r#"
+            name = "profile"
+            provider = "okta"
+            url = "https://example.okta.com/home/amazon_aws/0oa/272"
+
+            [headers]
+            X-Waf-Token = "secret-token"
+        "#,
+        )?;
+        let headers = Client::new(profile.clone())?.headers();
+
+        assert_eq!("secret-token", headers["x-waf-token"]);
+        assert!(headers["x-waf-token"].is_sensitive());
+        assert!(!format!("{:?}", profile).contains("secret-token"));
+
+        Ok(())
+    }
+
+    fn create_profile() -> Result<AppProfile> {
+        Ok(toml::from_str(
+            r#"
+            name = "profile"
+            provider = "okta"
+            url = "https://example.okta.com/home/amazon_aws/0oa/272"
+        "#,
+        )?)
     }
 }
@@ -0,0 +1,120 @@
+//! Device trust Okta grants when a factor is verified with "remember device".
+//! Okta sets the `DT` cookie for it, which is cached in the keyring per
+//! organization and sent with the next logins, so policies that skip MFA on
+//! remembered devices apply to crowbar like to a browser. `--forget-device`
+//! drops it again.
+
+use crate::config::app::AppProfile;
+use crate::credentials::keystore;
+use crate::credentials::CredentialType;
+
+use anyhow::Result;
+use reqwest::header::{HeaderMap, SET_COOKIE};
+
+pub const DEVICE_COOKIE: &str = "DT";
+
+/// The `DT` cookie an earlier login kept for the organization of the profile
+pub fn trusted_device(profile: &AppProfile) -> Option<String> {
+    keystore::get_cached(&service(profile).ok()?, &profile.username).ok()
+}
+
+/// Caches the `DT` cookie Okta set for the next logins to the organization
+pub fn trust(profile: &AppProfile, cookie: &str) {
+    let _ = service(profile)
+        .and_then(|service| keystore::set_password(&service, &profile.username, cookie))
+        .map_err(|e| debug!("Couldn't cache the Okta device cookie: {}", e));
+}
+
+/// Drops the device trust of the organization, so the next login verifies
+/// MFA again
+pub fn forget(profile: &AppProfile) {
+    let _ = service(profile)
+        .and_then(|service| keystore::delete_password(&service, &profile.username))
+        .map_err(|e| debug!("Couldn't remove the Okta device cookie: {}", e));
+}
+
+/// The value of the `DT` cookie set by a response, if any. Okta clears the
+/// cookie by setting it empty, which isn't worth keeping
+pub fn device_cookie(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(SET_COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .filter_map(|value| value.split(';').next())
+        .filter_map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            match (parts.next()?.trim(), parts.next()?.trim()) {
+                (DEVICE_COOKIE, value) if !value.is_empty() => Some(value.to_string()),
+                _ => None,
+            }
+        })
+        .next_back()
+}
+
+// Devices are trusted by the organization rather than by its apps, so
+// profiles of one organization share the cookie
+fn service(profile: &AppProfile) -> Result<String> {
+    let base_url = profile.base_url()?;
+
+    Ok(CredentialType::Device.service(&base_url.host_str().unwrap_or_default()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::app::test::okta_profile;
+    use crate::credentials::keystore::test::MemoryBackend;
+    use reqwest::header::HeaderValue;
+    use std::rc::Rc;
+
+    #[test]
+    fn reads_device_cookie_from_response() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(None, device_cookie(&headers));
+
+        headers.append(
+            SET_COOKIE,
+            HeaderValue::from_static("JSESSIONID=8F2C; Path=/; Secure; HttpOnly"),
+        );
+        headers.append(
+            SET_COOKIE,
+            HeaderValue::from_static(
+                "DT=DI0dZ5mFB7bQ2W8kJ3tNWK3Ag; Version=1; Path=/; Max-Age=63072000; Secure; HttpOnly",
+            ),
+        );
+        assert_eq!(
+            Some("DI0dZ5mFB7bQ2W8kJ3tNWK3Ag".to_string()),
+            device_cookie(&headers)
+        );
+
+        let mut cleared = HeaderMap::new();
+        cleared.append(SET_COOKIE, HeaderValue::from_static("DT=; Max-Age=0"));
+        assert_eq!(None, device_cookie(&cleared));
+    }
+
+    #[test]
+    fn shares_device_trust_within_organization() -> Result<()> {
+        let prod = okta_profile("prod", "https://example.okta.com/home/amazon_aws/0oa1/272");
+        let staging = okta_profile(
+            "staging",
+            "https://example.okta.com/home/amazon_aws/0oa2/272",
+        );
+        let other = okta_profile("other", "https://other.okta.com/home/amazon_aws/0oa3/272");
+
+        keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+            assert_eq!(None, trusted_device(&prod));
+
+            trust(&prod, "DI0dZ5mFB7bQ2W8kJ3tNWK3Ag");
+            assert_eq!(
+                Some("DI0dZ5mFB7bQ2W8kJ3tNWK3Ag".to_string()),
+                trusted_device(&staging)
+            );
+            assert_eq!(None, trusted_device(&other));
+
+            forget(&staging);
+            assert_eq!(None, trusted_device(&prod));
+
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,292 @@
+use crate::providers::okta::client::Client;
+use crate::providers::okta::factors::{Factor, FactorActivation, FactorProvider, FactorSelector};
+use crate::providers::okta::response::{Links, Response, Status};
+
+use anyhow::{anyhow, Context, Result};
+use reqwest::Url;
+
+const TOTP_FACTOR_TYPE: &str = "token:software:totp";
+
+/// How to go on when Okta asks to enroll a factor before logging in
+#[derive(Debug, PartialEq)]
+pub enum EnrollAction {
+    Totp,
+    Explain(String),
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct EnrollRequest {
+    state_token: String,
+    factor_type: String,
+    provider: FactorProvider,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ActivationRequest {
+    state_token: String,
+    pass_code: String,
+}
+
+impl Client {
+    /// Starts enrolling a software TOTP factor for a user Okta asks to set
+    /// up MFA (status MFA_ENROLL) and returns the pending activation
+    pub fn enroll_totp(&self, response: Response) -> Result<Response> {
+        if response.status != Status::MfaEnroll {
+            return Err(anyhow!(
+                "Okta didn't ask for a new MFA factor to be enrolled (status {:?}). \
+                 Additional factors can only be enrolled through the Okta dashboard",
+                response.status
+            ));
+        }
+
+        let state_token = response
+            .state_token
+            .with_context(|| "Missing state token in response")?;
+        let factors = response
+            .embedded
+            .and_then(|e| e.factors)
+            .with_context(|| "Missing enrollable factors in response")?;
+        let (provider, url) = select_enrollable_totp(&factors)?;
+
+        let request = EnrollRequest {
+            state_token,
+            factor_type: TOTP_FACTOR_TYPE.to_string(),
+            provider,
+        };
+
+        debug!("Enrollment request: {:?}", &request);
+
+        self.post(url, &request)
+    }
+
+    pub fn activate_totp(&self, response: &Response, pass_code: String) -> Result<Response> {
+        let state_token = response
+            .state_token
+            .clone()
+            .with_context(|| "Missing state token in response")?;
+        let url = match response.links.as_ref().and_then(|l| l.get("next")) {
+            Some(Links::Single(link)) => link.href.clone(),
+            Some(Links::Multi(links)) if !links.is_empty() => links[0].href.clone(),
+            _ => return Err(anyhow!("Missing activation link for the enrolled factor")),
+        };
+
+        self.post(
+            url,
+            &ActivationRequest {
+                state_token,
+                pass_code,
+            },
+        )
+    }
+}
+
+/// Enrolls a TOTP factor right away if the user asked for TOTP and Okta
+/// offers it, otherwise explains which factors need to be set up
+pub fn enroll_action(response: &Response, selector: Option<&FactorSelector>) -> EnrollAction {
+    let factors = enrollable_factors(response);
+    let requested = selector.is_some_and(|s| s.kind == "totp");
+
+    match select_enrollable_totp(&factors) {
+        Ok(_) if requested => EnrollAction::Totp,
+        _ => EnrollAction::Explain(enrollment_required(response)),
+    }
+}
+
+pub fn enrollment_required(response: &Response) -> String {
+    let factors = enrollable_factors(response);
+    let names = factors
+        .iter()
+        .filter_map(|f| match (f.kind(), f.provider()) {
+            (Some(kind), Some(provider)) => Some(format!("{}:{}", kind, provider)),
+            _ => None,
+        })
+        .collect::<Vec<String>>();
+
+    let mut message = match names.is_empty() {
+        true => "Okta asks you to enroll an MFA factor before logging in".to_string(),
+        false => format!(
+            "Okta asks you to enroll an MFA factor before logging in, enrollable factors are: {}",
+            names.join(", ")
+        ),
+    };
+
+    match select_enrollable_totp(&factors) {
+        Ok(_) => message.push_str(
+            ". Run crowbar enroll-totp, or log in with --mfa-factor totp, to enroll a TOTP factor, \
+             or enroll a factor through the Okta dashboard",
+        ),
+        Err(_) => message.push_str(". Please enroll a factor through the Okta dashboard"),
+    }
+
+    message
+}
+
+fn enrollable_factors(response: &Response) -> Vec<Factor> {
+    response
+        .embedded
+        .as_ref()
+        .and_then(|e| e.factors.clone())
+        .unwrap_or_default()
+}
+
+pub fn totp_activation(response: &Response) -> Result<FactorActivation> {
+    let factor = response
+        .embedded
+        .as_ref()
+        .and_then(|e| e.factor.as_ref())
+        .with_context(|| "Missing enrolled factor in response")?;
+
+    match factor {
+        Factor::Totp {
+            embedded: Some(embedded),
+            ..
+        } => embedded
+            .activation
+            .clone()
+            .with_context(|| "Missing activation details for the enrolled factor"),
+        _ => Err(anyhow!("Okta didn't return a TOTP factor to activate")),
+    }
+}
+
+pub fn otpauth_uri(issuer: &str, account: &str, activation: &FactorActivation) -> Result<Url> {
+    let mut url = Url::parse(&format!("otpauth://totp/{}:{}", issuer, account))?;
+    url.query_pairs_mut()
+        .append_pair("secret", &activation.shared_secret)
+        .append_pair("issuer", issuer);
+
+    if let Some(period) = activation.time_step {
+        url.query_pairs_mut()
+            .append_pair("period", &period.to_string());
+    }
+
+    Ok(url)
+}
+
+// Google Authenticator compatible factors work with any authenticator app,
+// so we prefer those over Okta Verify
+fn select_enrollable_totp(factors: &[Factor]) -> Result<(FactorProvider, Url)> {
+    let mut candidates = factors
+        .iter()
+        .filter_map(|f| match f {
+            Factor::Totp {
+                provider,
+                links: Some(links),
+                ..
+            } => match links.get("enroll") {
+                Some(Links::Single(link)) => Some((provider.clone(), link.href.clone())),
+                Some(Links::Multi(l)) if !l.is_empty() => {
+                    Some((provider.clone(), l[0].href.clone()))
+                }
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect::<Vec<(FactorProvider, Url)>>();
+
+    candidates.sort_by_key(|(provider, _)| *provider != FactorProvider::Google);
+    candidates
+        .into_iter()
+        .next()
+        .with_context(|| "Your Okta organization doesn't allow enrolling a TOTP factor")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn prefers_google_authenticator_for_enrollment() -> Result<()> {
+        let response = read_response("tests/fixtures/okta/login_response_mfa_enroll.json")?;
+        assert_eq!(response.status, Status::MfaEnroll);
+
+        let factors = response.embedded.unwrap().factors.unwrap();
+        let (provider, url) = select_enrollable_totp(&factors)?;
+
+        assert_eq!(FactorProvider::Google, provider);
+        assert_eq!(
+            "https://example.okta.com/api/v1/authn/factors",
+            url.as_str()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn enrolls_totp_only_when_requested() -> Result<()> {
+        let response = read_response("tests/fixtures/okta/login_response_mfa_enroll.json")?;
+        let totp: FactorSelector = "totp".parse()?;
+        let push: FactorSelector = "push".parse()?;
+
+        assert_eq!(EnrollAction::Totp, enroll_action(&response, Some(&totp)));
+
+        for selector in &[None, Some(&push)] {
+            match enroll_action(&response, *selector) {
+                EnrollAction::Explain(message) => {
+                    assert!(message.contains("totp:okta, totp:google, push:okta"));
+                    assert!(message.contains("crowbar enroll-totp"));
+                }
+                action => panic!("Unexpected enroll action {:?}", action),
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn explains_enrollment_without_totp() -> Result<()> {
+        let mut response = read_response("tests/fixtures/okta/login_response_mfa_enroll.json")?;
+        if let Some(embedded) = response.embedded.as_mut() {
+            embedded
+                .factors
+                .as_mut()
+                .unwrap()
+                .retain(|f| f.kind() == Some("push"));
+        }
+        let totp: FactorSelector = "totp".parse()?;
+
+        match enroll_action(&response, Some(&totp)) {
+            EnrollAction::Explain(message) => {
+                assert!(message.contains("push:okta"));
+                assert!(!message.contains("enroll-totp"));
+            }
+            action => panic!("Unexpected enroll action {:?}", action),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn parses_pending_totp_activation() -> Result<()> {
+        let response = read_response("tests/fixtures/okta/enroll_response_totp_activate.json")?;
+        assert_eq!(response.status, Status::MfaEnrollActivate);
+
+        let activation = totp_activation(&response)?;
+        assert_eq!("JBSWY3DPEHPK3PXP", activation.shared_secret);
+        assert_eq!(Some(30), activation.time_step);
+
+        Ok(())
+    }
+
+    #[test]
+    fn builds_otpauth_uri() -> Result<()> {
+        let response = read_response("tests/fixtures/okta/enroll_response_totp_activate.json")?;
+        let activation = totp_activation(&response)?;
+        let uri = otpauth_uri("example.okta.com", "jdoe@example.com", &activation)?;
+
+        assert_eq!(
+            "otpauth://totp/example.okta.com:jdoe@example.com?secret=JBSWY3DPEHPK3PXP&issuer=example.okta.com&period=30",
+            uri.as_str()
+        );
+
+        Ok(())
+    }
+
+    fn read_response(path: &str) -> Result<Response> {
+        Ok(serde_json::de::from_str::<Response>(&fs::read_to_string(
+            path,
+        )?)?)
+    }
+}
@@ -0,0 +1,322 @@
+use crate::clock;
+use crate::error::OKTA_INVALID_PASSCODE;
+use crate::ui;
+
+use chrono::{DateTime, Utc};
+use reqwest::blocking::Response;
+use reqwest::header::{HeaderMap, DATE};
+use reqwest::StatusCode;
+use std::cell::Cell;
+use std::fmt;
+
+// Beyond this difference between our clock and Okta's, TOTP codes and SAML
+// assertions start to fail in ways that don't point at the clock
+const MAX_CLOCK_SKEW_SECONDS: i64 = 30;
+
+thread_local! {
+    static SKEW_WARNED: Cell<bool> = const { Cell::new(false) };
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorResponse {
+    pub error_code: String,
+    pub error_summary: String,
+    #[serde(default)]
+    pub error_causes: Vec<ErrorCause>,
+    /// How many more wrong codes lock the user out, which only some orgs tell
+    #[serde(default)]
+    pub remaining_attempts: Option<u32>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorCause {
+    pub error_summary: String,
+}
+
+impl fmt::Display for ErrorResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.error_summary, self.error_code)?;
+
+        for cause in &self.error_causes {
+            write!(f, "\n    {}", cause.error_summary)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ErrorResponse {}
+
+impl ErrorResponse {
+    /// The most specific reason Okta gave, e.g. the first password policy
+    /// the new password violates rather than "Api validation failed"
+    pub fn reason(&self) -> &str {
+        self.error_causes
+            .first()
+            .map(|cause| cause.error_summary.as_str())
+            .unwrap_or(&self.error_summary)
+    }
+}
+
+/// An unsuccessful response without an explanation from Okta, e.g. for a web page
+#[derive(Debug)]
+pub struct StatusError {
+    pub status: StatusCode,
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Okta responded with {}", self.status)
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+/// Turns an unsuccessful response into an error carrying Okta's own
+/// explanation of what went wrong, if the body contains one
+pub fn extract_okta_error(response: Response) -> anyhow::Error {
+    let status = response.status();
+
+    okta_error(
+        status,
+        response.json::<ErrorResponse>().map_err(|e| e.into()),
+    )
+}
+
+// The first cause makes it into the message, all of them into the cause
+// below it, so that e.g. every violated password policy shows at once
+pub fn okta_error(status: StatusCode, body: anyhow::Result<ErrorResponse>) -> anyhow::Error {
+    match body {
+        Ok(e) => {
+            let context = format!("Okta responded with {}: {}", status, e.reason());
+            anyhow::Error::new(e).context(context)
+        }
+        Err(e) => {
+            debug!("Unable to parse Okta error response: {}", e);
+            anyhow::Error::new(StatusError { status })
+        }
+    }
+}
+
+/// Whether Okta rejected the MFA code of a verification
+pub fn is_invalid_passcode(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|c| c.downcast_ref::<ErrorResponse>())
+        .any(|response| response.error_code == OKTA_INVALID_PASSCODE)
+}
+
+/// Explains Okta rejecting an MFA code, telling how many attempts are left
+/// before the user gets locked out where Okta says so
+pub fn explain_invalid_passcode(e: anyhow::Error) -> anyhow::Error {
+    let remaining_attempts = match e.chain().find_map(|c| c.downcast_ref::<ErrorResponse>()) {
+        Some(response) if response.error_code == OKTA_INVALID_PASSCODE => {
+            response.remaining_attempts
+        }
+        _ => return e,
+    };
+
+    let message = match remaining_attempts {
+        Some(1) => "Incorrect code, 1 attempt remaining before the account gets locked".to_string(),
+        Some(attempts) => format!(
+            "Incorrect code, {} attempts remaining before the account gets locked",
+            attempts
+        ),
+        None => "Incorrect code, please check it before trying again".to_string(),
+    };
+
+    e.context(message)
+}
+
+pub fn check_okta_response(response: Response) -> anyhow::Result<Response> {
+    warn_about_clock_skew(response.headers());
+
+    let status = response.status();
+    if status.is_client_error() || status.is_server_error() {
+        Err(extract_okta_error(response))
+    } else {
+        Ok(response)
+    }
+}
+
+// Every response tells Okta's time, but the warning only needs to show once
+fn warn_about_clock_skew(headers: &HeaderMap) {
+    if SKEW_WARNED.with(|w| w.get()) {
+        return;
+    }
+
+    if let Some(warning) = clock_skew_warning(headers) {
+        eprintln!("{}", ui::warning(&warning));
+        SKEW_WARNED.with(|w| w.set(true));
+    }
+}
+
+fn clock_skew_warning(headers: &HeaderMap) -> Option<String> {
+    let date = headers.get(DATE)?.to_str().ok()?;
+    let okta_time = DateTime::parse_from_rfc2822(date).ok()?.with_timezone(&Utc);
+    let skew = (clock::now() - okta_time).num_seconds();
+
+    match skew.abs() > MAX_CLOCK_SKEW_SECONDS {
+        true => Some(format!(
+            "Your system clock is off by ~{}s ({} Okta); this can cause authentication failures, \
+             please sync your clock",
+            skew.abs(),
+            match skew > 0 {
+                true => "ahead of",
+                false => "behind",
+            }
+        )),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::test::FixedClock;
+    use crate::error::ErrorKind;
+    use anyhow::Result;
+    use reqwest::header::HeaderValue;
+    use std::fs;
+
+    #[test]
+    fn parses_error_response() -> Result<()> {
+        let error = serde_json::de::from_str::<ErrorResponse>(&fs::read_to_string(
+            "tests/fixtures/okta/error_response.json",
+        )?)?;
+
+        assert_eq!(error.error_code, "E0000001");
+        assert_eq!(error.error_summary, "Api validation failed: password");
+        assert_eq!(error.error_causes.len(), 2);
+        assert_eq!(
+            format!("{}", error),
+            "Api validation failed: password (E0000001)\n    \
+             password: Password requirements were not met. Password requirements: at least 8 characters.\n    \
+             password: Password cannot be your current password."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn surfaces_first_cause_and_keeps_all() -> Result<()> {
+        let response = serde_json::de::from_str::<ErrorResponse>(&fs::read_to_string(
+            "tests/fixtures/okta/error_response.json",
+        )?)?;
+
+        let error = okta_error(StatusCode::BAD_REQUEST, Ok(response));
+        assert_eq!(
+            "Okta responded with 400 Bad Request: password: Password requirements were not met. \
+             Password requirements: at least 8 characters.",
+            error.to_string()
+        );
+
+        let causes = format!("{:#}", error);
+        assert!(causes.contains("password: Password cannot be your current password."));
+
+        let error = okta_error(
+            StatusCode::UNAUTHORIZED,
+            Ok(serde_json::de::from_str(
+                r#"{"errorCode":"E0000004","errorSummary":"Authentication failed"}"#,
+            )?),
+        );
+        assert_eq!(
+            "Okta responded with 401 Unauthorized: Authentication failed",
+            error.to_string()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn parses_error_response_without_causes() -> Result<()> {
+        let error = serde_json::de::from_str::<ErrorResponse>(
+            r#"{"errorCode":"E0000004","errorSummary":"Authentication failed"}"#,
+        )?;
+
+        assert!(error.error_causes.is_empty());
+        assert_eq!(format!("{}", error), "Authentication failed (E0000004)");
+
+        Ok(())
+    }
+
+    #[test]
+    fn explains_remaining_attempts_after_incorrect_code() -> Result<()> {
+        let response = serde_json::de::from_str::<ErrorResponse>(&fs::read_to_string(
+            "tests/fixtures/okta/error_response_invalid_passcode.json",
+        )?)?;
+        assert_eq!(Some(2), response.remaining_attempts);
+
+        let error = explain_invalid_passcode(anyhow::Error::new(response.clone()));
+        assert_eq!(
+            "Incorrect code, 2 attempts remaining before the account gets locked",
+            error.to_string()
+        );
+        assert_eq!(ErrorKind::MfaRejected, crate::error::kind_of(&error));
+
+        let error = explain_invalid_passcode(anyhow::Error::new(ErrorResponse {
+            remaining_attempts: None,
+            ..response
+        }));
+        assert_eq!(
+            "Incorrect code, please check it before trying again",
+            error.to_string()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn leaves_other_errors_alone() -> Result<()> {
+        let response = serde_json::de::from_str::<ErrorResponse>(&fs::read_to_string(
+            "tests/fixtures/okta/error_response.json",
+        )?)?;
+
+        let error = explain_invalid_passcode(anyhow::Error::new(response));
+        assert_eq!(
+            "Api validation failed: password (E0000001)",
+            error.to_string().lines().next().unwrap()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn warns_about_skewed_clock() {
+        let mut headers = HeaderMap::new();
+        let at = |timestamp| FixedClock::at(timestamp);
+        // Sun, 14 Oct 2001 12:00:00 GMT
+        let okta_time = 1_003_060_800;
+
+        assert_eq!(None, clock_skew_warning(&headers));
+
+        headers.insert(
+            DATE,
+            HeaderValue::from_static("Sun, 14 Oct 2001 12:00:00 GMT"),
+        );
+        assert_eq!(
+            None,
+            clock::with_clock(at(okta_time + 30), || clock_skew_warning(&headers))
+        );
+        assert_eq!(
+            Some(
+                "Your system clock is off by ~95s (ahead of Okta); this can cause authentication \
+                 failures, please sync your clock"
+                    .to_string()
+            ),
+            clock::with_clock(at(okta_time + 95), || clock_skew_warning(&headers))
+        );
+        assert!(
+            clock::with_clock(at(okta_time - 3600), || clock_skew_warning(&headers))
+                .unwrap()
+                .contains("~3600s (behind Okta)")
+        );
+
+        headers.insert(DATE, HeaderValue::from_static("yesterday"));
+        assert_eq!(
+            None,
+            clock::with_clock(at(okta_time + 95), || clock_skew_warning(&headers))
+        );
+    }
+}
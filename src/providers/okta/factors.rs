@@ -1,16 +1,21 @@
 use crate::providers::okta::response::Links;
+
+use anyhow::{anyhow, Result};
 use std::collections::HashMap;
 use std::fmt;
+use std::str::FromStr;
 
 #[allow(clippy::large_enum_variant)]
-#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "lowercase", tag = "factorType")]
 pub enum Factor {
     #[serde(rename_all = "camelCase")]
     Push {
+        #[serde(default)]
         id: String,
         provider: FactorProvider,
         status: Option<FactorStatus>,
+        #[serde(default)]
         profile: PushFactorProfile,
         #[serde(rename = "_links")]
         links: Option<HashMap<String, Links>>,
@@ -19,9 +24,11 @@ pub enum Factor {
     },
     #[serde(rename_all = "camelCase")]
     Sms {
+        #[serde(default)]
         id: String,
         provider: FactorProvider,
         status: Option<FactorStatus>,
+        #[serde(default)]
         profile: SmsFactorProfile,
         #[serde(rename = "_links")]
         links: Option<HashMap<String, Links>>,
@@ -29,49 +36,197 @@ pub enum Factor {
 
     #[serde(rename = "token:software:totp", rename_all = "camelCase")]
     Totp {
+        #[serde(default)]
         id: String,
         provider: FactorProvider,
         status: Option<FactorStatus>,
+        #[serde(default)]
+        profile: TokenFactorProfile,
+        #[serde(rename = "_links")]
+        links: Option<HashMap<String, Links>>,
+        #[serde(rename = "_embedded")]
+        embedded: Option<FactorEmbedded>,
+    },
+    // Hardware or one-time password tokens like Symantec VIP, verified with
+    // the code they show just like TOTP
+    #[serde(rename_all = "camelCase")]
+    Token {
+        #[serde(default)]
+        id: String,
+        provider: FactorProvider,
+        status: Option<FactorStatus>,
+        #[serde(default)]
         profile: TokenFactorProfile,
         #[serde(rename = "_links")]
         links: Option<HashMap<String, Links>>,
     },
     WebAuthn {
+        #[serde(default)]
         id: String,
         provider: FactorProvider,
         status: Option<FactorStatus>,
+        #[serde(default)]
         profile: WebAuthnFactorProfile,
         #[serde(rename = "_links")]
         links: Option<HashMap<String, Links>>,
         #[serde(rename = "_embedded")]
         embedded: Option<FactorEmbedded>,
     },
+    U2f {
+        #[serde(default)]
+        id: String,
+        provider: FactorProvider,
+        status: Option<FactorStatus>,
+        #[serde(default)]
+        profile: U2fFactorProfile,
+        #[serde(rename = "_links")]
+        links: Option<HashMap<String, Links>>,
+        #[serde(rename = "_embedded")]
+        embedded: Option<FactorEmbedded>,
+    },
     #[serde(other)]
     Unimplemented,
 }
 
-#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct FactorEmbedded {
     pub challenge: Option<FactorChallenge>,
+    // Carries the shared secret of a TOTP factor being enrolled
+    #[serde(skip_serializing)]
+    pub activation: Option<FactorActivation>,
 }
 
-#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct FactorChallenge {
     pub challenge: Option<String>,
     pub correct_answer: Option<u64>,
+    pub nonce: Option<String>,
+    pub timeout_seconds: Option<u64>,
 }
 
 #[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FactorActivation {
+    pub shared_secret: String,
+    pub time_step: Option<u64>,
+    pub key_length: Option<u64>,
+    pub encoding: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum FactorProvider {
     Okta,
     Google,
     Fido,
+    Duo,
+    Rsa,
+    Symantec,
+    Yubico,
 }
 
-#[derive(Deserialize, Debug, Clone, PartialEq)]
+impl FromStr for FactorProvider {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "okta" => Ok(FactorProvider::Okta),
+            "google" => Ok(FactorProvider::Google),
+            "fido" => Ok(FactorProvider::Fido),
+            "duo" => Ok(FactorProvider::Duo),
+            "rsa" => Ok(FactorProvider::Rsa),
+            "symantec" => Ok(FactorProvider::Symantec),
+            "yubico" => Ok(FactorProvider::Yubico),
+            _ => Err(anyhow!("Unknown factor provider {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for FactorProvider {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FactorProvider::Okta => write!(f, "okta"),
+            FactorProvider::Google => write!(f, "google"),
+            FactorProvider::Fido => write!(f, "fido"),
+            FactorProvider::Duo => write!(f, "duo"),
+            FactorProvider::Rsa => write!(f, "rsa"),
+            FactorProvider::Symantec => write!(f, "symantec"),
+            FactorProvider::Yubico => write!(f, "yubico"),
+        }
+    }
+}
+
+/// Picks a factor by its type and, optionally, its provider, e.g.
+/// `totp` or `totp:google`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FactorSelector {
+    pub kind: String,
+    pub provider: Option<FactorProvider>,
+}
+
+impl FromStr for FactorSelector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        let kind = parts.next().unwrap_or_default().to_lowercase();
+
+        if !FACTOR_KINDS.contains(&kind.as_str()) {
+            return Err(anyhow!(
+                "Unknown MFA factor {}, valid factors are: {}",
+                kind,
+                FACTOR_KINDS.join(", ")
+            ));
+        }
+
+        let provider = match parts.next() {
+            Some(p) => Some(p.parse()?),
+            None => None,
+        };
+
+        Ok(FactorSelector { kind, provider })
+    }
+}
+
+impl fmt::Display for FactorSelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.provider {
+            Some(provider) => write!(f, "{}:{}", self.kind, provider),
+            None => write!(f, "{}", self.kind),
+        }
+    }
+}
+
+impl FactorSelector {
+    pub fn matches(&self, factor: &Factor) -> bool {
+        let kind_matches = factor.kind() == Some(self.kind.as_str());
+        match &self.provider {
+            Some(provider) => kind_matches && factor.provider() == Some(provider),
+            None => kind_matches,
+        }
+    }
+}
+
+/// A factor as `creds --factor-list-only` lists it for external selectors,
+/// along with how to pick it again. Okta's fields of the factor stay as they
+/// are, so every entry reads back as a factor as well
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ListedFactor<'a> {
+    /// For `--factor-index`
+    pub index: usize,
+    /// For `--mfa-factor`, e.g. `totp:google`
+    pub selector: String,
+    /// How the factor shows in the menu
+    pub label: String,
+    #[serde(flatten)]
+    pub factor: &'a Factor,
+}
+
+const FACTOR_KINDS: [&str; 6] = ["push", "sms", "totp", "token", "webauthn", "u2f"];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum FactorStatus {
     NotSetup,
@@ -82,50 +237,469 @@ pub enum FactorStatus {
     Expired,
 }
 
-#[derive(Deserialize, Debug, Clone, PartialEq)]
-#[serde(rename_all = "camelCase")]
+impl fmt::Display for FactorStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FactorStatus::NotSetup => write!(f, "NOT_SETUP"),
+            FactorStatus::PendingActivation => write!(f, "PENDING_ACTIVATION"),
+            FactorStatus::Enrolled => write!(f, "ENROLLED"),
+            FactorStatus::Active => write!(f, "ACTIVE"),
+            FactorStatus::Inactive => write!(f, "INACTIVE"),
+            FactorStatus::Expired => write!(f, "EXPIRED"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase", default)]
 pub struct SmsFactorProfile {
     pub phone_number: String,
 }
 
-#[derive(Deserialize, Debug, Clone, PartialEq)]
-#[serde(rename_all = "camelCase")]
+impl SmsFactorProfile {
+    /// The number in one E.164-like form, however Okta formatted it, e.g.
+    /// `+1 (XXX) XXX-1337` and `001-XXX-XXX-1337` both show as `+1XXXXXX1337`
+    pub fn display_number(&self) -> String {
+        let number = self.phone_number.trim();
+        let number = match number.strip_prefix("00") {
+            Some(rest) => format!("+{}", rest),
+            None => number.to_string(),
+        };
+
+        let normalized = number
+            .char_indices()
+            .filter_map(|(i, c)| match c {
+                '+' if i == 0 => Some('+'),
+                '0'..='9' => Some(c),
+                'x' | 'X' | '*' | '•' => Some('X'),
+                _ => None,
+            })
+            .collect::<String>();
+
+        match normalized.trim_start_matches('+').is_empty() {
+            true => self.phone_number.clone(),
+            false => normalized,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase", default)]
 pub struct PushFactorProfile {
     credential_id: String,
     device_type: String,
-    name: String,
+    pub name: String,
     platform: String,
     version: String,
 }
 
-#[derive(Deserialize, Debug, Clone, PartialEq)]
-#[serde(rename_all = "camelCase")]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase", default)]
 pub struct TokenFactorProfile {
     credential_id: String,
 }
 
-#[derive(Deserialize, Debug, Clone, PartialEq)]
-#[serde(rename_all = "camelCase")]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase", default)]
 pub struct WebAuthnFactorProfile {
     pub credential_id: String,
     pub authenticator_name: String,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct U2fFactorProfile {
+    pub credential_id: String,
+    pub app_id: Option<String>,
+    pub version: Option<String>,
+}
+
+impl Factor {
+    pub fn kind(&self) -> Option<&str> {
+        match self {
+            Factor::Push { .. } => Some("push"),
+            Factor::Sms { .. } => Some("sms"),
+            Factor::Totp { .. } => Some("totp"),
+            Factor::Token { .. } => Some("token"),
+            Factor::WebAuthn { .. } => Some("webauthn"),
+            Factor::U2f { .. } => Some("u2f"),
+            Factor::Unimplemented => None,
+        }
+    }
+
+    /// Where the factor goes in the menu, lowest first: out of band
+    /// approvals, then codes from an authenticator, security keys and SMS
+    pub fn priority(&self) -> u8 {
+        match self {
+            Factor::Push { .. } => 0,
+            Factor::Totp { .. } | Factor::Token { .. } => 1,
+            Factor::WebAuthn { .. } => 2,
+            Factor::U2f { .. } => 3,
+            Factor::Sms { .. } => 4,
+            Factor::Unimplemented => u8::MAX,
+        }
+    }
+
+    /// Whether the factor is verified with a code the user enters, rather
+    /// than approved out of band like Push or with a security key
+    pub fn requires_code(&self) -> bool {
+        match self {
+            Factor::Sms { .. } | Factor::Totp { .. } | Factor::Token { .. } => true,
+            Factor::Push { .. }
+            | Factor::WebAuthn { .. }
+            | Factor::U2f { .. }
+            | Factor::Unimplemented => false,
+        }
+    }
+
+    pub fn id(&self) -> Option<&str> {
+        match self {
+            Factor::Push { ref id, .. }
+            | Factor::Sms { ref id, .. }
+            | Factor::Totp { ref id, .. }
+            | Factor::Token { ref id, .. }
+            | Factor::WebAuthn { ref id, .. }
+            | Factor::U2f { ref id, .. } => Some(id),
+            Factor::Unimplemented => None,
+        }
+    }
+
+    /// Whether this is the Push factor of `device`, given by its name or the
+    /// end of the factor ID
+    pub fn is_push_device(&self, device: &str) -> bool {
+        match self {
+            Factor::Push {
+                ref id,
+                ref profile,
+                ..
+            } => {
+                profile.name.eq_ignore_ascii_case(device)
+                    || (!device.is_empty() && id.ends_with(device))
+            }
+            _ => false,
+        }
+    }
+
+    pub fn status(&self) -> Option<&FactorStatus> {
+        match self {
+            Factor::Push { ref status, .. }
+            | Factor::Sms { ref status, .. }
+            | Factor::Totp { ref status, .. }
+            | Factor::Token { ref status, .. }
+            | Factor::WebAuthn { ref status, .. }
+            | Factor::U2f { ref status, .. } => status.as_ref(),
+            Factor::Unimplemented => None,
+        }
+    }
+
+    pub fn provider(&self) -> Option<&FactorProvider> {
+        match self {
+            Factor::Push { ref provider, .. }
+            | Factor::Sms { ref provider, .. }
+            | Factor::Totp { ref provider, .. }
+            | Factor::Token { ref provider, .. }
+            | Factor::WebAuthn { ref provider, .. }
+            | Factor::U2f { ref provider, .. } => Some(provider),
+            Factor::Unimplemented => None,
+        }
+    }
+}
+
 impl fmt::Display for Factor {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
+            Factor::Push { ref profile, .. } if profile.name.is_empty() => {
+                write!(f, "Okta Verify Push")
+            }
             Factor::Push { ref profile, .. } => write!(f, "Okta Verify Push to {}", profile.name),
-            Factor::Sms { ref profile, .. } => write!(f, "Okta SMS to {}", profile.phone_number),
+            Factor::Sms { ref profile, .. } => {
+                write!(f, "Okta SMS to {}", profile.display_number())
+            }
+            Factor::Totp {
+                provider: FactorProvider::Symantec,
+                ..
+            }
+            | Factor::Token {
+                provider: FactorProvider::Symantec,
+                ..
+            } => write!(f, "Symantec VIP Token"),
+            Factor::Token {
+                provider: FactorProvider::Rsa,
+                ..
+            } => write!(f, "RSA SecurID"),
             Factor::Totp {
                 // Okta identifies any other TOTP provider as "Google"
                 provider: FactorProvider::Google,
                 ..
             } => write!(f, "Software TOTP"),
             Factor::Totp { .. } => write!(f, "Okta Verify TOTP"),
+            Factor::Token { .. } => write!(f, "Hardware token"),
             Factor::WebAuthn { ref profile, .. } => {
                 write!(f, "WebAuthn with {}", profile.authenticator_name)
             }
+            Factor::U2f { .. } => write!(f, "U2F security key"),
             _ => write!(f, "Unimplemented factor"),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::providers::okta::response::Response;
+    use claim::assert_err;
+    use serde_json::json;
+
+    // Knobs of the generated factors, see factor_variation
+    const FACTOR_TYPES: [(&str, Option<&str>); 8] = [
+        ("push", Some("push")),
+        ("sms", Some("sms")),
+        ("token:software:totp", Some("totp")),
+        ("token", Some("token")),
+        ("webauthn", Some("webauthn")),
+        ("u2f", Some("u2f")),
+        ("question", None),
+        ("token:hardware", None),
+    ];
+    const PROVIDERS: [Option<&str>; 6] = [
+        Some("OKTA"),
+        Some("GOOGLE"),
+        Some("FIDO"),
+        Some("DUO"),
+        Some("LASTPASS"),
+        None,
+    ];
+    const STATUSES: [Option<&str>; 4] = [
+        Some("ACTIVE"),
+        Some("PENDING_ACTIVATION"),
+        Some("LOST"),
+        None,
+    ];
+    const PROFILE_FIELDS: [&str; 8] = [
+        "credentialId",
+        "deviceType",
+        "name",
+        "platform",
+        "version",
+        "phoneNumber",
+        "authenticatorName",
+        "somethingNew",
+    ];
+
+    #[test]
+    fn deserializes_factor_variations() {
+        let mut random = XorShift(0x2545_f491_4f6c_dd1d);
+
+        for _ in 0..2000 {
+            let (factor_type, kind) = FACTOR_TYPES[random.below(FACTOR_TYPES.len())];
+            let provider = PROVIDERS[random.below(PROVIDERS.len())];
+            let status = STATUSES[random.below(STATUSES.len())];
+            let json = factor_variation(&mut random, factor_type, provider, status);
+
+            let result = serde_json::from_value::<Factor>(json.clone());
+            let valid_provider = matches!(
+                provider,
+                Some("OKTA") | Some("GOOGLE") | Some("FIDO") | Some("DUO")
+            );
+            let valid_status = status != Some("LOST");
+
+            match (kind, result) {
+                (None, Ok(factor)) => assert_eq!(Factor::Unimplemented, factor, "{}", json),
+                (Some(kind), Ok(factor)) => {
+                    assert!(valid_provider && valid_status, "accepted {}", json);
+                    assert_eq!(Some(kind), factor.kind(), "{}", json);
+                    assert_eq!(
+                        provider
+                            .map(|p| p.parse::<FactorProvider>().unwrap())
+                            .as_ref(),
+                        factor.provider(),
+                        "{}",
+                        json
+                    );
+                }
+                (Some(_), Err(_)) => assert!(!valid_provider || !valid_status, "rejected {}", json),
+                (None, Err(e)) => panic!("rejected unknown factor type {}: {}", json, e),
+            }
+        }
+    }
+
+    #[test]
+    fn deserializes_captured_responses() -> Result<()> {
+        for entry in std::fs::read_dir("tests/fixtures/okta")? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+
+            if !(name.starts_with("login_response") || name.starts_with("challenge_response")) {
+                continue;
+            }
+
+            let response: Response = serde_json::from_str(&std::fs::read_to_string(&path)?)
+                .map_err(|e| anyhow!("{} doesn't deserialize: {}", name, e))?;
+            let embedded = response
+                .embedded
+                .ok_or_else(|| anyhow!("{} has no embedded factors", name))?;
+
+            assert!(
+                embedded.factors.is_some() || embedded.factor.is_some(),
+                "{}",
+                name
+            );
+        }
+
+        Ok(())
+    }
+
+    fn factor_variation(
+        random: &mut XorShift,
+        factor_type: &str,
+        provider: Option<&str>,
+        status: Option<&str>,
+    ) -> serde_json::Value {
+        let mut factor = serde_json::Map::new();
+        factor.insert("factorType".to_string(), json!(factor_type));
+
+        if let Some(provider) = provider {
+            factor.insert("provider".to_string(), json!(provider));
+        }
+        if let Some(status) = status {
+            factor.insert("status".to_string(), json!(status));
+        }
+        if random.below(2) == 0 {
+            factor.insert("id".to_string(), json!("opf1a2b3c4"));
+        }
+        if random.below(2) == 0 {
+            factor.insert("vendorName".to_string(), json!("OKTA"));
+        }
+        if random.below(2) == 0 {
+            let profile = PROFILE_FIELDS
+                .iter()
+                .filter(|_| random.below(2) == 0)
+                .map(|f| (f.to_string(), json!("value")))
+                .collect::<serde_json::Map<String, serde_json::Value>>();
+            factor.insert("profile".to_string(), profile.into());
+        }
+        if random.below(2) == 0 {
+            factor.insert(
+                "_links".to_string(),
+                json!({"verify": {"href": "https://example.okta.com/api/v1/authn/factors/opf/verify"}}),
+            );
+        }
+
+        factor.into()
+    }
+
+    /// Keeps the generated factors the same on every run
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+    }
+
+    #[test]
+    fn normalizes_phone_numbers() {
+        let numbers = [
+            ("+49 XXXX XXX1234", "+49XXXXXXX1234"),
+            ("+1 XXX-XXX-1337", "+1XXXXXX1337"),
+            ("+1 (XXX) XXX-1337", "+1XXXXXX1337"),
+            ("001-xxx-xxx-1337", "+1XXXXXX1337"),
+            ("+44 ***.***.1234", "+44XXXXXX1234"),
+            ("(555) 123 4567", "5551234567"),
+            ("unknown", "unknown"),
+            ("", ""),
+        ];
+
+        for (number, expected) in numbers.iter() {
+            let profile = SmsFactorProfile {
+                phone_number: number.to_string(),
+            };
+            assert_eq!(*expected, profile.display_number(), "for {}", number);
+        }
+    }
+
+    #[test]
+    fn parses_factor_selectors() -> Result<()> {
+        assert_eq!(
+            FactorSelector {
+                kind: "totp".to_string(),
+                provider: None
+            },
+            "totp".parse()?
+        );
+        assert_eq!(
+            FactorSelector {
+                kind: "totp".to_string(),
+                provider: Some(FactorProvider::Google)
+            },
+            "TOTP:Google".parse()?
+        );
+        assert_err!("call".parse::<FactorSelector>());
+        assert_err!("totp:lastpass".parse::<FactorSelector>());
+
+        Ok(())
+    }
+
+    #[test]
+    fn matches_factor_type_and_provider() -> Result<()> {
+        let google = totp_factor(FactorProvider::Google);
+        let okta = totp_factor(FactorProvider::Okta);
+
+        let selector: FactorSelector = "totp:google".parse()?;
+        assert!(selector.matches(&google));
+        assert!(!selector.matches(&okta));
+
+        let selector: FactorSelector = "totp:okta".parse()?;
+        assert!(!selector.matches(&google));
+        assert!(selector.matches(&okta));
+
+        let selector: FactorSelector = "totp".parse()?;
+        assert!(selector.matches(&google));
+        assert!(selector.matches(&okta));
+        assert!(!selector.matches(&Factor::Unimplemented));
+
+        let selector: FactorSelector = "push".parse()?;
+        assert!(!selector.matches(&google));
+
+        Ok(())
+    }
+
+    #[test]
+    fn tells_factors_asking_for_code() -> Result<()> {
+        let expected = [
+            ("push", false),
+            ("sms", true),
+            ("token:software:totp", true),
+            ("webauthn", false),
+            ("u2f", false),
+            ("question", false),
+        ];
+
+        for (factor_type, requires_code) in expected.iter() {
+            let factor: Factor = serde_json::from_value(serde_json::json!({
+                "factorType": factor_type,
+                "provider": "OKTA",
+            }))?;
+
+            assert_eq!(*requires_code, factor.requires_code(), "{}", factor_type);
+        }
+
+        Ok(())
+    }
+
+    fn totp_factor(provider: FactorProvider) -> Factor {
+        Factor::Totp {
+            id: "id".to_string(),
+            provider,
+            status: None,
+            profile: TokenFactorProfile {
+                credential_id: "jdoe@example.com".to_string(),
+            },
+            links: None,
+            embedded: None,
+        }
+    }
+}
@@ -0,0 +1,171 @@
+//! How usable the MFA factors of a user look, judged by what Okta tells about
+//! them on login, without verifying any of them.
+
+use crate::providers::okta::factors::{Factor, FactorStatus};
+use crate::providers::okta::security_key;
+use crate::providers::okta::verification;
+
+// E.164 numbers have at most 15 digits, and hardly any country uses fewer
+// than 8 including the country code
+const MIN_PHONE_DIGITS: usize = 8;
+const MAX_PHONE_DIGITS: usize = 15;
+
+#[derive(Debug, PartialEq)]
+pub struct FactorHealth {
+    pub factor: String,
+    pub problems: Vec<String>,
+}
+
+impl FactorHealth {
+    pub fn is_healthy(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+pub fn check_factors(factors: &[Factor]) -> Vec<FactorHealth> {
+    factors
+        .iter()
+        .map(|factor| FactorHealth {
+            factor: factor.to_string(),
+            problems: problems(factor),
+        })
+        .collect()
+}
+
+fn problems(factor: &Factor) -> Vec<String> {
+    if *factor == Factor::Unimplemented {
+        return vec!["crowbar doesn't support this type of factor".to_string()];
+    }
+
+    let mut problems = vec![];
+
+    match factor.status() {
+        Some(FactorStatus::Active) | None => (),
+        Some(status) => problems.push(format!("its status is {}", status)),
+    }
+
+    if verification::verify_url(factor).is_err() {
+        problems.push("Okta offers no link to verify it".to_string());
+    }
+
+    match factor {
+        Factor::Sms { profile, .. } => {
+            let number = profile.display_number();
+            let digits = number.chars().filter(|c| *c != '+').count();
+
+            if !number.starts_with('+') || !(MIN_PHONE_DIGITS..=MAX_PHONE_DIGITS).contains(&digits)
+            {
+                problems.push(format!(
+                    "the phone number {} doesn't look valid",
+                    profile.phone_number
+                ));
+            }
+        }
+        Factor::WebAuthn { .. } | Factor::U2f { .. } if security_key::platform_key().is_err() => {
+            problems.push("crowbar can't use security keys on this platform yet".to_string());
+        }
+        _ => (),
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Result;
+    use serde_json::json;
+
+    #[test]
+    fn reports_problems_of_mixed_factors() -> Result<()> {
+        let verify = |id: &str| {
+            json!({
+                "verify": {
+                    "href": format!("https://example.okta.com/api/v1/authn/factors/{}/verify", id)
+                }
+            })
+        };
+        let factors: Vec<Factor> = serde_json::from_value(json!([
+            {
+                "factorType": "push",
+                "id": "push",
+                "provider": "OKTA",
+                "status": "ACTIVE",
+                "profile": { "name": "iPhone" },
+                "_links": verify("push"),
+            },
+            {
+                "factorType": "sms",
+                "id": "sms",
+                "provider": "OKTA",
+                "status": "PENDING_ACTIVATION",
+                "profile": { "phoneNumber": "+49 123" },
+                "_links": verify("sms"),
+            },
+            {
+                "factorType": "token:software:totp",
+                "id": "totp",
+                "provider": "GOOGLE",
+            },
+            {
+                "factorType": "webauthn",
+                "id": "webauthn",
+                "provider": "FIDO",
+                "profile": { "authenticatorName": "YubiKey" },
+                "_links": verify("webauthn"),
+            },
+            { "factorType": "question", "provider": "OKTA" },
+        ]))?;
+
+        let health = check_factors(&factors);
+
+        assert_eq!(
+            vec![
+                FactorHealth {
+                    factor: "Okta Verify Push to iPhone".to_string(),
+                    problems: vec![],
+                },
+                FactorHealth {
+                    factor: "Okta SMS to +49123".to_string(),
+                    problems: vec![
+                        "its status is PENDING_ACTIVATION".to_string(),
+                        "the phone number +49 123 doesn't look valid".to_string(),
+                    ],
+                },
+                FactorHealth {
+                    factor: "Software TOTP".to_string(),
+                    problems: vec!["Okta offers no link to verify it".to_string()],
+                },
+                FactorHealth {
+                    factor: "WebAuthn with YubiKey".to_string(),
+                    problems: vec![
+                        "crowbar can't use security keys on this platform yet".to_string()
+                    ],
+                },
+                FactorHealth {
+                    factor: "Unimplemented factor".to_string(),
+                    problems: vec!["crowbar doesn't support this type of factor".to_string()],
+                },
+            ],
+            health
+        );
+        assert!(health[0].is_healthy());
+
+        Ok(())
+    }
+
+    #[test]
+    fn accepts_masked_phone_numbers() -> Result<()> {
+        let factor: Factor = serde_json::from_value(json!({
+            "factorType": "sms",
+            "id": "sms",
+            "provider": "OKTA",
+            "profile": { "phoneNumber": "+1 XXX-XXX-1337" },
+            "_links": { "verify": { "href": "https://example.okta.com/api/v1/authn/factors/sms/verify" } },
+        }))?;
+
+        assert_eq!(Vec::<String>::new(), problems(&factor));
+
+        Ok(())
+    }
+}
@@ -1,6 +1,5 @@
 use crate::providers::okta::client::Client;
-use crate::providers::okta::factors::Factor;
-use crate::providers::okta::response::{Response, User};
+use crate::providers::okta::response::Response;
 use crate::providers::okta::API_AUTHN_PATH;
 
 use anyhow::Result;
@@ -30,14 +29,18 @@ impl LoginRequest {
             state_token: None,
         }
     }
-}
 
-#[derive(Deserialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
-pub struct LoginEmbedded {
-    #[serde(default)]
-    pub factors: Vec<Factor>,
-    user: User,
+    /// Continues the authentication transaction of `state_token`, e.g. one
+    /// Okta started for an app's step-up MFA
+    pub fn from_state_token(state_token: String) -> Self {
+        Self {
+            username: None,
+            password: None,
+            relay_state: None,
+            options: None,
+            state_token: Some(state_token),
+        }
+    }
 }
 
 #[derive(Serialize, Debug)]
@@ -50,6 +53,38 @@ struct Options {
 impl Client {
     pub fn login(&self, req: &LoginRequest) -> Result<Response> {
         let url = self.base_url.join(API_AUTHN_PATH)?;
-        self.post(url, req)
+        self.post_with_headers(url, req, self.authn_headers()?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sends_non_ascii_credentials_unchanged() -> Result<()> {
+        let username = "jürgen.müller@exämple.com";
+        let password = "Pässwörd-Дом-密码-🔑";
+        let request = reqwest::blocking::Client::new()
+            .post("https://example.okta.com/api/v1/authn")
+            .json(&LoginRequest::from_credentials(
+                username.to_string(),
+                password.to_string(),
+            ))
+            .build()?;
+        let body = request
+            .body()
+            .and_then(|body| body.as_bytes())
+            .unwrap_or_default();
+
+        // Raw UTF-8 rather than escapes or another encoding
+        let expected = format!(r#"{{"username":"{}","password":"{}"}}"#, username, password);
+        assert_eq!(expected.as_bytes(), body);
+
+        let parsed: serde_json::Value = serde_json::from_slice(body)?;
+        assert_eq!(username, parsed["username"]);
+        assert_eq!(password, parsed["password"]);
+
+        Ok(())
     }
 }
@@ -1,18 +1,24 @@
 use crate::providers::okta::auth::PUSH_WAIT_TIMEOUT;
 use crate::providers::okta::factors::Factor;
 
+use anyhow::{Context, Result};
 use reqwest::Url;
 use std::collections::HashMap;
 use std::fmt;
+use std::time::Duration;
+
+const POLL_LINK: &str = "poll";
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct Response {
     pub state_token: Option<String>,
     pub session_token: Option<String>,
+    #[allow(dead_code)]
     expires_at: String,
     pub status: Status,
     pub factor_result: Option<FactorResult>,
+    #[allow(dead_code)]
     relay_state: Option<String>,
     #[serde(rename = "_links", default)]
     pub links: Option<HashMap<String, Links>>,
@@ -20,6 +26,49 @@ pub struct Response {
     pub embedded: Option<Embedded>,
 }
 
+impl Response {
+    /// The factors a MFA_REQUIRED response offers to choose from. Orgs with a
+    /// policy enforcing exactly one factor may embed just that `factor`
+    pub fn factors(&self) -> Result<Vec<Factor>> {
+        self.embedded
+            .as_ref()
+            .and_then(|e| {
+                e.factors
+                    .clone()
+                    .or_else(|| e.factor.clone().map(|f| vec![f]))
+            })
+            .with_context(|| format!("Missing factors in {:?} response", self.status))
+    }
+
+    /// The factor a MFA_CHALLENGE response is about
+    pub fn factor(&self) -> Result<Factor> {
+        self.embedded
+            .as_ref()
+            .and_then(|e| e.factor.clone())
+            .with_context(|| format!("Missing factor in {:?} response", self.status))
+    }
+
+    /// How long Okta recommends waiting before polling the factor again, if
+    /// the poll link hints at it
+    pub fn poll_interval(&self) -> Option<Duration> {
+        let links = self.links.as_ref()?;
+        let link = match links.get(POLL_LINK).or_else(|| links.get("next"))? {
+            Links::Single(link) => link,
+            Links::Multi(links) => links.first()?,
+        };
+
+        link.hints
+            .as_ref()
+            .and_then(|h| h.refresh)
+            .map(Duration::from_millis)
+    }
+
+    /// The user logging in, which Okta leaves out of some responses
+    pub fn user(&self) -> Option<&User> {
+        self.embedded.as_ref().and_then(|e| e.user.as_ref())
+    }
+}
+
 #[derive(Deserialize, Debug, PartialEq)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum Status {
@@ -45,19 +94,23 @@ pub enum FactorResult {
     Timeout,
     Waiting,
     Rejected,
+    Cancelled,
+    Error,
 }
 
 impl fmt::Display for FactorResult {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            FactorResult::Waiting { .. } | FactorResult::Challenge => {
+            FactorResult::Waiting | FactorResult::Challenge => {
                 write!(f, "Waiting for confirmation")
             }
             FactorResult::Timeout => {
                 write!(f, "No verification after {} seconds", PUSH_WAIT_TIMEOUT)
             }
-            FactorResult::Rejected { .. } => write!(f, "Verification challenge was rejected"),
-            FactorResult::Success { .. } => write!(f, "Verification challenge was successful"),
+            FactorResult::Rejected => write!(f, "Verification challenge was rejected"),
+            FactorResult::Success => write!(f, "Verification challenge was successful"),
+            FactorResult::Cancelled => write!(f, "Verification challenge was cancelled"),
+            FactorResult::Error => write!(f, "Verification challenge failed at Okta"),
         }
     }
 }
@@ -69,46 +122,51 @@ pub struct Embedded {
     pub factors: Option<Vec<Factor>>,
     #[serde(default)]
     pub factor: Option<Factor>,
-    user: User,
+    #[serde(default)]
+    pub user: Option<User>,
 }
 
-#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(untagged)]
 pub enum Links {
     Single(Link),
     Multi(Vec<Link>),
 }
 
-#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct Link {
     name: Option<String>,
     #[serde(with = "serde_str")]
     pub href: Url,
-    hints: Hint,
+    hints: Option<Hint>,
 }
 
-#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct Hint {
     allow: Vec<String>,
+    // Milliseconds to wait between polls of the link, where Okta recommends it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    refresh: Option<u64>,
 }
 
 #[derive(Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct User {
-    id: String,
-    profile: UserProfile,
+    pub id: String,
+    #[serde(default)]
+    pub profile: UserProfile,
 }
 
-#[derive(Deserialize, Debug, Clone, PartialEq)]
-#[serde(rename_all = "camelCase")]
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase", default)]
 pub struct UserProfile {
-    login: String,
-    first_name: String,
-    last_name: String,
-    locale: String,
-    time_zone: String,
+    pub login: String,
+    pub first_name: String,
+    pub last_name: String,
+    pub locale: String,
+    pub time_zone: String,
 }
 
 #[cfg(test)]
@@ -140,6 +198,36 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn parses_embedded_user_and_factors() -> Result<()> {
+        let response = serde_json::de::from_str::<Response>(&fs::read_to_string(
+            "tests/fixtures/okta/login_response_mfa_required.json",
+        )?)?;
+
+        let user = response.user().unwrap();
+        assert_eq!("user-id", user.id);
+        assert_eq!("jdoe@example.com", user.profile.login);
+        assert_eq!("John", user.profile.first_name);
+        assert_eq!("America/Los_Angeles", user.profile.time_zone);
+
+        let factors = response.factors()?;
+        let kinds: Vec<_> = factors.iter().map(|f| f.kind()).collect();
+        assert_eq!(
+            vec![
+                Some("webauthn"),
+                Some("totp"),
+                Some("sms"),
+                Some("push"),
+                Some("totp")
+            ],
+            kinds
+        );
+        // Only challenges are about a single factor
+        assert!(response.factor().is_err());
+
+        Ok(())
+    }
+
     #[test]
     fn parses_webauthn_challenge_response() -> Result<()> {
         let response = serde_json::de::from_str::<Response>(&fs::read_to_string(
@@ -172,6 +260,28 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn parses_all_factor_results() -> Result<()> {
+        let results = vec![
+            ("WAITING", FactorResult::Waiting),
+            ("CHALLENGE", FactorResult::Challenge),
+            ("SUCCESS", FactorResult::Success),
+            ("REJECTED", FactorResult::Rejected),
+            ("TIMEOUT", FactorResult::Timeout),
+            ("CANCELLED", FactorResult::Cancelled),
+            ("ERROR", FactorResult::Error),
+        ];
+
+        for (name, result) in results {
+            assert_eq!(
+                result,
+                serde_json::from_value::<FactorResult>(serde_json::json!(name))?
+            );
+        }
+
+        Ok(())
+    }
+
     #[test]
     fn parses_login_response_with_unknown_factors() -> Result<()> {
         let response = serde_json::de::from_str::<Response>(&fs::read_to_string(
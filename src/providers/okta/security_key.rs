@@ -0,0 +1,226 @@
+//! Security key factors, U2F and WebAuthn. Okta answers the first verify
+//! call with a challenge that the key signs, and the signature is posted to
+//! the verify link like a code.
+
+use crate::error::{self, ErrorKind};
+use crate::providers::okta::factors::{Factor, FactorChallenge};
+use crate::providers::okta::verification::VerificationRequest;
+use crate::ui;
+
+use anyhow::{anyhow, Context, Result};
+use std::time::Duration;
+
+// How long the user has to touch the key unless Okta tells otherwise
+const DEFAULT_TOUCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    U2f,
+    WebAuthn,
+}
+
+/// What a security key has to sign to verify a factor
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyChallenge {
+    pub protocol: Protocol,
+    /// The nonce for U2F, the challenge for WebAuthn
+    pub challenge: String,
+    pub app_id: Option<String>,
+    pub credential_id: String,
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeySignature {
+    pub client_data: String,
+    pub signature_data: String,
+    /// Only WebAuthn keys authenticate themselves
+    pub authenticator_data: Option<String>,
+}
+
+pub trait SecurityKey {
+    /// Signs the challenge once the user touched the key, failing with
+    /// `ErrorKind::MfaTimeout` if that doesn't happen within its timeout
+    fn sign(&self, challenge: &KeyChallenge) -> Result<KeySignature>;
+}
+
+/// The security key of this machine. crowbar can't talk to keys on any
+/// platform yet, so this makes choosing a key factor fail before Okta is
+/// asked for a challenge
+pub fn platform_key() -> Result<Box<dyn SecurityKey>> {
+    Err(error::error(
+        ErrorKind::Authentication,
+        "Security keys aren't supported on this platform yet, please select another MFA factor, e.g. with --mfa-factor",
+    ))
+}
+
+/// Reads the challenge Okta embedded into a factor of an MFA_CHALLENGE response
+pub fn key_challenge(factor: &Factor) -> Result<KeyChallenge> {
+    let (protocol, credential_id, app_id, embedded) = match factor {
+        Factor::U2f {
+            profile, embedded, ..
+        } => (
+            Protocol::U2f,
+            &profile.credential_id,
+            profile.app_id.clone(),
+            embedded,
+        ),
+        Factor::WebAuthn {
+            profile, embedded, ..
+        } => (Protocol::WebAuthn, &profile.credential_id, None, embedded),
+        _ => return Err(anyhow!("The factor {} isn't a security key", factor)),
+    };
+
+    let challenge = embedded
+        .as_ref()
+        .and_then(|e| e.challenge.as_ref())
+        .with_context(|| format!("Okta sent no challenge for the factor {}", factor))?;
+
+    Ok(KeyChallenge {
+        protocol,
+        challenge: challenge_value(protocol, challenge)
+            .with_context(|| format!("The challenge for the factor {} is incomplete", factor))?,
+        app_id,
+        credential_id: credential_id.clone(),
+        timeout: challenge
+            .timeout_seconds
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_TOUCH_TIMEOUT),
+    })
+}
+
+fn challenge_value(protocol: Protocol, challenge: &FactorChallenge) -> Option<String> {
+    match protocol {
+        Protocol::U2f => challenge.nonce.clone(),
+        Protocol::WebAuthn => challenge.challenge.clone(),
+    }
+}
+
+/// Has the key sign the challenge. U2F signatures use the same request as
+/// WebAuthn ones, just without authenticator data
+pub fn verification_request(
+    state_token: String,
+    challenge: &KeyChallenge,
+    key: &dyn SecurityKey,
+) -> Result<VerificationRequest> {
+    eprintln!(
+        "{}",
+        ui::emphasis("Touch your security key to confirm the login")
+    );
+    let signature = key.sign(challenge)?;
+
+    Ok(VerificationRequest::WebAuthn {
+        state_token,
+        client_data: Some(signature.client_data),
+        signature_data: Some(signature.signature_data),
+        authenticator_data: signature.authenticator_data,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::providers::okta::response::Response;
+    use std::cell::RefCell;
+    use std::fs;
+
+    // Signs like a key that got touched right away
+    #[derive(Default)]
+    struct TouchedKey {
+        challenges: RefCell<Vec<KeyChallenge>>,
+    }
+
+    impl SecurityKey for TouchedKey {
+        fn sign(&self, challenge: &KeyChallenge) -> Result<KeySignature> {
+            self.challenges.borrow_mut().push(challenge.clone());
+            Ok(KeySignature {
+                client_data: "client-data".to_string(),
+                signature_data: format!("signed-{}", challenge.challenge),
+                authenticator_data: None,
+            })
+        }
+    }
+
+    #[test]
+    fn parses_u2f_challenge() -> Result<()> {
+        let challenge = key_challenge(&challenged_factor("u2f")?)?;
+
+        assert_eq!(
+            KeyChallenge {
+                protocol: Protocol::U2f,
+                challenge: "challenge-nonce".to_string(),
+                app_id: Some("https://example.okta.com".to_string()),
+                credential_id: "credential-id".to_string(),
+                timeout: Duration::from_secs(20),
+            },
+            challenge
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn parses_webauthn_challenge() -> Result<()> {
+        let challenge = key_challenge(&challenged_factor("webauthn")?)?;
+
+        assert_eq!(Protocol::WebAuthn, challenge.protocol);
+        assert_eq!("challenge", challenge.challenge);
+        assert_eq!(None, challenge.app_id);
+        assert_eq!(DEFAULT_TOUCH_TIMEOUT, challenge.timeout);
+
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_incomplete_challenge() -> Result<()> {
+        let mut factor = challenged_factor("u2f")?;
+        if let Factor::U2f {
+            ref mut embedded, ..
+        } = factor
+        {
+            embedded.as_mut().unwrap().challenge.as_mut().unwrap().nonce = None;
+        }
+
+        let error = key_challenge(&factor).unwrap_err().to_string();
+        assert!(error.contains("is incomplete"), "{}", error);
+
+        Ok(())
+    }
+
+    #[test]
+    fn posts_signed_challenge() -> Result<()> {
+        let challenge = key_challenge(&challenged_factor("u2f")?)?;
+        let key = TouchedKey::default();
+
+        let request = verification_request("state-token".to_string(), &challenge, &key)?;
+
+        assert_eq!(vec![challenge], *key.challenges.borrow());
+        assert_eq!(
+            serde_json::json!({
+                "stateToken": "state-token",
+                "clientData": "client-data",
+                "signatureData": "signed-challenge-nonce",
+            }),
+            serde_json::to_value(&request)?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn explains_missing_key_support() {
+        let error = platform_key().err().unwrap();
+
+        assert_eq!(ErrorKind::Authentication, error::kind_of(&error));
+        assert!(error.to_string().contains("--mfa-factor"));
+    }
+
+    fn challenged_factor(kind: &str) -> Result<Factor> {
+        let response = serde_json::from_str::<Response>(&fs::read_to_string(format!(
+            "tests/fixtures/okta/challenge_response_{}.json",
+            kind
+        ))?)?;
+
+        Ok(response.embedded.unwrap().factor.unwrap())
+    }
+}
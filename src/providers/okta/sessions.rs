@@ -0,0 +1,327 @@
+//! Okta sessions kept between runs with `--keep-session`. The id of the
+//! session, which browsers send as the `sid` cookie, is cached in the keyring
+//! and extended after every run, so the next run skips the password and MFA
+//! for as long as Okta keeps the session alive. Its expiry is cached along
+//! with it, so that `watch` can extend the session before it ends.
+
+use crate::config::app::AppProfile;
+use crate::credentials::keystore;
+use crate::credentials::CredentialType;
+use crate::error::OKTA_NOT_PERMITTED;
+use crate::providers::okta::client::Client;
+use crate::providers::okta::error::{okta_error, ErrorResponse};
+use crate::ui;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use reqwest::StatusCode;
+
+const API_SESSIONS_PATH: &str = "api/v1/sessions";
+const API_CURRENT_SESSION_PATH: &str = "api/v1/sessions/me";
+const API_REFRESH_SESSION_PATH: &str = "api/v1/sessions/me/lifecycle/refresh";
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Session {
+    pub id: String,
+    pub expires_at: Option<String>,
+}
+
+/// The session cached in the keyring. Earlier versions cached the bare id
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct KeptSession {
+    id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<String>,
+}
+
+/// How Okta answered checking the kept session
+#[derive(Debug, PartialEq)]
+pub enum Validation {
+    /// Along with when the session expires, if Okta tells
+    Valid(Option<String>),
+    /// Okta ended the session, so the user logs in again
+    Invalid,
+    /// The org doesn't let users read their session without an API token, so
+    /// it's used until a request fails with it
+    NotPermitted,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct SessionRequest {
+    session_token: String,
+}
+
+impl Client {
+    /// Exchanges a session token for a session, which spends the token
+    pub fn create_session(&self, session_token: String) -> Result<Session> {
+        self.post(
+            self.base_url.join(API_SESSIONS_PATH)?,
+            &SessionRequest { session_token },
+        )
+        .with_context(|| "Unable to create an Okta session")
+    }
+
+    /// Checks whether Okta still accepts the client's session id
+    pub fn validate_session(&self) -> Result<Validation> {
+        let response = self.get_unchecked(self.base_url.join(API_CURRENT_SESSION_PATH)?)?;
+        let status = response.status();
+
+        match status.is_success() {
+            true => Ok(Validation::Valid(
+                response.json::<Session>().ok().and_then(|s| s.expires_at),
+            )),
+            false => validation(status, response.json().map_err(|e| e.into())),
+        }
+    }
+
+    /// Extends the session of the client's session id as if the user was active
+    pub fn refresh_session(&self) -> Result<Session> {
+        self.post(
+            self.base_url.join(API_REFRESH_SESSION_PATH)?,
+            &serde_json::Map::new(),
+        )
+    }
+}
+
+/// The id of the session an earlier run kept for the profile
+pub fn kept_session(profile: &AppProfile) -> Option<String> {
+    load(profile).map(|session| session.id)
+}
+
+/// When the session an earlier run kept for the profile expires, if known
+pub fn kept_session_expiry(profile: &AppProfile) -> Option<DateTime<Utc>> {
+    parse_expiry(load(profile)?.expires_at.as_deref()?)
+}
+
+/// Extends the session `id`, known to expire at `expires_at`, and caches it
+/// for the next run. Organizations may not allow extending sessions, which
+/// keeps them until they expire. Returns when the kept session expires
+pub fn keep<F>(
+    profile: &AppProfile,
+    id: &str,
+    expires_at: Option<String>,
+    refresh: F,
+) -> Option<DateTime<Utc>>
+where
+    F: FnOnce() -> Result<Session>,
+{
+    let session = match refresh() {
+        Ok(session) => {
+            debug!("Extended the Okta session until {:?}", session.expires_at);
+            KeptSession {
+                id: session.id,
+                expires_at: session.expires_at.or(expires_at),
+            }
+        }
+        Err(e) => {
+            eprintln!(
+                "{}",
+                ui::warning(&format!(
+                    "Unable to extend the Okta session, it's kept until it expires: {:#}",
+                    e
+                ))
+            );
+            KeptSession {
+                id: id.to_string(),
+                expires_at,
+            }
+        }
+    };
+
+    store(profile, &session);
+    parse_expiry(session.expires_at.as_deref()?)
+}
+
+/// Keeps the session `id` another profile of the same organization logged in
+/// with for the profile, so that it continues the session as well
+pub fn adopt(profile: &AppProfile, id: &str) {
+    store(
+        profile,
+        &KeptSession {
+            id: id.to_string(),
+            expires_at: None,
+        },
+    );
+}
+
+/// Drops a session Okta doesn't accept anymore
+pub fn forget(profile: &AppProfile) {
+    let _ = keystore::delete_password(&service(profile), &profile.username)
+        .map_err(|e| debug!("Couldn't remove the Okta session: {}", e));
+}
+
+// Okta answers for an ended session with 404, and with 401 where the org
+// requires more than the session cookie
+fn validation(status: StatusCode, body: Result<ErrorResponse>) -> Result<Validation> {
+    match (status, body) {
+        (StatusCode::UNAUTHORIZED, _) | (StatusCode::NOT_FOUND, _) => Ok(Validation::Invalid),
+        (StatusCode::FORBIDDEN, Ok(ref e)) if e.error_code == OKTA_NOT_PERMITTED => {
+            Ok(Validation::NotPermitted)
+        }
+        (status, body) => Err(okta_error(status, body)),
+    }
+}
+
+fn load(profile: &AppProfile) -> Option<KeptSession> {
+    let cached = keystore::get_cached(&service(profile), &profile.username).ok()?;
+
+    Some(serde_json::from_str(&cached).unwrap_or(KeptSession {
+        id: cached,
+        expires_at: None,
+    }))
+}
+
+fn store(profile: &AppProfile, session: &KeptSession) {
+    let _ = serde_json::to_string(session)
+        .map_err(|e| e.into())
+        .and_then(|session| keystore::set_password(&service(profile), &profile.username, &session))
+        .map_err(|e| debug!("Couldn't cache the Okta session: {}", e));
+}
+
+fn parse_expiry(expires_at: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(expires_at)
+        .map(|expiry| expiry.with_timezone(&Utc))
+        .map_err(|e| debug!("Ignoring the Okta session expiry {}: {}", expires_at, e))
+        .ok()
+}
+
+fn service(profile: &AppProfile) -> String {
+    CredentialType::Session.service(&profile.name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::app::test::{okta_profile, OKTA_URL};
+    use crate::credentials::keystore::test::MemoryBackend;
+    use anyhow::anyhow;
+    use std::cell::Cell;
+    use std::fs;
+    use std::rc::Rc;
+
+    #[test]
+    fn caches_refreshed_session() -> Result<()> {
+        let profile = okta_profile("profile", OKTA_URL);
+        let refreshed = Cell::new(0);
+
+        keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+            assert_eq!(None, kept_session(&profile));
+
+            let expiry = keep(&profile, "101W_juydrDRByB7fUdRyE2JQ", None, || {
+                refreshed.set(refreshed.get() + 1);
+                read_session()
+            });
+
+            assert_eq!(1, refreshed.get());
+            assert_eq!(
+                Some("102bZDNFfWaQSyEZQuDgWt-uQ".to_string()),
+                kept_session(&profile)
+            );
+            let expected = parse_expiry("2020-03-16T20:32:08Z");
+            assert!(expected.is_some());
+            assert_eq!(expected, expiry);
+            assert_eq!(expected, kept_session_expiry(&profile));
+
+            forget(&profile);
+            assert_eq!(None, kept_session(&profile));
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn logs_in_again_once_session_ended() -> Result<()> {
+        assert_eq!(
+            Validation::Invalid,
+            validation(StatusCode::UNAUTHORIZED, read_error("error_response.json"))?
+        );
+        assert_eq!(
+            Validation::Invalid,
+            validation(StatusCode::UNAUTHORIZED, Err(anyhow!("Empty body")))?
+        );
+        assert_eq!(
+            Validation::Invalid,
+            validation(StatusCode::NOT_FOUND, read_error("error_response.json"))?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn skips_validation_not_permitted_by_org() -> Result<()> {
+        assert_eq!(
+            Validation::NotPermitted,
+            validation(
+                StatusCode::FORBIDDEN,
+                read_error("error_response_not_permitted.json")
+            )?
+        );
+
+        // Any other refusal is an error of its own
+        let error =
+            validation(StatusCode::FORBIDDEN, read_error("error_response.json")).unwrap_err();
+        assert!(error.to_string().contains("403"), "{}", error);
+        assert!(validation(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Err(anyhow!("Empty body"))
+        )
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_session_if_refreshing_is_not_allowed() -> Result<()> {
+        let profile = okta_profile("profile", OKTA_URL);
+
+        keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+            let expiry = keep(
+                &profile,
+                "101W_juydrDRByB7fUdRyE2JQ",
+                Some("2020-03-16T19:32:08.000Z".to_string()),
+                || Err(anyhow!("Okta responded with 403 Forbidden")),
+            );
+
+            assert_eq!(
+                Some("101W_juydrDRByB7fUdRyE2JQ".to_string()),
+                kept_session(&profile)
+            );
+            assert_eq!(parse_expiry("2020-03-16T19:32:08Z"), expiry);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn reads_sessions_kept_by_earlier_versions() -> Result<()> {
+        let profile = okta_profile("profile", OKTA_URL);
+
+        keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+            keystore::set_password(&service(&profile), "jdoe", "101W_juydrDRByB7fUdRyE2JQ")?;
+
+            assert_eq!(
+                Some("101W_juydrDRByB7fUdRyE2JQ".to_string()),
+                kept_session(&profile)
+            );
+            assert_eq!(None, kept_session_expiry(&profile));
+
+            Ok(())
+        })
+    }
+
+    fn read_session() -> Result<Session> {
+        Ok(serde_json::from_str(&fs::read_to_string(
+            "tests/fixtures/okta/session_response.json",
+        )?)?)
+    }
+
+    fn read_error(name: &str) -> Result<ErrorResponse> {
+        Ok(serde_json::from_str(&fs::read_to_string(format!(
+            "tests/fixtures/okta/{}",
+            name
+        ))?)?)
+    }
+}
@@ -0,0 +1,160 @@
+//! Step-up MFA some apps require on access, even right after a login. Okta
+//! answers the app embed link with a verification page instead of the SAML
+//! form, whose state token continues the authentication transaction through
+//! the authn API like a login does.
+
+use crate::providers::okta::client::Client;
+use crate::providers::okta::login::LoginRequest;
+use crate::providers::okta::response::Status;
+use crate::providers::okta::SignInPage;
+
+use anyhow::{Context, Result};
+
+const STATE_TOKEN_MARKER: &str = "var stateToken = '";
+
+impl Client {
+    /// Verifies MFA for the transaction of `state_token` and returns the
+    /// session token of the satisfied session, or none if Okta doesn't ask
+    /// for MFA, as is the case for a plain sign-in page
+    pub fn step_up(&self, state_token: String) -> Result<Option<String>> {
+        let response = self.login(&LoginRequest::from_state_token(state_token))?;
+
+        match response.status {
+            Status::MfaRequired | Status::MfaChallenge | Status::Success => {
+                Ok(Some(self.get_session_token(response)?))
+            }
+            status => {
+                debug!("Okta answered the step-up with {:?}", status);
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Returns the app `page` if it's the SAML form. A step-up page is satisfied
+/// with `verify` before fetching the page again with `refetch` and the
+/// resulting session token
+pub fn satisfy<V, F>(page: String, verify: V, refetch: F) -> Result<String>
+where
+    V: FnOnce(String) -> Result<Option<String>>,
+    F: FnOnce(String) -> Result<String>,
+{
+    if page.contains("SAMLResponse") {
+        return Ok(page);
+    }
+
+    let state_token = match state_token(&page) {
+        Some(state_token) => state_token,
+        None => return Ok(page),
+    };
+
+    eprintln!("The app requires verifying your identity again");
+
+    match verify(state_token).with_context(|| "Verifying MFA again (step-up) for the app failed")? {
+        Some(session_token) => refetch(session_token),
+        None => Err(SignInPage.into()),
+    }
+}
+
+/// The state token the sign-in widget of `html` is set up with
+pub fn state_token(html: &str) -> Option<String> {
+    let start = html.find(STATE_TOKEN_MARKER)? + STATE_TOKEN_MARKER.len();
+    let end = html[start..].find('\'')?;
+
+    Some(unescape(&html[start..start + end]))
+}
+
+// Okta writes the values of the page's script with JavaScript escapes like
+// \x2D for -
+fn unescape(s: &str) -> String {
+    let mut unescaped = String::new();
+    let mut rest = s;
+
+    while let Some(i) = rest.find("\\x") {
+        unescaped.push_str(&rest[..i]);
+
+        match rest
+            .get(i + 2..i + 4)
+            .and_then(|code| u8::from_str_radix(code, 16).ok())
+        {
+            Some(c) => {
+                unescaped.push(c as char);
+                rest = &rest[i + 4..];
+            }
+            None => {
+                unescaped.push_str("\\x");
+                rest = &rest[i + 2..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+
+    unescaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::providers::okta;
+    use anyhow::anyhow;
+    use std::fs;
+
+    const STATE_TOKEN: &str = "00Mh8K1Ja5x4msRDd-wAKhnHQhrR0bSN0Q8uDEDhvW";
+    const SAML_FORM: &str = r#"<form><input name="SAMLResponse" value="abc"/></form>"#;
+
+    #[test]
+    fn extracts_state_token_of_step_up_page() -> Result<()> {
+        let page = fs::read_to_string("tests/fixtures/okta/step_up_page.html")?;
+
+        assert_eq!(Some(STATE_TOKEN.to_string()), state_token(&page));
+        assert_eq!(None, state_token(SAML_FORM));
+
+        Ok(())
+    }
+
+    #[test]
+    fn fetches_saml_again_after_step_up() -> Result<()> {
+        let page = fs::read_to_string("tests/fixtures/okta/step_up_page.html")?;
+
+        let input = satisfy(
+            page,
+            |state_token| {
+                assert_eq!(STATE_TOKEN, state_token);
+                Ok(Some("20111nHMj4dJ8Ns".to_string()))
+            },
+            |session_token| {
+                assert_eq!("20111nHMj4dJ8Ns", session_token);
+                Ok(SAML_FORM.to_string())
+            },
+        )?;
+        assert_eq!(SAML_FORM, input);
+
+        let input = satisfy(
+            SAML_FORM.to_string(),
+            |_| panic!("The SAML form needs no step-up"),
+            |_| panic!("The SAML form needs no refetch"),
+        )?;
+        assert_eq!(SAML_FORM, input);
+
+        Ok(())
+    }
+
+    #[test]
+    fn tells_failed_step_up_from_sign_in_page() -> Result<()> {
+        let page = fs::read_to_string("tests/fixtures/okta/step_up_page.html")?;
+
+        let sign_in = satisfy(page.clone(), |_| Ok(None), |_| unreachable!()).unwrap_err();
+        assert!(okta::needs_login(&sign_in));
+
+        let failed = satisfy(
+            page,
+            |_| Err(anyhow!("The MFA request was rejected")),
+            |_| unreachable!(),
+        )
+        .unwrap_err();
+        assert!(!okta::needs_login(&failed));
+        assert!(failed.to_string().contains("step-up"), "{}", failed);
+
+        Ok(())
+    }
+}
@@ -3,9 +3,14 @@ use crate::providers::okta::factors::Factor;
 use crate::providers::okta::response::{Links, Response};
 
 use anyhow::{anyhow, Result};
+use reqwest::Url;
 use std::collections::HashMap;
+use std::fmt;
 
-#[derive(Deserialize, Debug, Serialize)]
+const MASK: &str = "******";
+const TOKEN_PREFIX_LENGTH: usize = 3;
+
+#[derive(Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum VerificationRequest {
     #[serde(rename_all = "camelCase")]
@@ -33,28 +38,73 @@ pub enum VerificationRequest {
     },
 }
 
+// Requests end up in debug logs users paste into issues, so codes are masked
+// and state tokens cut down to a prefix
+impl fmt::Debug for VerificationRequest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerificationRequest::Sms {
+                state_token,
+                pass_code,
+            } => f
+                .debug_struct("Sms")
+                .field("state_token", &token_prefix(state_token))
+                .field("pass_code", &pass_code.as_ref().map(|_| MASK))
+                .finish(),
+            VerificationRequest::Push { state_token } => f
+                .debug_struct("Push")
+                .field("state_token", &token_prefix(state_token))
+                .finish(),
+            VerificationRequest::Totp { state_token, .. } => f
+                .debug_struct("Totp")
+                .field("state_token", &token_prefix(state_token))
+                .field("pass_code", &MASK)
+                .finish(),
+            VerificationRequest::WebAuthn {
+                state_token,
+                signature_data,
+                authenticator_data,
+                client_data,
+            } => f
+                .debug_struct("WebAuthn")
+                .field("state_token", &token_prefix(state_token))
+                .field("signature_data", &signature_data.as_ref().map(|_| MASK))
+                .field(
+                    "authenticator_data",
+                    &authenticator_data.as_ref().map(|_| MASK),
+                )
+                .field("client_data", &client_data.as_ref().map(|_| MASK))
+                .finish(),
+        }
+    }
+}
+
+fn token_prefix(token: &str) -> String {
+    format!(
+        "{}…",
+        token.chars().take(TOKEN_PREFIX_LENGTH).collect::<String>()
+    )
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ChallengeRequest {
+    state_token: String,
+}
+
 impl Client {
+    /// Makes Okta send the code or notification of a two-phase factor, SMS or
+    /// Push, without answering it. Okta responds with MFA_CHALLENGE and the
+    /// state to verify the factor with
+    pub fn challenge(&self, factor: &Factor, state_token: String) -> Result<Response> {
+        self.post(
+            remember_device(challenge_url(factor)?),
+            &ChallengeRequest { state_token },
+        )
+    }
+
     pub fn verify(&self, factor: &Factor, request: &VerificationRequest) -> Result<Response> {
-        match *factor {
-            Factor::Sms { ref links, .. }
-            | Factor::Totp { ref links, .. }
-            | Factor::Push { ref links, .. }
-            | Factor::WebAuthn { ref links, .. } => {
-                if let Some(l) = links {
-                    let url = match l.get("verify").unwrap() {
-                        Links::Single(ref link) => link.href.clone(),
-                        Links::Multi(ref links) => links.first().unwrap().href.clone(),
-                    };
-
-                    self.post(url, request)
-                } else {
-                    Err(anyhow!("Missing verification link in factor"))
-                }
-            }
-            _ => Err(anyhow!(
-                "The factor cannot be verified since it isn't implemented"
-            )),
-        }
+        self.post(remember_device(verify_url(factor)?), request)
     }
 
     pub fn poll(
@@ -70,3 +120,152 @@ impl Client {
         self.post(url, request)
     }
 }
+
+// Okta starts a challenge by posting to the factor's verify link without a
+// code, or for security keys without a signature
+fn challenge_url(factor: &Factor) -> Result<Url> {
+    match factor {
+        Factor::Sms { .. } | Factor::Push { .. } | Factor::WebAuthn { .. } | Factor::U2f { .. } => {
+            verify_url(factor)
+        }
+        _ => Err(anyhow!("The factor {} can't be challenged", factor)),
+    }
+}
+
+// Asks Okta to trust the device once the factor is verified, which it does
+// with the DT cookie where the sign-on policy allows it
+fn remember_device(mut url: Url) -> Url {
+    url.query_pairs_mut().append_pair("rememberDevice", "true");
+    url
+}
+
+pub fn verify_url(factor: &Factor) -> Result<Url> {
+    match *factor {
+        Factor::Sms { ref links, .. }
+        | Factor::Totp { ref links, .. }
+        | Factor::Token { ref links, .. }
+        | Factor::Push { ref links, .. }
+        | Factor::WebAuthn { ref links, .. }
+        | Factor::U2f { ref links, .. } => match links.as_ref().and_then(|l| l.get("verify")) {
+            Some(Links::Single(ref link)) => Ok(link.href.clone()),
+            Some(Links::Multi(ref links)) if !links.is_empty() => Ok(links[0].href.clone()),
+            _ => Err(anyhow!("Missing verification link in factor")),
+        },
+        _ => Err(anyhow!(
+            "The factor cannot be verified since it isn't implemented"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn challenges_two_phase_factors_at_their_verify_link() -> Result<()> {
+        let factors = read_factors()?;
+
+        assert_eq!(
+            "https://example.okta.com/api/v1/authn/factors/factor-id-sms/verify",
+            challenge_url(find_factor(&factors, "sms"))?.as_str()
+        );
+        assert_eq!(
+            "https://example.okta.com/api/v1/authn/factors/factor-id-push/verify",
+            challenge_url(find_factor(&factors, "push"))?.as_str()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_to_challenge_code_factors() -> Result<()> {
+        let factors = read_factors()?;
+        let totp = find_factor(&factors, "totp");
+
+        assert!(challenge_url(totp).is_err());
+        assert_eq!(
+            "https://example.okta.com/api/v1/authn/factors/factor-id-totp-software/verify",
+            verify_url(totp)?.as_str()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn serializes_challenge_without_code() -> Result<()> {
+        let request = ChallengeRequest {
+            state_token: "state-token".to_string(),
+        };
+
+        assert_eq!(
+            r#"{"stateToken":"state-token"}"#,
+            serde_json::to_string(&request)?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn omits_pass_code_from_initiating_sms_request() -> Result<()> {
+        let challenge = serde_json::to_value(&ChallengeRequest {
+            state_token: "state-token".to_string(),
+        })?;
+        let verification = serde_json::to_value(&VerificationRequest::Sms {
+            state_token: "state-token".to_string(),
+            pass_code: None,
+        })?;
+
+        for body in &[challenge, verification] {
+            let body = body.as_object().unwrap();
+            assert!(body.contains_key("stateToken"));
+            assert!(!body.contains_key("passCode"));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn redacts_debug_output() {
+        let requests = [
+            VerificationRequest::Totp {
+                state_token: "state-token".to_string(),
+                pass_code: "123456".to_string(),
+            },
+            VerificationRequest::Sms {
+                state_token: "state-token".to_string(),
+                pass_code: Some("123456".to_string()),
+            },
+        ];
+
+        for request in requests.iter() {
+            for output in &[format!("{:?}", request), format!("{:#?}", request)] {
+                assert!(!output.contains("123456"), "{}", output);
+                assert!(!output.contains("state-token"), "{}", output);
+                assert!(output.contains("\"******\""), "{}", output);
+                assert!(output.contains("\"sta…\""), "{}", output);
+            }
+        }
+
+        let challenge = VerificationRequest::Sms {
+            state_token: "state-token".to_string(),
+            pass_code: None,
+        };
+        assert!(format!("{:?}", challenge).contains("pass_code: None"));
+    }
+
+    fn find_factor<'a>(factors: &'a [Factor], kind: &str) -> &'a Factor {
+        factors.iter().find(|f| f.kind() == Some(kind)).unwrap()
+    }
+
+    fn read_factors() -> Result<Vec<Factor>> {
+        let response = serde_json::de::from_str::<Response>(&fs::read_to_string(
+            "tests/fixtures/okta/login_response_mfa_required.json",
+        )?)?;
+
+        Ok(response
+            .embedded
+            .and_then(|e| e.factors)
+            .unwrap_or_default())
+    }
+}
@@ -0,0 +1,104 @@
+//! Summaries of failed runs to copy into bug reports, see `--bug-report`.
+//! They are only printed, nothing is sent anywhere.
+
+use crate::error;
+use crate::timings;
+
+use std::cell::RefCell;
+use std::env::consts::{ARCH, OS};
+use std::panic;
+use std::sync::atomic::{AtomicBool, Ordering};
+use url::{Host, Url};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static ORG_DOMAIN: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Prints a summary after failures, including panics
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        eprintln!("\n{}", summary("panic"));
+    }));
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Remembers the IdP of the profile in use, keeping only its top-level domain
+pub fn set_org_url(url: &str) {
+    let domain = Url::parse(url).ok().and_then(|url| match url.host() {
+        Some(Host::Domain(domain)) => domain.rsplit('.').next().map(|tld| format!("*.{}", tld)),
+        Some(_) => Some("<ip address>".to_string()),
+        None => None,
+    });
+
+    ORG_DOMAIN.with(|d| d.replace(domain));
+}
+
+pub fn error_summary(e: &anyhow::Error) -> String {
+    summary(&error::kind_of(e).to_string())
+}
+
+// Error messages are left out on purpose, they are printed right above and
+// can contain usernames or URLs
+fn summary(error_kind: &str) -> String {
+    let org_domain = ORG_DOMAIN.with(|d| d.borrow().clone());
+    let phase = timings::last_phase();
+
+    [
+        "Please include the following in your bug report:".to_string(),
+        format!("  crowbar version: {}", env!("CARGO_PKG_VERSION")),
+        format!("  OS: {} ({})", OS, ARCH),
+        format!(
+            "  Org domain: {}",
+            org_domain.as_deref().unwrap_or("unknown")
+        ),
+        format!("  Error kind: {}", error_kind),
+        format!(
+            "  Last phase: {}",
+            phase.map_or("none".to_string(), |p| p.to_string())
+        ),
+    ]
+    .join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::ErrorKind;
+    use crate::timings::Phase;
+
+    #[test]
+    fn summarizes_failure_without_secrets() {
+        set_org_url("https://secret-org.okta.com/home/amazon_aws/0oa1b2c3d4/272");
+        timings::measure(Phase::MfaWait, || ());
+        let e = error::error(ErrorKind::MfaRejected, "Authentication failed")
+            .context("Password hunter2 of jdoe@secret-org.com wasn't accepted");
+
+        let summary = error_summary(&e);
+
+        assert!(summary.contains("Error kind: mfa_rejected"), "{}", summary);
+        assert!(summary.contains("Last phase: MFA wait"), "{}", summary);
+        assert!(summary.contains("Org domain: *.com"), "{}", summary);
+        assert!(summary.contains(env!("CARGO_PKG_VERSION")), "{}", summary);
+        for secret in &["secret-org", "okta.com", "hunter2", "jdoe", "amazon_aws"] {
+            assert!(!summary.contains(secret), "{}", summary);
+        }
+    }
+
+    #[test]
+    fn redacts_org_addresses() {
+        set_org_url("https://10.0.0.1/adfs/ls/IdpInitiatedSignOn.aspx");
+        assert!(summary("unknown").contains("Org domain: <ip address>"));
+
+        set_org_url("not a url");
+        assert!(summary("unknown").contains("Org domain: unknown"));
+    }
+}
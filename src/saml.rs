@@ -1,69 +1,286 @@
+use crate::aws::duration;
 use crate::aws::role as RoleManager;
-use crate::aws::role::Role;
+use crate::aws::role::{AccessDenied, Role};
 use crate::credentials::aws::AwsCredentials;
-use crate::utils;
+use crate::timings::{self, Phase};
+use crate::ui;
+use crate::utils::{self, RoleOptions};
 
 use anyhow::{anyhow, Context as AnyhowContext, Result};
 use base64::decode;
+use chrono::{DateTime, Duration, Utc};
+use rusoto_sts::AssumeRoleWithSAMLResponse;
 use select::document::Document;
-use select::predicate::Attr;
+use select::predicate::{Attr, Name, Predicate};
+use std::cell::RefCell;
 use std::collections::HashSet;
+use std::fmt;
 use std::str::FromStr;
 use sxd_document::parser;
+use sxd_document::Package;
 use sxd_xpath::{Context, Factory, Value};
 
+// Tolerated difference between our clock and the IdP's
+const ALLOWED_CLOCK_SKEW_SECONDS: i64 = 300;
+const ROLE_ATTRIBUTE: &str = "https://aws.amazon.com/SAML/Attributes/Role";
+const SESSION_DURATION_ATTRIBUTE: &str = "https://aws.amazon.com/SAML/Attributes/SessionDuration";
+
+thread_local! {
+    static ASSUMED_ROLE: RefCell<Option<Role>> = const { RefCell::new(None) };
+}
+
+/// The role assumed with an assertion since the last call, if any
+pub fn take_assumed_role() -> Option<Role> {
+    ASSUMED_ROLE.with(|r| r.take())
+}
+
 #[derive(PartialEq, Debug)]
 pub struct Response {
     pub raw: String,
     pub roles: HashSet<Role>,
+    pub conditions: Conditions,
+    /// The longest session in seconds the IdP allows, if it states one
+    pub session_duration: Option<i64>,
+}
+
+/// The validity period of an assertion, if the IdP states one
+#[derive(PartialEq, Debug, Default)]
+pub struct Conditions {
+    pub not_before: Option<DateTime<Utc>>,
+    pub not_on_or_after: Option<DateTime<Utc>>,
+}
+
+impl Conditions {
+    /// Catches assertions STS would reject as an opaque invalid SAML response,
+    /// typically because the local clock is off
+    pub fn check(&self, now: DateTime<Utc>) -> Result<()> {
+        let skew = Duration::seconds(ALLOWED_CLOCK_SKEW_SECONDS);
+
+        if let Some(not_before) = self.not_before {
+            if now + skew < not_before {
+                return Err(anyhow!(
+                    "The SAML assertion is only valid from {}, but the system clock says it is {}. \
+                     Your system clock may be wrong",
+                    not_before,
+                    now
+                ));
+            }
+        }
+
+        if let Some(not_on_or_after) = self.not_on_or_after {
+            if now - skew >= not_on_or_after {
+                return Err(anyhow!(
+                    "The SAML assertion expired at {}, but the system clock says it is {}. \
+                     Your system clock may be wrong",
+                    not_on_or_after,
+                    now
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// An attribute of the assertion's AttributeStatement, e.g. the roles or
+/// session tags the IdP hands to AWS
+#[derive(PartialEq, Debug)]
+pub struct Attribute {
+    pub name: String,
+    pub values: Vec<String>,
+}
+
+impl fmt::Display for Attribute {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+
+        for value in &self.values {
+            match self.name.as_str() {
+                ROLE_ATTRIBUTE => match value.parse::<Role>() {
+                    Ok(role) => write!(
+                        f,
+                        "\n  role {}, principal {}",
+                        role.role_arn, role.provider_arn
+                    )?,
+                    Err(e) => write!(f, "\n  {} (not a role/principal pair: {})", value, e)?,
+                },
+                _ => write!(f, "\n  {}", value)?,
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl FromStr for Response {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self> {
-        let decoded_saml = String::from_utf8(decode(&s)?)?;
-
         trace!("SAML: {}", s);
 
-        let package = parser::parse(&decoded_saml).expect("Failed parsing xml");
-        let document = package.as_document();
+        let assertion = ParsedAssertion::parse(s)?;
 
-        let xpath = Factory::new()
-            .build("//saml2:Attribute[@Name='https://aws.amazon.com/SAML/Attributes/Role']/saml2:AttributeValue")?
-            .with_context(|| "No XPath was compiled")?;
+        Ok(Response {
+            roles: assertion.roles()?,
+            conditions: assertion.conditions()?,
+            session_duration: assertion.session_duration()?,
+            raw: assertion.raw,
+        })
+    }
+}
 
-        let mut context = Context::new();
-        context.set_namespace("saml2", "urn:oasis:names:tc:SAML:2.0:assertion");
+/// A base64 encoded assertion, decoded and parsed once for all the values
+/// read from it
+pub struct ParsedAssertion {
+    pub raw: String,
+    package: Package,
+}
 
-        let roles = match xpath.evaluate(&context, document.root())? {
-            Value::Nodeset(ns) => ns
-                .iter()
-                .map(|a| a.string_value().parse())
-                .collect::<Result<HashSet<Role>, anyhow::Error>>()?,
-            _ => HashSet::new(),
-        };
+impl ParsedAssertion {
+    pub fn parse(raw: &str) -> Result<Self> {
+        let decoded_saml = String::from_utf8(decode(raw)?)?;
+        let package = parser::parse(&decoded_saml).with_context(|| "Failed parsing xml")?;
 
-        Ok(Response {
-            raw: s.to_owned(),
-            roles,
+        Ok(ParsedAssertion {
+            raw: raw.to_owned(),
+            package,
         })
     }
+
+    pub fn roles(&self) -> Result<HashSet<Role>> {
+        match self.evaluate(&format!(
+            "//saml2:Attribute[@Name='{}']/saml2:AttributeValue",
+            ROLE_ATTRIBUTE
+        ))? {
+            Value::Nodeset(ns) => ns.iter().map(|a| a.string_value().parse()).collect(),
+            _ => Ok(HashSet::new()),
+        }
+    }
+
+    pub fn conditions(&self) -> Result<Conditions> {
+        Ok(Conditions {
+            not_before: self.time("//saml2:Conditions/@NotBefore")?,
+            not_on_or_after: self.time("//saml2:Conditions/@NotOnOrAfter")?,
+        })
+    }
+
+    /// The longest session in seconds the IdP allows, if it states one
+    pub fn session_duration(&self) -> Result<Option<i64>> {
+        Ok(self
+            .evaluate(&format!(
+                "//saml2:Attribute[@Name='{}']/saml2:AttributeValue",
+                SESSION_DURATION_ATTRIBUTE
+            ))?
+            .string()
+            .trim()
+            .parse()
+            .ok())
+    }
+
+    /// Every attribute of the AttributeStatement, without interpreting values
+    pub fn attributes(&self) -> Result<Vec<Attribute>> {
+        let context = context();
+        let values = Factory::new()
+            .build("saml2:AttributeValue")?
+            .with_context(|| "No XPath was compiled")?;
+
+        let nodes = match self.evaluate("//saml2:AttributeStatement/saml2:Attribute")? {
+            Value::Nodeset(ns) => ns.document_order(),
+            _ => vec![],
+        };
+
+        nodes
+            .into_iter()
+            .map(|node| {
+                let name = node
+                    .element()
+                    .and_then(|e| e.attribute_value("Name"))
+                    .unwrap_or_default()
+                    .to_owned();
+                let values = match values.evaluate(&context, node)? {
+                    Value::Nodeset(ns) => ns
+                        .document_order()
+                        .iter()
+                        .map(|v| v.string_value())
+                        .collect(),
+                    _ => vec![],
+                };
+
+                Ok(Attribute { name, values })
+            })
+            .collect()
+    }
+
+    fn time(&self, path: &str) -> Result<Option<DateTime<Utc>>> {
+        match self.evaluate(path)? {
+            Value::Nodeset(ns) => match ns.document_order_first() {
+                Some(node) => Ok(Some(
+                    DateTime::parse_from_rfc3339(&node.string_value())
+                        .with_context(|| format!("Invalid time in SAML assertion at {}", path))?
+                        .with_timezone(&Utc),
+                )),
+                None => Ok(None),
+            },
+            _ => Ok(None),
+        }
+    }
+
+    fn evaluate(&self, path: &str) -> Result<Value<'_>> {
+        let xpath = Factory::new()
+            .build(path)?
+            .with_context(|| "No XPath was compiled")?;
+
+        Ok(xpath.evaluate(&context(), self.package.as_document().root())?)
+    }
 }
 
-pub fn get_credentials_from_saml(input: String, role: Option<String>) -> Result<AwsCredentials> {
+fn context<'d>() -> Context<'d> {
+    let mut context = Context::new();
+    context.set_namespace("saml2", "urn:oasis:names:tc:SAML:2.0:assertion");
+    context
+}
+
+pub fn get_credentials_from_saml(input: String, options: &RoleOptions) -> Result<AwsCredentials> {
     let saml = extract_saml_assertion(&input)?;
 
     debug!("SAML response: {:?}", &saml);
 
+    saml.conditions.check(Utc::now())?;
+
     let roles = saml.roles;
 
     debug!("SAML Roles: {:?}", &roles);
 
-    let role = utils::select_role(roles, role)?;
+    let raw = saml.raw;
+    let session_duration = saml.session_duration;
+    let assumption_response = assume_selected_role(roles, options, |role| {
+        let duration = match options.duration {
+            None if options.prompt_duration => duration::ask(role, session_duration)?,
+            duration => duration,
+        };
+
+        let response = duration::assume_with(role, duration, session_duration, |seconds| {
+            ui::explain(&format!(
+                "Assuming role {} for {}s…",
+                role.role_arn,
+                seconds.unwrap_or(duration::DEFAULT_SECONDS)
+            ));
+            timings::measure_bounded(Phase::RoleAssumption, || {
+                RoleManager::assume_role(
+                    role,
+                    raw.clone(),
+                    seconds,
+                    options.region.as_ref(),
+                    options.global_sts,
+                    options.sts_endpoint_url.as_deref(),
+                )
+            })
+        })?;
 
-    let assumption_response =
-        RoleManager::assume_role(&role, saml.raw).with_context(|| "Error assuming role")?;
+        ASSUMED_ROLE.with(|r| r.replace(Some(role.clone())));
+        Ok(response)
+    })
+    .with_context(|| "Error assuming role")?;
 
     Ok(AwsCredentials::from(
         assumption_response.credentials.with_context(|| {
@@ -72,27 +289,76 @@ pub fn get_credentials_from_saml(input: String, role: Option<String>) -> Result<
     ))
 }
 
+/// Assumes the selected role. If STS denies a role the user picked from the
+/// menu, the menu is shown again without it, as the assertion is still valid
+fn assume_selected_role<F>(
+    mut roles: HashSet<Role>,
+    options: &RoleOptions,
+    assume: F,
+) -> Result<AssumeRoleWithSAMLResponse>
+where
+    F: Fn(&Role) -> Result<AssumeRoleWithSAMLResponse>,
+{
+    let mut options = options.clone();
+
+    loop {
+        let selected = utils::select_role(roles.clone(), &options)?;
+
+        match assume(&selected.role) {
+            Err(e) if selected.prompted && e.is::<AccessDenied>() => {
+                eprintln!(
+                    "{}",
+                    ui::warning(&format!("{}. Please select another role", e))
+                );
+                roles.remove(&selected.role);
+                options.role = None;
+            }
+            result => return result,
+        }
+    }
+}
+
 pub fn extract_saml_assertion(text: &str) -> Result<Response> {
+    extract_saml_value(text)?.parse()
+}
+
+/// Reads every attribute of the assertion in the given HTML form. Unlike the
+/// roles of [`Response`] this doesn't fail for malformed values, so they can
+/// be inspected
+pub fn extract_attributes(text: &str) -> Result<Vec<Attribute>> {
+    ParsedAssertion::parse(&extract_saml_value(text)?)?.attributes()
+}
+
+// The value of the input the IdP's form posts to AWS. The parser copes with
+// any quoting, attribute order and line breaks within the tag and decodes
+// entities, skipping other elements named SAMLResponse, e.g. meta tags. Some
+// IdPs wrap long values over several lines, which base64 doesn't allow
+fn extract_saml_value(text: &str) -> Result<String> {
     let document = Document::from(text);
-    let node = document.find(Attr("name", "SAMLResponse")).next();
+    let mut inputs = document
+        .find(Name("input").and(Attr("name", "SAMLResponse")))
+        .peekable();
 
-    if let Some(element) = node {
-        if let Some(value) = element.attr("value") {
-            value.parse()
-        } else {
-            Err(anyhow!("Missing SAML response in assertion element"))
-        }
-    } else {
-        Err(anyhow!("Could not find SAML element in HTML response"))
+    if inputs.peek().is_none() {
+        return Err(anyhow!("Could not find SAML element in HTML response"));
     }
+
+    inputs
+        .filter_map(|input| input.attr("value"))
+        .map(|value| value.split_whitespace().collect::<String>())
+        .find(|value| !value.is_empty())
+        .with_context(|| "Missing SAML response in assertion element")
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::prompt::{self, test::ScriptedPrompter};
     use base64::encode;
     use claim::assert_ok;
+    use std::cell::RefCell;
     use std::fs;
+    use std::rc::Rc;
 
     #[test]
     fn parse_okta_response() -> Result<()> {
@@ -154,6 +420,292 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn extracts_saml_value_from_markup_variants() -> Result<()> {
+        let value = encode(&fs::read_to_string(
+            "tests/fixtures/okta/saml_response.xml",
+        )?);
+        let wrapped = value
+            .as_bytes()
+            .chunks(76)
+            .map(|line| String::from_utf8_lossy(line).to_string())
+            .collect::<Vec<String>>()
+            .join("\r\n");
+        let forms = vec![
+            format!(r#"<input name="SAMLResponse" type="hidden" value="{}"/>"#, value),
+            format!(r#"<input type='hidden' value='{}' name='SAMLResponse'>"#, value),
+            format!(
+                "<form method=\"post\" action=\"https://signin.aws.amazon.com/saml\">\n  <input\n    type=\"hidden\"\n    name=\"SAMLResponse\"\n    value=\"{}\"\n  />\n</form>",
+                value
+            ),
+            format!(r#"<INPUT TYPE=hidden NAME=SAMLResponse VALUE={}>"#, value),
+            format!(
+                r#"<input name="SAMLResponse" value="{}">"#,
+                value.replace('+', "&#x2b;").replace('=', "&#x3d;")
+            ),
+            format!(r#"<input name="SAMLResponse" value="&#10;{}&#10;">"#, wrapped),
+            format!(
+                r#"<meta name="SAMLResponse" content="ignored"><input name="SAMLResponse" value=""><input name="SAMLResponse" value="{}">"#,
+                value
+            ),
+            // Cut off right after the input, e.g. by a dropped connection
+            format!(r#"<html><body><form><input name="SAMLResponse" value="{}"><input type="sub"#, value),
+        ];
+
+        for form in forms {
+            assert_eq!(value, extract_saml_value(&form)?, "{}", form);
+        }
+
+        assert!(extract_saml_value(r#"<meta name="SAMLResponse" content="value">"#).is_err());
+        assert!(extract_saml_value(r#"<input name="SAMLResponse">"#).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn parses_assertion_conditions() -> Result<()> {
+        let response = get_response("tests/fixtures/okta/saml_response.xml")?;
+
+        assert_eq!(
+            Conditions {
+                not_before: Some(time("2014-07-17T01:01:18Z")),
+                not_on_or_after: Some(time("2024-01-18T06:21:48Z")),
+            },
+            response.conditions
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn parses_session_duration() -> Result<()> {
+        assert_eq!(
+            Some(43200),
+            get_response("tests/fixtures/okta/saml_response.xml")?.session_duration
+        );
+        assert_eq!(
+            Some(21600),
+            get_response("tests/fixtures/jumpcloud/saml_response.xml")?.session_duration
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_expired_assertion() -> Result<()> {
+        let conditions = get_response("tests/fixtures/jumpcloud/saml_response.xml")?.conditions;
+
+        assert_ok!(conditions.check(time("2020-04-10T19:50:00Z")));
+        // Within the allowed skew
+        assert_ok!(conditions.check(time("2020-04-10T19:55:00Z")));
+
+        let error = conditions
+            .check(time("2020-04-10T20:00:00Z"))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("expired"));
+        assert!(error.contains("system clock may be wrong"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_not_yet_valid_assertion() -> Result<()> {
+        let conditions = get_response("tests/fixtures/jumpcloud/saml_response.xml")?.conditions;
+
+        assert_ok!(conditions.check(time("2020-04-10T19:40:00Z")));
+
+        let error = conditions
+            .check(time("2020-04-10T19:30:00Z"))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("only valid from"));
+        assert!(error.contains("system clock may be wrong"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn reselects_role_denied_by_sts() -> Result<()> {
+        let roles = vec![
+            create_role("role1"),
+            create_role("role2"),
+            create_role("role3"),
+        ]
+        .into_iter()
+        .collect::<HashSet<Role>>();
+        let attempts = RefCell::new(vec![]);
+        let prompter = Rc::new(ScriptedPrompter::new(&["0", "0"]));
+
+        prompt::with_prompter(prompter, || -> Result<()> {
+            assume_selected_role(roles, &RoleOptions::default(), |role| {
+                attempts.borrow_mut().push(role.role_arn.clone());
+                assume_unless_denied(role)
+            })?;
+            Ok(())
+        })?;
+
+        assert_eq!(
+            vec![
+                "arn:aws:iam::123456789012:role/role1",
+                "arn:aws:iam::123456789012:role/role2"
+            ],
+            *attempts.borrow()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn fails_for_denied_configured_role() {
+        let roles = vec![create_role("role1"), create_role("role2")]
+            .into_iter()
+            .collect::<HashSet<Role>>();
+        let options = RoleOptions {
+            role: Some("arn:aws:iam::123456789012:role/role1".to_string()),
+            ..RoleOptions::default()
+        };
+
+        let error = assume_selected_role(roles, &options, assume_unless_denied)
+            .unwrap_err()
+            .to_string();
+
+        assert!(error.contains("arn:aws:iam::123456789012:role/role1"));
+    }
+
+    #[test]
+    fn extracts_assertion_attributes() -> Result<()> {
+        let attributes = extract_attributes(&saml_form("tests/fixtures/okta/saml_response.xml")?)?;
+
+        assert_eq!(
+            vec![
+                "https://aws.amazon.com/SAML/Attributes/Role",
+                "https://aws.amazon.com/SAML/Attributes/RoleSessionName",
+                "https://aws.amazon.com/SAML/Attributes/SessionDuration",
+            ],
+            attributes
+                .iter()
+                .map(|a| a.name.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            "https://aws.amazon.com/SAML/Attributes/Role\n  \
+             role arn:aws:iam::123456789012:role/role1, principal arn:aws:iam::123456789012:saml-provider/okta-idp\n  \
+             role arn:aws:iam::123456789012:role/role2, principal arn:aws:iam::123456789012:saml-provider/okta-idp",
+            attributes[0].to_string()
+        );
+        assert_eq!(vec!["test@example.com"], attributes[1].values);
+        assert_eq!(vec!["43200"], attributes[2].values);
+
+        Ok(())
+    }
+
+    #[test]
+    fn shows_malformed_role_attributes() -> Result<()> {
+        let attributes = extract_attributes(&saml_form(
+            "tests/fixtures/okta/saml_response_invalid_no_role.xml",
+        )?)?;
+
+        assert!(attributes[0]
+            .to_string()
+            .contains("not a role/principal pair: Not enough elements"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn reads_all_values_from_one_parse() -> Result<()> {
+        let raw = encode(&fs::read_to_string(
+            "tests/fixtures/okta/saml_response.xml",
+        )?);
+        let assertion = ParsedAssertion::parse(&raw)?;
+        let roles = assertion.roles()?;
+        let attributes = assertion.attributes()?;
+
+        assert_eq!(Some(43200), assertion.session_duration()?);
+        assert_eq!(vec!["43200"], attributes[2].values);
+        assert_eq!(
+            roles,
+            attributes[0]
+                .values
+                .iter()
+                .map(|v| v.parse())
+                .collect::<Result<HashSet<Role>>>()?
+        );
+        assert_eq!(raw.parse::<Response>()?.roles, roles);
+
+        Ok(())
+    }
+
+    // A benchmark rather than a test, run with
+    // `cargo test --release saml -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn parsing_once_is_faster_than_per_value() -> Result<()> {
+        use std::time::Instant;
+
+        const RUNS: u32 = 2000;
+        let form = saml_form("tests/fixtures/okta/saml_response.xml")?;
+
+        let start = Instant::now();
+        for _ in 0..RUNS {
+            let assertion = ParsedAssertion::parse(&extract_saml_value(&form)?)?;
+            assertion.roles()?;
+            assertion.conditions()?;
+            assertion.session_duration()?;
+            assertion.attributes()?;
+        }
+        let once = start.elapsed();
+
+        let start = Instant::now();
+        for _ in 0..RUNS {
+            let raw = extract_saml_value(&form)?;
+            ParsedAssertion::parse(&raw)?.roles()?;
+            ParsedAssertion::parse(&raw)?.conditions()?;
+            ParsedAssertion::parse(&raw)?.session_duration()?;
+            extract_attributes(&form)?;
+        }
+        let per_value = start.elapsed();
+
+        println!(
+            "parsed once: {:?}/run, parsed per value: {:?}/run",
+            once / RUNS,
+            per_value / RUNS
+        );
+        assert!(once < per_value);
+
+        Ok(())
+    }
+
+    fn saml_form(path: &str) -> Result<String> {
+        Ok(format!(
+            r#"<input name="SAMLResponse" type="hidden" value="{}"/>"#,
+            encode(&fs::read_to_string(path)?)
+        ))
+    }
+
+    fn assume_unless_denied(role: &Role) -> Result<AssumeRoleWithSAMLResponse> {
+        match role.role_arn.ends_with("role1") {
+            true => Err(AccessDenied {
+                role_arn: role.role_arn.clone(),
+                message: "Not authorized to perform sts:AssumeRoleWithSAML".to_string(),
+            }
+            .into()),
+            false => Ok(AssumeRoleWithSAMLResponse::default()),
+        }
+    }
+
+    fn create_role(name: &str) -> Role {
+        Role {
+            provider_arn: "arn:aws:iam::123456789012:saml-provider/okta-idp".to_string(),
+            role_arn: format!("arn:aws:iam::123456789012:role/{}", name),
+        }
+    }
+
+    fn time(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
     fn get_response(path: &str) -> Result<Response> {
         let saml_xml: String = fs::read_to_string(path)?;
         let saml_base64 = encode(&saml_xml);
@@ -0,0 +1,489 @@
+//! Hands out the credentials of a profile over a Unix socket, so that many
+//! shells and tools share one process answering the password and MFA prompts.
+//! Every connection gets the credential_process JSON and is closed again,
+//! e.g. `credential_process = nc -U /run/user/1000/crowbar-default.sock`.
+//!
+//! With `--prompt-once` it serves every configured profile at one socket,
+//! each connection naming its profile on the first line. The Okta session of
+//! the first login is kept and continued by all profiles of the same
+//! organization and user, so MFA is only asked for again once Okta ends it.
+
+use crate::config::app::AppProfile;
+use crate::config::CrowbarConfig;
+use crate::credentials::aws::{self as CredentialsProvider, AwsCredentials, FetchOptions};
+use crate::providers::okta::sessions;
+use crate::ui;
+use crate::watch;
+
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+// How long a client may take to name its profile, as it holds up the others
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// Only the owner may connect, the credentials are as good as a login
+const SOCKET_MODE: u32 = 0o600;
+
+/// Serves the credentials of a profile at `socket` until interrupted,
+/// refreshing them once they expired
+pub fn serve(
+    profile: String,
+    socket: Option<String>,
+    crowbar_config: CrowbarConfig,
+    options: &FetchOptions,
+) -> Result<()> {
+    let path = socket
+        .map(PathBuf::from)
+        .unwrap_or_else(|| default_socket(&profile));
+
+    watch::handle_signals()?;
+    let socket = Socket::bind(&path)?;
+    ui::print_success(&format!(
+        "Serving credentials of profile {} at {}",
+        profile,
+        path.display()
+    ));
+
+    let mut options = options.clone();
+    let mut cached = None;
+    socket.serve(|stream| {
+        respond(stream, &mut cached, &mut || {
+            let result = CredentialsProvider::fetch_aws_credentials(
+                profile.clone(),
+                crowbar_config.clone(),
+                &options,
+            )?;
+            // Re-entering the password once is enough
+            options.force = false;
+
+            Ok(result.credentials)
+        })
+    })?;
+
+    eprintln!("Stopped serving profile {}", profile);
+
+    Ok(())
+}
+
+/// Serves the credentials of every configured profile at `socket` until
+/// interrupted, sharing one Okta session between the profiles of an
+/// organization. Connections are answered one at a time, so that two
+/// profiles never prompt at once
+pub fn serve_all(
+    socket: Option<String>,
+    crowbar_config: CrowbarConfig,
+    options: &FetchOptions,
+) -> Result<()> {
+    let path = socket
+        .map(PathBuf::from)
+        .unwrap_or_else(default_shared_socket);
+
+    watch::handle_signals()?;
+    let socket = Socket::bind(&path)?;
+    ui::print_success(&format!(
+        "Serving credentials of all profiles at {}",
+        path.display()
+    ));
+
+    let mut options = FetchOptions {
+        keep_session: true,
+        ..options.clone()
+    };
+    let mut shared = SharedSessions::default();
+    let mut cached = HashMap::new();
+    socket.serve(|stream| {
+        respond_to_profile(stream, &mut cached, &mut |name| {
+            let profile = crowbar_config
+                .find_profile(name)?
+                .with_resolved_username()?;
+            let result = shared.fetch(&profile, || {
+                CredentialsProvider::fetch_aws_credentials(
+                    name.to_string(),
+                    crowbar_config.clone(),
+                    &options,
+                )
+            })?;
+            options.force = false;
+
+            Ok(result.credentials)
+        })
+    })?;
+
+    eprintln!("Stopped serving credentials");
+
+    Ok(())
+}
+
+/// The runtime directory of the user where there is one, e.g.
+/// `/run/user/1000/crowbar-default.sock`
+pub fn default_socket(profile: &str) -> PathBuf {
+    dirs_next::runtime_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join(format!("crowbar-{}.sock", profile))
+}
+
+/// Where `--prompt-once` listens without `--socket`, next to the sockets of
+/// single profiles
+pub fn default_shared_socket() -> PathBuf {
+    dirs_next::runtime_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("crowbar.sock")
+}
+
+/// The Okta sessions profiles logged in with by organization and user, which
+/// the other profiles of the same organization and user continue
+#[derive(Debug, Default)]
+struct SharedSessions(HashMap<(String, String), String>);
+
+impl SharedSessions {
+    fn fetch<T, F>(&mut self, profile: &AppProfile, fetch: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T>,
+    {
+        let key = (
+            profile
+                .base_url()?
+                .host_str()
+                .unwrap_or_default()
+                .to_string(),
+            profile.username.clone(),
+        );
+
+        if let Some(id) = self.0.get(&key) {
+            if sessions::kept_session(profile).as_ref() != Some(id) {
+                sessions::adopt(profile, id);
+            }
+        }
+
+        let result = fetch();
+        // A profile that had to log in again brings a new session
+        if let Some(id) = sessions::kept_session(profile) {
+            self.0.insert(key, id);
+        }
+
+        result
+    }
+}
+
+/// A listening socket, removed again once dropped
+struct Socket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl Socket {
+    fn bind(path: &Path) -> Result<Socket> {
+        remove_stale(path)?;
+
+        // The socket mustn't be accessible by others even for a moment
+        let umask = unsafe { libc::umask(0o177) };
+        let listener = UnixListener::bind(path);
+        unsafe { libc::umask(umask) };
+        let listener =
+            listener.with_context(|| format!("Unable to listen at {}", path.display()))?;
+        fs::set_permissions(path, fs::Permissions::from_mode(SOCKET_MODE))?;
+
+        Ok(Socket {
+            listener,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Answers connections with `respond` until interrupted. A failure to
+    /// respond closes the connection without an answer
+    fn serve<F>(&self, mut respond: F) -> Result<()>
+    where
+        F: FnMut(UnixStream) -> Result<()>,
+    {
+        self.listener.set_nonblocking(true)?;
+
+        while !watch::is_stopped() {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = respond(stream) {
+                        eprintln!(
+                            "{}",
+                            ui::warning(&format!("Unable to serve credentials: {:#}", e))
+                        );
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    watch::idle(|| thread::sleep(ACCEPT_INTERVAL))
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// `fetch` is only asked for new credentials if there are none yet or they
+// expired
+fn respond<F>(
+    mut stream: UnixStream,
+    cached: &mut Option<AwsCredentials>,
+    fetch: &mut F,
+) -> Result<()>
+where
+    F: FnMut() -> Result<AwsCredentials>,
+{
+    stream.set_nonblocking(false)?;
+
+    let credentials = match cached.take() {
+        Some(credentials) if credentials.valid() && !credentials.is_expired() => credentials,
+        _ => fetch()?,
+    };
+    let written = writeln!(stream, "{}", credentials);
+    *cached = Some(credentials);
+
+    Ok(written?)
+}
+
+// Like `respond` for the profile the client names on the first line
+fn respond_to_profile<F>(
+    stream: UnixStream,
+    cached: &mut HashMap<String, AwsCredentials>,
+    fetch: &mut F,
+) -> Result<()>
+where
+    F: FnMut(&str) -> Result<AwsCredentials>,
+{
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let mut line = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut line)
+        .with_context(|| "Unable to read the profile of the request")?;
+    let profile = line.trim();
+    if profile.is_empty() {
+        return Err(anyhow!(
+            "The request named no profile, send its name on the first line"
+        ));
+    }
+
+    let mut credentials = cached.remove(profile);
+    let result = respond(stream, &mut credentials, &mut || fetch(profile));
+    if let Some(credentials) = credentials {
+        cached.insert(profile.to_string(), credentials);
+    }
+
+    result.with_context(|| format!("Unable to serve profile {}", profile))
+}
+
+// A socket left behind by a crowbar that got killed is replaced, one that
+// still answers or anything that isn't a socket is left alone
+fn remove_stale(path: &Path) -> Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    if !metadata.file_type().is_socket() {
+        return Err(anyhow!(
+            "{} exists and isn't a socket, refusing to replace it",
+            path.display()
+        ));
+    }
+
+    if UnixStream::connect(path).is_ok() {
+        return Err(anyhow!(
+            "Credentials are already served at {}",
+            path.display()
+        ));
+    }
+
+    Ok(fs::remove_file(path)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::app::test::okta_profile;
+    use crate::credentials::keystore::{self, test::MemoryBackend};
+    use std::cell::Cell;
+    use std::io::Read;
+    use std::net::Shutdown;
+    use std::rc::Rc;
+
+    #[test]
+    fn serves_credentials_over_the_socket() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("crowbar-profile.sock");
+        let socket = Socket::bind(&path)?;
+        let fetched = Cell::new(0);
+
+        let client_path = path.clone();
+        let client = thread::spawn(move || -> Result<Vec<String>> {
+            (0..2)
+                .map(|_| {
+                    let mut response = String::new();
+                    UnixStream::connect(&client_path)?.read_to_string(&mut response)?;
+                    Ok(response)
+                })
+                .collect()
+        });
+
+        let mut cached = None;
+        for _ in 0..2 {
+            let (stream, _) = socket.listener.accept()?;
+            respond(stream, &mut cached, &mut || {
+                fetched.set(fetched.get() + 1);
+                Ok(create_credentials("2038-01-01T10:10:10Z"))
+            })?;
+        }
+
+        let expected = format!("{}\n", create_credentials("2038-01-01T10:10:10Z"));
+        assert_eq!(vec![expected.clone(), expected], client.join().unwrap()?);
+        // The second request is answered from memory
+        assert_eq!(1, fetched.get());
+
+        Ok(())
+    }
+
+    #[test]
+    fn refreshes_expired_credentials() -> Result<()> {
+        let (server, mut client) = UnixStream::pair()?;
+        let mut cached = Some(create_credentials("2004-01-01T10:10:10Z"));
+
+        respond(server, &mut cached, &mut || {
+            Ok(create_credentials("2038-01-01T10:10:10Z"))
+        })?;
+
+        let mut response = String::new();
+        client.read_to_string(&mut response)?;
+        assert!(response.contains("2038-01-01T10:10:10Z"), "{}", response);
+        assert_eq!(Some(create_credentials("2038-01-01T10:10:10Z")), cached);
+
+        Ok(())
+    }
+
+    #[test]
+    fn serves_all_profiles_from_one_login() -> Result<()> {
+        let profiles = [
+            okta_profile("prod", "https://example.okta.com/home/amazon_aws/0oa1/272"),
+            okta_profile(
+                "staging",
+                "https://example.okta.com/home/amazon_aws/0oa2/272",
+            ),
+            okta_profile("other", "https://other.okta.com/home/amazon_aws/0oa3/272"),
+        ];
+        let logins = Cell::new(0);
+        let mut shared = SharedSessions::default();
+        let mut cached = HashMap::new();
+
+        keystore::with_backend(Rc::new(MemoryBackend::default()), || {
+            let mut request = |name: &str| -> Result<String> {
+                let (server, mut client) = UnixStream::pair()?;
+                writeln!(client, "{}", name)?;
+                client.shutdown(Shutdown::Write)?;
+
+                respond_to_profile(server, &mut cached, &mut |name| {
+                    let profile = profiles.iter().find(|p| p.name == name).unwrap();
+                    // Logs in like a provider does without a session to continue
+                    shared.fetch(profile, || {
+                        if sessions::kept_session(profile).is_none() {
+                            logins.set(logins.get() + 1);
+                            sessions::adopt(profile, &format!("session-{}", logins.get()));
+                        }
+                        Ok(create_credentials("2038-01-01T10:10:10Z"))
+                    })
+                })?;
+
+                let mut response = String::new();
+                client.read_to_string(&mut response)?;
+                Ok(response)
+            };
+
+            for name in &["prod", "staging", "prod"] {
+                let response = request(name)?;
+                assert!(response.contains("2038-01-01T10:10:10Z"), "{}", response);
+            }
+            assert_eq!(1, logins.get());
+            assert_eq!(
+                Some("session-1".to_string()),
+                sessions::kept_session(&profiles[1])
+            );
+
+            // Another organization needs a login of its own
+            request("other")?;
+            assert_eq!(2, logins.get());
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn refuses_requests_without_profile() -> Result<()> {
+        let (server, client) = UnixStream::pair()?;
+        client.shutdown(Shutdown::Write)?;
+
+        let error = respond_to_profile(server, &mut HashMap::new(), &mut |_| {
+            Ok(create_credentials("2038-01-01T10:10:10Z"))
+        })
+        .unwrap_err();
+        assert!(error.to_string().contains("named no profile"), "{}", error);
+
+        Ok(())
+    }
+
+    #[test]
+    fn restricts_socket_to_owner_and_removes_it() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("crowbar-profile.sock");
+
+        let socket = Socket::bind(&path)?;
+        let mode = fs::metadata(&path)?.permissions().mode();
+        assert_eq!(SOCKET_MODE, mode & 0o777);
+
+        let error = Socket::bind(&path).err().unwrap().to_string();
+        assert!(error.contains("already served"), "{}", error);
+
+        drop(socket);
+        assert!(!path.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn replaces_stale_socket_only() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("crowbar-profile.sock");
+
+        drop(UnixListener::bind(&path)?);
+        assert!(path.exists());
+        Socket::bind(&path)?;
+
+        let file = dir.path().join("credentials");
+        fs::write(&file, "keep me")?;
+        assert!(Socket::bind(&file).is_err());
+        assert_eq!("keep me", fs::read_to_string(&file)?);
+
+        Ok(())
+    }
+
+    fn create_credentials(expiration: &str) -> AwsCredentials {
+        AwsCredentials {
+            version: 1,
+            access_key_id: Some("some_key".to_string()),
+            secret_access_key: Some("some_secret".to_string()),
+            session_token: Some("some_token".to_string()),
+            expiration: Some(expiration.to_string()),
+        }
+    }
+}
@@ -0,0 +1,419 @@
+//! The identity of the last run in a small JSON file, see `--status-file`,
+//! for shell prompts to show the current AWS role without running crowbar.
+//! Runs that used cached credentials didn't assume a role, so the role of the
+//! file is kept as long as it describes the same credentials.
+//!
+//! `crowbar status` lists how the cached credentials of every profile stand,
+//! from the keyring and this file only, without asking the IdP or AWS.
+
+use crate::clock;
+use crate::config::app::AppProfile;
+use crate::credentials::aws::{AwsCredentials, RunResult};
+use crate::credentials::Credential;
+use crate::output::SummaryFormat;
+use crate::ui;
+use crate::utils;
+
+use anyhow::{Context, Result};
+use std::cell::RefCell;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const STATUS_DIRECTORY: &str = "crowbar";
+const STATUS_FILE: &str = "status";
+
+thread_local! {
+    static FILE: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Status {
+    profile: String,
+    role_arn: Option<String>,
+    account_id: Option<String>,
+    expiration: Option<String>,
+}
+
+impl Status {
+    fn new(profile: &str, result: &RunResult) -> Status {
+        let role = result.role.as_ref();
+
+        Status {
+            profile: profile.to_string(),
+            role_arn: role.map(|r| r.role_arn.clone()),
+            account_id: role.map(|r| r.account_id().to_string()),
+            expiration: result.credentials.expiration.clone(),
+        }
+    }
+
+    fn same_credentials(&self, other: &Status) -> bool {
+        self.profile == other.profile && self.expiration == other.expiration
+    }
+}
+
+/// `~/.cache/crowbar/status`, where `--status-file` without a path writes to
+pub fn default_file() -> Option<PathBuf> {
+    dirs_next::cache_dir().map(|dir| dir.join(STATUS_DIRECTORY).join(STATUS_FILE))
+}
+
+/// Writes the status to `path` after every successful run from now on
+pub fn set_file(path: Option<PathBuf>) {
+    FILE.with(|f| f.replace(path));
+}
+
+/// Describes the credentials of `profile` a run ended up with. Failing to
+/// write them only warns, the credentials are still good
+pub fn record(profile: &str, result: &Result<RunResult>) {
+    if let (Some(path), Ok(result)) = (FILE.with(|f| f.borrow().clone()), result) {
+        if let Err(e) = write(&path, profile, result) {
+            warn!("Unable to write the status to {}: {:#}", path.display(), e);
+        }
+    }
+}
+
+fn write(path: &Path, profile: &str, result: &RunResult) -> Result<()> {
+    let mut status = Status::new(profile, result);
+
+    if status.role_arn.is_none() {
+        if let Some(previous) = read(path).filter(|p| p.same_credentials(&status)) {
+            status.role_arn = previous.role_arn;
+            status.account_id = previous.account_id;
+        }
+    }
+
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)
+            .with_context(|| format!("Unable to create {}", directory.display()))?;
+    }
+
+    // Prompts may read the file at any time, so it's replaced at once
+    let temporary = path.with_extension("tmp");
+    let mut content = serde_json::to_string(&status)?;
+    content.push('\n');
+    utils::write_secure(&temporary, content.as_bytes())
+        .with_context(|| format!("Unable to write {}", temporary.display()))?;
+    fs::rename(&temporary, path).with_context(|| format!("Unable to replace {}", path.display()))
+}
+
+fn read(path: &Path) -> Option<Status> {
+    serde_json::from_str(&fs::read_to_string(path).ok()?).ok()
+}
+
+/// How the cached credentials of a profile stand. Expiring ones have less
+/// than the buffer left crowbar refreshes credentials at
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CredentialState {
+    Valid,
+    Expiring,
+    Expired,
+    Missing,
+}
+
+impl fmt::Display for CredentialState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CredentialState::Valid => write!(f, "valid"),
+            CredentialState::Expiring => write!(f, "expiring soon"),
+            CredentialState::Expired => write!(f, "expired"),
+            CredentialState::Missing => write!(f, "none cached"),
+        }
+    }
+}
+
+impl CredentialState {
+    // Credentials with an expiration that can't be parsed count as expired,
+    // as they do everywhere else
+    fn of(credentials: &AwsCredentials) -> CredentialState {
+        if !credentials.valid() {
+            return CredentialState::Missing;
+        }
+
+        match credentials.expires_at() {
+            Some(expiration) if expiration > clock::now() && !credentials.is_expired() => {
+                CredentialState::Valid
+            }
+            Some(expiration) if expiration > clock::now() => CredentialState::Expiring,
+            _ => CredentialState::Expired,
+        }
+    }
+
+    fn paint(self) -> String {
+        match self {
+            CredentialState::Valid => ui::success(&self.to_string()),
+            CredentialState::Expiring => ui::warning(&self.to_string()),
+            CredentialState::Expired | CredentialState::Missing => ui::error(&self.to_string()),
+        }
+    }
+}
+
+/// A profile as `crowbar status` lists it. The role is the one the status
+/// file recorded for the same credentials, otherwise the configured one
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ProfileStatus {
+    profile: String,
+    state: CredentialState,
+    expiration: Option<String>,
+    role_arn: Option<String>,
+    account_id: Option<String>,
+}
+
+impl ProfileStatus {
+    fn new(profile: &AppProfile, recorded: Option<&Status>) -> ProfileStatus {
+        let credentials = AwsCredentials::load(profile).unwrap_or_default();
+        let state = CredentialState::of(&credentials);
+        let recorded = recorded.filter(|r| {
+            r.profile == profile.name
+                && state != CredentialState::Missing
+                && r.expiration == credentials.expiration
+        });
+        let role_arn = match recorded {
+            Some(recorded) => recorded.role_arn.clone(),
+            None => profile.role.clone().filter(|r| r.starts_with("arn:")),
+        };
+        let account_id = match recorded {
+            Some(recorded) => recorded.account_id.clone(),
+            None => role_arn
+                .as_ref()
+                .and_then(|arn| arn.split(':').nth(4))
+                .map(str::to_string),
+        };
+
+        ProfileStatus {
+            profile: profile.name.clone(),
+            state,
+            expiration: credentials
+                .expiration
+                .filter(|_| state != CredentialState::Missing),
+            role_arn,
+            account_id,
+        }
+    }
+}
+
+/// The state of the cached credentials of every profile, read from the
+/// keyring and the status file only
+pub fn profile_statuses(profiles: &[AppProfile]) -> Vec<ProfileStatus> {
+    let recorded = FILE
+        .with(|f| f.borrow().clone())
+        .or_else(default_file)
+        .and_then(|path| read(&path));
+
+    profiles
+        .iter()
+        .map(|profile| ProfileStatus::new(profile, recorded.as_ref()))
+        .collect()
+}
+
+/// Prints the statuses as an aligned table or a JSON array
+pub fn print(statuses: &[ProfileStatus], format: SummaryFormat) -> Result<()> {
+    match format {
+        SummaryFormat::Table => print!("{}", table(statuses)),
+        SummaryFormat::Json => println!("{}", serde_json::to_string_pretty(statuses)?),
+    }
+
+    Ok(())
+}
+
+fn table(statuses: &[ProfileStatus]) -> String {
+    let rows = statuses
+        .iter()
+        .map(|status| {
+            vec![
+                status.profile.clone(),
+                status.account_id.clone().unwrap_or_else(|| "-".to_string()),
+                status.role_arn.clone().unwrap_or_else(|| "-".to_string()),
+                status.expiration.clone().unwrap_or_else(|| "-".to_string()),
+                status.state.paint(),
+            ]
+        })
+        .collect::<Vec<_>>();
+
+    ui::table(&["PROFILE", "ACCOUNT", "ROLE", "EXPIRES", "STATE"], &rows)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::aws::role::Role;
+    use crate::clock::test::FixedClock;
+    use crate::credentials::keystore::{self, test::MemoryBackend};
+    use crate::providers::ProviderType;
+    use crate::timings::Timings;
+    use serde_json::json;
+    use std::rc::Rc;
+
+    #[test]
+    fn writes_identity_of_run() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("crowbar").join("status");
+        let result = run_result(Some(create_role()), "2038-01-01T10:10:10Z");
+
+        write(&path, "prod", &result)?;
+
+        let status: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path)?)?;
+        assert_eq!(
+            json!({
+                "profile": "prod",
+                "role_arn": "arn:aws:iam::123456789012:role/Admin",
+                "account_id": "123456789012",
+                "expiration": "2038-01-01T10:10:10Z",
+            }),
+            status
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_role_of_cached_credentials() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("status");
+
+        write(
+            &path,
+            "prod",
+            &run_result(Some(create_role()), "2038-01-01T10:10:10Z"),
+        )?;
+        write(&path, "prod", &run_result(None, "2038-01-01T10:10:10Z"))?;
+        assert_eq!(
+            Some("arn:aws:iam::123456789012:role/Admin".to_string()),
+            read(&path).and_then(|s| s.role_arn)
+        );
+
+        // Cached credentials of another profile didn't come from the role
+        write(&path, "staging", &run_result(None, "2038-01-01T10:10:10Z"))?;
+        assert_eq!(
+            Some(Status {
+                profile: "staging".to_string(),
+                role_arn: None,
+                account_id: None,
+                expiration: Some("2038-01-01T10:10:10Z".to_string()),
+            }),
+            read(&path)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn tells_state_of_cached_credentials() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("status");
+        write(
+            &path,
+            "prod",
+            &run_result(Some(create_role()), "2038-01-01T10:10:10Z"),
+        )?;
+        set_file(Some(path));
+
+        let profiles = ["prod", "staging", "dev", "sandbox", "legacy"]
+            .iter()
+            .map(|name| {
+                AppProfile::new(
+                    name,
+                    ProviderType::Okta,
+                    "https://example.okta.com/home/amazon_aws/0oa1/272",
+                    "jdoe",
+                    Some("arn:aws:iam::210987654321:role/ReadOnly"),
+                )
+            })
+            .collect::<Vec<AppProfile>>();
+        let cached = [
+            ("prod", "2038-01-01T10:10:10Z"),
+            ("staging", "2038-01-01T09:00:00Z"),
+            ("dev", "2037-12-31T10:10:10Z"),
+            ("legacy", "yesterday"),
+        ];
+
+        let statuses =
+            keystore::with_backend(Rc::new(MemoryBackend::default()), || -> Result<_> {
+                for (name, expiration) in cached.iter() {
+                    let profile = profiles.iter().find(|p| p.name == *name).unwrap();
+                    run_result(None, expiration).credentials.write(profile)?;
+                }
+
+                // 2038-01-01T08:50:10Z, so staging has 10 minutes left
+                Ok(clock::with_clock(FixedClock::at(2_145_948_610), || {
+                    profile_statuses(&profiles)
+                }))
+            })?;
+        set_file(None);
+
+        assert_eq!(
+            json!([
+                {
+                    "profile": "prod",
+                    "state": "valid",
+                    "expiration": "2038-01-01T10:10:10Z",
+                    "role_arn": "arn:aws:iam::123456789012:role/Admin",
+                    "account_id": "123456789012",
+                },
+                {
+                    "profile": "staging",
+                    "state": "expiring",
+                    "expiration": "2038-01-01T09:00:00Z",
+                    "role_arn": "arn:aws:iam::210987654321:role/ReadOnly",
+                    "account_id": "210987654321",
+                },
+                {
+                    "profile": "dev",
+                    "state": "expired",
+                    "expiration": "2037-12-31T10:10:10Z",
+                    "role_arn": "arn:aws:iam::210987654321:role/ReadOnly",
+                    "account_id": "210987654321",
+                },
+                {
+                    "profile": "sandbox",
+                    "state": "missing",
+                    "expiration": null,
+                    "role_arn": "arn:aws:iam::210987654321:role/ReadOnly",
+                    "account_id": "210987654321",
+                },
+                {
+                    "profile": "legacy",
+                    "state": "expired",
+                    "expiration": "yesterday",
+                    "role_arn": "arn:aws:iam::210987654321:role/ReadOnly",
+                    "account_id": "210987654321",
+                },
+            ]),
+            serde_json::to_value(&statuses)?
+        );
+
+        let table = console::strip_ansi_codes(&table(&statuses[..2])).to_string();
+        assert_eq!(
+            vec![
+                "PROFILE  ACCOUNT       ROLE                                     EXPIRES               STATE",
+                "prod     123456789012  arn:aws:iam::123456789012:role/Admin     2038-01-01T10:10:10Z  valid",
+                "staging  210987654321  arn:aws:iam::210987654321:role/ReadOnly  2038-01-01T09:00:00Z  expiring soon",
+            ],
+            table.lines().collect::<Vec<_>>()
+        );
+
+        Ok(())
+    }
+
+    fn run_result(role: Option<Role>, expiration: &str) -> RunResult {
+        RunResult {
+            credentials: AwsCredentials {
+                version: 1,
+                access_key_id: Some("ASIA".to_string()),
+                secret_access_key: Some("secret".to_string()),
+                session_token: Some("token".to_string()),
+                expiration: Some(expiration.to_string()),
+            },
+            timings: Timings::default(),
+            session_expires_at: None,
+            role,
+        }
+    }
+
+    fn create_role() -> Role {
+        Role {
+            provider_arn: "arn:aws:iam::123456789012:saml-provider/okta-idp".to_string(),
+            role_arn: "arn:aws:iam::123456789012:role/Admin".to_string(),
+        }
+    }
+}
@@ -0,0 +1,352 @@
+//! The switcher `crowbar` opens without any arguments in a terminal. It lists
+//! the profiles along with their cached credentials, fetches credentials for
+//! the picked one, asking for its role in the same list where the profile
+//! doesn't name one, and shows until when they're valid.
+
+use crate::config::CrowbarConfig;
+use crate::credentials::aws::{self as CredentialsProvider, AwsCredentials, FetchOptions};
+use crate::credentials::Credential;
+use crate::prompt::{self, Prompter, TerminalPrompter};
+use crate::ui;
+
+use anyhow::{anyhow, Result};
+use console::{Key, Term};
+use std::ops::Range;
+use std::rc::Rc;
+
+const HELP: &str = "↑/↓ to move, / to filter, enter to pick, esc to quit";
+// The line below the list the terminal's cursor rests on
+const RESERVED_LINES: usize = 1;
+
+/// How picking from the list ended
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    /// The index of the picked item among all items
+    Selected(usize),
+    Cancelled,
+}
+
+/// The state of a list to pick an item from, moved through with the arrow
+/// keys and narrowed down with a filter typed after `/`
+#[derive(Debug)]
+pub struct Picker {
+    items: Vec<String>,
+    filter: String,
+    filtering: bool,
+    // Position among the visible items
+    cursor: usize,
+}
+
+impl Picker {
+    pub fn new(items: Vec<String>, default: usize) -> Picker {
+        Picker {
+            cursor: default.min(items.len().saturating_sub(1)),
+            items,
+            filter: String::new(),
+            filtering: false,
+        }
+    }
+
+    /// The indices of the items containing the filter, ignoring case
+    pub fn visible(&self) -> Vec<usize> {
+        let filter = self.filter.to_lowercase();
+
+        (0..self.items.len())
+            .filter(|&i| self.items[i].to_lowercase().contains(&filter))
+            .collect()
+    }
+
+    /// The index of the item under the cursor among all items, if any
+    /// matches the filter
+    pub fn current(&self) -> Option<usize> {
+        self.visible().get(self.cursor).copied()
+    }
+
+    /// Applies `key` to the list, returning how picking ended once it did
+    pub fn handle(&mut self, key: &Key) -> Option<Outcome> {
+        match key {
+            Key::ArrowUp => self.cursor = self.cursor.saturating_sub(1),
+            Key::ArrowDown if self.cursor + 1 < self.visible().len() => self.cursor += 1,
+            Key::Enter => return self.current().map(Outcome::Selected),
+            // Leaving the filter comes first, so a typo doesn't end it all
+            Key::Escape if self.filtering => {
+                self.filtering = false;
+                self.set_filter(String::new());
+            }
+            Key::Escape => return Some(Outcome::Cancelled),
+            Key::Backspace if self.filtering => {
+                let mut filter = self.filter.clone();
+                filter.pop();
+                self.set_filter(filter);
+            }
+            Key::Char(c) if self.filtering && !c.is_control() => {
+                let filter = format!("{}{}", self.filter, c);
+                self.set_filter(filter);
+            }
+            Key::Char('/') => self.filtering = true,
+            Key::Char('q') => return Some(Outcome::Cancelled),
+            _ => (),
+        }
+
+        None
+    }
+
+    /// The range of visible items to show on `height` lines, keeping the
+    /// cursor in view
+    pub fn window(&self, height: usize) -> Range<usize> {
+        let visible = self.visible().len();
+        let height = height.max(1).min(visible);
+        let start = (self.cursor + 1).saturating_sub(height);
+
+        start..start + height
+    }
+
+    // The lines showing the list with `prompt` on `height` lines at most
+    fn lines(&self, prompt: &str, height: usize) -> Vec<String> {
+        let header = match self.filtering {
+            true => format!("{} /{}", prompt, self.filter),
+            false => format!("{} ({})", prompt, HELP),
+        };
+        let visible = self.visible();
+
+        if visible.is_empty() {
+            return vec![header, format!("  Nothing matches {}", self.filter)];
+        }
+
+        let items = self.window(height.saturating_sub(1)).map(|position| {
+            let marker = if position == self.cursor { ">" } else { " " };
+            format!("{} {}", marker, self.items[visible[position]])
+        });
+
+        std::iter::once(header).chain(items).collect()
+    }
+
+    fn set_filter(&mut self, filter: String) {
+        self.filter = filter;
+        self.cursor = 0;
+    }
+}
+
+/// Lets the user pick a profile, fetches its credentials and shows until
+/// when they are valid
+pub fn switch(crowbar_config: CrowbarConfig, options: &FetchOptions) -> Result<()> {
+    let term = Term::stderr();
+    let profiles = crowbar_config.profiles.clone();
+
+    if profiles.is_empty() {
+        return Err(anyhow!(
+            "No profiles configured yet, add one with crowbar profiles add"
+        ));
+    }
+
+    let width = profiles.iter().map(|p| p.name.len()).max().unwrap_or(0);
+    let labels = profiles
+        .iter()
+        .map(|p| {
+            let credentials = AwsCredentials::load(p).unwrap_or_default();
+            format!("{:width$}  {}", p.name, status(&credentials), width = width)
+        })
+        .collect::<Vec<String>>();
+
+    let profile = match pick(&term, "Profile", labels, 0)? {
+        Outcome::Selected(index) => profiles[index].name.clone(),
+        Outcome::Cancelled => return Ok(()),
+    };
+
+    let prompter = Rc::new(SwitchPrompter { term });
+    let result = prompt::with_prompter(prompter, || {
+        CredentialsProvider::fetch_aws_credentials(profile.clone(), crowbar_config, options)
+    })?;
+
+    ui::print_success(&format!(
+        "Credentials of profile {} are {}",
+        profile,
+        status(&result.credentials)
+    ));
+
+    Ok(())
+}
+
+fn status(credentials: &AwsCredentials) -> String {
+    match (credentials.valid(), credentials.is_expired()) {
+        (false, _) => "no credentials".to_string(),
+        (true, true) => "expired".to_string(),
+        (true, false) => format!(
+            "valid until {}",
+            credentials.expiration.clone().unwrap_or_default()
+        ),
+    }
+}
+
+// Shows the list on the terminal until the user picks an item or cancels,
+// clearing it afterwards
+fn pick(term: &Term, prompt: &str, items: Vec<String>, default: usize) -> Result<Outcome> {
+    let mut picker = Picker::new(items, default);
+    let height = (term.size().0 as usize).saturating_sub(RESERVED_LINES);
+    let mut drawn = 0;
+
+    term.hide_cursor()?;
+    let outcome = loop {
+        let lines = picker.lines(prompt, height);
+        term.clear_last_lines(drawn)?;
+        for line in &lines {
+            term.write_line(line)?;
+        }
+        drawn = lines.len();
+
+        match term.read_key() {
+            Ok(key) => {
+                if let Some(outcome) = picker.handle(&key) {
+                    break Ok(outcome);
+                }
+            }
+            Err(e) => break Err(e),
+        }
+    };
+    term.clear_last_lines(drawn)?;
+    term.show_cursor()?;
+
+    Ok(outcome?)
+}
+
+// Picks the role in the same list as the profile and asks for anything else
+// like crowbar does elsewhere
+struct SwitchPrompter {
+    term: Term,
+}
+
+impl Prompter for SwitchPrompter {
+    fn input(&self, prompt: &str) -> Result<String> {
+        TerminalPrompter.input(prompt)
+    }
+
+    fn password(&self, prompt: &str) -> Result<String> {
+        TerminalPrompter.password(prompt)
+    }
+
+    fn select(&self, prompt: &str, items: &[String]) -> Result<usize> {
+        self.select_with_default(prompt, items, 0)
+    }
+
+    fn select_with_default(&self, prompt: &str, items: &[String], default: usize) -> Result<usize> {
+        match pick(&self.term, prompt, items.to_vec(), default)? {
+            Outcome::Selected(index) => Ok(index),
+            Outcome::Cancelled => Err(anyhow!("Cancelled at: {}", prompt)),
+        }
+    }
+
+    fn is_interactive(&self) -> bool {
+        TerminalPrompter.is_interactive()
+    }
+
+    fn progress(&self, message: &str) -> Result<()> {
+        TerminalPrompter.progress(message)
+    }
+
+    fn message(&self, message: &str) {
+        TerminalPrompter.message(message)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn picker() -> Picker {
+        Picker::new(
+            vec![
+                "prod-admin".to_string(),
+                "prod-readonly".to_string(),
+                "staging-admin".to_string(),
+                "Sandbox".to_string(),
+            ],
+            0,
+        )
+    }
+
+    fn type_keys(picker: &mut Picker, keys: &str) {
+        for c in keys.chars() {
+            assert_eq!(None, picker.handle(&Key::Char(c)));
+        }
+    }
+
+    #[test]
+    fn moves_cursor_within_items() {
+        let mut picker = picker();
+
+        assert_eq!(None, picker.handle(&Key::ArrowUp));
+        assert_eq!(Some(0), picker.current());
+
+        for _ in 0..10 {
+            picker.handle(&Key::ArrowDown);
+        }
+        assert_eq!(Some(3), picker.current());
+
+        picker.handle(&Key::ArrowUp);
+        assert_eq!(Some(Outcome::Selected(2)), picker.handle(&Key::Enter));
+    }
+
+    #[test]
+    fn filters_items_ignoring_case() {
+        let mut picker = picker();
+
+        // Typing without / is no filter
+        type_keys(&mut picker, "s");
+        assert_eq!(vec![0, 1, 2, 3], picker.visible());
+
+        type_keys(&mut picker, "/admin");
+        assert_eq!(vec![0, 2], picker.visible());
+
+        picker.handle(&Key::ArrowDown);
+        assert_eq!(Some(Outcome::Selected(2)), picker.handle(&Key::Enter));
+
+        let mut picker = self::picker();
+        type_keys(&mut picker, "/SAND");
+        assert_eq!(vec![3], picker.visible());
+    }
+
+    #[test]
+    fn edits_filter() {
+        let mut picker = picker();
+        type_keys(&mut picker, "/prod-r");
+        assert_eq!(vec![1], picker.visible());
+
+        picker.handle(&Key::Backspace);
+        picker.handle(&Key::Backspace);
+        assert_eq!(vec![0, 1], picker.visible());
+
+        // Nothing to pick from
+        type_keys(&mut picker, "x");
+        assert!(picker.visible().is_empty());
+        assert_eq!(None, picker.handle(&Key::Enter));
+    }
+
+    #[test]
+    fn leaves_filter_before_cancelling() {
+        let mut picker = picker();
+        type_keys(&mut picker, "/staging");
+        picker.handle(&Key::ArrowDown);
+
+        assert_eq!(None, picker.handle(&Key::Escape));
+        assert_eq!(4, picker.visible().len());
+        assert_eq!(Some(0), picker.current());
+
+        // Without a filter q cancels too
+        assert_eq!(Some(Outcome::Cancelled), picker.handle(&Key::Escape));
+        assert_eq!(Some(Outcome::Cancelled), picker.handle(&Key::Char('q')));
+    }
+
+    #[test]
+    fn keeps_cursor_in_window() {
+        let mut picker = Picker::new((0..10).map(|i| i.to_string()).collect(), 7);
+
+        assert_eq!(Some(7), picker.current());
+        assert_eq!(5..8, picker.window(3));
+        assert_eq!(0..10, picker.window(20));
+
+        picker.handle(&Key::ArrowUp);
+        picker.handle(&Key::ArrowUp);
+        picker.handle(&Key::ArrowUp);
+        assert_eq!(2..5, picker.window(3));
+    }
+}
@@ -0,0 +1,195 @@
+use crate::error::{self, ErrorKind};
+
+use anyhow::Result;
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+thread_local! {
+    static CURRENT: RefCell<Timings> = RefCell::new(Timings::default());
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+    static LAST_PHASE: Cell<Option<Phase>> = const { Cell::new(None) };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Authentication,
+    MfaWait,
+    SamlFetch,
+    RoleAssumption,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Phase::Authentication => write!(f, "IdP authentication"),
+            Phase::MfaWait => write!(f, "MFA wait"),
+            Phase::SamlFetch => write!(f, "SAML fetch"),
+            Phase::RoleAssumption => write!(f, "STS role assumption"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Timings {
+    authentication: Duration,
+    mfa_wait: Duration,
+    saml_fetch: Duration,
+    role_assumption: Duration,
+}
+
+impl Timings {
+    pub fn record(&mut self, phase: Phase, duration: Duration) {
+        match phase {
+            Phase::Authentication => self.authentication += duration,
+            Phase::MfaWait => self.mfa_wait += duration,
+            Phase::SamlFetch => self.saml_fetch += duration,
+            Phase::RoleAssumption => self.role_assumption += duration,
+        }
+    }
+
+    pub fn get(&self, phase: Phase) -> Duration {
+        match phase {
+            // MFA prompts and polling happen while authenticating, we
+            // only want to report the time spent talking to the IdP here
+            Phase::Authentication => self.authentication.saturating_sub(self.mfa_wait),
+            Phase::MfaWait => self.mfa_wait,
+            Phase::SamlFetch => self.saml_fetch,
+            Phase::RoleAssumption => self.role_assumption,
+        }
+    }
+
+    pub fn total(&self) -> Duration {
+        self.authentication.max(self.mfa_wait) + self.saml_fetch + self.role_assumption
+    }
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Timings:")?;
+        for phase in &[
+            Phase::Authentication,
+            Phase::MfaWait,
+            Phase::SamlFetch,
+            Phase::RoleAssumption,
+        ] {
+            writeln!(f, "  {}: {:.2}s", phase, self.get(*phase).as_secs_f64())?;
+        }
+        write!(f, "  Total: {:.2}s", self.total().as_secs_f64())
+    }
+}
+
+pub fn measure<T, F>(phase: Phase, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    LAST_PHASE.with(|p| p.set(Some(phase)));
+    let start = Instant::now();
+    let result = f();
+    let elapsed = start.elapsed();
+
+    trace!("{} took {:?}", phase, elapsed);
+    CURRENT.with(|t| t.borrow_mut().record(phase, elapsed));
+
+    result
+}
+
+/// Like `measure`, but refuses to start the phase once the deadline passed
+pub fn measure_bounded<T, F>(phase: Phase, f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T>,
+{
+    check_deadline(phase)?;
+    measure(phase, f)
+}
+
+/// Bounds all phases that follow, see `--deadline`
+pub fn set_deadline(deadline: Option<Instant>) {
+    DEADLINE.with(|d| d.set(deadline));
+}
+
+pub fn check_deadline(phase: Phase) -> Result<()> {
+    match DEADLINE.with(|d| d.get()) {
+        Some(deadline) if Instant::now() >= deadline => Err(error::error(
+            ErrorKind::DeadlineExceeded,
+            format!("Deadline exceeded during {}", phase),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Shortens a wait so it doesn't overshoot the deadline
+pub fn until_deadline(duration: Duration) -> Duration {
+    match DEADLINE.with(|d| d.get()) {
+        Some(deadline) => duration.min(deadline.saturating_duration_since(Instant::now())),
+        None => duration,
+    }
+}
+
+/// The phase started most recently, i.e. the one a failing run failed in
+/// unless it failed in between phases
+pub fn last_phase() -> Option<Phase> {
+    LAST_PHASE.with(|p| p.get())
+}
+
+pub fn take() -> Timings {
+    CURRENT.with(|t| t.replace(Timings::default()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn captures_all_phases() {
+        let _ = take();
+        let pause = Duration::from_millis(5);
+
+        measure(Phase::Authentication, || {
+            thread::sleep(pause);
+            measure(Phase::MfaWait, || thread::sleep(pause));
+        });
+        measure(Phase::SamlFetch, || thread::sleep(pause));
+        measure(Phase::RoleAssumption, || thread::sleep(pause));
+
+        let timings = take();
+
+        for phase in &[
+            Phase::Authentication,
+            Phase::MfaWait,
+            Phase::SamlFetch,
+            Phase::RoleAssumption,
+        ] {
+            assert!(timings.get(*phase) >= pause, "{} was not recorded", phase);
+        }
+        assert!(timings.total() >= pause * 4);
+        assert_eq!(Timings::default(), take());
+    }
+
+    #[test]
+    fn refuses_phases_past_deadline() {
+        set_deadline(Some(Instant::now()));
+
+        let error = measure_bounded(Phase::SamlFetch, || Ok(())).unwrap_err();
+        assert_eq!("Deadline exceeded during SAML fetch", error.to_string());
+        assert_eq!(
+            Duration::from_secs(0),
+            until_deadline(Duration::from_secs(2))
+        );
+
+        set_deadline(None);
+        assert!(measure_bounded(Phase::SamlFetch, || Ok(())).is_ok());
+    }
+
+    #[test]
+    fn reports_mfa_wait_separately_from_authentication() {
+        let mut timings = Timings::default();
+        timings.record(Phase::Authentication, Duration::from_secs(10));
+        timings.record(Phase::MfaWait, Duration::from_secs(8));
+
+        assert_eq!(Duration::from_secs(2), timings.get(Phase::Authentication));
+        assert_eq!(Duration::from_secs(8), timings.get(Phase::MfaWait));
+        assert_eq!(Duration::from_secs(10), timings.total());
+    }
+}
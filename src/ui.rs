@@ -0,0 +1,182 @@
+use crate::utils;
+
+use console::{style, StyledObject, Term};
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub const NO_COLOR: &str = "NO_COLOR";
+
+static COLORS: AtomicBool = AtomicBool::new(false);
+static QUIET_SUCCESS: AtomicBool = AtomicBool::new(false);
+static EXPLAIN: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    // Success messages printed while capturing, see `capture_successes`
+    static CAPTURED: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+    // Narration while capturing, see `capture_explanations`
+    static EXPLANATIONS: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+/// Turns on colors unless asked not to or stderr, where all messages go,
+/// isn't a terminal
+pub fn init(no_color: bool) {
+    set_colors(use_colors(
+        no_color,
+        utils::non_empty_env_var(NO_COLOR).is_some(),
+        Term::stderr().is_term(),
+    ));
+}
+
+pub fn set_colors(enabled: bool) {
+    COLORS.store(enabled, Ordering::SeqCst);
+}
+
+/// Leaves out success messages, so that a credential_process only prints the
+/// credentials. Warnings, errors and prompts are still shown
+pub fn set_quiet_success(quiet: bool) {
+    QUIET_SUCCESS.store(quiet, Ordering::SeqCst);
+}
+
+/// Prints a success message to stderr unless `--quiet-success` is given
+pub fn print_success(message: &str) {
+    if QUIET_SUCCESS.load(Ordering::SeqCst) {
+        return;
+    }
+
+    let message = success(message);
+    CAPTURED.with(|c| match c.borrow_mut().as_mut() {
+        Some(captured) => captured.push(message),
+        None => eprintln!("{}", message),
+    });
+}
+
+/// Runs `f`, returning the success messages it printed instead of printing them
+#[cfg(test)]
+pub fn capture_successes<T, F: FnOnce() -> T>(f: F) -> (T, Vec<String>) {
+    let previous = CAPTURED.with(|c| c.replace(Some(Vec::new())));
+    let result = f();
+    let captured = CAPTURED.with(|c| c.replace(previous)).unwrap_or_default();
+
+    (result, captured)
+}
+
+/// Narrates each step of fetching credentials, see `--explain`
+pub fn set_explain(explain: bool) {
+    EXPLAIN.store(explain, Ordering::SeqCst);
+}
+
+/// Prints what crowbar is about to do to stderr if `--explain` is given
+pub fn explain(message: &str) {
+    EXPLANATIONS.with(|e| match e.borrow_mut().as_mut() {
+        Some(captured) => captured.push(message.to_string()),
+        None if EXPLAIN.load(Ordering::SeqCst) => eprintln!("{}", paint(style(message).dim())),
+        None => (),
+    });
+}
+
+/// Runs `f`, returning the narration of its steps instead of printing it
+#[cfg(test)]
+pub fn capture_explanations<T, F: FnOnce() -> T>(f: F) -> (T, Vec<String>) {
+    let previous = EXPLANATIONS.with(|e| e.replace(Some(Vec::new())));
+    let result = f();
+    let captured = EXPLANATIONS
+        .with(|e| e.replace(previous))
+        .unwrap_or_default();
+
+    (result, captured)
+}
+
+pub fn success(message: &str) -> String {
+    paint(style(message).green())
+}
+
+pub fn warning(message: &str) -> String {
+    paint(style(message).yellow())
+}
+
+pub fn error(message: &str) -> String {
+    paint(style(message).red())
+}
+
+/// `rows` below `header` in aligned columns. The last column isn't padded,
+/// so it may be painted
+pub fn table(header: &[&str], rows: &[Vec<String>]) -> String {
+    let widths = (0..header.len())
+        .map(|column| {
+            rows.iter()
+                .map(|row| row[column].chars().count())
+                .chain(std::iter::once(header[column].len()))
+                .max()
+                .unwrap_or_default()
+        })
+        .collect::<Vec<usize>>();
+    let line = |cells: Vec<&str>| {
+        let (last, cells) = cells.split_last().unwrap_or((&"", &[]));
+        let mut line = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}  ", cell, width = width))
+            .collect::<String>();
+        line.push_str(last);
+        line.push('\n');
+        line
+    };
+
+    let mut table = line(header.to_vec());
+    for row in rows {
+        table.push_str(&line(row.iter().map(String::as_str).collect()));
+    }
+
+    table
+}
+
+pub fn emphasis(message: &str) -> String {
+    paint(style(message).bold())
+}
+
+fn use_colors(no_color_flag: bool, no_color_env: bool, is_tty: bool) -> bool {
+    !no_color_flag && !no_color_env && is_tty
+}
+
+fn paint(message: StyledObject<&str>) -> String {
+    message
+        .force_styling(COLORS.load(Ordering::SeqCst))
+        .to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn colors_only_interactive_terminals() {
+        assert!(use_colors(false, false, true));
+        assert!(!use_colors(true, false, true));
+        assert!(!use_colors(false, true, true));
+        assert!(!use_colors(false, false, false));
+    }
+
+    #[test]
+    fn styles_messages_when_forced() {
+        set_colors(true);
+        assert_eq!("\u{1b}[32mdone\u{1b}[0m", success("done"));
+        assert_eq!("\u{1b}[31mfailed\u{1b}[0m", error("failed"));
+        assert_eq!("\u{1b}[1m42\u{1b}[0m", emphasis("42"));
+
+        set_colors(false);
+        assert_eq!("done", success("done"));
+        assert_eq!("42", emphasis("42"));
+    }
+
+    #[test]
+    fn leaves_out_success_messages_when_quiet() {
+        let ((), printed) = capture_successes(|| print_success("done"));
+        assert_eq!(1, printed.len());
+
+        set_quiet_success(true);
+        let ((), printed) = capture_successes(|| print_success("done"));
+        set_quiet_success(false);
+
+        assert!(printed.is_empty());
+    }
+}
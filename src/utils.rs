@@ -1,12 +1,26 @@
-use crate::aws::role::Role as AwsRole;
+use crate::aws::accounts::AccountNames;
+use crate::aws::duration::SessionDuration;
+use crate::aws::role::{Role as AwsRole, RoleName};
 use crate::config::app::AppProfile;
+use crate::error::{self, ErrorKind};
+use crate::prompt;
+use crate::timings::{self, Phase};
+use crate::ui;
 
-use anyhow::{Context, Result};
-use dialoguer::{theme::SimpleTheme, Select};
-use dialoguer::{Input, PasswordInput};
+use anyhow::{anyhow, Context, Result};
 use log::LevelFilter as LogLevelFilter;
+use regex::Regex;
+use rusoto_core::Region;
 use std::collections::HashSet;
 use std::env::var;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+
+#[cfg(unix)]
+const SECURE_FILE_MODE: u32 = 0o600;
 
 #[derive(Deserialize, Serialize, Debug, Clone, Eq, PartialEq, Hash)]
 pub enum LevelFilter {
@@ -31,6 +45,23 @@ impl From<LevelFilter> for LogLevelFilter {
     }
 }
 
+/// Writes a file only the user may read, whatever their umask, replacing its
+/// content. On Windows the file keeps the permissions of its directory, which
+/// for the user's profile directory are the user's own
+pub fn write_secure(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(SECURE_FILE_MODE);
+
+    let mut file = options.open(path)?;
+    // The mode only applies to new files, after the umask took bits away
+    #[cfg(unix)]
+    file.set_permissions(std::fs::Permissions::from_mode(SECURE_FILE_MODE))?;
+
+    file.write_all(contents)
+}
+
 pub fn non_empty_env_var(name: &str) -> Option<String> {
     match var(name) {
         Ok(value) => {
@@ -45,57 +76,753 @@ pub fn non_empty_env_var(name: &str) -> Option<String> {
 }
 
 pub fn prompt_password(profile: &AppProfile) -> Result<String> {
-    PasswordInput::new()
-        .with_prompt(&format!(
-            "Password for {} at {}",
-            &profile.username,
-            profile.clone().base_url()?.host().unwrap()
-        ))
-        .interact()
-        .map_err(|e| e.into())
+    prompt::current().password(&format!(
+        "Password for {} at {}",
+        &profile.username,
+        idp_host(profile)?
+    ))
+}
+
+pub fn prompt_username(profile: &AppProfile) -> Result<String> {
+    prompt::current()
+        .input(&format!("Username for {}", idp_host(profile)?))
+        .with_context(|| "Failed to get username input")
+}
+
+// The host of the IdP the prompts for credentials name
+fn idp_host(profile: &AppProfile) -> Result<String> {
+    profile
+        .base_url()?
+        .host_str()
+        .map(|host| host.to_string())
+        .with_context(|| format!("The URL of profile {} has no host", profile.name))
+}
+
+pub fn prompt_totp_secret(profile: &AppProfile) -> Result<String> {
+    prompt::current().password(&format!(
+        "TOTP secret or otpauth:// URI for {}",
+        &profile.username
+    ))
 }
 
 pub fn prompt_mfa() -> Result<String> {
-    Input::new()
-        .with_prompt("Enter MFA code")
-        .interact()
-        .with_context(|| "Failed to get MFA input")
-}
-
-pub fn select_role(roles: HashSet<AwsRole>, role: Option<String>) -> Result<AwsRole> {
-    let selection = match role {
-        None => match roles.clone() {
-            r if r.len() < 2 => 0,
-            r => Select::with_theme(&SimpleTheme)
-                .with_prompt("Select the role to assume:")
-                .default(0)
-                .items(
-                    &r.iter()
-                        .map(|r| r.clone().role_arn)
-                        .collect::<Vec<String>>(),
-                )
-                .interact()
-                .unwrap(),
+    timings::measure_bounded(Phase::MfaWait, || {
+        prompt::current()
+            .input("Enter MFA code")
+            .with_context(|| "Failed to get MFA input")
+    })
+}
+
+pub const AWS_ENDPOINT_URL_VARIABLES: [&str; 2] = ["AWS_ENDPOINT_URL_STS", "AWS_ENDPOINT_URL"];
+
+/// How to pick the role to assume out of the ones granted by an assertion,
+/// and how to reach STS for it
+#[derive(Debug, Default, Clone)]
+pub struct RoleOptions {
+    pub role: Option<String>,
+    pub auto_fallback: bool,
+    pub index: Option<usize>,
+    pub filter: Option<String>,
+    pub allowed: Vec<String>,
+    pub denied: Vec<String>,
+    pub duration: Option<SessionDuration>,
+    pub prompt_duration: bool,
+    pub region: Option<Region>,
+    pub global_sts: bool,
+    pub sts_endpoint_url: Option<String>,
+    pub accounts: AccountNames,
+}
+
+impl From<&AppProfile> for RoleOptions {
+    fn from(profile: &AppProfile) -> RoleOptions {
+        RoleOptions {
+            role: profile.role.clone(),
+            auto_fallback: profile.auto_fallback_role,
+            index: profile.role_index,
+            filter: profile.role_filter.clone(),
+            allowed: profile.allowed_roles.clone(),
+            denied: profile.denied_roles.clone(),
+            duration: profile.duration,
+            prompt_duration: profile.prompt_duration,
+            region: profile.region.as_ref().and_then(|r| r.parse().ok()),
+            global_sts: !profile.sts_regional.unwrap_or(true),
+            sts_endpoint_url: profile.aws_endpoint_url.clone().or_else(|| {
+                AWS_ENDPOINT_URL_VARIABLES
+                    .iter()
+                    .find_map(|v| non_empty_env_var(v))
+            }),
+            accounts: account_names(profile),
+        }
+    }
+}
+
+// Names that can't be loaded only cost the friendly names, so they don't
+// keep the profile from being used
+fn account_names(profile: &AppProfile) -> AccountNames {
+    match profile.account_map.as_ref().map(AccountNames::load) {
+        Some(Ok(names)) => names,
+        Some(Err(e)) => {
+            eprintln!(
+                "{}",
+                ui::warning(&format!("Showing account ids instead of names: {:#}", e))
+            );
+            AccountNames::default()
+        }
+        None => AccountNames::default(),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum RoleSelection {
+    Selected(usize),
+    Prompt(String),
+}
+
+/// A role out of the granted ones, and whether the user picked it in a menu
+#[derive(Debug, PartialEq)]
+pub struct SelectedRole {
+    pub role: AwsRole,
+    pub prompted: bool,
+}
+
+const FILTERED_OUT_EXAMPLES: usize = 3;
+
+pub fn select_role(roles: HashSet<AwsRole>, options: &RoleOptions) -> Result<SelectedRole> {
+    if let Some(role) = options.role.as_deref() {
+        // Roles given by name are checked by the ARN of the granted role.
+        // Names resolve among the permitted roles first, so the same role
+        // denied in another account doesn't make them ambiguous. If none is
+        // permitted, matching_roles tells why below
+        let permitted = matching_roles(roles.clone(), options).unwrap_or_default();
+        let granted = sort_roles(roles.clone());
+        let role_arn = match find_role(&permitted, role)? {
+            Some(selection) => permitted[selection].role_arn.as_str(),
+            None => match find_role(&granted, role).unwrap_or(None) {
+                Some(selection) => granted[selection].role_arn.as_str(),
+                None => role,
+            },
+        };
+
+        if let Some(reason) = RolePolicy::new(options)?.refusal(role_arn) {
+            return Err(error::error(
+                ErrorKind::Config,
+                format!("Role {} not permitted by config: {}", role, reason),
+            ));
+        }
+    }
+
+    let roles = matching_roles(roles, options)?;
+
+    let selection = match options.index {
+        Some(index) => RoleSelection::Selected(select_index(index, roles.len(), "Role")?),
+        None => resolve_role_selection(&roles, options.role.as_deref(), options.auto_fallback)?,
+    };
+
+    let (selection, prompted) = match selection {
+        RoleSelection::Selected(selection) => (selection, false),
+        RoleSelection::Prompt(message) => (
+            prompt::current().select(
+                &message,
+                &roles
+                    .iter()
+                    .map(|r| role_label(r, &options.accounts))
+                    .collect::<Vec<String>>(),
+            )?,
+            true,
+        ),
+    };
+
+    Ok(SelectedRole {
+        role: roles[selection].to_owned(),
+        prompted,
+    })
+}
+
+/// The role's ARN as listed in the menu, along with the name of its account
+/// where there is one
+pub fn role_label(role: &AwsRole, accounts: &AccountNames) -> String {
+    match accounts.get(role.account_id()) {
+        Some(name) => format!("{} ({})", role.role_arn, name),
+        None => role.role_arn.clone(),
+    }
+}
+
+/// The granted roles in menu order, only those the profile permits and that
+/// match the filter if any
+pub fn matching_roles(roles: HashSet<AwsRole>, options: &RoleOptions) -> Result<Vec<AwsRole>> {
+    let roles = permitted_roles(sort_roles(roles), options)?;
+
+    match options.filter.as_deref() {
+        Some(filter) => filter_roles(roles, filter),
+        None => Ok(roles),
+    }
+}
+
+/// The `allowed_roles` and `denied_roles` of a profile. They keep users from
+/// assuming a role by accident, but aren't a security boundary, as anyone
+/// can change their own configuration
+struct RolePolicy {
+    allowed: Vec<Regex>,
+    denied: Vec<Regex>,
+}
+
+impl RolePolicy {
+    fn new(options: &RoleOptions) -> Result<RolePolicy> {
+        let compile = |key: &str, patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|pattern| {
+                    Regex::new(pattern).with_context(|| {
+                        format!(
+                            "The {} pattern {} isn't a valid regular expression",
+                            key, pattern
+                        )
+                    })
+                })
+                .collect::<Result<Vec<Regex>>>()
+        };
+
+        Ok(RolePolicy {
+            allowed: compile("allowed_roles", &options.allowed)?,
+            denied: compile("denied_roles", &options.denied)?,
+        })
+    }
+
+    /// Why the role with the ARN can't be assumed, if it can't
+    fn refusal(&self, role_arn: &str) -> Option<String> {
+        if let Some(pattern) = self.denied.iter().find(|r| r.is_match(role_arn)) {
+            return Some(format!("it matches the denied_roles pattern {}", pattern));
+        }
+
+        match self.allowed.is_empty() || self.allowed.iter().any(|r| r.is_match(role_arn)) {
+            true => None,
+            false => Some("it matches none of the allowed_roles patterns".to_string()),
+        }
+    }
+}
+
+fn permitted_roles(roles: Vec<AwsRole>, options: &RoleOptions) -> Result<Vec<AwsRole>> {
+    let policy = RolePolicy::new(options)?;
+    let granted = roles.len();
+    let permitted = roles
+        .into_iter()
+        .filter(|r| policy.refusal(&r.role_arn).is_none())
+        .collect::<Vec<AwsRole>>();
+
+    if permitted.is_empty() && granted > 0 {
+        return Err(error::error(
+            ErrorKind::Config,
+            format!(
+                "None of the {} granted roles is permitted by config, check allowed_roles and denied_roles of the profile",
+                granted
+            ),
+        ));
+    }
+
+    Ok(permitted)
+}
+
+/// Orders roles by account and name so the menu, and with it --role-index,
+/// is stable. Duplicates in the assertion are already gone in the set
+pub fn sort_roles(roles: HashSet<AwsRole>) -> Vec<AwsRole> {
+    let mut roles = roles.into_iter().collect::<Vec<AwsRole>>();
+    roles.sort_by(|a, b| {
+        (a.account_id(), a.name(), &a.role_arn).cmp(&(b.account_id(), b.name(), &b.role_arn))
+    });
+
+    roles
+}
+
+/// Keeps the roles whose ARN matches `filter` anywhere, so a menu of hundreds
+/// of roles can be narrowed down, or skipped if only one is left
+fn filter_roles(roles: Vec<AwsRole>, filter: &str) -> Result<Vec<AwsRole>> {
+    let regex = Regex::new(filter).with_context(|| {
+        format!(
+            "The role filter {} isn't a valid regular expression",
+            filter
+        )
+    })?;
+
+    let (matching, others): (Vec<AwsRole>, Vec<AwsRole>) =
+        roles.into_iter().partition(|r| regex.is_match(&r.role_arn));
+
+    if matching.is_empty() && !others.is_empty() {
+        let mut examples = others
+            .iter()
+            .take(FILTERED_OUT_EXAMPLES)
+            .map(|r| r.role_arn.clone())
+            .collect::<Vec<String>>();
+        if others.len() > FILTERED_OUT_EXAMPLES {
+            examples.push(format!("and {} more", others.len() - FILTERED_OUT_EXAMPLES));
+        }
+
+        return Err(anyhow!(
+            "No granted role matches the role filter {}, granted roles are e.g. {}",
+            filter,
+            examples.join(", ")
+        ));
+    }
+
+    Ok(matching)
+}
+
+/// Validates an index given on the command line in place of a menu selection
+pub fn select_index(index: usize, len: usize, name: &str) -> Result<usize> {
+    match len {
+        0 => Err(anyhow!("No {} available to select", name.to_lowercase())),
+        len if index >= len => Err(anyhow!(
+            "{} index {} is out of range, valid indices are 0 to {}",
+            name,
+            index,
+            len - 1
+        )),
+        _ => Ok(index),
+    }
+}
+
+fn resolve_role_selection(
+    roles: &[AwsRole],
+    role: Option<&str>,
+    auto_fallback_role: bool,
+) -> Result<RoleSelection> {
+    match role {
+        None => match roles.len() {
+            0 => Err(anyhow!("No roles were granted by the SAML assertion")),
+            1 => Ok(RoleSelection::Selected(0)),
+            _ => Ok(RoleSelection::Prompt(
+                "Select the role to assume:".to_string(),
+            )),
         },
-        Some(role) => match roles.iter().position(|r| r.role_arn == role) {
-            None => match roles.clone() {
-                r if r.len() < 2 => 0,
-                r => Select::with_theme(&SimpleTheme)
-                    .with_prompt(
-                        &format!("Role {} not found; select the role to assume:", role).to_string(),
-                    )
-                    .default(0)
-                    .items(
-                        &r.iter()
-                            .map(|r| r.clone().role_arn)
-                            .collect::<Vec<String>>(),
-                    )
-                    .interact()
-                    .unwrap(),
+        Some(role) => match find_role(roles, role)? {
+            Some(selection) => Ok(RoleSelection::Selected(selection)),
+            None => match roles.len() {
+                0 => Err(anyhow!("No roles were granted by the SAML assertion")),
+                1 if auto_fallback_role => {
+                    eprintln!(
+                        "{}",
+                        ui::warning(&format!(
+                            "Warning: role {} not found, falling back to the only granted role {}",
+                            role, roles[0].role_arn
+                        ))
+                    );
+                    Ok(RoleSelection::Selected(0))
+                }
+                1 => Err(anyhow!(
+                    "Role {} not found, the only granted role is {}. \
+                     Update your profile or pass --auto-fallback-role to use it",
+                    role,
+                    roles[0].role_arn
+                )),
+                _ => Ok(RoleSelection::Prompt(format!(
+                    "Role {} not found; select the role to assume:",
+                    role
+                ))),
             },
-            Some(selection) => selection,
         },
+    }
+}
+
+// The configured role, given by its ARN or by its name. A name granted in
+// several accounts needs the account as well
+fn find_role(roles: &[AwsRole], role: &str) -> Result<Option<usize>> {
+    if let Some(selection) = roles.iter().position(|r| r.role_arn == role) {
+        return Ok(Some(selection));
+    }
+
+    let name = match RoleName::parse(role) {
+        Some(name) => name,
+        None => return Ok(None),
     };
+    let matching = roles
+        .iter()
+        .enumerate()
+        .filter(|(_, r)| name.matches(r))
+        .map(|(i, _)| i)
+        .collect::<Vec<usize>>();
+
+    match matching.as_slice() {
+        [] => Ok(None),
+        [selection] => Ok(Some(*selection)),
+        _ => Err(error::error(
+            ErrorKind::Config,
+            format!(
+                "The role {} is granted in the accounts {}, pick one with e.g. role = \"{}@{}\"",
+                role,
+                matching
+                    .iter()
+                    .map(|i| roles[*i].account_id())
+                    .collect::<Vec<&str>>()
+                    .join(", "),
+                name.name,
+                roles[matching[0]].account_id()
+            ),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::aws::accounts::AccountMap;
+    use crate::config::app::test::{okta_profile, OKTA_URL};
+    use crate::prompt::test::ScriptedPrompter;
+    use claim::assert_err;
+    use std::rc::Rc;
+
+    const STALE_ROLE: &str = "arn:aws:iam::123456789012:role/removed";
+
+    #[test]
+    fn selects_configured_role() -> Result<()> {
+        let roles = vec![create_role("role1"), create_role("role2")];
+
+        assert_eq!(
+            RoleSelection::Selected(1),
+            resolve_role_selection(&roles, Some("arn:aws:iam::123456789012:role/role2"), false)?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn selects_configured_role_by_name() -> Result<()> {
+        let mut granted = create_roles(&["Admin", "path/ReadOnly"]);
+        granted.insert(AwsRole {
+            provider_arn: "arn:aws:iam::210987654321:saml-provider/okta-idp".to_string(),
+            role_arn: "arn:aws:iam::210987654321:role/Admin".to_string(),
+        });
+        let roles = sort_roles(granted);
+
+        // A name granted in one account only needs no account, in any case
+        assert_eq!(
+            RoleSelection::Selected(1),
+            resolve_role_selection(&roles, Some("readonly"), false)?
+        );
+        assert_eq!(
+            RoleSelection::Selected(2),
+            resolve_role_selection(&roles, Some("Admin@210987654321"), false)?
+        );
+        assert_eq!(
+            RoleSelection::Selected(0),
+            resolve_role_selection(&roles, Some("Admin@123456789012"), false)?
+        );
+
+        let error = resolve_role_selection(&roles, Some("Admin"), false).unwrap_err();
+        assert_eq!(ErrorKind::Config, error::kind_of(&error));
+        assert_eq!(
+            "The role Admin is granted in the accounts 123456789012, 210987654321, \
+             pick one with e.g. role = \"Admin@123456789012\"",
+            error.to_string()
+        );
+
+        // Names granted nowhere are stale like ARNs
+        assert!(matches!(
+            resolve_role_selection(&roles, Some("Admin@999999999999"), false)?,
+            RoleSelection::Prompt(_)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn falls_back_to_single_role_only_when_allowed() -> Result<()> {
+        let roles = vec![create_role("role1")];
+
+        assert_err!(resolve_role_selection(&roles, Some(STALE_ROLE), false));
+        assert_eq!(
+            RoleSelection::Selected(0),
+            resolve_role_selection(&roles, Some(STALE_ROLE), true)?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn prompts_for_stale_role_with_multiple_roles() -> Result<()> {
+        let roles = vec![create_role("role1"), create_role("role2")];
+
+        for auto_fallback_role in &[false, true] {
+            assert!(matches!(
+                resolve_role_selection(&roles, Some(STALE_ROLE), *auto_fallback_role)?,
+                RoleSelection::Prompt(_)
+            ));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn selects_role_by_index() -> Result<()> {
+        let roles = vec![create_role("role2"), create_role("role1")]
+            .into_iter()
+            .collect::<HashSet<AwsRole>>();
+        let options = RoleOptions {
+            index: Some(1),
+            ..RoleOptions::default()
+        };
+
+        assert_eq!(
+            SelectedRole {
+                role: create_role("role2"),
+                prompted: false,
+            },
+            select_role(roles, &options)?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn prompts_through_current_prompter() -> Result<()> {
+        let roles = vec![create_role("role1"), create_role("role2")]
+            .into_iter()
+            .collect::<HashSet<AwsRole>>();
+        let prompter = Rc::new(ScriptedPrompter::new(&["123456", "1"]));
+
+        prompt::with_prompter(prompter, || -> Result<()> {
+            assert_eq!("123456", prompt_mfa()?);
+            assert_eq!(
+                SelectedRole {
+                    role: create_role("role2"),
+                    prompted: true,
+                },
+                select_role(roles, &RoleOptions::default())?
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn selects_only_role_left_by_filter() -> Result<()> {
+        let roles = create_roles(&["prod-admin", "prod-readonly", "staging-admin"]);
+        let options = RoleOptions {
+            filter: Some("staging".to_string()),
+            ..RoleOptions::default()
+        };
+
+        prompt::with_prompter(Rc::new(ScriptedPrompter::new(&[])), || {
+            assert_eq!(
+                SelectedRole {
+                    role: create_role("staging-admin"),
+                    prompted: false,
+                },
+                select_role(roles, &options)?
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn offers_roles_left_by_filter() -> Result<()> {
+        let roles = create_roles(&["prod-admin", "prod-readonly", "staging-admin"]);
+        let options = RoleOptions {
+            filter: Some("/.*-admin$".to_string()),
+            ..RoleOptions::default()
+        };
+
+        // The second entry of the filtered menu, not of all roles
+        prompt::with_prompter(Rc::new(ScriptedPrompter::new(&["1"])), || {
+            assert_eq!(
+                SelectedRole {
+                    role: create_role("staging-admin"),
+                    prompted: true,
+                },
+                select_role(roles, &options)?
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn offers_allowed_roles_only() -> Result<()> {
+        let roles = create_roles(&["prod-admin", "prod-readonly", "staging-admin"]);
+        let options = RoleOptions {
+            allowed: vec!["/prod-".to_string()],
+            ..RoleOptions::default()
+        };
+
+        prompt::with_prompter(Rc::new(ScriptedPrompter::new(&["1"])), || -> Result<()> {
+            assert_eq!(
+                SelectedRole {
+                    role: create_role("prod-readonly"),
+                    prompted: true,
+                },
+                select_role(roles.clone(), &options)?
+            );
+            Ok(())
+        })?;
+
+        let options = RoleOptions {
+            allowed: vec!["/dev-".to_string()],
+            ..RoleOptions::default()
+        };
+        assert!(select_role(roles, &options)
+            .unwrap_err()
+            .to_string()
+            .starts_with("None of the 3 granted roles is permitted by config"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn hides_denied_roles() -> Result<()> {
+        let roles = create_roles(&["prod-admin", "prod-readonly", "staging-admin"]);
+        let options = RoleOptions {
+            denied: vec!["/prod-admin$".to_string(), "readonly".to_string()],
+            ..RoleOptions::default()
+        };
 
-    Ok(roles.iter().collect::<Vec<&AwsRole>>()[selection].to_owned())
+        // The only role left needs no menu
+        prompt::with_prompter(Rc::new(ScriptedPrompter::new(&[])), || {
+            assert_eq!(
+                SelectedRole {
+                    role: create_role("staging-admin"),
+                    prompted: false,
+                },
+                select_role(roles, &options)?
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn refuses_configured_role_not_permitted() {
+        let roles = create_roles(&["prod-admin", "staging-admin"]);
+        let mut options = RoleOptions {
+            role: Some("arn:aws:iam::123456789012:role/prod-admin".to_string()),
+            denied: vec!["/prod-".to_string()],
+            ..RoleOptions::default()
+        };
+
+        let error = select_role(roles.clone(), &options).unwrap_err();
+        assert_eq!(ErrorKind::Config, error::kind_of(&error));
+        assert_eq!(
+            "Role arn:aws:iam::123456789012:role/prod-admin not permitted by config: \
+             it matches the denied_roles pattern /prod-",
+            error.to_string()
+        );
+
+        options.denied = vec![];
+        options.allowed = vec!["staging".to_string()];
+        assert_eq!(
+            "Role arn:aws:iam::123456789012:role/prod-admin not permitted by config: \
+             it matches none of the allowed_roles patterns",
+            select_role(roles.clone(), &options)
+                .unwrap_err()
+                .to_string()
+        );
+
+        // A role given by name is checked by its ARN
+        options.role = Some("prod-admin".to_string());
+        assert_eq!(
+            "Role prod-admin not permitted by config: it matches none of the allowed_roles patterns",
+            select_role(roles, &options).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn asks_for_username_at_idp_host() -> Result<()> {
+        prompt::with_prompter(Rc::new(ScriptedPrompter::new(&["jdoe"])), || {
+            assert_eq!("jdoe", prompt_username(&okta_profile("profile", OKTA_URL))?);
+            Ok::<_, anyhow::Error>(())
+        })?;
+
+        // Profiles from the config file aren't validated before
+        assert_err!(prompt_username(&okta_profile("profile", "data:x")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn resolves_role_name_among_permitted_roles() -> Result<()> {
+        let roles = ["111111111111", "222222222222"]
+            .iter()
+            .map(|account| AwsRole {
+                provider_arn: format!("arn:aws:iam::{}:saml-provider/okta-idp", account),
+                role_arn: format!("arn:aws:iam::{}:role/Admin", account),
+            })
+            .collect::<HashSet<AwsRole>>();
+        let mut options = RoleOptions {
+            role: Some("Admin".to_string()),
+            ..RoleOptions::default()
+        };
+        assert_eq!(
+            ErrorKind::Config,
+            error::kind_of(&select_role(roles.clone(), &options).unwrap_err())
+        );
+
+        options.denied = vec!["::222222222222:".to_string()];
+        assert_eq!(
+            "arn:aws:iam::111111111111:role/Admin",
+            select_role(roles.clone(), &options)?.role.role_arn
+        );
+
+        options.denied = vec!["/Admin$".to_string()];
+        assert!(select_role(roles, &options)
+            .unwrap_err()
+            .to_string()
+            .starts_with("None of the 2 granted roles is permitted by config"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn labels_roles_with_mapped_account_names() -> Result<()> {
+        let accounts = AccountNames::load(&AccountMap::File(
+            "tests/fixtures/account_map.toml".to_string(),
+        ))?;
+        let unmapped = AwsRole {
+            provider_arn: "arn:aws:iam::987654321098:saml-provider/okta-idp".to_string(),
+            role_arn: "arn:aws:iam::987654321098:role/role1".to_string(),
+        };
+
+        assert_eq!(
+            "arn:aws:iam::123456789012:role/role1 (production)",
+            role_label(&create_role("role1"), &accounts)
+        );
+        assert_eq!(
+            "arn:aws:iam::987654321098:role/role1",
+            role_label(&unmapped, &accounts)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn names_filter_and_examples_when_filtering_out_all_roles() {
+        let roles = create_roles(&["role1", "role2", "role3", "role4", "role5"]);
+        let options = RoleOptions {
+            filter: Some("prod".to_string()),
+            ..RoleOptions::default()
+        };
+
+        assert_eq!(
+            "No granted role matches the role filter prod, granted roles are e.g. \
+             arn:aws:iam::123456789012:role/role1, arn:aws:iam::123456789012:role/role2, \
+             arn:aws:iam::123456789012:role/role3, and 2 more",
+            select_role(roles, &options).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn names_valid_range_for_out_of_range_index() {
+        assert_eq!(
+            "Role index 3 is out of range, valid indices are 0 to 1",
+            select_index(3, 2, "Role").unwrap_err().to_string()
+        );
+        assert_eq!(
+            "No factor available to select",
+            select_index(0, 0, "Factor").unwrap_err().to_string()
+        );
+    }
+
+    fn create_roles(names: &[&str]) -> HashSet<AwsRole> {
+        names.iter().map(|name| create_role(name)).collect()
+    }
+
+    fn create_role(name: &str) -> AwsRole {
+        AwsRole {
+            provider_arn: "arn:aws:iam::123456789012:saml-provider/okta-idp".to_string(),
+            role_arn: format!("arn:aws:iam::123456789012:role/{}", name),
+        }
+    }
 }
@@ -0,0 +1,202 @@
+use crate::config::CrowbarConfig;
+use crate::credentials::aws::{self as CredentialsProvider, FetchOptions, SECONDS_TO_EXPIRATION};
+use crate::ui;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+// Keeps very short sessions from being refreshed in a tight loop, as they
+// count as expired right away
+const MIN_REFRESH_INTERVAL_SECONDS: i64 = 60;
+// How long before a kept Okta session ends it's extended, leaving time for a
+// slow IdP
+const SESSION_MARGIN_SECONDS: i64 = 300;
+const SLEEP_STEP: Duration = Duration::from_secs(1);
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+static SLEEPING: AtomicBool = AtomicBool::new(false);
+static STOPPED: AtomicBool = AtomicBool::new(false);
+
+/// When the next refresh is due and why
+#[derive(Debug, PartialEq)]
+enum Refresh {
+    /// The credentials are about to expire
+    Credentials(DateTime<Utc>),
+    /// The Okta session kept with `--keep-session` ends first. Refreshing
+    /// while it lasts extends it, instead of asking for MFA again later
+    Session(DateTime<Utc>),
+}
+
+impl Refresh {
+    fn at(&self) -> DateTime<Utc> {
+        match self {
+            Refresh::Credentials(at) | Refresh::Session(at) => *at,
+        }
+    }
+}
+
+/// Keeps the cached credentials of a profile valid until interrupted,
+/// refreshing them shortly before they expire
+pub fn watch(profile: String, crowbar_config: CrowbarConfig, options: &FetchOptions) -> Result<()> {
+    handle_signals()?;
+
+    let mut options = options.clone();
+
+    while !is_stopped() {
+        let result = CredentialsProvider::fetch_aws_credentials(
+            profile.clone(),
+            crowbar_config.clone(),
+            &options,
+        )?;
+        // Re-entering the password once is enough
+        options.force = false;
+
+        let expiration = result
+            .credentials
+            .expiration
+            .with_context(|| "The credentials are missing their expiration")?;
+        let now = Utc::now();
+        let refresh = next_refresh(&expiration, result.session_expires_at, now)?;
+
+        ui::print_success(&match (&refresh, result.session_expires_at) {
+            (Refresh::Session(at), Some(session_expires_at)) => format!(
+                "Credentials of profile {} are valid until {}, refreshing them at {} to extend the Okta session ending at {}",
+                profile, expiration, at, session_expires_at
+            ),
+            (refresh, _) => format!(
+                "Credentials of profile {} are valid until {}, refreshing them at {}",
+                profile,
+                expiration,
+                refresh.at()
+            ),
+        });
+
+        // Cached credentials are still valid when the session is extended
+        options.renew = matches!(refresh, Refresh::Session(_));
+        sleep_until(refresh.at());
+    }
+
+    eprintln!("Stopped watching profile {}", profile);
+
+    Ok(())
+}
+
+fn next_refresh(
+    expiration: &str,
+    session_expires_at: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+) -> Result<Refresh> {
+    let expiration = CredentialsProvider::parse_expiration(expiration)?;
+    let earliest = now + ChronoDuration::seconds(MIN_REFRESH_INTERVAL_SECONDS);
+
+    // Credentials count as expired from this point on, see AwsCredentials::is_expired
+    let refresh_at = expiration - ChronoDuration::seconds(SECONDS_TO_EXPIRATION);
+
+    match session_expires_at.map(|at| at - ChronoDuration::seconds(SESSION_MARGIN_SECONDS)) {
+        Some(extend_at) if extend_at < refresh_at => Ok(Refresh::Session(extend_at.max(earliest))),
+        _ => Ok(Refresh::Credentials(refresh_at.max(earliest))),
+    }
+}
+
+fn sleep_until(time: DateTime<Utc>) {
+    idle(|| {
+        while !is_stopped() && Utc::now() < time {
+            thread::sleep(SLEEP_STEP);
+        }
+    })
+}
+
+/// Whether an interruption asked a long-running command, e.g. `watch` or
+/// `serve`, to stop
+pub fn is_stopped() -> bool {
+    STOPPED.load(Ordering::SeqCst)
+}
+
+/// Runs `f`, during which an interruption only marks the command as stopped
+pub fn idle<T, F: FnOnce() -> T>(f: F) -> T {
+    SLEEPING.store(true, Ordering::SeqCst);
+    let result = f();
+    SLEEPING.store(false, Ordering::SeqCst);
+
+    result
+}
+
+// While idle the loop is stopped cleanly. Anywhere else, e.g. at a password
+// or MFA prompt, an interruption exits right away as usual
+pub fn handle_signals() -> Result<()> {
+    for signal in &[libc::SIGINT, libc::SIGTERM] {
+        unsafe {
+            signal_hook_registry::register(*signal, || {
+                STOPPED.store(true, Ordering::SeqCst);
+
+                if !SLEEPING.load(Ordering::SeqCst) {
+                    libc::_exit(INTERRUPTED_EXIT_CODE);
+                }
+            })
+        }
+        .map_err(|e| anyhow!("Unable to handle signal {}: {}", signal, e))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn refreshes_before_expiration() -> Result<()> {
+        let now = time("2038-01-01T10:00:00Z");
+
+        assert_eq!(
+            Refresh::Credentials(time("2038-01-01T10:45:00Z")),
+            next_refresh("2038-01-01T11:00:00Z", None, now)?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn waits_between_refreshes_of_short_sessions() -> Result<()> {
+        let now = time("2038-01-01T10:00:00Z");
+
+        assert_eq!(
+            Refresh::Credentials(time("2038-01-01T10:01:00Z")),
+            next_refresh("2038-01-01T10:15:00Z", None, now)?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn refreshes_before_session_ends_if_earlier() -> Result<()> {
+        let now = time("2038-01-01T10:00:00Z");
+
+        assert_eq!(
+            Refresh::Session(time("2038-01-01T10:25:00Z")),
+            next_refresh(
+                "2038-01-01T11:00:00Z",
+                Some(time("2038-01-01T10:30:00Z")),
+                now
+            )?
+        );
+        // A session outlasting the credentials is extended along with them
+        assert_eq!(
+            Refresh::Credentials(time("2038-01-01T10:45:00Z")),
+            next_refresh(
+                "2038-01-01T11:00:00Z",
+                Some(time("2038-01-01T12:00:00Z")),
+                now
+            )?
+        );
+
+        Ok(())
+    }
+
+    fn time(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+}
@@ -0,0 +1,37 @@
+extern crate crowbar;
+
+mod common;
+
+use anyhow::Result;
+use crowbar::config::aws::{AwsConfig, AWS_CONFIG_FILE, PROFILE_KEY};
+use std::env;
+use std::fs;
+use tempfile::tempdir;
+
+#[test]
+fn creates_missing_config_only_user_may_read() -> Result<()> {
+    let dir = tempdir()?;
+    let location = dir.path().join("home/.aws/config");
+    let app_profile = common::short_app_profile_a();
+
+    env::set_var(AWS_CONFIG_FILE, &location);
+
+    let config = AwsConfig::new()?;
+    fs::remove_dir_all(dir.path().join("home"))?;
+    config.add_profile(&app_profile)?.write()?;
+
+    let section = format!("profile {}", app_profile.name);
+    assert!(AwsConfig::new()?
+        .profiles
+        .get_from(Some(section), PROFILE_KEY)
+        .is_some());
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(0o600, fs::metadata(&location)?.permissions().mode() & 0o777);
+    }
+
+    env::remove_var(AWS_CONFIG_FILE);
+    Ok(())
+}
@@ -0,0 +1,46 @@
+extern crate crowbar;
+
+mod common;
+
+use anyhow::Result;
+use crowbar::config::aws::{self, AwsConfig, AWS_CONFIG_FILE, PROFILE_KEY, REGION_KEY};
+use std::env;
+use std::io::Write;
+use tempfile::NamedTempFile;
+
+#[test]
+fn installs_credential_process_keeping_other_settings() -> Result<()> {
+    let mut file = NamedTempFile::new()?;
+    let section = "profile profile";
+
+    writeln!(
+        file,
+        "{}\n[profile other]\nregion=us-east-1",
+        common::long_aws_profile()
+    )?;
+    env::set_var(AWS_CONFIG_FILE, file.path());
+
+    AwsConfig::new()?.install_process("profile")?.write()?;
+
+    let profiles = AwsConfig::new()?.profiles;
+    assert_eq!(
+        Some("sh -c 'crowbar creds profile -p --output json 2> /dev/tty'"),
+        profiles.get_from(Some(section), PROFILE_KEY)
+    );
+    assert_eq!(
+        Some("eu-central-1"),
+        profiles.get_from(Some(section), REGION_KEY)
+    );
+    assert_eq!(
+        Some("us-east-1"),
+        profiles.get_from(Some("profile other"), REGION_KEY)
+    );
+
+    assert_eq!(
+        "[profile profile]\ncredential_process=sh -c 'crowbar creds profile -p --output json 2> /dev/tty'\n",
+        aws::process_snippet("profile")?
+    );
+
+    env::remove_var(AWS_CONFIG_FILE);
+    Ok(())
+}
@@ -0,0 +1,59 @@
+extern crate crowbar;
+
+mod common;
+
+use anyhow::Result;
+use crowbar::config::aws::{AwsConfig, AWS_CONFIG_FILE, PROFILE_KEY, REGION_KEY};
+use crowbar::config::CrowbarConfig;
+use std::env;
+use std::io::Write;
+use tempfile::NamedTempFile;
+
+#[test]
+fn writes_region_keeping_other_settings() -> Result<()> {
+    let crowbar_file = NamedTempFile::new()?;
+    let crowbar_location = crowbar_file.path().to_str().unwrap().to_owned();
+    let mut aws_file = NamedTempFile::new()?;
+    let app_profile = common::short_app_profile_a();
+    let section = format!("profile {}", app_profile.name);
+
+    writeln!(
+        aws_file,
+        "[{}]\nregion=us-east-1\noutput=json\n\n{}",
+        section,
+        common::long_aws_profile()
+    )?;
+
+    env::set_var(AWS_CONFIG_FILE, aws_file.path());
+
+    CrowbarConfig::with_location(Some(crowbar_location.clone()))
+        .add_profile(&app_profile)?
+        .write()?;
+    AwsConfig::new()?
+        .add_profile(&app_profile)?
+        .set_region(&app_profile.name, "eu-west-1")?
+        .write()?;
+
+    let crowbar_config = CrowbarConfig::with_location(Some(crowbar_location)).read()?;
+    let aws_config = AwsConfig::new()?;
+    let profiles = &aws_config.profiles;
+
+    assert_eq!(1, crowbar_config.profiles.len());
+    assert_eq!(app_profile.name, crowbar_config.profiles[0].name);
+    assert_eq!(
+        Some("eu-west-1"),
+        profiles.get_from(Some(section.clone()), REGION_KEY)
+    );
+    assert_eq!(
+        Some("json"),
+        profiles.get_from(Some(section.clone()), "output")
+    );
+    assert!(profiles.get_from(Some(section), PROFILE_KEY).is_some());
+    assert_eq!(
+        Some("eu-central-1"),
+        profiles.get_from(Some("profile profile"), REGION_KEY)
+    );
+
+    env::remove_var(AWS_CONFIG_FILE);
+    Ok(())
+}
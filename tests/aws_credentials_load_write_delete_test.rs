@@ -4,6 +4,7 @@ extern crate keyring;
 mod common;
 
 use anyhow::{anyhow, Result};
+use crowbar::credentials;
 use crowbar::credentials::aws;
 use crowbar::credentials::aws::AwsCredentials;
 use crowbar::credentials::Credential;
@@ -45,3 +46,20 @@ fn handles_credentials_with_keystore() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn cleans_cached_credentials() -> Result<()> {
+    let mut app_profile = common::short_app_profile_b();
+    app_profile.name = "profile_clean".to_string();
+
+    let _creds = common::create_credentials().write(&app_profile)?;
+    let _deleted = credentials::clean(&app_profile, false)?;
+
+    assert_eq!(
+        AwsCredentials::default(),
+        AwsCredentials::load(&app_profile)?
+    );
+    assert!(credentials::clean(&app_profile, false)?.is_empty());
+
+    Ok(())
+}
@@ -0,0 +1,41 @@
+#![cfg(unix)]
+
+extern crate crowbar;
+
+mod common;
+
+use anyhow::Result;
+use crowbar::config::CrowbarConfig;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use tempfile::tempdir;
+
+#[test]
+fn writes_config_only_user_may_read_despite_umask() -> Result<()> {
+    let dir = tempdir()?;
+    let created = dir.path().join("crowbar/crowbar.toml");
+    let existing = dir.path().join("existing.toml");
+    fs::write(&existing, "")?;
+    fs::set_permissions(&existing, fs::Permissions::from_mode(0o666))?;
+
+    let umask = unsafe { libc::umask(0o000) };
+
+    for location in &[&created, &existing] {
+        let location = location.to_str().unwrap().to_owned();
+        CrowbarConfig::with_location(Some(location.clone()))
+            .add_profile(&common::short_app_profile_a())?
+            .write()?;
+
+        assert_eq!(0o600, fs::metadata(&location)?.permissions().mode() & 0o777);
+        assert_eq!(
+            1,
+            CrowbarConfig::with_location(Some(location))
+                .read()?
+                .profiles
+                .len()
+        );
+    }
+
+    unsafe { libc::umask(umask) };
+    Ok(())
+}
@@ -0,0 +1,41 @@
+extern crate crowbar;
+
+mod common;
+
+use anyhow::Result;
+use crowbar::config::aws::{AwsConfig, AWS_CONFIG_FILE, PROFILE_KEY};
+use crowbar::config::CrowbarConfig;
+use std::env;
+use tempfile::tempdir;
+
+#[test]
+fn manages_profiles_without_home_given_explicit_locations() -> Result<()> {
+    let dir = tempdir()?;
+    let crowbar_location = dir.path().join("crowbar.toml");
+    let aws_location = dir.path().join("aws/config");
+    let app_profile = common::short_app_profile_a();
+
+    env::remove_var("HOME");
+    env::set_var(AWS_CONFIG_FILE, &aws_location);
+
+    let location = Some(crowbar_location.to_str().unwrap().to_owned());
+    CrowbarConfig::with_location(location.clone())
+        .read()?
+        .add_profile(&app_profile)?
+        .write()?;
+    AwsConfig::new()?.add_profile(&app_profile)?.write()?;
+
+    assert_eq!(
+        vec![app_profile.name.as_str()],
+        CrowbarConfig::with_location(location)
+            .read()?
+            .profile_names()
+    );
+    assert!(AwsConfig::new()?
+        .profiles
+        .get_from(Some(format!("profile {}", app_profile.name)), PROFILE_KEY)
+        .is_some());
+
+    env::remove_var(AWS_CONFIG_FILE);
+    Ok(())
+}